@@ -14,6 +14,10 @@ pub struct Config {
     pub auth_url: String,
     pub token_url: String,
     pub text2qti_path: String,
+    /// Largest package, in MB, that Brightspace accepts; `convert` warns
+    /// above it. Empty for no limit.
+    #[serde(default)]
+    pub upload_limit_mb: String,
 }
 
 fn get_config_path() -> Result<PathBuf> {
@@ -23,6 +27,14 @@ fn get_config_path() -> Result<PathBuf> {
     Ok(config_dir.join("brightspace-cli.yaml"))
 }
 
+fn build() -> Result<AppConfig> {
+    let config_file = get_config_path()?;
+    Ok(AppConfig::builder()
+        .add_source(File::new("brightspace-cli.yaml", FileFormat::Yaml).required(false))
+        .add_source(File::from(config_file).required(false))
+        .build()?)
+}
+
 impl Config {
     pub fn new() -> Self {
         Self {
@@ -34,18 +46,19 @@ impl Config {
             auth_url: "https://auth.brightspace.com/oauth2/auth".to_string(),
             token_url: "https://auth.brightspace.com/oauth2/token".to_string(),
             text2qti_path: "".to_string(),
+            upload_limit_mb: "".to_string(),
         }
     }
 
     pub fn load() -> Result<Self> {
-        let config_file = get_config_path()?;
-
-        let config = AppConfig::builder()
-            .add_source(File::new("brightspace-cli.yaml", FileFormat::Yaml).required(false))
-            .add_source(File::from(config_file).required(false))
-            .build()?;
+        Ok(build()?.try_deserialize()?)
+    }
 
-        Ok(config.try_deserialize()?)
+    /// The configured upload limit in bytes. Read on its own, so `convert`
+    /// works with a config file that only sets this key, or none at all.
+    pub fn upload_limit() -> Option<u64> {
+        let mb = build().ok()?.get_string("upload_limit_mb").ok()?;
+        mb.trim().parse::<f64>().ok().map(|mb| (mb * 1_000_000.0) as u64)
     }
 
     pub fn get(key: &str) -> Result<String> {
@@ -59,6 +72,7 @@ impl Config {
             "auth_url" => Ok(config.auth_url),
             "token_url" => Ok(config.token_url),
             "text2qti_path" => Ok(config.text2qti_path),
+            "upload_limit_mb" => Ok(config.upload_limit_mb),
             _ => Err(anyhow::anyhow!("Invalid config key")),
         }
     }
@@ -26,8 +26,65 @@ enum Commands {
     /// Manage authentication
     #[command(subcommand)]
     Auth(AuthCmd),
-    /// Convert a text file to a QTI zip file
-    Convert { path: PathBuf },
+    /// Convert quiz files to QTI zip files
+    Convert {
+        /// Quiz files, directories of them, or glob patterns
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Zip file to write for a single quiz, or directory for several
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Number of quizzes to convert in parallel (default: one per CPU)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Recompress PNG images and minify SVGs
+        #[arg(long)]
+        optimize_images: bool,
+        /// Scale PNG images wider than this many pixels down to it
+        #[arg(long)]
+        max_image_width: Option<u32>,
+        /// Run code blocks marked `verify` and check their answers (runs the code)
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Render a quiz file to a standalone HTML preview
+    Preview {
+        path: PathBuf,
+        /// Highlight the correct answers
+        #[arg(long)]
+        show_answers: bool,
+        /// Serve a live preview that reloads when the quiz or its images change
+        #[arg(long)]
+        serve: bool,
+        /// Port for the live preview server
+        #[arg(long, default_value_t = 8000)]
+        port: u16,
+    },
+    /// Render a quiz file as printable paper exams with answer keys
+    Print {
+        path: PathBuf,
+        /// Number of versions (A, B, C, ...) to print
+        #[arg(long, default_value_t = 1)]
+        versions: usize,
+        /// Seed for shuffling questions and choices in each version
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Export a quiz file to an editable Word document
+    Docx { path: PathBuf },
+    /// Audit a quiz file's generated content for WCAG 2.1 AA issues
+    Audit { path: PathBuf },
+    /// Check quiz files for structural and pedagogical problems
+    Lint {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = qti::lint::LintFormat::Text)]
+        format: qti::lint::LintFormat,
+        /// Warn when a question prompt is longer than this many words
+        #[arg(long, default_value_t = 150)]
+        max_stem_words: usize,
+    },
 }
 
 #[derive(Subcommand)]
@@ -100,11 +157,49 @@ async fn main() -> Result<()> {
             println!("Display Name: {}", user.display_name);
             println!("Unique Identifier: {}", user.unique_identifier);
         }
-        Commands::Convert { path } => {
-            if let Err(e) = qti::convert_to_qti(&path) {
-                eprintln!("Error converting quiz: {}", e);
+        Commands::Convert { paths, output, jobs, optimize_images, max_image_width, verify } => {
+            let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            let package = qti::generator::PackageOptions {
+                optimize_images: *optimize_images,
+                max_image_width: *max_image_width,
+                size_limit: config::Config::upload_limit(),
+            };
+            let options = qti::batch::BatchOptions { output: output.clone(), jobs, verify: *verify, package };
+            let report = qti::batch::convert_all(paths, &options)?;
+            print!("{}", qti::batch::format_summary(&report));
+            if report.failure_count() > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::Preview { path, show_answers, serve, port } => {
+            let options = qti::preview::PreviewOptions { show_answers: *show_answers };
+            if *serve {
+                qti::serve::serve_preview(path, options, *port).await?;
             } else {
-                println!("Conversion successful.");
+                qti::preview_quiz(path, &options).map_err(|e| anyhow::anyhow!("Could not generate preview: {}", e))?;
+            }
+        }
+        Commands::Print { path, versions, seed } => {
+            let options = qti::print::PrintOptions { versions: *versions, seed: *seed };
+            qti::print_quiz(path, &options).map_err(|e| anyhow::anyhow!("Could not generate printable exam: {}", e))?;
+        }
+        Commands::Docx { path } => {
+            qti::export_docx(path).map_err(|e| anyhow::anyhow!("Could not export Word document: {}", e))?;
+        }
+        Commands::Audit { path } => {
+            let quiz = qti::load_quiz(path)?;
+            let audits = qti::a11y::audit_quiz(&quiz)?;
+            print!("{}", qti::a11y::format_audit(&audits, false));
+            if qti::a11y::has_errors(&audits) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Lint { paths, format, max_stem_words } => {
+            let options = qti::lint::LintOptions { max_stem_words: *max_stem_words };
+            let report = qti::lint::lint_files(paths, &options)?;
+            println!("{}", qti::lint::format_report(&report, *format)?);
+            if report.error_count() > 0 {
+                std::process::exit(1);
             }
         }
     }
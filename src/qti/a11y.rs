@@ -0,0 +1,400 @@
+use anyhow::Result;
+use crate::qti::diagnostics::Severity;
+use crate::qti::generator::{generate_items, ContentOptions, MUSIC_ALT};
+use crate::qti::highlight::stylesheet;
+use crate::qti::lexer::{lex_content, Token};
+use crate::qti::model::{Question, Quiz};
+use quick_xml::events::Event;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+// Checks the XHTML that the generator actually emits for each question
+// against WCAG 2.1 AA, so the audit follows whatever the generator does
+// rather than re-deriving it from the source.
+
+pub(crate) const MIN_CONTRAST: f64 = 4.5;
+const MAX_ALT_LENGTH: usize = 150;
+
+const GENERIC_ALT: &[&str] = &[
+    "image", "img", "picture", "photo", "figure", "graph", "chart", "diagram", "logo", "icon", "screenshot", "graphic",
+];
+const REDUNDANT_ALT_PREFIXES: &[&str] = &["image of", "picture of", "photo of", "graphic of", "an image of", "a picture of"];
+const COLOR_WORDS: &[&str] = &["red", "green", "blue", "orange", "purple", "yellow", "highlighted", "colored", "coloured"];
+
+pub struct A11yIssue {
+    pub severity: Severity,
+    /// WCAG 2.1 success criterion, e.g. "1.1.1".
+    pub criterion: &'static str,
+    pub message: String,
+}
+
+pub struct QuestionAudit {
+    pub number: usize,
+    pub line: usize,
+    /// The included file `line` is in, if the question was included.
+    pub source: Option<PathBuf>,
+    pub title: String,
+    pub issues: Vec<A11yIssue>,
+}
+
+pub fn audit_quiz(quiz: &Quiz) -> Result<Vec<QuestionAudit>> {
+    audit_items(quiz, &generate_items(quiz, &ContentOptions::for_quiz(quiz))?)
+}
+
+/// Audits the `assessmentItem`s `generate_items` wrote for the quiz.
+pub(crate) fn audit_items(quiz: &Quiz, items: &[String]) -> Result<Vec<QuestionAudit>> {
+    let rules = parse_stylesheet(&stylesheet(&quiz.code)?);
+    quiz.questions
+        .iter()
+        .zip(items)
+        .enumerate()
+        .map(|(i, (question, xhtml))| {
+            Ok(QuestionAudit {
+                number: i + 1,
+                line: question.line,
+                source: question.source.clone(),
+                title: question.title.clone(),
+                issues: audit_question(question, xhtml, &rules)?,
+            })
+        })
+        .collect()
+}
+
+fn audit_question(question: &Question, xhtml: &str, rules: &[StyleRule]) -> Result<Vec<A11yIssue>> {
+    let mut issues = Vec::new();
+    let mut has_code = false;
+
+    let texts = std::iter::once(&question.prompt).chain(question.answers.iter().map(|a| &a.text));
+    for text in texts {
+        for token in lex_content(text) {
+            if let Token::Code { .. } = token {
+                has_code = true;
+            }
+        }
+    }
+    audit_xhtml(xhtml, rules, &mut issues)?;
+
+    if has_code {
+        let prompt = question.prompt.to_lowercase();
+        let words: HashSet<&str> = prompt.split(|c: char| !c.is_alphabetic()).collect();
+        if let Some(word) = COLOR_WORDS.iter().find(|w| words.contains(*w)) {
+            issues.push(A11yIssue {
+                severity: Severity::Warning,
+                criterion: "1.4.1",
+                message: format!(
+                    "prompt refers to \"{}\" next to syntax-highlighted code; color must not be the only way to identify what is meant",
+                    word
+                ),
+            });
+        }
+    }
+
+    Ok(issues)
+}
+
+fn audit_xhtml(xhtml: &str, rules: &[StyleRule], issues: &mut Vec<A11yIssue>) -> Result<()> {
+    let mut reader = quick_xml::Reader::from_str(xhtml);
+    let mut seen_colors = HashSet::new();
+    let mut backgrounds: Vec<String> = Vec::new();
+    // The classes of each open element, for stylesheet rules that apply
+    // inside other elements.
+    let mut ancestors: Vec<Vec<String>> = Vec::new();
+    let mut unlabelled_math = 0;
+    let (mut tables, mut captions) = (0, 0);
+
+    loop {
+        let (element, is_start) = match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => (e, true),
+            Event::Empty(e) => (e, false),
+            Event::End(e) => {
+                ancestors.pop();
+                if e.name().as_ref() == b"pre" {
+                    backgrounds.pop();
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        let attr = |key: &[u8]| -> Option<String> {
+            element
+                .attributes()
+                .flatten()
+                .find(|a| a.key.as_ref() == key)
+                .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+        };
+        let classes: Vec<String> = attr(b"class").unwrap_or_default().split_whitespace().map(str::to_string).collect();
+
+        match element.name().as_ref() {
+            // Math images are hidden from screen readers, which read the
+            // MathML beside them instead.
+            b"img" if attr(b"aria-hidden").as_deref() == Some("true") => {}
+            b"img" => {
+                let alt = attr(b"alt").unwrap_or_default();
+                let src = attr(b"src").unwrap_or_default();
+                if alt == MUSIC_ALT {
+                    issues.push(A11yIssue {
+                        severity: Severity::Warning,
+                        criterion: "1.1.1",
+                        message: format!(
+                            "music notation has the generic alt text \"{}\"; give the tune a title (`T:` in ABC, `work-title` in MusicXML) or describe the passage in the prompt",
+                            MUSIC_ALT
+                        ),
+                    });
+                } else {
+                    audit_alt_text(&alt, &src, issues);
+                }
+            }
+            b"math" if attr(b"alttext").is_none_or(|a| a.trim().is_empty()) => {
+                unlabelled_math += 1;
+            }
+            b"table" if is_start => tables += 1,
+            b"caption" if is_start => captions += 1,
+            b"pre" if is_start => {
+                let background = element_color(attr(b"style"), &classes, &ancestors, rules, "background-color");
+                backgrounds.push(background.unwrap_or_else(|| "#ffffff".to_string()));
+            }
+            b"span" => {
+                let color = element_color(attr(b"style"), &classes, &ancestors, rules, "color");
+                if let (Some(color), Some(background)) = (color, backgrounds.last()) {
+                    audit_contrast(&color, background, &mut seen_colors, issues);
+                }
+            }
+            _ => {}
+        }
+        if is_start {
+            ancestors.push(classes);
+        }
+    }
+
+    if tables > captions {
+        issues.push(A11yIssue {
+            severity: Severity::Warning,
+            criterion: "1.3.1",
+            message: "table has no caption; add a `Table: ...` line so screen reader users know what it shows".to_string(),
+        });
+    }
+    if unlabelled_math > 0 {
+        issues.push(A11yIssue {
+            severity: Severity::Error,
+            criterion: "1.1.1",
+            message: format!(
+                "{} math expression{} no alttext; screen readers without MathML support will skip {}",
+                unlabelled_math,
+                if unlabelled_math == 1 { " has" } else { "s have" },
+                if unlabelled_math == 1 { "it" } else { "them" }
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn audit_alt_text(alt: &str, src: &str, issues: &mut Vec<A11yIssue>) {
+    let alt_trimmed = alt.trim();
+    let lower = alt_trimmed.to_lowercase();
+    let file_name = if src.starts_with("data:") { "embedded image" } else { src.rsplit('/').next().unwrap_or(src) };
+    let file_like = [".png", ".jpg", ".jpeg", ".gif", ".svg", ".webp"].iter().any(|ext| lower.ends_with(ext));
+
+    let (severity, message) = if alt_trimmed.is_empty() {
+        (
+            Severity::Warning,
+            format!("image {} has empty alt text, which marks it decorative; quiz images usually carry information needed to answer", file_name),
+        )
+    } else if file_like || alt_trimmed == file_name {
+        (Severity::Error, format!("alt text \"{}\" is a file name, not a description", alt_trimmed))
+    } else if GENERIC_ALT.contains(&lower.as_str()) {
+        (Severity::Error, format!("alt text \"{}\" does not describe the image content", alt_trimmed))
+    } else if let Some(prefix) = REDUNDANT_ALT_PREFIXES.iter().find(|p| lower.starts_with(*p)) {
+        (
+            Severity::Warning,
+            format!("alt text starts with \"{}\"; screen readers already announce images", prefix),
+        )
+    } else if alt_trimmed.chars().count() > MAX_ALT_LENGTH {
+        (
+            Severity::Warning,
+            format!(
+                "alt text for {} is {} characters; put long descriptions in the prompt text instead",
+                file_name,
+                alt_trimmed.chars().count()
+            ),
+        )
+    } else {
+        return;
+    };
+
+    issues.push(A11yIssue { severity, criterion: "1.1.1", message });
+}
+
+fn audit_contrast(color: &str, background: &str, seen_colors: &mut HashSet<String>, issues: &mut Vec<A11yIssue>) {
+    if !seen_colors.insert(format!("{}/{}", color, background)) {
+        return;
+    }
+    let (Some(fg), Some(bg)) = (parse_hex(color), parse_hex(background)) else {
+        return;
+    };
+    let ratio = contrast_ratio(fg, bg);
+    if ratio < MIN_CONTRAST {
+        issues.push(A11yIssue {
+            severity: Severity::Error,
+            criterion: "1.4.3",
+            message: format!(
+                "code color {} on {} has contrast {:.2}:1 (AA requires {}:1)",
+                color, background, ratio, MIN_CONTRAST
+            ),
+        });
+    }
+}
+
+/// A rule of the code stylesheet: the classes an element and, in order, its
+/// ancestors need for it to apply, and the colours it sets.
+struct StyleRule {
+    selector: Vec<Vec<String>>,
+    color: Option<String>,
+    background: Option<String>,
+}
+
+impl StyleRule {
+    fn matches(&self, classes: &[String], ancestors: &[Vec<String>]) -> bool {
+        let has_all = |compound: &Vec<String>, classes: &[String]| compound.iter().all(|c| classes.contains(c));
+        let Some((last, outer)) = self.selector.split_last() else { return false };
+        if !has_all(last, classes) {
+            return false;
+        }
+        // Each outer part must match some ancestor further out than the
+        // one the part after it matched.
+        let mut ancestors = ancestors.iter().rev();
+        outer.iter().rev().all(|compound| ancestors.any(|classes| has_all(compound, classes)))
+    }
+
+    fn specificity(&self) -> usize {
+        self.selector.iter().map(Vec::len).sum()
+    }
+}
+
+/// Reads the rules of the class-mode code stylesheet. Selectors are taken to
+/// be classes joined by descendant combinators, which is all syntect writes.
+fn parse_stylesheet(css: &str) -> Vec<StyleRule> {
+    let mut css = css.to_string();
+    while let Some(start) = css.find("/*") {
+        let end = css[start..].find("*/").map_or(css.len(), |end| start + end + 2);
+        css.replace_range(start..end, "");
+    }
+    let mut rules = Vec::new();
+    for block in css.split('}') {
+        let Some((selectors, body)) = block.split_once('{') else { continue };
+        let color = style_color(body, "color");
+        let background = style_color(body, "background-color");
+        if color.is_none() && background.is_none() {
+            continue;
+        }
+        for selector in selectors.split(',') {
+            let selector: Vec<Vec<String>> = selector
+                .split_whitespace()
+                .map(|compound| compound.split('.').filter(|c| !c.is_empty()).map(str::to_string).collect())
+                .collect();
+            if selector.is_empty() {
+                continue;
+            }
+            rules.push(StyleRule {
+                selector,
+                color: color.clone(),
+                background: background.clone(),
+            });
+        }
+    }
+    rules
+}
+
+/// The colour an element gets for `property`: from its inline style, or
+/// from the most specific stylesheet rule that matches it, the later one on
+/// a tie, as in CSS.
+fn element_color(
+    style: Option<String>,
+    classes: &[String],
+    ancestors: &[Vec<String>],
+    rules: &[StyleRule],
+    property: &str,
+) -> Option<String> {
+    if let Some(color) = style.and_then(|style| style_color(&style, property)) {
+        return Some(color);
+    }
+    if classes.is_empty() {
+        return None;
+    }
+    rules
+        .iter()
+        .filter(|rule| rule.matches(classes, ancestors))
+        .filter_map(|rule| {
+            let color = if property == "color" { &rule.color } else { &rule.background };
+            color.as_ref().map(|color| (rule.specificity(), color))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, color)| color.clone())
+}
+
+/// Extracts a `#rrggbb` value for `property` from an inline style.
+fn style_color(style: &str, property: &str) -> Option<String> {
+    style.split(';').find_map(|decl| {
+        let (key, value) = decl.split_once(':')?;
+        (key.trim() == property).then(|| value.trim().to_lowercase())
+    })
+}
+
+fn parse_hex(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() < 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn relative_luminance((r, g, b): (u8, u8, u8)) -> f64 {
+    let linear = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.03928 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+}
+
+pub(crate) fn contrast_ratio(a: (u8, u8, u8), b: (u8, u8, u8)) -> f64 {
+    let (la, lb) = (relative_luminance(a), relative_luminance(b));
+    let (light, dark) = if la > lb { (la, lb) } else { (lb, la) };
+    (light + 0.05) / (dark + 0.05)
+}
+
+/// Formats the audit as a per-question report. With `only_issues`, questions
+/// that passed are left out.
+pub fn format_audit(audits: &[QuestionAudit], only_issues: bool) -> String {
+    let mut out = String::new();
+    for audit in audits {
+        if only_issues && audit.issues.is_empty() {
+            continue;
+        }
+        let location = match &audit.source {
+            Some(source) => format!("line {} of {}", audit.line, source.display()),
+            None => format!("line {}", audit.line),
+        };
+        let _ = writeln!(out, "Question {} ({}): {}", audit.number, location, audit.title);
+        if audit.issues.is_empty() {
+            let _ = writeln!(out, "  no issues found");
+        }
+        for issue in &audit.issues {
+            let label = match issue.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            let _ = writeln!(out, "  {} [WCAG {}]: {}", label, issue.criterion, issue.message);
+        }
+    }
+    out
+}
+
+pub fn has_errors(audits: &[QuestionAudit]) -> bool {
+    audits
+        .iter()
+        .flat_map(|a| &a.issues)
+        .any(|i| i.severity == Severity::Error)
+}
@@ -0,0 +1,937 @@
+use anyhow::Result;
+use crate::qti::music::key_alter;
+use crate::qti::preview::write_text_element;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::Writer;
+use std::collections::HashMap;
+use std::io::Cursor;
+
+// Converts a tune in ABC notation (abcnotation.com, standard 2.1) to MusicXML
+// for the engraver in `music`. It reads the fields that shape the notation
+// (T, M, L, K and V) and the notes, rests, chords, accidentals, lengths,
+// broken rhythms, tuplets, ties and bar lines of the first voice. Beams
+// follow ABC's rule: notes shorter than a quarter written without spaces
+// between them are beamed together. Decorations, chord symbols, grace notes,
+// slurs and lyrics are skipped.
+
+/// Divisions of a quarter note in the MusicXML written, chosen so the
+/// common tuplets come out whole.
+const DIVISIONS: u64 = 480;
+
+/// Converts ABC notation to a score-partwise MusicXML document.
+pub(crate) fn abc_to_musicxml(abc: &str) -> Result<String> {
+    let tune = parse_tune(abc)?;
+    write_musicxml(&tune)
+}
+
+/// A length as a fraction of a whole note.
+#[derive(Clone, Copy, PartialEq)]
+struct Fraction {
+    num: u64,
+    den: u64,
+}
+
+impl Fraction {
+    fn new(num: u64, den: u64) -> Self {
+        let divisor = gcd(num, den).max(1);
+        Self {
+            num: num / divisor,
+            den: den / divisor,
+        }
+    }
+
+    fn mul(self, other: Fraction) -> Self {
+        Fraction::new(self.num * other.num, self.den * other.den)
+    }
+
+    fn sub(self, other: Fraction) -> Self {
+        Fraction::new(self.num * other.den - other.num * self.den, self.den * other.den)
+    }
+
+    fn le(self, other: Fraction) -> bool {
+        self.num * other.den <= other.num * self.den
+    }
+
+    /// The length in MusicXML divisions.
+    fn divisions(self) -> u64 {
+        (self.num * 4 * DIVISIONS + self.den / 2) / self.den
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// A length a single note can show: its type, dots and flags.
+struct NoteValue {
+    length: Fraction,
+    kind: &'static str,
+    dots: u8,
+    flags: u8,
+}
+
+/// Every undotted, dotted and double-dotted note value, longest first.
+fn note_values() -> Vec<NoteValue> {
+    const TYPES: [(&str, u64, u64, u8); 8] = [
+        ("breve", 2, 1, 0),
+        ("whole", 1, 1, 0),
+        ("half", 1, 2, 0),
+        ("quarter", 1, 4, 0),
+        ("eighth", 1, 8, 1),
+        ("16th", 1, 16, 2),
+        ("32nd", 1, 32, 3),
+        ("64th", 1, 64, 4),
+    ];
+    let mut values: Vec<NoteValue> = TYPES
+        .iter()
+        .flat_map(|&(kind, num, den, flags)| {
+            (0..3u8).map(move |dots| NoteValue {
+                // Each dot adds half the previous length: 3/2, then 7/4.
+                length: Fraction::new(num * ((2 << dots) - 1), den << dots),
+                kind,
+                dots,
+                flags,
+            })
+        })
+        .collect();
+    values.sort_by(|a, b| (b.length.num * a.length.den).cmp(&(a.length.num * b.length.den)));
+    values
+}
+
+/// Splits a length into note values to be tied together, longest first.
+fn split_length(mut length: Fraction) -> Vec<NoteValue> {
+    let mut pieces = Vec::new();
+    while length.num > 0 {
+        let Some(value) = note_values().into_iter().find(|v| v.length.le(length)) else {
+            break;
+        };
+        length = length.sub(value.length);
+        pieces.push(value);
+    }
+    pieces
+}
+
+enum Meter {
+    Numbers(String, String),
+    Common,
+    Cut,
+}
+
+struct Key {
+    fifths: i32,
+    mode: &'static str,
+}
+
+#[derive(Clone, Copy)]
+struct Clef {
+    sign: &'static str,
+    line: u8,
+}
+
+const TREBLE: Clef = Clef { sign: "G", line: 2 };
+
+#[derive(Clone, Copy)]
+struct Pitch {
+    /// 0 for C up to 6 for B.
+    step: i32,
+    octave: i32,
+    alter: i32,
+    accidental: Option<&'static str>,
+}
+
+struct Note {
+    /// Empty for rests.
+    pitches: Vec<Pitch>,
+    whole_measure: bool,
+    /// Left out for whole-measure rests.
+    value: Option<NoteValue>,
+    duration: u64,
+    tie_start: bool,
+    tie_stop: bool,
+    /// Actual and normal notes of a tuplet.
+    tuplet: Option<(u64, u64)>,
+    /// Whether the note is beamed to the one before it.
+    joined: bool,
+    beams: Vec<&'static str>,
+}
+
+impl Note {
+    fn flags(&self) -> u8 {
+        match &self.value {
+            Some(value) if !self.pitches.is_empty() => value.flags,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Measure {
+    key: Option<Key>,
+    meter: Option<Meter>,
+    clef: Option<Clef>,
+    notes: Vec<Note>,
+    forward_repeat: bool,
+    /// The right bar line's style, and whether it ends a repeat.
+    barline: Option<(&'static str, bool)>,
+}
+
+struct Tune {
+    title: Option<String>,
+    measures: Vec<Measure>,
+}
+
+fn parse_tune(abc: &str) -> Result<Tune> {
+    let mut parser = TuneParser::new();
+    let mut in_body = false;
+    for line in abc.lines() {
+        let line = line.split('%').next().unwrap_or_default();
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            // A blank line ends the tune.
+            if in_body {
+                break;
+            }
+            continue;
+        }
+        let mut chars = trimmed.chars();
+        if let (Some(field), Some(':')) = (chars.next(), chars.next())
+            && field.is_ascii_alphabetic()
+        {
+            parser.field(field, trimmed[2..].trim(), in_body)?;
+            in_body |= field == 'K';
+            continue;
+        }
+        in_body = true;
+        if parser.voice_active {
+            parser.music(line)?;
+        }
+    }
+    parser.finish()
+}
+
+struct TuneParser {
+    title: Option<String>,
+    measures: Vec<Measure>,
+    current: Measure,
+    unit: Option<Fraction>,
+    /// The length of a measure, which also sets the default unit length.
+    measure_length: Fraction,
+    fifths: i32,
+    /// Accidentals written earlier in the measure, by step and octave.
+    accidentals: HashMap<(i32, i32), i32>,
+    /// Factor a broken rhythm applies to the next note.
+    broken: Option<Fraction>,
+    /// A tuplet's actual and normal notes, and how many notes it has left.
+    tuplet: Option<(u64, u64, u32)>,
+    /// Whether the last note can be beamed to the next.
+    joined: bool,
+    forward_repeat: bool,
+    first_voice: Option<String>,
+    voice_active: bool,
+    /// Set after `&` until the next bar line; overlaid voices are skipped.
+    in_overlay: bool,
+}
+
+impl TuneParser {
+    fn new() -> Self {
+        Self {
+            title: None,
+            measures: Vec::new(),
+            current: Measure {
+                key: Some(Key { fifths: 0, mode: "major" }),
+                clef: Some(TREBLE),
+                ..Measure::default()
+            },
+            unit: None,
+            measure_length: Fraction::new(1, 1),
+            fifths: 0,
+            accidentals: HashMap::new(),
+            broken: None,
+            tuplet: None,
+            joined: false,
+            forward_repeat: false,
+            first_voice: None,
+            voice_active: true,
+            in_overlay: false,
+        }
+    }
+
+    fn field(&mut self, field: char, value: &str, in_body: bool) -> Result<()> {
+        match field {
+            'T' if self.title.is_none() => self.title = Some(value.to_string()),
+            'V' => {
+                let id = value.split_whitespace().next().unwrap_or_default().to_string();
+                let first = self.first_voice.get_or_insert_with(|| id.clone());
+                let is_first = *first == id;
+                if in_body {
+                    self.voice_active = is_first;
+                }
+                if is_first && let Some(clef) = value.split_whitespace().skip(1).find_map(parse_clef) {
+                    self.current.clef = Some(clef);
+                }
+            }
+            _ if !self.voice_active => {}
+            'L' => {
+                let (num, den) = value.split_once('/').unwrap_or((value, "1"));
+                match (num.trim().parse(), den.trim().parse()) {
+                    (Ok(num), Ok(den)) if num > 0 && den > 0 => self.unit = Some(Fraction::new(num, den)),
+                    _ => anyhow::bail!("Invalid unit note length L:{}", value),
+                }
+            }
+            'M' => {
+                let (meter, length) = parse_meter(value)?;
+                self.current.meter = meter;
+                self.measure_length = length;
+            }
+            'K' => {
+                let key = parse_key(value)?;
+                self.fifths = key.fifths;
+                self.current.key = Some(key);
+                if let Some(clef) = value.split_whitespace().skip(1).find_map(parse_clef) {
+                    self.current.clef = Some(clef);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The unit note length, which defaults to a sixteenth for meters
+    /// under 3/4 and an eighth otherwise.
+    fn unit(&self) -> Fraction {
+        self.unit.unwrap_or_else(|| {
+            if self.measure_length.num * 4 < self.measure_length.den * 3 {
+                Fraction::new(1, 16)
+            } else {
+                Fraction::new(1, 8)
+            }
+        })
+    }
+
+    fn music(&mut self, line: &str) -> Result<()> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        // A new line of music starts a new beam.
+        self.joined = false;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if self.in_overlay && !matches!(c, '|' | ':') {
+                i += 1;
+                continue;
+            }
+            i = match c {
+                ' ' | '\t' => {
+                    self.joined = false;
+                    i + 1
+                }
+                '|' | ':' => self.bar(&chars, i),
+                '[' if next == Some('|') => self.bar(&chars, i),
+                // The number of a first or second ending.
+                '[' if next.is_some_and(|n| n.is_ascii_digit()) => skip_ending(&chars, i + 1),
+                '[' if next.is_some_and(|n| n.is_ascii_alphabetic()) && chars.get(i + 2) == Some(&':') => {
+                    let close = find(&chars, i, ']')?;
+                    let value: String = chars[i + 3..close].iter().collect();
+                    self.field(chars[i + 1], value.trim(), true)?;
+                    close + 1
+                }
+                '[' => self.chord(&chars, i)?,
+                '"' => find(&chars, i + 1, '"')? + 1,
+                '!' | '+' => find(&chars, i + 1, c)? + 1,
+                '{' => find(&chars, i + 1, '}')? + 1,
+                '(' if next.is_some_and(|n| n.is_ascii_digit()) => self.tuplet_start(&chars, i + 1),
+                '&' => {
+                    self.in_overlay = true;
+                    i + 1
+                }
+                // Slurs, ties, decorations, spacers and line continuations.
+                '(' | ')' | '-' | '.' | '~' | '`' | 'y' | '\\' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S' | 'T' | 'u' | 'v' => i + 1,
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let (pitch, end) = self.pitch(&chars, i)?;
+                    let (length, end) = parse_length(&chars, end);
+                    let end = self.note(vec![pitch], self.unit().mul(length), &chars, end)?;
+                    skip_tie(&chars, end)
+                }
+                'z' | 'x' => {
+                    let (length, end) = parse_length(&chars, i + 1);
+                    self.note(Vec::new(), self.unit().mul(length), &chars, end)?
+                }
+                'Z' | 'X' => self.measure_rests(&chars, i + 1),
+                _ => anyhow::bail!("Unexpected '{}' in ABC line \"{}\"", c, line.trim()),
+            };
+        }
+        Ok(())
+    }
+
+    /// Reads a bar line starting at `start`, closing the measure before it.
+    fn bar(&mut self, chars: &[char], start: usize) -> usize {
+        let mut end = start;
+        while end < chars.len()
+            && (matches!(chars[end], '|' | ':' | ']') || (chars[end] == '[' && chars.get(end + 1) == Some(&'|')))
+        {
+            end += 1;
+        }
+        let bar: String = chars[start..end].iter().collect();
+        let style = if bar.starts_with(':') {
+            Some(("light-heavy", true))
+        } else if bar.contains("||") {
+            Some(("light-light", false))
+        } else if bar.contains("|]") {
+            Some(("light-heavy", false))
+        } else if bar.starts_with("[|") {
+            Some(("heavy-light", false))
+        } else {
+            None
+        };
+        self.close_measure(style);
+        self.forward_repeat |= bar.ends_with(':');
+        skip_ending(chars, end)
+    }
+
+    fn close_measure(&mut self, barline: Option<(&'static str, bool)>) {
+        self.joined = false;
+        self.in_overlay = false;
+        self.accidentals.clear();
+        if self.current.notes.is_empty() {
+            return;
+        }
+        self.current.barline = barline;
+        self.current.forward_repeat = std::mem::take(&mut self.forward_repeat);
+        self.measures.push(std::mem::take(&mut self.current));
+    }
+
+    /// Reads a note's accidental, letter and octave marks.
+    fn pitch(&mut self, chars: &[char], start: usize) -> Result<(Pitch, usize)> {
+        let mut i = start;
+        let mut written = None;
+        while i < chars.len() && matches!(chars[i], '^' | '_' | '=') {
+            i += 1;
+        }
+        let marks: String = chars[start..i].iter().collect();
+        if !marks.is_empty() {
+            written = Some(match marks.as_str() {
+                "^" => (1, "sharp"),
+                "^^" => (2, "double-sharp"),
+                "_" => (-1, "flat"),
+                "__" => (-2, "flat-flat"),
+                "=" => (0, "natural"),
+                _ => anyhow::bail!("Invalid accidental '{}'", marks),
+            });
+        }
+        let Some(letter) = chars.get(i).filter(|c| matches!(c, 'A'..='G' | 'a'..='g')) else {
+            anyhow::bail!("Accidental '{}' is not followed by a note", marks);
+        };
+        let step = "CDEFGAB".find(letter.to_ascii_uppercase()).unwrap_or(0) as i32;
+        let mut octave = if letter.is_ascii_uppercase() { 4 } else { 5 };
+        i += 1;
+        while let Some(mark) = chars.get(i) {
+            match mark {
+                '\'' => octave += 1,
+                ',' => octave -= 1,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        // Accidentals hold for the rest of the measure.
+        let alter = match written {
+            Some((alter, _)) => {
+                self.accidentals.insert((step, octave), alter);
+                alter
+            }
+            None => self
+                .accidentals
+                .get(&(step, octave))
+                .copied()
+                .unwrap_or_else(|| key_alter(self.fifths, step)),
+        };
+        let pitch = Pitch {
+            step,
+            octave,
+            alter,
+            accidental: written.map(|(_, name)| name),
+        };
+        Ok((pitch, i))
+    }
+
+    /// Reads a chord such as `[CEG]2`, whose length is its first note's.
+    fn chord(&mut self, chars: &[char], start: usize) -> Result<usize> {
+        let close = find(chars, start, ']')?;
+        let mut pitches = Vec::new();
+        let mut length = None;
+        let mut i = start + 1;
+        while i < close {
+            match chars[i] {
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let (pitch, end) = self.pitch(chars, i)?;
+                    let (note_length, end) = parse_length(chars, end);
+                    length.get_or_insert(note_length);
+                    pitches.push(pitch);
+                    i = skip_tie(chars, end);
+                }
+                _ => i += 1,
+            }
+        }
+        if pitches.is_empty() {
+            anyhow::bail!("Empty chord in ABC");
+        }
+        let (outer, end) = parse_length(chars, close + 1);
+        let length = self.unit().mul(length.unwrap_or(Fraction::new(1, 1))).mul(outer);
+        let end = self.note(pitches, length, chars, end)?;
+        Ok(skip_tie(chars, end))
+    }
+
+    /// Reads `(p:q:r`: `r` notes in the time of `q`, written as `p`.
+    fn tuplet_start(&mut self, chars: &[char], start: usize) -> usize {
+        let mut numbers = [None; 3];
+        let mut i = start;
+        for (n, number) in numbers.iter_mut().enumerate() {
+            if n > 0 {
+                if chars.get(i) != Some(&':') {
+                    break;
+                }
+                i += 1;
+            }
+            let digits_end = (i..chars.len()).find(|&j| !chars[j].is_ascii_digit()).unwrap_or(chars.len());
+            *number = chars[i..digits_end].iter().collect::<String>().parse::<u64>().ok();
+            i = digits_end;
+        }
+        let p = numbers[0].unwrap_or(3).max(1);
+        let q = numbers[1].unwrap_or(match p {
+            3 | 6 => 2,
+            2 | 4 | 8 => 3,
+            _ => 2,
+        });
+        let r = numbers[2].unwrap_or(p) as u32;
+        self.tuplet = Some((p, q, r));
+        i
+    }
+
+    /// Reads `Z` or `Z4`: whole-measure rests for one or more measures.
+    fn measure_rests(&mut self, chars: &[char], start: usize) -> usize {
+        let end = (start..chars.len()).find(|&j| !chars[j].is_ascii_digit()).unwrap_or(chars.len());
+        let count: usize = chars[start..end].iter().collect::<String>().parse().unwrap_or(1);
+        for n in 0..count.max(1) {
+            if n > 0 {
+                self.close_measure(None);
+            }
+            self.current.notes.push(Note {
+                pitches: Vec::new(),
+                whole_measure: true,
+                value: None,
+                duration: self.measure_length.divisions(),
+                tie_start: false,
+                tie_stop: false,
+                tuplet: None,
+                joined: false,
+                beams: Vec::new(),
+            });
+        }
+        self.joined = false;
+        end
+    }
+
+    /// Adds a note, chord or rest (with no pitches) after applying any broken
+    /// rhythm that follows at `end`, and returns where the input continues.
+    fn note(&mut self, pitches: Vec<Pitch>, length: Fraction, chars: &[char], end: usize) -> Result<usize> {
+        let mut length = length;
+        if let Some(factor) = self.broken.take() {
+            length = length.mul(factor);
+        }
+        let mut end = end;
+        let arrows = chars[end..].iter().take_while(|&&c| c == '>' || c == '<').count() as u32;
+        if arrows > 0 {
+            // `>` dots this note and halves the next; `>>` double-dots it.
+            let long = Fraction::new((2 << arrows) - 1, 1 << arrows);
+            let short = Fraction::new(1, 1 << arrows);
+            let (this, next) = if chars[end] == '>' { (long, short) } else { (short, long) };
+            length = length.mul(this);
+            self.broken = Some(next);
+            end += arrows as usize;
+        }
+
+        let tuplet = self.tuplet.map(|(p, q, _)| (p, q));
+        if let Some((_, _, left)) = self.tuplet.as_mut() {
+            *left -= 1;
+            if *left == 0 {
+                self.tuplet = None;
+            }
+        }
+        let scale = tuplet.map_or(Fraction::new(1, 1), |(p, q)| Fraction::new(q, p));
+
+        let pieces = split_length(length);
+        if pieces.is_empty() {
+            anyhow::bail!("A note in the ABC is shorter than a 64th");
+        }
+        let rest = pitches.is_empty();
+        let count = pieces.len();
+        for (k, value) in pieces.into_iter().enumerate() {
+            let beamable = !rest && value.flags > 0;
+            // Only the first of several tied notes shows its accidentals.
+            let pitches = pitches
+                .iter()
+                .map(|pitch| Pitch {
+                    accidental: pitch.accidental.filter(|_| k == 0),
+                    ..*pitch
+                })
+                .collect();
+            self.current.notes.push(Note {
+                pitches,
+                whole_measure: false,
+                duration: value.length.mul(scale).divisions(),
+                value: Some(value),
+                tie_start: !rest && k + 1 < count,
+                tie_stop: !rest && k > 0,
+                tuplet,
+                joined: self.joined && beamable,
+                beams: Vec::new(),
+            });
+            self.joined = beamable;
+        }
+        Ok(end)
+    }
+
+    fn finish(mut self) -> Result<Tune> {
+        self.close_measure(None);
+        if self.measures.is_empty() {
+            anyhow::bail!("The ABC tune has no notes");
+        }
+        for measure in &mut self.measures {
+            beam(&mut measure.notes);
+        }
+        Ok(Tune {
+            title: self.title,
+            measures: self.measures,
+        })
+    }
+}
+
+/// Fills in the beams of each run of joined notes, with hooks for beams
+/// that have no neighbour to join.
+fn beam(notes: &mut [Note]) {
+    let mut start = 0;
+    while start < notes.len() {
+        let mut end = start + 1;
+        while end < notes.len() && notes[end].joined {
+            end += 1;
+        }
+        if end - start > 1 {
+            let levels = notes[start..end].iter().map(Note::flags).max().unwrap_or(0);
+            for level in 1..=levels {
+                for i in start..end {
+                    if notes[i].flags() < level {
+                        continue;
+                    }
+                    let before = i > start && notes[i - 1].flags() >= level;
+                    let after = i + 1 < end && notes[i + 1].flags() >= level;
+                    let value = match (before, after) {
+                        (false, true) => "begin",
+                        (true, true) => "continue",
+                        (true, false) => "end",
+                        (false, false) if i == start => "forward hook",
+                        (false, false) => "backward hook",
+                    };
+                    notes[i].beams.push(value);
+                }
+            }
+        }
+        start = end;
+    }
+}
+
+/// Reads a length multiplier: `2`, `3/2`, `/`, `//` or `/4`.
+fn parse_length(chars: &[char], start: usize) -> (Fraction, usize) {
+    let digits = |from: usize| (from..chars.len()).find(|&j| !chars[j].is_ascii_digit()).unwrap_or(chars.len());
+    let number = |from: usize, to: usize| chars[from..to].iter().collect::<String>().parse::<u64>().ok();
+    let mut i = digits(start);
+    let num = number(start, i).unwrap_or(1);
+    let mut den = 1;
+    while chars.get(i) == Some(&'/') {
+        let end = digits(i + 1);
+        den *= number(i + 1, end).unwrap_or(2);
+        i = end;
+    }
+    (Fraction::new(num.max(1), den.max(1)), i)
+}
+
+fn skip_tie(chars: &[char], start: usize) -> usize {
+    if chars.get(start) == Some(&'-') { start + 1 } else { start }
+}
+
+/// Skips the ending numbers after a bar line, as in `|1` or `:|2`.
+fn skip_ending(chars: &[char], start: usize) -> usize {
+    (start..chars.len())
+        .find(|&j| !(chars[j].is_ascii_digit() || chars[j] == ',' || chars[j] == '-'))
+        .unwrap_or(chars.len())
+}
+
+fn find(chars: &[char], start: usize, target: char) -> Result<usize> {
+    (start..chars.len())
+        .find(|&j| chars[j] == target)
+        .ok_or_else(|| anyhow::anyhow!("Missing closing '{}' in ABC", target))
+}
+
+/// Reads a meter and the length of its measures in whole notes.
+fn parse_meter(value: &str) -> Result<(Option<Meter>, Fraction)> {
+    match value {
+        "C" => Ok((Some(Meter::Common), Fraction::new(1, 1))),
+        "C|" => Ok((Some(Meter::Cut), Fraction::new(1, 1))),
+        "none" | "" => Ok((None, Fraction::new(1, 1))),
+        _ => {
+            let parsed = value.split_once('/').and_then(|(beats, beat_type)| {
+                let total: u64 = beats
+                    .split('+')
+                    .map(|b| b.trim().parse::<u64>().ok())
+                    .sum::<Option<u64>>()?;
+                let beat_type: u64 = beat_type.trim().parse().ok()?;
+                (total > 0 && beat_type > 0).then(|| (beats.trim().to_string(), beat_type, total))
+            });
+            let Some((beats, beat_type, total)) = parsed else {
+                anyhow::bail!("Invalid meter M:{}", value);
+            };
+            Ok((
+                Some(Meter::Numbers(beats, beat_type.to_string())),
+                Fraction::new(total, beat_type),
+            ))
+        }
+    }
+}
+
+/// Reads a key such as `G`, `F#m`, `Bb mix` or `none`.
+fn parse_key(value: &str) -> Result<Key> {
+    let mut words = value.split_whitespace();
+    let Some(tonic) = words.next() else {
+        return Ok(Key { fifths: 0, mode: "major" });
+    };
+    if tonic == "none" || tonic.eq_ignore_ascii_case("hp") || parse_clef(tonic).is_some() {
+        return Ok(Key { fifths: 0, mode: "major" });
+    }
+    let mut chars = tonic.chars();
+    let letter = chars.next().unwrap_or('C');
+    let Some(index) = "FCGDAEB".find(letter) else {
+        anyhow::bail!("Invalid key K:{}", value);
+    };
+    let mut fifths = index as i32 - 1;
+    let rest = chars.as_str();
+    let rest = if let Some(rest) = rest.strip_prefix('#') {
+        fifths += 7;
+        rest
+    } else if let Some(rest) = rest.strip_prefix('b') {
+        fifths -= 7;
+        rest
+    } else {
+        rest
+    };
+    // The mode may follow the tonic directly or as the next word.
+    let mode = if rest.is_empty() {
+        words.next().filter(|w| mode_shift(w).is_some()).unwrap_or("")
+    } else {
+        rest
+    };
+    let Some((shift, mode)) = mode_shift(mode) else {
+        anyhow::bail!("Invalid key K:{}", value);
+    };
+    fifths += shift;
+    if !(-7..=7).contains(&fifths) {
+        anyhow::bail!("Key K:{} needs more than seven sharps or flats", value);
+    }
+    Ok(Key { fifths, mode })
+}
+
+/// How many fifths a mode moves the key from major, and its MusicXML name.
+fn mode_shift(mode: &str) -> Option<(i32, &'static str)> {
+    let lower = mode.to_ascii_lowercase();
+    let prefix = lower.get(..3).unwrap_or(&lower);
+    match prefix {
+        "" | "maj" | "ion" => Some((0, "major")),
+        "m" | "min" | "aeo" => Some((-3, "minor")),
+        "mix" => Some((-1, "mixolydian")),
+        "dor" => Some((-2, "dorian")),
+        "phr" => Some((-4, "phrygian")),
+        "lyd" => Some((1, "lydian")),
+        "loc" => Some((-5, "locrian")),
+        _ => None,
+    }
+}
+
+/// Reads a clef word from a K: or V: field, such as `bass` or `clef=alto`.
+fn parse_clef(word: &str) -> Option<Clef> {
+    let name = word.strip_prefix("clef=").unwrap_or(word);
+    match name {
+        "treble" => Some(TREBLE),
+        "bass" => Some(Clef { sign: "F", line: 4 }),
+        "bass3" => Some(Clef { sign: "F", line: 3 }),
+        "alto" => Some(Clef { sign: "C", line: 3 }),
+        "tenor" => Some(Clef { sign: "C", line: 4 }),
+        "perc" => Some(Clef { sign: "percussion", line: 3 }),
+        _ => None,
+    }
+}
+
+fn write_musicxml(tune: &Tune) -> Result<String> {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    let mut score = BytesStart::new("score-partwise");
+    score.push_attribute(("version", "4.0"));
+    writer.write_event(Event::Start(score))?;
+    if let Some(title) = &tune.title {
+        writer.write_event(Event::Start(BytesStart::new("work")))?;
+        write_text_element(&mut writer, "work-title", title)?;
+        writer.write_event(Event::End(BytesEnd::new("work")))?;
+    }
+    writer.write_event(Event::Start(BytesStart::new("part-list")))?;
+    let mut score_part = BytesStart::new("score-part");
+    score_part.push_attribute(("id", "P1"));
+    writer.write_event(Event::Start(score_part))?;
+    write_text_element(&mut writer, "part-name", "Music")?;
+    writer.write_event(Event::End(BytesEnd::new("score-part")))?;
+    writer.write_event(Event::End(BytesEnd::new("part-list")))?;
+
+    let mut part = BytesStart::new("part");
+    part.push_attribute(("id", "P1"));
+    writer.write_event(Event::Start(part))?;
+    for (i, measure) in tune.measures.iter().enumerate() {
+        let mut start = BytesStart::new("measure");
+        start.push_attribute(("number", (i + 1).to_string().as_str()));
+        writer.write_event(Event::Start(start))?;
+        if measure.forward_repeat {
+            write_barline(&mut writer, "left", "heavy-light", Some("forward"))?;
+        }
+        write_attributes(&mut writer, measure, i == 0)?;
+        for note in &measure.notes {
+            write_note(&mut writer, note)?;
+        }
+        if let Some((style, repeat)) = measure.barline {
+            write_barline(&mut writer, "right", style, repeat.then_some("backward"))?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("measure")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("part")))?;
+    writer.write_event(Event::End(BytesEnd::new("score-partwise")))?;
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+fn write_attributes(writer: &mut Writer<Cursor<Vec<u8>>>, measure: &Measure, first: bool) -> Result<()> {
+    if !first && measure.key.is_none() && measure.meter.is_none() && measure.clef.is_none() {
+        return Ok(());
+    }
+    writer.write_event(Event::Start(BytesStart::new("attributes")))?;
+    if first {
+        write_text_element(writer, "divisions", &DIVISIONS.to_string())?;
+    }
+    if let Some(key) = &measure.key {
+        writer.write_event(Event::Start(BytesStart::new("key")))?;
+        write_text_element(writer, "fifths", &key.fifths.to_string())?;
+        write_text_element(writer, "mode", key.mode)?;
+        writer.write_event(Event::End(BytesEnd::new("key")))?;
+    }
+    if let Some(meter) = &measure.meter {
+        let (symbol, beats, beat_type) = match meter {
+            Meter::Numbers(beats, beat_type) => (None, beats.as_str(), beat_type.as_str()),
+            Meter::Common => (Some("common"), "4", "4"),
+            Meter::Cut => (Some("cut"), "2", "2"),
+        };
+        let mut time = BytesStart::new("time");
+        if let Some(symbol) = symbol {
+            time.push_attribute(("symbol", symbol));
+        }
+        writer.write_event(Event::Start(time))?;
+        write_text_element(writer, "beats", beats)?;
+        write_text_element(writer, "beat-type", beat_type)?;
+        writer.write_event(Event::End(BytesEnd::new("time")))?;
+    }
+    if let Some(clef) = measure.clef {
+        writer.write_event(Event::Start(BytesStart::new("clef")))?;
+        write_text_element(writer, "sign", clef.sign)?;
+        write_text_element(writer, "line", &clef.line.to_string())?;
+        writer.write_event(Event::End(BytesEnd::new("clef")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("attributes")))?;
+    Ok(())
+}
+
+fn write_barline(writer: &mut Writer<Cursor<Vec<u8>>>, location: &str, style: &str, repeat: Option<&str>) -> Result<()> {
+    let mut barline = BytesStart::new("barline");
+    barline.push_attribute(("location", location));
+    writer.write_event(Event::Start(barline))?;
+    write_text_element(writer, "bar-style", style)?;
+    if let Some(direction) = repeat {
+        let mut element = BytesStart::new("repeat");
+        element.push_attribute(("direction", direction));
+        writer.write_event(Event::Empty(element))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("barline")))?;
+    Ok(())
+}
+
+/// Writes a note, a chord as one `<note>` per pitch, or a rest.
+fn write_note(writer: &mut Writer<Cursor<Vec<u8>>>, note: &Note) -> Result<()> {
+    let rest = [None];
+    let pitches: Vec<Option<&Pitch>> = if note.pitches.is_empty() {
+        rest.to_vec()
+    } else {
+        note.pitches.iter().map(Some).collect()
+    };
+    for (i, pitch) in pitches.into_iter().enumerate() {
+        writer.write_event(Event::Start(BytesStart::new("note")))?;
+        if i > 0 {
+            writer.write_event(Event::Empty(BytesStart::new("chord")))?;
+        }
+        match pitch {
+            Some(pitch) => {
+                writer.write_event(Event::Start(BytesStart::new("pitch")))?;
+                write_text_element(writer, "step", &"CDEFGAB"[pitch.step as usize..pitch.step as usize + 1])?;
+                if pitch.alter != 0 {
+                    write_text_element(writer, "alter", &pitch.alter.to_string())?;
+                }
+                write_text_element(writer, "octave", &pitch.octave.to_string())?;
+                writer.write_event(Event::End(BytesEnd::new("pitch")))?;
+            }
+            None => {
+                let mut element = BytesStart::new("rest");
+                if note.whole_measure {
+                    element.push_attribute(("measure", "yes"));
+                }
+                writer.write_event(Event::Empty(element))?;
+            }
+        }
+        write_text_element(writer, "duration", &note.duration.to_string())?;
+        for (tied, kind) in [(note.tie_stop, "stop"), (note.tie_start, "start")] {
+            if tied {
+                let mut tie = BytesStart::new("tie");
+                tie.push_attribute(("type", kind));
+                writer.write_event(Event::Empty(tie))?;
+            }
+        }
+        write_text_element(writer, "voice", "1")?;
+        match &note.value {
+            Some(value) => {
+                write_text_element(writer, "type", value.kind)?;
+                for _ in 0..value.dots {
+                    writer.write_event(Event::Empty(BytesStart::new("dot")))?;
+                }
+            }
+            None => write_text_element(writer, "type", "whole")?,
+        }
+        if let Some(accidental) = pitch.and_then(|p| p.accidental) {
+            write_text_element(writer, "accidental", accidental)?;
+        }
+        if let Some((actual, normal)) = note.tuplet {
+            writer.write_event(Event::Start(BytesStart::new("time-modification")))?;
+            write_text_element(writer, "actual-notes", &actual.to_string())?;
+            write_text_element(writer, "normal-notes", &normal.to_string())?;
+            writer.write_event(Event::End(BytesEnd::new("time-modification")))?;
+        }
+        if i == 0 {
+            for (level, value) in note.beams.iter().enumerate() {
+                let mut beam = BytesStart::new("beam");
+                beam.push_attribute(("number", (level + 1).to_string().as_str()));
+                writer.write_event(Event::Start(beam))?;
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(value)))?;
+                writer.write_event(Event::End(BytesEnd::new("beam")))?;
+            }
+        }
+        writer.write_event(Event::End(BytesEnd::new("note")))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,301 @@
+use anyhow::{anyhow, bail, Result};
+use crate::qti::generator::PackageOptions;
+use crate::qti::{convert_to_qti, write_question_ids, Conversion};
+use regex::Regex;
+use crate::qti::parser::has_front_matter;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Settings for `convert` over one or more inputs.
+pub struct BatchOptions {
+    /// A zip path for a single quiz, or a directory for the zips of several.
+    pub output: Option<PathBuf>,
+    /// How many quizzes to convert at once.
+    pub jobs: usize,
+    /// Run `verify` code blocks and check the answers against their output.
+    pub verify: bool,
+    pub package: PackageOptions,
+}
+
+/// A quiz to convert and where its zip goes.
+struct Job {
+    input: PathBuf,
+    zip_path: Option<PathBuf>,
+}
+
+pub struct BatchReport {
+    pub files: Vec<(PathBuf, Result<Conversion>)>,
+}
+
+impl BatchReport {
+    pub fn failure_count(&self) -> usize {
+        self.files.iter().filter(|(_, result)| result.is_err()).count()
+    }
+}
+
+/// Expands the inputs and converts every quiz found, `options.jobs` at a
+/// time. A quiz that fails is reported in its row rather than stopping the
+/// rest. Question ids are written back once every quiz is done, as a quiz
+/// may be a bank that another one in the batch includes and is reading.
+pub fn convert_all(inputs: &[PathBuf], options: &BatchOptions) -> Result<BatchReport> {
+    let jobs = plan(inputs, options.output.as_deref())?;
+    let results: Mutex<Vec<Option<Result<Conversion>>>> = Mutex::new(jobs.iter().map(|_| None).collect());
+    let next = Mutex::new(0);
+
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let index = {
+                        let mut next = next.lock().unwrap();
+                        *next += 1;
+                        *next - 1
+                    };
+                    let Some(job) = jobs.get(index) else { break };
+                    let result = convert_to_qti(&job.input, job.zip_path.as_deref(), options.verify, &options.package);
+                    if let Err(e) = &result {
+                        eprintln!("Error converting {}: {}", job.input.display(), e);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    let files = jobs
+        .into_iter()
+        .zip(results.into_inner().unwrap())
+        .map(|(job, result)| {
+            let mut result = result.unwrap_or_else(|| Err(anyhow!("not converted")));
+            if let Ok(conversion) = &mut result {
+                conversion.warnings += write_question_ids(&job.input, &conversion.added_ids);
+            }
+            (job.input, result)
+        })
+        .collect();
+    Ok(BatchReport { files })
+}
+
+/// Resolves inputs to quiz files and picks each one's zip path. With one
+/// quiz, `output` is the zip itself if it ends in `.zip` and is not an
+/// existing directory; otherwise, and always with several, it is a
+/// directory, and quizzes found under an input directory keep their place
+/// relative to it.
+fn plan(inputs: &[PathBuf], output: Option<&Path>) -> Result<Vec<Job>> {
+    // Each quiz with the directory its zip path is relative to, if any.
+    let mut found: Vec<(PathBuf, Option<PathBuf>)> = Vec::new();
+    for input in inputs {
+        let pattern = input.to_string_lossy();
+        if input.is_dir() {
+            let mut files = Vec::new();
+            quiz_files(input, &mut files)?;
+            files.retain(|file| is_quiz(file));
+            if files.is_empty() {
+                eprintln!("Warning: no .qmd files in {}", input.display());
+            }
+            found.extend(files.into_iter().map(|file| (file, Some(input.clone()))));
+        } else if !input.exists() && pattern.contains(['*', '?', '[']) {
+            let (base, mut files) = glob(&pattern)?;
+            if files.is_empty() {
+                bail!("no files match {}", pattern);
+            }
+            files.retain(|file| is_quiz(file));
+            found.extend(files.into_iter().map(|file| (file, Some(base.clone()))));
+        } else if input.is_file() {
+            found.push((input.clone(), None));
+        } else {
+            bail!("{} does not exist", input.display());
+        }
+    }
+    found.sort();
+    found.dedup_by(|a, b| a.0 == b.0);
+    if found.is_empty() {
+        bail!("no quiz files to convert");
+    }
+
+    let single = found.len() == 1 && inputs.len() == 1 && inputs[0].is_file();
+    let jobs: Vec<Job> = found
+        .into_iter()
+        .map(|(input, base)| {
+            let zip_path = output.map(|output| {
+                if single && !is_directory(output) {
+                    return output.to_path_buf();
+                }
+                let relative = base
+                    .and_then(|base| input.strip_prefix(base).ok().map(Path::to_path_buf))
+                    .unwrap_or_else(|| input.file_name().map(PathBuf::from).unwrap_or_default());
+                output.join(relative).with_extension("zip")
+            });
+            Job { input, zip_path }
+        })
+        .collect();
+
+    // Two quizzes with the same name in different directories would
+    // otherwise overwrite one another's zip.
+    let mut outputs: HashMap<PathBuf, &Path> = HashMap::new();
+    for job in &jobs {
+        let zip_path = job.zip_path.clone().unwrap_or_else(|| job.input.with_extension("zip"));
+        if let Some(other) = outputs.insert(zip_path.clone(), &job.input) {
+            bail!(
+                "{} and {} would both be written to {}\nconvert them separately, or pass the directory that contains both so each keeps its relative path",
+                other.display(),
+                job.input.display(),
+                zip_path.display()
+            );
+        }
+    }
+    Ok(jobs)
+}
+
+/// Whether `output` names a directory: one that exists, one written with a
+/// trailing separator, or any path not ending in `.zip`.
+fn is_directory(output: &Path) -> bool {
+    let trailing_separator = output.to_string_lossy().ends_with(['/', std::path::MAIN_SEPARATOR]);
+    output.is_dir() || trailing_separator || !output.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Whether `file` is a quiz rather than a bank of questions for other
+/// quizzes to include, which has no front matter. Files that cannot be read
+/// count as quizzes, so converting them reports why.
+fn is_quiz(file: &Path) -> bool {
+    std::fs::read_to_string(file).map_or(true, |content| has_front_matter(&content))
+}
+
+/// Every `.qmd` file under `dir`, skipping hidden directories.
+fn quiz_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if path.is_dir() {
+            if !hidden {
+                quiz_files(&path, files)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "qmd") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Files matching a glob pattern, for shells that pass patterns through
+/// unexpanded. `*` and `?` match within a path component, `**` matches any
+/// number of directories, and `[...]` matches a character class. Returns
+/// the directory before the first wildcard along with the matches.
+fn glob(pattern: &str) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let pattern = pattern.replace('\\', "/");
+    let components: Vec<&str> = pattern.split('/').collect();
+    let literal = components.iter().take_while(|c| !c.contains(['*', '?', '['])).count();
+    let base = match components[..literal].join("/") {
+        prefix if prefix.is_empty() && pattern.starts_with('/') => PathBuf::from("/"),
+        prefix if prefix.is_empty() => PathBuf::from("."),
+        prefix => PathBuf::from(prefix),
+    };
+
+    let mut regex = String::from("^");
+    let rest = &components[literal..];
+    for (i, component) in rest.iter().enumerate() {
+        let last = i + 1 == rest.len();
+        if *component == "**" {
+            regex.push_str(if last { ".*" } else { "(?:[^/]+/)*" });
+            continue;
+        }
+        let mut chars = component.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    let class: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(negated) => format!("^{}", negated),
+                        None => class,
+                    };
+                    regex.push_str(&format!("[{}]", class.replace('\\', "\\\\")));
+                }
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        if !last {
+            regex.push('/');
+        }
+    }
+    regex.push('$');
+    let regex = Regex::new(&regex).map_err(|e| anyhow!("invalid pattern {}: {}", pattern, e))?;
+
+    let mut files = Vec::new();
+    if base.is_dir() {
+        walk(&base, &base, &regex, &mut files)?;
+    }
+    Ok((base, files))
+}
+
+fn walk(base: &Path, dir: &Path, regex: &Regex, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if path.is_dir() {
+            if !hidden {
+                walk(base, &path, regex, files)?;
+            }
+        } else if let Ok(relative) = path.strip_prefix(base) {
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            if regex.is_match(&relative) {
+                files.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A table of each file's result, with totals when there are several.
+pub fn format_summary(report: &BatchReport) -> String {
+    let mut rows: Vec<[String; 5]> = vec![["File".into(), "Questions".into(), "Points".into(), "Warnings".into(), "Output".into()]];
+    for (path, result) in &report.files {
+        rows.push(match result {
+            Ok(conversion) => [
+                path.display().to_string(),
+                conversion.questions.to_string(),
+                conversion.points.to_string(),
+                conversion.warnings.to_string(),
+                conversion.zip_path.display().to_string(),
+            ],
+            Err(_) => [path.display().to_string(), "-".into(), "-".into(), "-".into(), "failed".into()],
+        });
+    }
+    if report.files.len() > 1 {
+        let converted: Vec<&Conversion> = report.files.iter().filter_map(|(_, r)| r.as_ref().ok()).collect();
+        rows.push([
+            format!("{} file(s), {} failed", report.files.len(), report.failure_count()),
+            converted.iter().map(|c| c.questions).sum::<usize>().to_string(),
+            converted.iter().map(|c| c.points).sum::<f32>().to_string(),
+            converted.iter().map(|c| c.warnings).sum::<usize>().to_string(),
+            String::new(),
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..5).map(|i| rows.iter().map(|row| row[i].chars().count()).max().unwrap_or(0)).collect();
+    let mut out = String::new();
+    for (n, row) in rows.iter().enumerate() {
+        let line = format!(
+            "{:<w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3]
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+        // Rules under the header and above the totals.
+        if n == 0 || (report.files.len() > 1 && n == rows.len() - 2) {
+            out.push_str(&"-".repeat(widths.iter().sum::<usize>() + 8));
+            out.push('\n');
+        }
+    }
+    out
+}
@@ -0,0 +1,130 @@
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a quiz source file, located by 1-based line and column
+/// so it can be rendered the way a compiler would.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, line: usize, column: usize, length: usize) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            line,
+            column,
+            length,
+            hint: None,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, line: usize, column: usize, length: usize) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message, line, column, length)
+        }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Renders the diagnostic with the offending source line and a caret
+    /// under the reported span:
+    ///
+    /// ```text
+    /// error: unknown question type `multi_choice`
+    ///   --> quiz.qmd:7:20
+    ///    |
+    ///  7 | :::{.question type=multi_choice points=1}
+    ///    |                    ^^^^^^^^^^^^
+    ///    = help: did you mean `multiple_choice`?
+    /// ```
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let label = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let line_text = source.lines().nth(self.line.saturating_sub(1)).unwrap_or("");
+        let gutter = self.line.to_string().len();
+        let pad = " ".repeat(gutter);
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}: {}", label, self.message);
+        let _ = writeln!(out, "{}--> {}:{}:{}", pad, file_name, self.line, self.column);
+        let _ = writeln!(out, "{} |", pad);
+        let _ = writeln!(out, "{} | {}", self.line, line_text);
+        let _ = writeln!(
+            out,
+            "{} | {}{}",
+            pad,
+            " ".repeat(self.column.saturating_sub(1)),
+            "^".repeat(self.length.max(1))
+        );
+        if let Some(hint) = &self.hint {
+            // Continuation lines line up under the first.
+            let hint = hint.replace('\n', &format!("\n{}         ", pad));
+            let _ = writeln!(out, "{} = help: {}", pad, hint);
+        }
+        out
+    }
+}
+
+/// Renders a list of diagnostics followed by a summary line when any of them
+/// are errors.
+pub fn render_all(diagnostics: &[Diagnostic], file_name: &str, source: &str) -> String {
+    let mut out = String::new();
+    for diagnostic in diagnostics {
+        out.push_str(&diagnostic.render(file_name, source));
+        out.push('\n');
+    }
+    let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+    if errors > 0 {
+        let _ = write!(
+            out,
+            "could not parse {} due to {} previous error{}",
+            file_name,
+            errors,
+            if errors == 1 { "" } else { "s" }
+        );
+    }
+    out
+}
+
+/// Picks the candidate closest to `input` by edit distance, if it is close
+/// enough to plausibly be a typo.
+pub fn suggest<'a>(input: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|c| (edit_distance(input, c), *c))
+        .filter(|(d, c)| *d <= (c.len() / 3).max(2))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current.push((prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1));
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
@@ -0,0 +1,957 @@
+use anyhow::Result;
+use crate::qti::dot::parse_dot;
+use crate::qti::mathsvg::{bundled_face, number, PathData};
+use crate::qti::mermaid::parse_mermaid;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::OnceLock;
+use ttf_parser::Face;
+
+// Lays out and draws the graphs of ```dot and ```mermaid fences as SVG, with
+// no Graphviz or browser needed. Both languages parse into a `Graph`, which
+// is laid out in layers the way Graphviz's dot does it: cycles are broken,
+// nodes are ranked by longest path, long and labelled edges get virtual
+// nodes, layers are reordered by barycentre to cut crossings, and each node
+// is placed as near its neighbours as the spacing allows. Labels are drawn
+// as paths in New Computer Modern, so the image looks the same everywhere.
+//
+// Layout works in two axes: `rank`, along which edges flow, and `across`,
+// along which each rank's nodes are spread. Only drawing maps them to x and
+// y for the graph's direction.
+
+const FONT: &str = "NewCM10-Regular";
+const FONT_SIZE: f32 = 14.0;
+const LINE_HEIGHT: f32 = 17.5;
+const NODE_GAP: f32 = 24.0;
+const RANK_GAP: f32 = 30.0;
+const MARGIN: f32 = 8.0;
+const STROKE_WIDTH: f32 = 1.2;
+const ARROW_LENGTH: f32 = 9.0;
+const ARROW_WIDTH: f32 = 7.0;
+/// How far a self-loop reaches out from its node.
+const LOOP_SIZE: f32 = 24.0;
+/// Room around an edge label.
+const LABEL_PADDING: f32 = 4.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Direction {
+    #[default]
+    TopBottom,
+    BottomTop,
+    LeftRight,
+    RightLeft,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Shape {
+    #[default]
+    Box,
+    Rounded,
+    Ellipse,
+    Circle,
+    DoubleCircle,
+    Diamond,
+    Hexagon,
+    /// The filled dot of a state machine's initial state.
+    Start,
+    /// The ringed dot of a final state.
+    End,
+    /// Just the label.
+    Plain,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum LineStyle {
+    #[default]
+    Solid,
+    Dashed,
+    Dotted,
+    Bold,
+}
+
+#[derive(Debug)]
+pub(crate) struct Node {
+    pub id: String,
+    /// Lines are separated by `\n`.
+    pub label: String,
+    pub shape: Shape,
+}
+
+#[derive(Debug)]
+pub(crate) struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub label: String,
+    pub style: LineStyle,
+    /// An arrowhead where the edge meets `to`.
+    pub head: bool,
+    /// An arrowhead where the edge leaves `from`.
+    pub tail: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Graph {
+    pub direction: Direction,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl Graph {
+    /// Index of the node `id`, which is added labelled with its id if new.
+    pub(crate) fn node(&mut self, id: &str, shape: Shape) -> usize {
+        match self.nodes.iter().position(|n| n.id == id) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(Node {
+                    id: id.to_string(),
+                    label: id.to_string(),
+                    shape,
+                });
+                self.nodes.len() - 1
+            }
+        }
+    }
+}
+
+/// Renders the source of a ```dot or ```mermaid fence as a standalone SVG
+/// with `alt` as its title.
+pub(crate) fn diagram_to_svg(lang: &str, source: &str, alt: &str) -> Result<String> {
+    let graph = match lang {
+        "dot" => parse_dot(source)?,
+        "mermaid" => parse_mermaid(source)?,
+        _ => anyhow::bail!("Unknown diagram language `{}`", lang),
+    };
+    if graph.nodes.is_empty() {
+        anyhow::bail!("The diagram has no nodes");
+    }
+    let face = label_face().ok_or_else(|| anyhow::anyhow!("Font {} is not available", FONT))?;
+    Ok(Drawing::new(face).render(&graph, alt))
+}
+
+static FACE: OnceLock<Option<Face<'static>>> = OnceLock::new();
+
+fn label_face() -> Option<&'static Face<'static>> {
+    FACE.get_or_init(|| bundled_face(FONT)).as_ref()
+}
+
+/// A point in screen coordinates.
+#[derive(Clone, Copy, Debug)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+impl Point {
+    fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+
+    fn sub(self, other: Point) -> Point {
+        Point::new(self.x - other.x, self.y - other.y)
+    }
+
+    fn add(self, other: Point) -> Point {
+        Point::new(self.x + other.x, self.y + other.y)
+    }
+
+    fn scale(self, factor: f32) -> Point {
+        Point::new(self.x * factor, self.y * factor)
+    }
+
+    fn length(self) -> f32 {
+        self.x.hypot(self.y)
+    }
+
+    fn unit(self) -> Point {
+        let length = self.length();
+        if length > 0.0 { self.scale(1.0 / length) } else { Point::new(0.0, 1.0) }
+    }
+}
+
+/// A node or a virtual node in the layered graph. Virtual nodes carry an
+/// edge across a rank, and the one in the middle of a labelled edge makes
+/// room for its label.
+struct Vertex {
+    node: Option<usize>,
+    label_of: Option<usize>,
+    rank: usize,
+    /// Extents across the ranks either side of the centre, and the size
+    /// along them.
+    before: f32,
+    after: f32,
+    along: f32,
+    across: f32,
+}
+
+/// Where everything ended up, in layout coordinates.
+struct Layout {
+    vertices: Vec<Vertex>,
+    /// Centre of each rank along the rank axis.
+    rank_centers: Vec<f32>,
+    rank_extent: f32,
+    /// For each edge that is not a self-loop, its vertices from the node it
+    /// leaves to the node it enters.
+    chains: Vec<Option<Vec<usize>>>,
+}
+
+/// Size of a label's text: its width and height.
+fn text_size(face: &Face, label: &str) -> (f32, f32) {
+    if label.is_empty() {
+        return (0.0, 0.0);
+    }
+    let scale = FONT_SIZE / face.units_per_em() as f32;
+    let width = label
+        .lines()
+        .map(|line| {
+            line.chars()
+                .filter_map(|c| face.glyph_index(c))
+                .map(|g| face.glyph_hor_advance(g).unwrap_or(0) as f32 * scale)
+                .sum::<f32>()
+        })
+        .fold(0.0, f32::max);
+    (width, label.lines().count().max(1) as f32 * LINE_HEIGHT)
+}
+
+/// Width and height of a node's shape around its label.
+fn node_size(shape: Shape, text: (f32, f32)) -> (f32, f32) {
+    let (width, height) = text;
+    match shape {
+        Shape::Box | Shape::Rounded => ((width + 20.0).max(40.0), (height + 12.0).max(30.0)),
+        Shape::Plain => (width + 8.0, height + 4.0),
+        Shape::Ellipse => (((width + 16.0) * 1.3).max(54.0), ((height + 8.0) * 1.35).max(32.0)),
+        Shape::Circle => {
+            let diameter = (width + 10.0).hypot(height + 6.0).max(30.0);
+            (diameter, diameter)
+        }
+        Shape::DoubleCircle => {
+            let diameter = (width + 10.0).hypot(height + 6.0).max(30.0) + 8.0;
+            (diameter, diameter)
+        }
+        // Wide enough that the label's box fits inside the diamond.
+        Shape::Diamond => ((2.0 * width + 20.0).max(40.0), (2.0 * height + 12.0).max(30.0)),
+        Shape::Hexagon => (width + height + 24.0, (height + 12.0).max(30.0)),
+        Shape::Start => (14.0, 14.0),
+        Shape::End => (18.0, 18.0),
+    }
+}
+
+fn is_vertical(direction: Direction) -> bool {
+    matches!(direction, Direction::TopBottom | Direction::BottomTop)
+}
+
+fn layout(graph: &Graph, node_sizes: &[(f32, f32)], label_sizes: &[(f32, f32)]) -> Layout {
+    let vertical = is_vertical(graph.direction);
+    let n = graph.nodes.len();
+
+    // Edges that close a cycle in a depth-first search are laid out backwards,
+    // leaving an acyclic graph to rank.
+    let mut outgoing: Vec<Vec<usize>> = vec![Vec::new(); n];
+    let mut incoming = vec![0; n];
+    for (e, edge) in graph.edges.iter().enumerate() {
+        if edge.from != edge.to {
+            outgoing[edge.from].push(e);
+            incoming[edge.to] += 1;
+        }
+    }
+    let mut reversed = vec![false; graph.edges.len()];
+    let mut state = vec![0u8; n];
+    let roots = (0..n).filter(|&v| incoming[v] == 0).chain(0..n);
+    for root in roots {
+        if state[root] != 0 {
+            continue;
+        }
+        let mut stack = vec![(root, 0)];
+        state[root] = 1;
+        while let Some((v, next)) = stack.last_mut() {
+            let v = *v;
+            if let Some(&e) = outgoing[v].get(*next) {
+                *next += 1;
+                let to = graph.edges[e].to;
+                match state[to] {
+                    0 => {
+                        state[to] = 1;
+                        stack.push((to, 0));
+                    }
+                    1 => reversed[e] = true,
+                    _ => {}
+                }
+            } else {
+                state[v] = 2;
+                stack.pop();
+            }
+        }
+    }
+    let ends = |e: usize| {
+        let edge = &graph.edges[e];
+        if reversed[e] { (edge.to, edge.from) } else { (edge.from, edge.to) }
+    };
+
+    // Labelled edges, and edges repeating a pair of nodes, span two ranks so
+    // a virtual node can hold the label or keep them apart.
+    let mut seen_pairs = HashMap::new();
+    let min_length: Vec<usize> = graph
+        .edges
+        .iter()
+        .map(|edge| {
+            let pair = (edge.from.min(edge.to), edge.from.max(edge.to));
+            let repeat = *seen_pairs.entry(pair).and_modify(|c| *c += 1).or_insert(0) > 0;
+            if !edge.label.is_empty() || repeat { 2 } else { 1 }
+        })
+        .collect();
+
+    // Longest-path ranking in topological order.
+    let layout_edges: Vec<usize> = (0..graph.edges.len()).filter(|&e| graph.edges[e].from != graph.edges[e].to).collect();
+    let mut in_degree = vec![0; n];
+    let mut successors: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for &e in &layout_edges {
+        let (from, to) = ends(e);
+        in_degree[to] += 1;
+        successors[from].push(e);
+    }
+    let mut order: Vec<usize> = (0..n).filter(|&v| in_degree[v] == 0).collect();
+    let mut i = 0;
+    while i < order.len() {
+        let v = order[i];
+        for &e in &successors[v] {
+            let (_, to) = ends(e);
+            in_degree[to] -= 1;
+            if in_degree[to] == 0 {
+                order.push(to);
+            }
+        }
+        i += 1;
+    }
+    let mut rank = vec![0usize; n];
+    for &v in &order {
+        for &e in &successors[v] {
+            let (_, to) = ends(e);
+            rank[to] = rank[to].max(rank[v] + min_length[e]);
+        }
+    }
+    // Pull nodes with nothing above them down next to what they point at.
+    for &v in order.iter().rev() {
+        let has_predecessor = layout_edges.iter().any(|&e| ends(e).1 == v);
+        if !has_predecessor && !successors[v].is_empty() {
+            rank[v] = successors[v].iter().map(|&e| rank[ends(e).1] - min_length[e]).min().unwrap_or(rank[v]);
+        }
+    }
+
+    // Vertices: the nodes, then virtual nodes along edges spanning ranks.
+    let loops_after: Vec<f32> = (0..n)
+        .map(|v| {
+            graph
+                .edges
+                .iter()
+                .enumerate()
+                .filter(|(_, edge)| edge.from == v && edge.to == v)
+                .map(|(e, _)| LOOP_SIZE + if vertical { label_sizes[e].0 } else { label_sizes[e].1 } + LABEL_PADDING)
+                .fold(0.0, f32::max)
+        })
+        .collect();
+    let mut vertices: Vec<Vertex> = (0..n)
+        .map(|v| {
+            let (width, height) = node_sizes[v];
+            let (across, along) = if vertical { (width, height) } else { (height, width) };
+            Vertex {
+                node: Some(v),
+                label_of: None,
+                rank: rank[v],
+                before: across / 2.0,
+                after: across / 2.0 + loops_after[v],
+                along,
+                across: 0.0,
+            }
+        })
+        .collect();
+    let mut chains = vec![None; graph.edges.len()];
+    for &e in &layout_edges {
+        let (from, to) = ends(e);
+        let mut chain = vec![from];
+        let label_rank = (rank[from] + rank[to]) / 2;
+        for r in rank[from] + 1..rank[to] {
+            let labelled = r == label_rank && !graph.edges[e].label.is_empty();
+            let (across, along) = if labelled {
+                let (width, height) = label_sizes[e];
+                if vertical { (width, height) } else { (height, width) }
+            } else {
+                (0.0, 0.0)
+            };
+            vertices.push(Vertex {
+                node: None,
+                label_of: labelled.then_some(e),
+                rank: r,
+                before: 1.0,
+                after: if labelled { across + 2.0 * LABEL_PADDING } else { 1.0 },
+                along,
+                across: 0.0,
+            });
+            chain.push(vertices.len() - 1);
+        }
+        chain.push(to);
+        chains[e] = Some(chain);
+    }
+
+    // Neighbours of each vertex in the ranks above and below.
+    let mut above: Vec<Vec<usize>> = vec![Vec::new(); vertices.len()];
+    let mut below: Vec<Vec<usize>> = vec![Vec::new(); vertices.len()];
+    for chain in chains.iter().flatten() {
+        for pair in chain.windows(2) {
+            below[pair[0]].push(pair[1]);
+            above[pair[1]].push(pair[0]);
+        }
+    }
+
+    // Initial order within each rank by depth-first discovery, so related
+    // nodes start side by side.
+    let rank_count = vertices.iter().map(|v| v.rank).max().unwrap_or(0) + 1;
+    let mut layers: Vec<Vec<usize>> = vec![Vec::new(); rank_count];
+    let mut placed = vec![false; vertices.len()];
+    let starts: Vec<usize> = (0..vertices.len()).filter(|&v| above[v].is_empty()).chain(0..vertices.len()).collect();
+    for start in starts {
+        let mut stack = vec![start];
+        while let Some(v) = stack.pop() {
+            if placed[v] {
+                continue;
+            }
+            placed[v] = true;
+            layers[vertices[v].rank].push(v);
+            stack.extend(below[v].iter().rev());
+        }
+    }
+
+    // Sweep down and up, sorting each rank by the mean position of its
+    // neighbours in the rank just placed, and keep the fewest crossings.
+    let mut best = layers.clone();
+    let mut best_crossings = crossings(&layers, &below, vertices.len());
+    for sweep in 0..12 {
+        let mut position = vec![0.0; vertices.len()];
+        for layer in &layers {
+            for (i, &v) in layer.iter().enumerate() {
+                position[v] = i as f32;
+            }
+        }
+        let ranks: Vec<usize> = if sweep % 2 == 0 { (1..rank_count).collect() } else { (0..rank_count.saturating_sub(1)).rev().collect() };
+        for r in ranks {
+            let neighbours = if sweep % 2 == 0 { &above } else { &below };
+            let mut keyed: Vec<(f32, usize)> = layers[r]
+                .iter()
+                .map(|&v| {
+                    let key = if neighbours[v].is_empty() {
+                        position[v]
+                    } else {
+                        neighbours[v].iter().map(|&u| position[u]).sum::<f32>() / neighbours[v].len() as f32
+                    };
+                    (key, v)
+                })
+                .collect();
+            keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+            layers[r] = keyed.into_iter().map(|(_, v)| v).collect();
+            for (i, &v) in layers[r].iter().enumerate() {
+                position[v] = i as f32;
+            }
+        }
+        let count = crossings(&layers, &below, vertices.len());
+        if count < best_crossings {
+            best_crossings = count;
+            best = layers.clone();
+        }
+    }
+    let layers = best;
+
+    place_across(&mut vertices, &layers, &above, &below);
+
+    // Ranks are as deep as their deepest vertex.
+    let mut rank_centers = Vec::with_capacity(rank_count);
+    let mut edge = 0.0;
+    for (r, layer) in layers.iter().enumerate() {
+        let depth = layer.iter().map(|&v| vertices[v].along).fold(0.0, f32::max);
+        if r > 0 {
+            edge += RANK_GAP;
+        }
+        rank_centers.push(edge + depth / 2.0);
+        edge += depth;
+    }
+
+    Layout {
+        vertices,
+        rank_centers,
+        rank_extent: edge,
+        chains,
+    }
+}
+
+/// Counts edge crossings between neighbouring ranks.
+fn crossings(layers: &[Vec<usize>], below: &[Vec<usize>], count: usize) -> usize {
+    let mut position = vec![0; count];
+    for layer in layers {
+        for (i, &v) in layer.iter().enumerate() {
+            position[v] = i;
+        }
+    }
+    let mut total = 0;
+    for layer in layers {
+        let edges: Vec<(usize, usize)> = layer
+            .iter()
+            .flat_map(|&v| below[v].iter().map(move |&w| (v, w)))
+            .map(|(v, w)| (position[v], position[w]))
+            .collect();
+        for (i, a) in edges.iter().enumerate() {
+            for b in &edges[i + 1..] {
+                if (a.0 < b.0 && a.1 > b.1) || (a.0 > b.0 && a.1 < b.1) {
+                    total += 1;
+                }
+            }
+        }
+    }
+    total
+}
+
+/// Spreads each rank's vertices across, moving each towards the mean of its
+/// neighbours while keeping them in order and apart.
+fn place_across(vertices: &mut [Vertex], layers: &[Vec<usize>], above: &[Vec<usize>], below: &[Vec<usize>]) {
+    let gap = |a: &Vertex, b: &Vertex| {
+        let spacing = if a.node.is_some() && b.node.is_some() { NODE_GAP } else { NODE_GAP / 2.0 };
+        a.after + spacing + b.before
+    };
+    for layer in layers {
+        let mut x = 0.0;
+        for (i, &v) in layer.iter().enumerate() {
+            if i > 0 {
+                x += gap(&vertices[layer[i - 1]], &vertices[v]);
+            }
+            vertices[v].across = x;
+        }
+    }
+
+    // Edges between virtual nodes pull hardest, which keeps long edges straight.
+    let weight = |a: &Vertex, b: &Vertex| match (a.node.is_some(), b.node.is_some()) {
+        (false, false) => 8.0,
+        (true, true) => 1.0,
+        _ => 2.0,
+    };
+    for pass in 0..16 {
+        let ranks: Vec<usize> = if pass % 2 == 0 { (0..layers.len()).collect() } else { (0..layers.len()).rev().collect() };
+        for r in ranks {
+            let layer = &layers[r];
+            let targets: Vec<f32> = layer
+                .iter()
+                .map(|&v| {
+                    // The last passes balance both sides at once.
+                    let neighbours: Vec<usize> = if pass >= 12 {
+                        above[v].iter().chain(&below[v]).copied().collect()
+                    } else if pass % 2 == 0 {
+                        above[v].clone()
+                    } else {
+                        below[v].clone()
+                    };
+                    let (sum, total) = neighbours.iter().fold((0.0, 0.0), |(sum, total), &u| {
+                        let w = weight(&vertices[v], &vertices[u]);
+                        (sum + w * vertices[u].across, total + w)
+                    });
+                    if total > 0.0 { sum / total } else { vertices[v].across }
+                })
+                .collect();
+
+            // The closest placement to the targets that keeps the gaps is an
+            // isotonic regression on the targets less each vertex's minimum
+            // offset, solved by pooling adjacent violators.
+            let mut offsets = vec![0.0; layer.len()];
+            for i in 1..layer.len() {
+                offsets[i] = offsets[i - 1] + gap(&vertices[layer[i - 1]], &vertices[layer[i]]);
+            }
+            let mut blocks: Vec<(f32, usize)> = Vec::new();
+            for (target, offset) in targets.iter().zip(&offsets) {
+                blocks.push((target - offset, 1));
+                while blocks.len() > 1 {
+                    let (last_sum, last_count) = blocks[blocks.len() - 1];
+                    let (prev_sum, prev_count) = blocks[blocks.len() - 2];
+                    if last_sum / last_count as f32 >= prev_sum / prev_count as f32 {
+                        break;
+                    }
+                    blocks.pop();
+                    *blocks.last_mut().unwrap() = (last_sum + prev_sum, last_count + prev_count);
+                }
+            }
+            let mut i = 0;
+            for (sum, count) in blocks {
+                for _ in 0..count {
+                    vertices[layer[i]].across = sum / count as f32 + offsets[i];
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    let min = vertices.iter().map(|v| v.across - v.before).fold(f32::MAX, f32::min);
+    for vertex in vertices.iter_mut() {
+        vertex.across -= min;
+    }
+}
+
+/// Where a ray from a shape's centre towards `toward` leaves its outline.
+fn boundary(shape: Shape, center: Point, size: (f32, f32), toward: Point) -> Point {
+    let d = toward.sub(center);
+    if d.length() < 1e-3 {
+        return center;
+    }
+    let (hw, hh) = (size.0 / 2.0, size.1 / 2.0);
+    let t = match shape {
+        Shape::Ellipse | Shape::Circle | Shape::DoubleCircle | Shape::Start | Shape::End => {
+            1.0 / ((d.x / hw).powi(2) + (d.y / hh).powi(2)).sqrt()
+        }
+        Shape::Diamond => 1.0 / (d.x.abs() / hw + d.y.abs() / hh),
+        _ => (hw / d.x.abs()).min(hh / d.y.abs()),
+    };
+    center.add(d.scale(t.min(1.0)))
+}
+
+struct Drawing {
+    face: &'static Face<'static>,
+    defs: BTreeMap<String, String>,
+    edges: String,
+    nodes: String,
+    labels: String,
+}
+
+impl Drawing {
+    fn new(face: &'static Face<'static>) -> Self {
+        Self {
+            face,
+            defs: BTreeMap::new(),
+            edges: String::new(),
+            nodes: String::new(),
+            labels: String::new(),
+        }
+    }
+
+    fn render(mut self, graph: &Graph, alt: &str) -> String {
+        let vertical = is_vertical(graph.direction);
+        let node_sizes: Vec<(f32, f32)> = graph
+            .nodes
+            .iter()
+            .map(|node| node_size(node.shape, text_size(self.face, &node.label)))
+            .collect();
+        let label_sizes: Vec<(f32, f32)> = graph.edges.iter().map(|edge| text_size(self.face, &edge.label)).collect();
+        let layout = layout(graph, &node_sizes, &label_sizes);
+
+        let across_extent = layout.vertices.iter().map(|v| v.across + v.after).fold(0.0, f32::max);
+        let (width, height) = if vertical {
+            (across_extent, layout.rank_extent)
+        } else {
+            (layout.rank_extent, across_extent)
+        };
+        let to_screen = |across: f32, rank: usize, along_offset: f32| {
+            let along = layout.rank_centers[rank] + along_offset;
+            let (x, y) = match graph.direction {
+                Direction::TopBottom => (across, along),
+                Direction::BottomTop => (across, layout.rank_extent - along),
+                Direction::LeftRight => (along, across),
+                Direction::RightLeft => (layout.rank_extent - along, across),
+            };
+            Point::new(x + MARGIN, y + MARGIN)
+        };
+        // The direction "across" points in on screen.
+        let across_unit = if vertical { Point::new(1.0, 0.0) } else { Point::new(0.0, 1.0) };
+        let centers: Vec<Point> = layout.vertices.iter().map(|v| to_screen(v.across, v.rank, 0.0)).collect();
+
+        for (e, edge) in graph.edges.iter().enumerate() {
+            match &layout.chains[e] {
+                Some(chain) => {
+                    let mut points: Vec<Point> = chain
+                        .iter()
+                        .map(|&v| {
+                            let vertex = &layout.vertices[v];
+                            match vertex.label_of {
+                                // The edge runs down the near side of its label.
+                                Some(_) => centers[v].add(across_unit.scale(-vertex.before + 1.0)),
+                                None => centers[v],
+                            }
+                        })
+                        .collect();
+                    if chain.first().map(|&v| layout.vertices[v].node) != Some(Some(edge.from)) {
+                        points.reverse();
+                    }
+                    let from = points[0];
+                    let to = points[points.len() - 1];
+                    let next = points[1];
+                    let previous = points[points.len() - 2];
+                    points[0] = boundary(graph.nodes[edge.from].shape, from, node_sizes[edge.from], next);
+                    let last = points.len() - 1;
+                    points[last] = boundary(graph.nodes[edge.to].shape, to, node_sizes[edge.to], previous);
+                    self.edge(&points, edge);
+
+                    if let Some(&v) = chain.iter().find(|&&v| layout.vertices[v].label_of == Some(e)) {
+                        let vertex = &layout.vertices[v];
+                        let center = centers[v].add(across_unit.scale(LABEL_PADDING + (vertex.after - vertex.before) / 2.0));
+                        self.text(&edge.label, center);
+                    }
+                }
+                None => self.self_loop(graph, e, &centers, &node_sizes, across_unit, &label_sizes),
+            }
+        }
+
+        for (v, node) in graph.nodes.iter().enumerate() {
+            self.shape(node.shape, centers[v], node_sizes[v]);
+            self.text(&node.label, centers[v]);
+        }
+
+        let (width, height) = (width + 2.0 * MARGIN, height + 2.0 * MARGIN);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" \
+             width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" role=\"img\"><title>{}</title><defs>",
+            number(width),
+            number(height),
+            number(width),
+            number(height),
+            quick_xml::escape::escape(alt)
+        );
+        for markup in self.defs.values() {
+            svg.push_str(markup);
+        }
+        let _ = writeln!(
+            svg,
+            "</defs><g fill=\"none\" stroke=\"#000\" stroke-width=\"{}\">{}</g><g stroke=\"#000\" stroke-width=\"{}\">{}</g><g fill=\"#000\">{}</g></svg>",
+            number(STROKE_WIDTH),
+            self.edges,
+            number(STROKE_WIDTH),
+            self.nodes,
+            self.labels
+        );
+        svg
+    }
+
+    /// Draws an edge through `points` as a smooth curve, with arrowheads
+    /// where the edge asks for them.
+    fn edge(&mut self, points: &[Point], edge: &Edge) {
+        let mut points = points.to_vec();
+        let last = points.len() - 1;
+        let mut arrows = Vec::new();
+        if edge.head {
+            let direction = points[last].sub(points[last - 1]).unit();
+            arrows.push((points[last], direction));
+            points[last] = points[last].sub(direction.scale(ARROW_LENGTH));
+        }
+        if edge.tail {
+            let direction = points[0].sub(points[1]).unit();
+            arrows.push((points[0], direction));
+            points[0] = points[0].sub(direction.scale(ARROW_LENGTH));
+        }
+
+        let mut d = format!("M{} {}", number(points[0].x), number(points[0].y));
+        if points.len() == 2 {
+            let _ = write!(d, "L{} {}", number(points[1].x), number(points[1].y));
+        } else {
+            // Catmull-Rom through the points, as cubic Béziers.
+            for i in 0..points.len() - 1 {
+                let p0 = points[i.saturating_sub(1)];
+                let p1 = points[i];
+                let p2 = points[i + 1];
+                let p3 = points[(i + 2).min(points.len() - 1)];
+                let c1 = p1.add(p2.sub(p0).scale(1.0 / 6.0));
+                let c2 = p2.sub(p3.sub(p1).scale(1.0 / 6.0));
+                let _ = write!(
+                    d,
+                    "C{} {} {} {} {} {}",
+                    number(c1.x),
+                    number(c1.y),
+                    number(c2.x),
+                    number(c2.y),
+                    number(p2.x),
+                    number(p2.y)
+                );
+            }
+        }
+        let _ = write!(self.edges, "<path d=\"{}\"{}/>", d, line_style(edge.style));
+        for (tip, direction) in arrows {
+            self.arrowhead(tip, direction);
+        }
+    }
+
+    fn arrowhead(&mut self, tip: Point, direction: Point) {
+        let base = tip.sub(direction.scale(ARROW_LENGTH));
+        let side = Point::new(-direction.y, direction.x).scale(ARROW_WIDTH / 2.0);
+        let (left, right) = (base.add(side), base.sub(side));
+        let _ = write!(
+            self.edges,
+            "<path d=\"M{} {}L{} {}L{} {}Z\" fill=\"#000\" stroke-linejoin=\"miter\"/>",
+            number(tip.x),
+            number(tip.y),
+            number(left.x),
+            number(left.y),
+            number(right.x),
+            number(right.y)
+        );
+    }
+
+    /// Draws an edge from a node to itself as a loop on the node's far side
+    /// across the ranks, with its label beyond it.
+    fn self_loop(&mut self, graph: &Graph, e: usize, centers: &[Point], node_sizes: &[(f32, f32)], across: Point, label_sizes: &[(f32, f32)]) {
+        let edge = &graph.edges[e];
+        let node = &graph.nodes[edge.from];
+        let center = centers[edge.from];
+        let size = node_sizes[edge.from];
+        let along = Point::new(across.y, across.x);
+        let reach = if across.x > 0.0 { size.0 / 2.0 } else { size.1 / 2.0 };
+        let start = boundary(node.shape, center, size, center.add(across.scale(reach)).sub(along.scale(9.0)));
+        let end = boundary(node.shape, center, size, center.add(across.scale(reach)).add(along.scale(9.0)));
+        let out = center.add(across.scale(reach + LOOP_SIZE));
+        let c1 = out.sub(along.scale(22.0));
+        let c2 = out.add(along.scale(22.0));
+        let direction = end.sub(c2).unit();
+        let end_line = if edge.head { end.sub(direction.scale(ARROW_LENGTH)) } else { end };
+        let _ = write!(
+            self.edges,
+            "<path d=\"M{} {}C{} {} {} {} {} {}\"{}/>",
+            number(start.x),
+            number(start.y),
+            number(c1.x),
+            number(c1.y),
+            number(c2.x),
+            number(c2.y),
+            number(end_line.x),
+            number(end_line.y),
+            line_style(edge.style)
+        );
+        if edge.head {
+            self.arrowhead(end, direction);
+        }
+        if !edge.label.is_empty() {
+            let (width, height) = label_sizes[e];
+            let half = if across.x > 0.0 { width / 2.0 } else { height / 2.0 };
+            let label_center = center.add(across.scale(reach + LOOP_SIZE * 0.75 + LABEL_PADDING + half));
+            self.text(&edge.label, label_center);
+        }
+    }
+
+    fn shape(&mut self, shape: Shape, c: Point, size: (f32, f32)) {
+        let (hw, hh) = (size.0 / 2.0, size.1 / 2.0);
+        let out = &mut self.nodes;
+        let _ = match shape {
+            Shape::Box => write!(
+                out,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#fff\"/>",
+                number(c.x - hw),
+                number(c.y - hh),
+                number(size.0),
+                number(size.1)
+            ),
+            Shape::Rounded => write!(
+                out,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"{}\" fill=\"#fff\"/>",
+                number(c.x - hw),
+                number(c.y - hh),
+                number(size.0),
+                number(size.1),
+                number(hh.min(10.0))
+            ),
+            Shape::Ellipse | Shape::Circle => write!(
+                out,
+                "<ellipse cx=\"{}\" cy=\"{}\" rx=\"{}\" ry=\"{}\" fill=\"#fff\"/>",
+                number(c.x),
+                number(c.y),
+                number(hw),
+                number(hh)
+            ),
+            Shape::DoubleCircle => write!(
+                out,
+                "<circle cx=\"{0}\" cy=\"{1}\" r=\"{2}\" fill=\"#fff\"/><circle cx=\"{0}\" cy=\"{1}\" r=\"{3}\" fill=\"none\"/>",
+                number(c.x),
+                number(c.y),
+                number(hw),
+                number(hw - 4.0)
+            ),
+            Shape::Diamond => write!(
+                out,
+                "<path d=\"M{} {}L{} {}L{} {}L{} {}Z\" fill=\"#fff\"/>",
+                number(c.x),
+                number(c.y - hh),
+                number(c.x + hw),
+                number(c.y),
+                number(c.x),
+                number(c.y + hh),
+                number(c.x - hw),
+                number(c.y)
+            ),
+            Shape::Hexagon => {
+                let inset = hh * 0.6;
+                write!(
+                    out,
+                    "<path d=\"M{} {}H{}L{} {}L{} {}H{}L{} {}Z\" fill=\"#fff\"/>",
+                    number(c.x - hw + inset),
+                    number(c.y - hh),
+                    number(c.x + hw - inset),
+                    number(c.x + hw),
+                    number(c.y),
+                    number(c.x + hw - inset),
+                    number(c.y + hh),
+                    number(c.x - hw + inset),
+                    number(c.x - hw),
+                    number(c.y)
+                )
+            }
+            Shape::Start => write!(
+                out,
+                "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"#000\"/>",
+                number(c.x),
+                number(c.y),
+                number(hw)
+            ),
+            Shape::End => write!(
+                out,
+                "<circle cx=\"{0}\" cy=\"{1}\" r=\"{2}\" fill=\"#fff\"/><circle cx=\"{0}\" cy=\"{1}\" r=\"{3}\" fill=\"#000\" stroke=\"none\"/>",
+                number(c.x),
+                number(c.y),
+                number(hw),
+                number(hw - 3.5)
+            ),
+            Shape::Plain => Ok(()),
+        };
+    }
+
+    /// Writes `label` centred on `center`, a line at a time.
+    fn text(&mut self, label: &str, center: Point) {
+        if label.is_empty() {
+            return;
+        }
+        let face = self.face;
+        let scale = FONT_SIZE / face.units_per_em() as f32;
+        let cap_height = face.capital_height().map_or(FONT_SIZE * 0.68, |h| h as f32 * scale);
+        let lines: Vec<&str> = label.lines().collect();
+        let top = center.y - lines.len() as f32 * LINE_HEIGHT / 2.0;
+        for (i, line) in lines.iter().enumerate() {
+            let width = text_size(face, line).0;
+            let mut x = center.x - width / 2.0;
+            let baseline = top + i as f32 * LINE_HEIGHT + (LINE_HEIGHT + cap_height) / 2.0;
+            for c in line.chars() {
+                let Some(glyph) = face.glyph_index(c) else {
+                    continue;
+                };
+                let id = format!("g{}", glyph.0);
+                self.defs.entry(id.clone()).or_insert_with(|| {
+                    let mut path = PathData(String::new());
+                    face.outline_glyph(glyph, &mut path);
+                    format!("<path id=\"{}\" d=\"{}\"/>", id, path.0)
+                });
+                let _ = write!(
+                    self.labels,
+                    "<use xlink:href=\"#{}\" transform=\"translate({} {}) scale({} -{})\"/>",
+                    id,
+                    number(x),
+                    number(baseline),
+                    number(scale),
+                    number(scale)
+                );
+                x += face.glyph_hor_advance(glyph).unwrap_or(0) as f32 * scale;
+            }
+        }
+    }
+}
+
+fn line_style(style: LineStyle) -> &'static str {
+    match style {
+        LineStyle::Solid => "",
+        LineStyle::Dashed => " stroke-dasharray=\"6 4\"",
+        LineStyle::Dotted => " stroke-dasharray=\"1.5 3\"",
+        LineStyle::Bold => " stroke-width=\"2.5\"",
+    }
+}
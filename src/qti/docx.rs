@@ -0,0 +1,745 @@
+use anyhow::Result;
+use crate::qti::abc::abc_to_musicxml;
+use crate::qti::diagram::diagram_to_svg;
+use crate::qti::generator::{diagram_filename, music_filename, plot_filename, MUSIC_ALT};
+use crate::qti::images::{package_path, read_image};
+use crate::qti::plot::plot_to_svg;
+use crate::qti::mhchem::transpile_mhchem;
+use crate::qti::music::{musicxml_title, musicxml_to_svg};
+use crate::qti::lexer::Token;
+use crate::qti::markdown::{is_safe_href, parse_blocks, plain_text, Alignment, Block, Inline, Table};
+use crate::qti::model::{ImageOptions, QuestionType, Quiz};
+use crate::qti::omml::write_latex_omml;
+use crate::qti::print::choice_label;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+
+const W_NS: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+const R_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const WP_NS: &str = "http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing";
+const A_NS: &str = "http://schemas.openxmlformats.org/drawingml/2006/main";
+const PIC_NS: &str = "http://schemas.openxmlformats.org/drawingml/2006/picture";
+const M_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/math";
+const IMAGE_REL: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships/image";
+const SVG_BLIP_EXT: &str = "{96DAC541-7B7A-43D3-8B79-37D633B846F1}";
+const SVG_NS: &str = "http://schemas.microsoft.com/office/drawing/2016/SVG/main";
+
+// 96 DPI pixels to English Metric Units, and the widest an image may be on a
+// Letter page with one-inch margins.
+const EMU_PER_PX: u64 = 9525;
+const MAX_IMAGE_WIDTH_PX: u64 = 624;
+
+const CONTENT_TYPES_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Default Extension="png" ContentType="image/png"/><Default Extension="jpg" ContentType="image/jpeg"/><Default Extension="jpeg" ContentType="image/jpeg"/><Default Extension="gif" ContentType="image/gif"/><Default Extension="svg" ContentType="image/svg+xml"/><Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/><Override PartName="/word/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.styles+xml"/></Types>"#;
+
+const ROOT_RELS_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="word/document.xml"/></Relationships>"#;
+
+// Title and heading styles give screen reader users a navigable outline.
+const STYLES_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:styles xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:docDefaults><w:rPrDefault><w:rPr><w:rFonts w:ascii="Calibri" w:hAnsi="Calibri" w:cs="Calibri"/><w:sz w:val="24"/><w:lang w:val="en-US"/></w:rPr></w:rPrDefault><w:pPrDefault><w:pPr><w:spacing w:after="120"/></w:pPr></w:pPrDefault></w:docDefaults><w:style w:type="paragraph" w:default="1" w:styleId="Normal"><w:name w:val="Normal"/><w:qFormat/></w:style><w:style w:type="paragraph" w:styleId="Title"><w:name w:val="Title"/><w:basedOn w:val="Normal"/><w:qFormat/><w:pPr><w:outlineLvl w:val="0"/></w:pPr><w:rPr><w:b/><w:sz w:val="40"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Heading2"><w:name w:val="heading 2"/><w:basedOn w:val="Normal"/><w:qFormat/><w:pPr><w:keepNext/><w:spacing w:before="240"/><w:outlineLvl w:val="1"/></w:pPr><w:rPr><w:b/><w:sz w:val="28"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Choice"><w:name w:val="Choice"/><w:basedOn w:val="Normal"/><w:pPr><w:ind w:left="720" w:hanging="360"/></w:pPr></w:style><w:style w:type="paragraph" w:styleId="Caption"><w:name w:val="caption"/><w:basedOn w:val="Normal"/><w:qFormat/><w:pPr><w:keepNext/><w:spacing w:after="60"/></w:pPr><w:rPr><w:i/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Code"><w:name w:val="Code"/><w:basedOn w:val="Normal"/><w:pPr><w:spacing w:after="0"/><w:shd w:val="clear" w:color="auto" w:fill="F2F2F2"/></w:pPr><w:rPr><w:rFonts w:ascii="Courier New" w:hAnsi="Courier New" w:cs="Courier New"/><w:sz w:val="20"/></w:rPr></w:style></w:styles>"#;
+
+#[derive(Clone, Copy, Default)]
+struct RunFormat {
+    bold: bool,
+    italic: bool,
+    monospace: bool,
+}
+
+struct Media {
+    rel_id: String,
+    zip_path: String,
+    data: Vec<u8>,
+}
+
+/// Builds `word/document.xml`, keeping track of the paragraph currently open
+/// so inline tokens (text, math, images) flow into it while block tokens
+/// (code) start their own paragraphs.
+struct DocumentBuilder<'a> {
+    writer: Writer<Cursor<Vec<u8>>>,
+    base_dir: &'a Path,
+    images: &'a ImageOptions,
+    media: Vec<Media>,
+    media_by_source: HashMap<String, usize>,
+    paragraph_open: bool,
+    paragraph_style: &'static str,
+    pending_prefix: Option<String>,
+    next_drawing_id: usize,
+}
+
+/// Writes the quiz as an editable `<stem>.docx` next to the input.
+///
+/// Math and chemistry become native Word equations, code blocks use a
+/// monospace "Code" style, and images are embedded with their alt text as the
+/// picture description. Answers are not marked, since the document is handed
+/// to students.
+pub fn write_docx(quiz: &Quiz, input_path: &Path) -> Result<PathBuf> {
+    let base_dir = input_path.parent().unwrap();
+    let mut builder = DocumentBuilder::new(base_dir, &quiz.images);
+    builder.write_quiz(quiz)?;
+    let (document_xml, media) = builder.finish()?;
+
+    let docx_path = input_path.with_extension("docx");
+    let file = File::create(&docx_path)?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    zip.start_file("[Content_Types].xml", options)?;
+    zip.write_all(CONTENT_TYPES_XML.as_bytes())?;
+    zip.start_file("_rels/.rels", options)?;
+    zip.write_all(ROOT_RELS_XML.as_bytes())?;
+    zip.start_file("word/styles.xml", options)?;
+    zip.write_all(STYLES_XML.as_bytes())?;
+    zip.start_file("word/_rels/document.xml.rels", options)?;
+    zip.write_all(generate_document_rels(&media)?.as_bytes())?;
+    zip.start_file("word/document.xml", options)?;
+    zip.write_all(document_xml.as_bytes())?;
+
+    for m in &media {
+        zip.start_file(m.zip_path.as_str(), options)?;
+        zip.write_all(&m.data)?;
+    }
+
+    zip.finish()?;
+    println!("Generated Word document at: {:?}", docx_path);
+    Ok(docx_path)
+}
+
+fn write_equation(writer: &mut Writer<Cursor<Vec<u8>>>, latex: &str, display: bool) -> Result<()> {
+    if display {
+        writer.write_event(Event::Start(BytesStart::new("m:oMathPara")))?;
+    }
+    write_latex_omml(writer, latex)?;
+    if display {
+        writer.write_event(Event::End(BytesEnd::new("m:oMathPara")))?;
+    }
+    Ok(())
+}
+
+fn generate_document_rels(media: &[Media]) -> Result<String> {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), Some("yes"))))?;
+
+    let mut root = BytesStart::new("Relationships");
+    root.push_attribute(("xmlns", "http://schemas.openxmlformats.org/package/2006/relationships"));
+    writer.write_event(Event::Start(root))?;
+
+    let mut styles = BytesStart::new("Relationship");
+    styles.push_attribute(("Id", "rIdStyles"));
+    styles.push_attribute(("Type", "http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles"));
+    styles.push_attribute(("Target", "styles.xml"));
+    writer.write_event(Event::Empty(styles))?;
+
+    for m in media {
+        let mut rel = BytesStart::new("Relationship");
+        rel.push_attribute(("Id", m.rel_id.as_str()));
+        rel.push_attribute(("Type", IMAGE_REL));
+        rel.push_attribute(("Target", m.zip_path.trim_start_matches("word/")));
+        writer.write_event(Event::Empty(rel))?;
+    }
+
+    writer.write_event(Event::End(BytesEnd::new("Relationships")))?;
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+impl<'a> DocumentBuilder<'a> {
+    fn new(base_dir: &'a Path, images: &'a ImageOptions) -> Self {
+        Self {
+            writer: Writer::new(Cursor::new(Vec::new())),
+            base_dir,
+            images,
+            media: Vec::new(),
+            media_by_source: HashMap::new(),
+            paragraph_open: false,
+            paragraph_style: "Normal",
+            pending_prefix: None,
+            next_drawing_id: 1,
+        }
+    }
+
+    fn write_quiz(&mut self, quiz: &Quiz) -> Result<()> {
+        self.writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), Some("yes"))))?;
+        let mut root = BytesStart::new("w:document");
+        root.push_attribute(("xmlns:w", W_NS));
+        root.push_attribute(("xmlns:r", R_NS));
+        root.push_attribute(("xmlns:wp", WP_NS));
+        root.push_attribute(("xmlns:a", A_NS));
+        root.push_attribute(("xmlns:pic", PIC_NS));
+        root.push_attribute(("xmlns:m", M_NS));
+        self.writer.write_event(Event::Start(root))?;
+        self.writer.write_event(Event::Start(BytesStart::new("w:body")))?;
+
+        self.write_plain_paragraph("Title", &quiz.title)?;
+        if let Some(description) = &quiz.description {
+            self.write_plain_paragraph("Normal", description)?;
+        }
+
+        for (i, question) in quiz.questions.iter().enumerate() {
+            let points = if question.points == 1.0 { "1 point".to_string() } else { format!("{} points", question.points) };
+            self.write_plain_paragraph("Heading2", &format!("Question {} ({})", i + 1, points))?;
+
+            self.write_content(&question.prompt, "Normal", None)?;
+
+            match question.question_type {
+                QuestionType::MultipleChoice | QuestionType::MultipleAnswers | QuestionType::TrueFalse => {
+                    if question.question_type == QuestionType::MultipleAnswers {
+                        self.write_plain_paragraph("Normal", "Select all that apply.")?;
+                    }
+                    for (j, answer) in question.answers.iter().enumerate() {
+                        let prefix = format!("{}.\t", choice_label(j));
+                        self.write_content(&answer.text, "Choice", Some(prefix))?;
+                    }
+                }
+                QuestionType::FileUpload => {
+                    self.write_plain_paragraph("Normal", "Submit your work as a separate file.")?;
+                }
+                QuestionType::ShortAnswer | QuestionType::Essay => {
+                    self.write_plain_paragraph("Normal", "Answer:")?;
+                    self.write_plain_paragraph("Normal", "")?;
+                }
+            }
+        }
+
+        // Letter page with one-inch margins.
+        self.writer.write_event(Event::Start(BytesStart::new("w:sectPr")))?;
+        let mut size = BytesStart::new("w:pgSz");
+        size.push_attribute(("w:w", "12240"));
+        size.push_attribute(("w:h", "15840"));
+        self.writer.write_event(Event::Empty(size))?;
+        let mut margins = BytesStart::new("w:pgMar");
+        for side in ["w:top", "w:right", "w:bottom", "w:left"] {
+            margins.push_attribute((side, "1440"));
+        }
+        self.writer.write_event(Event::Empty(margins))?;
+        self.writer.write_event(Event::End(BytesEnd::new("w:sectPr")))?;
+
+        self.writer.write_event(Event::End(BytesEnd::new("w:body")))?;
+        self.writer.write_event(Event::End(BytesEnd::new("w:document")))?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(String, Vec<Media>)> {
+        Ok((String::from_utf8(self.writer.into_inner().into_inner())?, self.media))
+    }
+
+    fn write_plain_paragraph(&mut self, style: &'static str, text: &str) -> Result<()> {
+        self.open_paragraph(style)?;
+        if !text.is_empty() {
+            self.write_run(text, RunFormat::default())?;
+        }
+        self.close_paragraph()
+    }
+
+    fn open_paragraph(&mut self, style: &'static str) -> Result<()> {
+        self.writer.write_event(Event::Start(BytesStart::new("w:p")))?;
+        self.writer.write_event(Event::Start(BytesStart::new("w:pPr")))?;
+        let mut p_style = BytesStart::new("w:pStyle");
+        p_style.push_attribute(("w:val", style));
+        self.writer.write_event(Event::Empty(p_style))?;
+        self.writer.write_event(Event::End(BytesEnd::new("w:pPr")))?;
+        self.paragraph_open = true;
+        Ok(())
+    }
+
+    fn close_paragraph(&mut self) -> Result<()> {
+        if self.paragraph_open {
+            self.writer.write_event(Event::End(BytesEnd::new("w:p")))?;
+            self.paragraph_open = false;
+        }
+        Ok(())
+    }
+
+    /// Opens a paragraph in the current content style if none is open,
+    /// writing any pending choice letter first.
+    fn ensure_paragraph(&mut self) -> Result<()> {
+        if !self.paragraph_open {
+            self.open_paragraph(self.paragraph_style)?;
+            if let Some(prefix) = self.pending_prefix.take() {
+                self.write_run(&prefix, RunFormat::default())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self, text: &str, format: RunFormat) -> Result<()> {
+        self.writer.write_event(Event::Start(BytesStart::new("w:r")))?;
+        if format.bold || format.italic || format.monospace {
+            self.writer.write_event(Event::Start(BytesStart::new("w:rPr")))?;
+            if format.monospace {
+                let mut fonts = BytesStart::new("w:rFonts");
+                fonts.push_attribute(("w:ascii", "Courier New"));
+                fonts.push_attribute(("w:hAnsi", "Courier New"));
+                self.writer.write_event(Event::Empty(fonts))?;
+            }
+            if format.bold {
+                self.writer.write_event(Event::Empty(BytesStart::new("w:b")))?;
+            }
+            if format.italic {
+                self.writer.write_event(Event::Empty(BytesStart::new("w:i")))?;
+            }
+            self.writer.write_event(Event::End(BytesEnd::new("w:rPr")))?;
+        }
+        for (i, part) in text.split('\t').enumerate() {
+            if i > 0 {
+                self.writer.write_event(Event::Empty(BytesStart::new("w:tab")))?;
+            }
+            if !part.is_empty() {
+                let mut t = BytesStart::new("w:t");
+                t.push_attribute(("xml:space", "preserve"));
+                self.writer.write_event(Event::Start(t))?;
+                self.writer.write_event(Event::Text(BytesText::new(part)))?;
+                self.writer.write_event(Event::End(BytesEnd::new("w:t")))?;
+            }
+        }
+        self.writer.write_event(Event::End(BytesEnd::new("w:r")))?;
+        Ok(())
+    }
+
+    fn write_content(&mut self, text: &str, style: &'static str, prefix: Option<String>) -> Result<()> {
+        self.paragraph_style = style;
+        self.pending_prefix = prefix;
+
+        for block in parse_blocks(text) {
+            match block {
+                Block::Paragraph(inlines) => {
+                    self.write_inlines(&inlines, RunFormat::default())?;
+                    self.close_paragraph()?;
+                }
+                Block::List { ordered, start, items } => {
+                    for (i, item) in items.iter().enumerate() {
+                        self.ensure_paragraph()?;
+                        let marker = if ordered { format!("{}.\t", start + i as u64) } else { "\u{2022}\t".to_string() };
+                        self.write_run(&marker, RunFormat::default())?;
+                        self.write_inlines(item, RunFormat::default())?;
+                        self.close_paragraph()?;
+                    }
+                }
+                Block::Table(table) => self.write_table(&table)?,
+                Block::Token(Token::Music(musicxml)) => self.write_music(Some(&musicxml), &musicxml)?,
+                Block::Token(Token::Abc(abc)) => self.write_music(abc_to_musicxml(&abc).ok().as_deref(), &abc)?,
+                Block::Token(Token::Diagram { lang, content, alt }) => match diagram_to_svg(&lang, &content, &alt) {
+                    Ok(svg) => {
+                        self.write_svg(&diagram_filename(&lang, &content, &alt), svg, &alt)?;
+                        self.close_paragraph()?;
+                    }
+                    Err(_) => self.write_code(&content)?,
+                },
+                Block::Token(Token::Plot { spec, alt }) => match plot_to_svg(&spec, &alt) {
+                    Ok(plot) => {
+                        self.write_svg(&plot_filename(&spec, &alt), plot.svg, &plot.alt)?;
+                        self.close_paragraph()?;
+                    }
+                    Err(_) => self.write_code(&spec)?,
+                },
+                Block::Token(Token::Code { content, .. }) => self.write_code(&content)?,
+                Block::Token(_) => {}
+            }
+        }
+
+        // A choice with no content still needs its letter.
+        if self.pending_prefix.is_some() {
+            self.ensure_paragraph()?;
+        }
+        self.close_paragraph()
+    }
+
+    /// Soft line breaks in the source become spaces.
+    fn write_inlines(&mut self, inlines: &[Inline], format: RunFormat) -> Result<()> {
+        for inline in inlines {
+            match inline {
+                Inline::Text(text) => {
+                    self.ensure_paragraph()?;
+                    self.write_run(&text.replace('\n', " "), format)?;
+                }
+                Inline::Strong(children) => self.write_inlines(children, RunFormat { bold: true, ..format })?,
+                Inline::Emphasis(children) => self.write_inlines(children, RunFormat { italic: true, ..format })?,
+                Inline::Code(code) => {
+                    self.ensure_paragraph()?;
+                    self.write_run(code, RunFormat { monospace: true, ..format })?;
+                }
+                // Word hyperlinks need their own relationships; the target is
+                // spelled out instead when it differs from the label.
+                Inline::Link { href, children } => {
+                    self.write_inlines(children, format)?;
+                    if is_safe_href(href) && plain_text(children) != *href {
+                        self.ensure_paragraph()?;
+                        self.write_run(&format!(" ({})", href), format)?;
+                    }
+                }
+                Inline::LineBreak => {
+                    self.ensure_paragraph()?;
+                    self.writer.write_event(Event::Start(BytesStart::new("w:r")))?;
+                    self.writer.write_event(Event::Empty(BytesStart::new("w:br")))?;
+                    self.writer.write_event(Event::End(BytesEnd::new("w:r")))?;
+                }
+                Inline::Token(Token::LatexMath { latex, display }) => self.write_math(latex, *display)?,
+                Inline::Token(Token::Chemistry(chem)) => self.write_math(&transpile_mhchem(chem), false)?,
+                Inline::Token(Token::Image { src, alt }) => {
+                    match package_path(src, self.images) {
+                        Some(_) => self.write_image(src, alt)?,
+                        // A linked remote image cannot be embedded.
+                        None => {
+                            self.ensure_paragraph()?;
+                            self.write_run(&format!("[Image: {}] ({})", alt, src), format)?;
+                        }
+                    }
+                }
+                Inline::Token(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Display math is wrapped in `<m:oMathPara>`, which Word lays out on a
+    /// line of its own.
+    fn write_math(&mut self, latex: &str, display: bool) -> Result<()> {
+        self.ensure_paragraph()?;
+        // Anything latex2mathml cannot handle is kept as its source so the
+        // document can still be corrected by hand.
+        let checkpoint = self.writer.get_ref().position();
+        if write_equation(&mut self.writer, latex, display).is_err() {
+            let cursor = self.writer.get_mut();
+            cursor.get_mut().truncate(checkpoint as usize);
+            cursor.set_position(checkpoint);
+            let delimiter = if display { "$$" } else { "$" };
+            self.write_run(&format!("{}{}{}", delimiter, latex, delimiter), RunFormat::default())?;
+        }
+        Ok(())
+    }
+
+    /// Block content can't share a paragraph with a choice letter, so the
+    /// letter gets one of its own.
+    fn write_pending_prefix(&mut self) -> Result<()> {
+        self.close_paragraph()?;
+        if let Some(prefix) = self.pending_prefix.take() {
+            self.open_paragraph(self.paragraph_style)?;
+            self.write_run(&prefix, RunFormat::default())?;
+            self.close_paragraph()?;
+        }
+        Ok(())
+    }
+
+    fn write_code(&mut self, content: &str) -> Result<()> {
+        self.write_pending_prefix()?;
+        for line in content.trim_end_matches('\n').lines() {
+            self.open_paragraph("Code")?;
+            if !line.is_empty() {
+                self.write_run(line, RunFormat { monospace: true, ..RunFormat::default() })?;
+            }
+            self.close_paragraph()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the engraved image of a score, or `source` as code when there
+    /// is none.
+    fn write_music(&mut self, musicxml: Option<&str>, source: &str) -> Result<()> {
+        let engraved = musicxml.and_then(|musicxml| Some((musicxml, musicxml_to_svg(musicxml).ok()?)));
+        match engraved {
+            Some((musicxml, svg)) => {
+                let alt = musicxml_title(musicxml).unwrap_or_else(|| MUSIC_ALT.to_string());
+                self.write_svg(&music_filename(musicxml), svg, &alt)?;
+                self.close_paragraph()
+            }
+            None => self.write_code(source),
+        }
+    }
+
+    /// Writes a bordered table whose header row repeats across pages and is
+    /// marked as a header for screen readers. The caption, if any, is also
+    /// the table's alt text.
+    fn write_table(&mut self, table: &Table) -> Result<()> {
+        self.write_pending_prefix()?;
+        if let Some(caption) = &table.caption {
+            self.open_paragraph("Caption")?;
+            self.write_inlines(caption, RunFormat::default())?;
+            self.close_paragraph()?;
+        }
+
+        self.writer.write_event(Event::Start(BytesStart::new("w:tbl")))?;
+        self.writer.write_event(Event::Start(BytesStart::new("w:tblPr")))?;
+        let mut width = BytesStart::new("w:tblW");
+        width.push_attribute(("w:w", "0"));
+        width.push_attribute(("w:type", "auto"));
+        self.writer.write_event(Event::Empty(width))?;
+        self.writer.write_event(Event::Start(BytesStart::new("w:tblBorders")))?;
+        for side in ["w:top", "w:left", "w:bottom", "w:right", "w:insideH", "w:insideV"] {
+            let mut border = BytesStart::new(side);
+            border.push_attribute(("w:val", "single"));
+            border.push_attribute(("w:sz", "4"));
+            border.push_attribute(("w:space", "0"));
+            border.push_attribute(("w:color", "767676"));
+            self.writer.write_event(Event::Empty(border))?;
+        }
+        self.writer.write_event(Event::End(BytesEnd::new("w:tblBorders")))?;
+        if let Some(caption) = &table.caption {
+            let mut alt = BytesStart::new("w:tblCaption");
+            alt.push_attribute(("w:val", plain_text(caption).as_str()));
+            self.writer.write_event(Event::Empty(alt))?;
+        }
+        self.writer.write_event(Event::End(BytesEnd::new("w:tblPr")))?;
+
+        self.writer.write_event(Event::Start(BytesStart::new("w:tr")))?;
+        self.writer.write_event(Event::Start(BytesStart::new("w:trPr")))?;
+        self.writer.write_event(Event::Empty(BytesStart::new("w:tblHeader")))?;
+        self.writer.write_event(Event::End(BytesEnd::new("w:trPr")))?;
+        for (i, cell) in table.header.iter().enumerate() {
+            self.write_table_cell(cell, table.alignments.get(i).copied(), true)?;
+        }
+        self.writer.write_event(Event::End(BytesEnd::new("w:tr")))?;
+
+        for row in &table.rows {
+            self.writer.write_event(Event::Start(BytesStart::new("w:tr")))?;
+            for (i, cell) in row.iter().enumerate() {
+                let is_header = i == 0 && table.row_headers;
+                self.write_table_cell(cell, table.alignments.get(i).copied(), is_header)?;
+            }
+            self.writer.write_event(Event::End(BytesEnd::new("w:tr")))?;
+        }
+
+        self.writer.write_event(Event::End(BytesEnd::new("w:tbl")))?;
+        // Word needs a paragraph between a table and whatever follows it.
+        self.write_plain_paragraph(self.paragraph_style, "")
+    }
+
+    fn write_table_cell(&mut self, inlines: &[Inline], alignment: Option<Alignment>, bold: bool) -> Result<()> {
+        self.writer.write_event(Event::Start(BytesStart::new("w:tc")))?;
+        self.writer.write_event(Event::Start(BytesStart::new("w:p")))?;
+        if let Some(align) = alignment.and_then(Alignment::css) {
+            self.writer.write_event(Event::Start(BytesStart::new("w:pPr")))?;
+            let mut jc = BytesStart::new("w:jc");
+            jc.push_attribute(("w:val", align));
+            self.writer.write_event(Event::Empty(jc))?;
+            self.writer.write_event(Event::End(BytesEnd::new("w:pPr")))?;
+        }
+        self.paragraph_open = true;
+        self.write_inlines(inlines, RunFormat { bold, ..RunFormat::default() })?;
+        self.close_paragraph()?;
+        self.writer.write_event(Event::End(BytesEnd::new("w:tc")))?;
+        Ok(())
+    }
+
+    /// Embeds the image at `src`, a path relative to the quiz or a data or
+    /// remote URI.
+    fn write_image(&mut self, src: &str, alt: &str) -> Result<()> {
+        self.ensure_paragraph()?;
+
+        let bytes = match read_image(src, self.base_dir, self.images) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Warning: Image not found: {}", e);
+                return self.write_run(&format!("[Image: {}]", alt), RunFormat::default());
+            }
+        };
+
+        let package_path = package_path(src, self.images).unwrap_or_default();
+        let file_name = package_path.rsplit('/').next().unwrap_or("image").to_string();
+        self.write_picture(src, &file_name, bytes, alt)
+    }
+
+    /// Embeds an SVG rendered from the quiz, such as a diagram, named `name`.
+    fn write_svg(&mut self, name: &str, svg: String, alt: &str) -> Result<()> {
+        self.ensure_paragraph()?;
+        self.write_picture(name, name, svg.into_bytes(), alt)
+    }
+
+    /// Embeds `bytes` as a picture, sharing one copy between pictures with
+    /// the same `key`.
+    fn write_picture(&mut self, key: &str, file_name: &str, bytes: Vec<u8>, alt: &str) -> Result<()> {
+        let extension = match file_name.rsplit_once('.') {
+            Some((_, extension)) => extension.to_ascii_lowercase(),
+            None => String::new(),
+        };
+        let (width, height) = image_size(&bytes, &extension).unwrap_or((400, 300));
+        let (cx, cy) = fit_to_page(width, height);
+
+        let index = match self.media_by_source.get(key) {
+            Some(&index) => index,
+            None => {
+                let n = self.media.len() + 1;
+                self.media.push(Media {
+                    rel_id: format!("rIdImage{}", n),
+                    zip_path: format!("word/media/image{}.{}", n, extension),
+                    data: bytes,
+                });
+                self.media_by_source.insert(key.to_string(), n - 1);
+                n - 1
+            }
+        };
+        let rel_id = self.media[index].rel_id.clone();
+        let name = file_name.to_string();
+        let id = self.next_drawing_id.to_string();
+        self.next_drawing_id += 1;
+
+        let w = &mut self.writer;
+        w.write_event(Event::Start(BytesStart::new("w:r")))?;
+        w.write_event(Event::Start(BytesStart::new("w:drawing")))?;
+        let mut inline = BytesStart::new("wp:inline");
+        for side in ["distT", "distB", "distL", "distR"] {
+            inline.push_attribute((side, "0"));
+        }
+        w.write_event(Event::Start(inline))?;
+
+        let mut extent = BytesStart::new("wp:extent");
+        extent.push_attribute(("cx", cx.to_string().as_str()));
+        extent.push_attribute(("cy", cy.to_string().as_str()));
+        w.write_event(Event::Empty(extent))?;
+
+        let mut doc_pr = BytesStart::new("wp:docPr");
+        doc_pr.push_attribute(("id", id.as_str()));
+        doc_pr.push_attribute(("name", name.as_str()));
+        doc_pr.push_attribute(("descr", alt));
+        w.write_event(Event::Empty(doc_pr))?;
+
+        w.write_event(Event::Start(BytesStart::new("a:graphic")))?;
+        let mut data = BytesStart::new("a:graphicData");
+        data.push_attribute(("uri", PIC_NS));
+        w.write_event(Event::Start(data))?;
+        w.write_event(Event::Start(BytesStart::new("pic:pic")))?;
+
+        w.write_event(Event::Start(BytesStart::new("pic:nvPicPr")))?;
+        let mut c_nv_pr = BytesStart::new("pic:cNvPr");
+        c_nv_pr.push_attribute(("id", id.as_str()));
+        c_nv_pr.push_attribute(("name", name.as_str()));
+        c_nv_pr.push_attribute(("descr", alt));
+        w.write_event(Event::Empty(c_nv_pr))?;
+        w.write_event(Event::Empty(BytesStart::new("pic:cNvPicPr")))?;
+        w.write_event(Event::End(BytesEnd::new("pic:nvPicPr")))?;
+
+        w.write_event(Event::Start(BytesStart::new("pic:blipFill")))?;
+        let mut blip = BytesStart::new("a:blip");
+        blip.push_attribute(("r:embed", rel_id.as_str()));
+        if extension == "svg" {
+            // Word only renders SVG through the Office 2016 extension.
+            w.write_event(Event::Start(blip))?;
+            w.write_event(Event::Start(BytesStart::new("a:extLst")))?;
+            let mut ext = BytesStart::new("a:ext");
+            ext.push_attribute(("uri", SVG_BLIP_EXT));
+            w.write_event(Event::Start(ext))?;
+            let mut svg_blip = BytesStart::new("asvg:svgBlip");
+            svg_blip.push_attribute(("xmlns:asvg", SVG_NS));
+            svg_blip.push_attribute(("r:embed", rel_id.as_str()));
+            w.write_event(Event::Empty(svg_blip))?;
+            w.write_event(Event::End(BytesEnd::new("a:ext")))?;
+            w.write_event(Event::End(BytesEnd::new("a:extLst")))?;
+            w.write_event(Event::End(BytesEnd::new("a:blip")))?;
+        } else {
+            w.write_event(Event::Empty(blip))?;
+        }
+        w.write_event(Event::Start(BytesStart::new("a:stretch")))?;
+        w.write_event(Event::Empty(BytesStart::new("a:fillRect")))?;
+        w.write_event(Event::End(BytesEnd::new("a:stretch")))?;
+        w.write_event(Event::End(BytesEnd::new("pic:blipFill")))?;
+
+        w.write_event(Event::Start(BytesStart::new("pic:spPr")))?;
+        w.write_event(Event::Start(BytesStart::new("a:xfrm")))?;
+        let mut off = BytesStart::new("a:off");
+        off.push_attribute(("x", "0"));
+        off.push_attribute(("y", "0"));
+        w.write_event(Event::Empty(off))?;
+        let mut ext = BytesStart::new("a:ext");
+        ext.push_attribute(("cx", cx.to_string().as_str()));
+        ext.push_attribute(("cy", cy.to_string().as_str()));
+        w.write_event(Event::Empty(ext))?;
+        w.write_event(Event::End(BytesEnd::new("a:xfrm")))?;
+        let mut geom = BytesStart::new("a:prstGeom");
+        geom.push_attribute(("prst", "rect"));
+        w.write_event(Event::Start(geom))?;
+        w.write_event(Event::Empty(BytesStart::new("a:avLst")))?;
+        w.write_event(Event::End(BytesEnd::new("a:prstGeom")))?;
+        w.write_event(Event::End(BytesEnd::new("pic:spPr")))?;
+
+        w.write_event(Event::End(BytesEnd::new("pic:pic")))?;
+        w.write_event(Event::End(BytesEnd::new("a:graphicData")))?;
+        w.write_event(Event::End(BytesEnd::new("a:graphic")))?;
+        w.write_event(Event::End(BytesEnd::new("wp:inline")))?;
+        w.write_event(Event::End(BytesEnd::new("w:drawing")))?;
+        w.write_event(Event::End(BytesEnd::new("w:r")))?;
+        Ok(())
+    }
+}
+
+fn fit_to_page(width: u64, height: u64) -> (u64, u64) {
+    let (width, height) = (width.max(1), height.max(1));
+    if width > MAX_IMAGE_WIDTH_PX {
+        let scaled = height * MAX_IMAGE_WIDTH_PX / width;
+        (MAX_IMAGE_WIDTH_PX * EMU_PER_PX, scaled.max(1) * EMU_PER_PX)
+    } else {
+        (width * EMU_PER_PX, height * EMU_PER_PX)
+    }
+}
+
+/// Reads pixel dimensions from PNG, GIF and JPEG headers, or the `width` and
+/// `height` (falling back to `viewBox`) of an SVG root element.
+fn image_size(bytes: &[u8], extension: &str) -> Option<(u64, u64)> {
+    match extension {
+        "png" if bytes.len() >= 24 && bytes.starts_with(b"\x89PNG") => {
+            let w = u32::from_be_bytes(bytes[16..20].try_into().ok()?);
+            let h = u32::from_be_bytes(bytes[20..24].try_into().ok()?);
+            Some((w as u64, h as u64))
+        }
+        "gif" if bytes.len() >= 10 && bytes.starts_with(b"GIF") => {
+            let w = u16::from_le_bytes([bytes[6], bytes[7]]);
+            let h = u16::from_le_bytes([bytes[8], bytes[9]]);
+            Some((w as u64, h as u64))
+        }
+        "jpg" | "jpeg" => jpeg_size(bytes),
+        "svg" => svg_size(std::str::from_utf8(bytes).ok()?),
+        _ => None,
+    }
+}
+
+fn jpeg_size(bytes: &[u8]) -> Option<(u64, u64)> {
+    let mut i = 2;
+    while i + 9 < bytes.len() {
+        if bytes[i] != 0xFF {
+            return None;
+        }
+        let marker = bytes[i + 1];
+        let length = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+        // SOF0..SOF15, excluding DHT, JPG and DAC
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let h = u16::from_be_bytes([bytes[i + 5], bytes[i + 6]]);
+            let w = u16::from_be_bytes([bytes[i + 7], bytes[i + 8]]);
+            return Some((w as u64, h as u64));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+fn svg_size(svg: &str) -> Option<(u64, u64)> {
+    let start = svg.find("<svg")?;
+    let tag = &svg[start..start + svg[start..].find('>')?];
+    let attr = |name: &str| -> Option<&str> {
+        let re = regex::Regex::new(&format!(r#"\s{}\s*=\s*"([^"]*)""#, name)).ok()?;
+        re.captures(tag).and_then(|c| c.get(1)).map(|m| m.as_str())
+    };
+    let number = |value: &str| -> Option<f64> {
+        let digits: String = value.chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect();
+        digits.parse().ok()
+    };
+
+    // Percentages and other relative units say nothing about the size.
+    let absolute = |value: &str| !value.ends_with('%');
+    if let (Some(w), Some(h)) = (attr("width"), attr("height"))
+        && absolute(w)
+        && absolute(h)
+        && let (Some(w), Some(h)) = (number(w), number(h))
+    {
+        return Some((w as u64, h as u64));
+    }
+    let view_box: Vec<f64> = attr("viewBox")?
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter_map(|v| v.parse().ok())
+        .collect();
+    match view_box.as_slice() {
+        [_, _, w, h] => Some((*w as u64, *h as u64)),
+        _ => None,
+    }
+}
@@ -0,0 +1,459 @@
+use anyhow::{bail, Result};
+use crate::qti::diagram::{Direction, Edge, Graph, LineStyle, Shape};
+use std::collections::HashMap;
+
+// Reads the Graphviz DOT language into a `Graph`. The whole grammar is
+// accepted, but only the attributes the diagram renderer can draw are
+// used: `label`, `shape`, `style`, `rankdir`, `dir` and `arrowhead`.
+// Subgraphs and clusters are flattened into the graph, scoping their
+// `node` and `edge` defaults as Graphviz does.
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    /// An identifier, number or string, quoted or not.
+    Id(String),
+    /// `->` or `--`.
+    Edge,
+    Punct(char),
+}
+
+type Attributes = HashMap<String, String>;
+
+pub(crate) fn parse_dot(source: &str) -> Result<Graph> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        graph: Graph::default(),
+        directed: true,
+    };
+    parser.graph_header()?;
+    let mut node_defaults = Attributes::new();
+    let mut edge_defaults = Attributes::new();
+    parser.statements(&mut node_defaults, &mut edge_defaults)?;
+    Ok(parser.graph)
+}
+
+fn tokenize(source: &str) -> Result<Vec<Tok>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line_start = true;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line_start = true;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            // C preprocessor output lines.
+            '#' if line_start => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            '-' if matches!(chars.peek(), Some('>') | Some('-')) => {
+                chars.next();
+                tokens.push(Tok::Edge);
+            }
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        None => bail!("Unterminated string in DOT source"),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('"') => value.push('"'),
+                            // A backslash before a newline continues the line.
+                            Some('\n') => {}
+                            Some(c) => {
+                                value.push('\\');
+                                value.push(c);
+                            }
+                            None => {}
+                        },
+                        Some(c) => value.push(c),
+                    }
+                }
+                // "a" + "b" concatenates.
+                if let Some(Tok::Punct('+')) = tokens.last() {
+                    tokens.pop();
+                    if let Some(Tok::Id(previous)) = tokens.last_mut() {
+                        previous.push_str(&value);
+                        line_start = false;
+                        continue;
+                    }
+                }
+                tokens.push(Tok::Id(value));
+            }
+            // HTML-like labels keep only their text, with <br/> as a line break.
+            '<' => {
+                let mut depth = 1;
+                let mut html = String::new();
+                for c in chars.by_ref() {
+                    match c {
+                        '<' => depth += 1,
+                        '>' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    html.push(c);
+                }
+                tokens.push(Tok::Id(html_text(&html)));
+            }
+            '{' | '}' | '[' | ']' | ';' | ',' | '=' | ':' | '+' => tokens.push(Tok::Punct(c)),
+            c if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' || !c.is_ascii() => {
+                let mut value = c.to_string();
+                while let Some(c) = chars.next_if(|&c| c.is_alphanumeric() || c == '_' || c == '.' || !c.is_ascii()) {
+                    value.push(c);
+                }
+                tokens.push(Tok::Id(value));
+            }
+            c => bail!("Unexpected character `{}` in DOT source", c),
+        }
+        line_start = false;
+    }
+    Ok(tokens)
+}
+
+/// The text of an HTML-like label, with tags removed and `<br/>` as `\n`.
+fn html_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        text.push_str(&rest[..open]);
+        let close = rest[open..].find('>').map_or(rest.len(), |i| open + i + 1);
+        if rest[open + 1..].trim_start().to_lowercase().starts_with("br") {
+            text.push('\n');
+        }
+        rest = &rest[close..];
+    }
+    text.push_str(rest);
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+struct Parser {
+    tokens: Vec<Tok>,
+    pos: usize,
+    graph: Graph,
+    directed: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Tok::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if !self.eat(c) {
+            bail!("Expected `{}` in DOT source, found {}", c, self.describe());
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            Some(Tok::Id(id)) => format!("`{}`", id),
+            Some(Tok::Edge) => "an edge operator".to_string(),
+            Some(Tok::Punct(c)) => format!("`{}`", c),
+            None => "the end of the source".to_string(),
+        }
+    }
+
+    fn keyword(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Id(id)) if id.eq_ignore_ascii_case(word))
+    }
+
+    /// `[strict] (graph | digraph) [ID] {`
+    fn graph_header(&mut self) -> Result<()> {
+        if self.keyword("strict") {
+            self.pos += 1;
+        }
+        if self.keyword("digraph") {
+            self.directed = true;
+        } else if self.keyword("graph") {
+            self.directed = false;
+        } else {
+            bail!("DOT source must start with `graph` or `digraph`, found {}", self.describe());
+        }
+        self.pos += 1;
+        if let Some(Tok::Id(_)) = self.peek() {
+            self.pos += 1;
+        }
+        self.expect('{')
+    }
+
+    /// Statements up to and including the closing `}`.
+    fn statements(&mut self, node_defaults: &mut Attributes, edge_defaults: &mut Attributes) -> Result<()> {
+        loop {
+            if self.eat('}') {
+                return Ok(());
+            }
+            if self.peek().is_none() {
+                bail!("Missing `}}` at the end of the DOT source");
+            }
+            if self.eat(';') {
+                continue;
+            }
+            self.statement(node_defaults, edge_defaults)?;
+        }
+    }
+
+    fn statement(&mut self, node_defaults: &mut Attributes, edge_defaults: &mut Attributes) -> Result<()> {
+        for (word, defaults) in [("node", &mut *node_defaults), ("edge", &mut *edge_defaults)] {
+            if self.keyword(word) && self.tokens.get(self.pos + 1) == Some(&Tok::Punct('[')) {
+                self.pos += 1;
+                defaults.extend(self.attribute_list()?);
+                return Ok(());
+            }
+        }
+        if self.keyword("graph") && self.tokens.get(self.pos + 1) == Some(&Tok::Punct('[')) {
+            self.pos += 1;
+            let attributes = self.attribute_list()?;
+            self.graph_attributes(&attributes);
+            return Ok(());
+        }
+        if let (Some(Tok::Id(key)), Some(Tok::Punct('='))) = (self.peek().cloned(), self.tokens.get(self.pos + 1)) {
+            self.pos += 2;
+            let Some(Tok::Id(value)) = self.next() else {
+                bail!("Expected a value for `{}` in DOT source", key);
+            };
+            self.graph_attributes(&HashMap::from([(key, value)]));
+            return Ok(());
+        }
+
+        let mut ends = vec![self.endpoint(node_defaults, edge_defaults)?];
+        while self.peek() == Some(&Tok::Edge) {
+            self.pos += 1;
+            ends.push(self.endpoint(node_defaults, edge_defaults)?);
+        }
+        let attributes = if self.peek() == Some(&Tok::Punct('[')) { self.attribute_list()? } else { Attributes::new() };
+
+        if ends.len() == 1 {
+            // A node statement; its attributes apply to the node itself.
+            if let Endpoint::Node(id) = &ends[0] {
+                let index = self.node(id, node_defaults);
+                self.node_attributes(index, &attributes);
+            }
+            return Ok(());
+        }
+        let mut merged = edge_defaults.clone();
+        merged.extend(attributes);
+        for pair in ends.windows(2) {
+            for &from in &self.endpoint_nodes(&pair[0], node_defaults) {
+                for &to in &self.endpoint_nodes(&pair[1], node_defaults) {
+                    self.edge(from, to, &merged);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A node id with an optional port, or a subgraph.
+    fn endpoint(&mut self, node_defaults: &Attributes, edge_defaults: &Attributes) -> Result<Endpoint> {
+        if self.keyword("subgraph") || self.peek() == Some(&Tok::Punct('{')) {
+            if self.keyword("subgraph") {
+                self.pos += 1;
+                if let Some(Tok::Id(_)) = self.peek() {
+                    self.pos += 1;
+                }
+            }
+            self.expect('{')?;
+            let before = self.graph.nodes.len();
+            let first_statement = self.pos;
+            let (mut inner_nodes, mut inner_edges) = (node_defaults.clone(), edge_defaults.clone());
+            self.statements(&mut inner_nodes, &mut inner_edges)?;
+            // A subgraph as an edge end stands for every node named in it,
+            // which may include nodes that already existed.
+            let mut members: Vec<String> = self.graph.nodes[before..].iter().map(|n| n.id.clone()).collect();
+            for token in &self.tokens[first_statement..self.pos] {
+                if let Tok::Id(id) = token
+                    && self.graph.nodes[..before].iter().any(|n| &n.id == id)
+                    && !members.contains(id)
+                {
+                    members.push(id.clone());
+                }
+            }
+            return Ok(Endpoint::Subgraph(members));
+        }
+        let Some(Tok::Id(id)) = self.next() else {
+            self.pos -= 1;
+            bail!("Expected a node in DOT source, found {}", self.describe());
+        };
+        // Ports and compass points don't affect the drawing.
+        while self.eat(':') {
+            self.next();
+        }
+        Ok(Endpoint::Node(id))
+    }
+
+    fn endpoint_nodes(&mut self, endpoint: &Endpoint, node_defaults: &Attributes) -> Vec<usize> {
+        match endpoint {
+            Endpoint::Node(id) => vec![self.node(id, node_defaults)],
+            Endpoint::Subgraph(members) => members.iter().map(|id| self.node(id, node_defaults)).collect(),
+        }
+    }
+
+    /// `[a=b, c=d; ...][...]`
+    fn attribute_list(&mut self) -> Result<Attributes> {
+        let mut attributes = Attributes::new();
+        while self.eat('[') {
+            while !self.eat(']') {
+                let Some(Tok::Id(key)) = self.next() else {
+                    bail!("Expected an attribute name in DOT source");
+                };
+                let value = if self.eat('=') {
+                    match self.next() {
+                        Some(Tok::Id(value)) => value,
+                        _ => bail!("Expected a value for `{}` in DOT source", key),
+                    }
+                } else {
+                    "true".to_string()
+                };
+                attributes.insert(key.to_lowercase(), value);
+                let _ = self.eat(',') || self.eat(';');
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// The node `id`, created with the current node defaults if new.
+    fn node(&mut self, id: &str, node_defaults: &Attributes) -> usize {
+        let count = self.graph.nodes.len();
+        let index = self.graph.node(id, Shape::Ellipse);
+        if index == count {
+            self.node_attributes(index, node_defaults);
+        }
+        index
+    }
+
+    fn node_attributes(&mut self, index: usize, attributes: &Attributes) {
+        let node = &mut self.graph.nodes[index];
+        if let Some(shape) = attributes.get("shape") {
+            node.shape = match shape.to_lowercase().as_str() {
+                "box" | "rect" | "rectangle" | "square" | "record" | "mrecord" | "component" | "note" | "tab" | "folder"
+                | "box3d" | "cylinder" => Shape::Box,
+                "circle" => Shape::Circle,
+                "doublecircle" => Shape::DoubleCircle,
+                "diamond" | "mdiamond" => Shape::Diamond,
+                "hexagon" | "octagon" | "doubleoctagon" | "tripleoctagon" => Shape::Hexagon,
+                "point" => Shape::Start,
+                "plaintext" | "plain" | "none" => Shape::Plain,
+                _ => Shape::Ellipse,
+            };
+        }
+        if let Some(style) = attributes.get("style")
+            && style.contains("rounded")
+            && node.shape == Shape::Box
+        {
+            node.shape = Shape::Rounded;
+        }
+        if let Some(label) = attributes.get("label") {
+            node.label = escaped_label(label, &node.id);
+        }
+        if node.shape == Shape::Start {
+            node.label.clear();
+        }
+    }
+
+    fn edge(&mut self, from: usize, to: usize, attributes: &Attributes) {
+        let mut head = self.directed;
+        let mut tail = false;
+        if let Some(dir) = attributes.get("dir") {
+            (head, tail) = match dir.as_str() {
+                "forward" => (true, false),
+                "back" => (false, true),
+                "both" => (true, true),
+                _ => (false, false),
+            };
+        }
+        if attributes.get("arrowhead").map(String::as_str) == Some("none") {
+            head = false;
+        }
+        if attributes.get("arrowtail").map(String::as_str) == Some("none") {
+            tail = false;
+        }
+        let style = match attributes.get("style").map(String::as_str) {
+            Some("invis") => return,
+            Some("dashed") => LineStyle::Dashed,
+            Some("dotted") => LineStyle::Dotted,
+            Some("bold") => LineStyle::Bold,
+            _ => LineStyle::Solid,
+        };
+        let label = attributes.get("label").map(|l| escaped_label(l, "")).unwrap_or_default();
+        self.graph.edges.push(Edge {
+            from,
+            to,
+            label,
+            style,
+            head,
+            tail,
+        });
+    }
+
+    fn graph_attributes(&mut self, attributes: &Attributes) {
+        if let Some(rankdir) = attributes.get("rankdir") {
+            self.graph.direction = match rankdir.to_uppercase().as_str() {
+                "LR" => Direction::LeftRight,
+                "RL" => Direction::RightLeft,
+                "BT" => Direction::BottomTop,
+                _ => Direction::TopBottom,
+            };
+        }
+    }
+}
+
+enum Endpoint {
+    Node(String),
+    Subgraph(Vec<String>),
+}
+
+/// Expands the escapes Graphviz allows in labels: `\n`, `\l` and `\r` end a
+/// line and `\N` is the node's name.
+fn escaped_label(label: &str, id: &str) -> String {
+    let mut out = String::new();
+    let mut chars = label.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'l' | 'r') => out.push('\n'),
+            Some('N') => out.push_str(id),
+            Some('G' | 'E' | 'T' | 'H') => {}
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out.trim_end_matches('\n').to_string()
+}
@@ -1,67 +1,221 @@
 use anyhow::Result;
-use crate::qti::model::{Quiz, Question, QuestionType};
+use crate::qti::a11y::{audit_items, format_audit};
+use crate::qti::abc::abc_to_musicxml;
+use crate::qti::diagram::diagram_to_svg;
+use crate::qti::highlight::{highlight_code, stylesheet};
+use crate::qti::images::{is_missing, package_path, read_image};
+use crate::qti::mathml::{group_scripts, merge_scripts, parse_mathml, write_mathml};
+use crate::qti::mathsvg::latex_to_svg;
+use crate::qti::optimize::{is_precompressed, optimize_image};
+use crate::qti::mhchem::transpile_mhchem;
+use crate::qti::music::{musicxml_title, musicxml_to_svg};
+use crate::qti::plot::plot_to_svg;
+use crate::qti::model::{CodeOptions, CodeStyle, ImageOptions, MathRendering, MissingImages, Quiz, Question, QuestionType};
 use quick_xml::events::{BytesDecl, Event};
 use quick_xml::Writer;
 use std::fs::File;
 use std::io::{Cursor, Write};
 use std::path::Path;
-use uuid::Uuid;
 use zip::write::FileOptions;
 
-use std::collections::HashSet;
-use std::io::Read;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// How `generate_qti` packs files into the zip.
+#[derive(Debug, Default, Clone)]
+pub struct PackageOptions {
+    /// Recompress PNGs and minify SVGs.
+    pub optimize_images: bool,
+    /// Scale PNGs wider than this down to it.
+    pub max_image_width: Option<u32>,
+    /// Warn when the package is larger than this many bytes.
+    pub size_limit: Option<u64>,
+}
 
-pub fn generate_qti(quiz: &Quiz, output_path: &Path) -> Result<()> {
-    let output_file_name = output_path.file_stem().unwrap().to_str().unwrap();
-    let zip_filename = format!("{}.zip", output_file_name);
-    let zip_path = output_path.with_file_name(zip_filename);
-    
-    let file = File::create(&zip_path)?;
-    let mut zip = zip::ZipWriter::new(file);
-    let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+/// Writes the quiz read from `input_path` as a QTI package at `zip_path`.
+/// Returns the number of warnings reported: images and content that could
+/// not be used, accessibility issues and the package size.
+pub fn generate_qti(quiz: &Quiz, input_path: &Path, zip_path: &Path, package: &PackageOptions) -> Result<usize> {
+    // Collect resources (images), reading them all before anything is
+    // written, so a missing or unreadable image leaves no partial package.
+    let base_dir = input_path.parent().unwrap();
+    let resources = collect_resources(quiz);
+    let mut content_options = ContentOptions::for_quiz(quiz);
+    let mut warnings = 0;
+    let missing: Vec<&String> = resources.iter().filter(|src| is_missing(src, base_dir)).collect();
+    if !missing.is_empty() {
+        let list: Vec<String> = missing
+            .iter()
+            .map(|src| match question_line(quiz, src) {
+                Some(line) => format!("{} (question on line {})", src, line),
+                None => src.to_string(),
+            })
+            .collect();
+        if quiz.images.missing == MissingImages::Error {
+            anyhow::bail!(
+                "Image{} not found in {}: {}\nImage paths are resolved relative to the quiz file; set `images: {{missing: warn}}` to convert without them",
+                if list.len() == 1 { "" } else { "s" },
+                input_path.display(),
+                list.join(", ")
+            );
+        }
+        for item in &list {
+            eprintln!("Warning: Image not found, writing its alt text instead: {}", item);
+        }
+        warnings += list.len();
+        content_options.missing_images = missing.into_iter().cloned().collect();
+    }
+    let mut images = BTreeMap::new();
+    for src in &resources {
+        if content_options.missing_images.contains(src) {
+            continue;
+        }
+        let Some(path) = package_path(src, &quiz.images) else { continue };
+        let data = read_image(src, base_dir, &quiz.images).map_err(|e| anyhow::anyhow!("Could not package image: {}", e))?;
+        images.insert(path, data);
+    }
+
+    // Writing the items renders math, music, diagrams and plots, which are
+    // packed alongside the images.
+    let items = generate_items(quiz, &content_options)?;
+    let assessment_xml = generate_assessment(quiz, &items)?;
+    images.extend(content_options.generated.take());
+    warnings += content_options.warnings.get();
+
+    let audits = audit_items(quiz, &items)?;
+    if audits.iter().any(|a| !a.issues.is_empty()) {
+        eprintln!("Accessibility issues in {}:", input_path.display());
+        eprint!("{}", format_audit(&audits, true));
+    }
+    let code_css = stylesheet(&quiz.code)?;
+    let manifest_xml = generate_manifest(quiz, &images, !code_css.is_empty())?;
 
-    // Collect resources (images)
-    let base_dir = output_path.parent().unwrap();
-    let resources = collect_resources(quiz, base_dir);
+    if let Some(dir) = zip_path.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Packed into a temporary file beside the zip and renamed into place,
+    // so a failure part way through leaves no partial package.
+    let file_name = zip_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp_path = zip_path.with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+    let packed = write_package(&temp_path, &manifest_xml, &code_css, &assessment_xml, images, package, &mut warnings)
+        .and_then(|sizes| std::fs::rename(&temp_path, zip_path).map(|_| sizes).map_err(Into::into));
+    let mut sizes = match packed {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+
+    println!("Generated QTI zip at: {:?}", zip_path);
+    warnings += audits.iter().map(|a| a.issues.len()).sum::<usize>();
+
+    let size = std::fs::metadata(zip_path)?.len();
+    if let Some(limit) = package.size_limit
+        && size > limit
+    {
+        sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let largest: Vec<String> = sizes.iter().take(3).map(|(name, bytes)| format!("{} ({})", name, format_size(*bytes as u64))).collect();
+        eprintln!(
+            "Warning: {} is {}, over the upload limit of {}{}",
+            zip_path.display(),
+            format_size(size),
+            format_size(limit),
+            if largest.is_empty() { String::new() } else { format!("; largest files: {}", largest.join(", ")) }
+        );
+        warnings += 1;
+    }
+    Ok(warnings)
+}
+
+/// Writes the package's files into a zip at `path`. Returns the size of each
+/// image as packed, optimizing them first when asked to.
+fn write_package(
+    path: &Path,
+    manifest_xml: &str,
+    code_css: &str,
+    assessment_xml: &str,
+    images: BTreeMap<String, Vec<u8>>,
+    package: &PackageOptions,
+    warnings: &mut usize,
+) -> Result<Vec<(String, usize)>> {
+    let file = File::create(path)?;
+    let mut zip = zip::ZipWriter::new(file);
+    // Fixed timestamps and permissions, so the same quiz always packs to
+    // the same bytes.
+    let options = FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .last_modified_time(zip::DateTime::default())
+        .unix_permissions(0o644);
+    let stored = options.compression_method(zip::CompressionMethod::Stored);
 
     // 1. imsmanifest.xml
-    let manifest_xml = generate_manifest(quiz, &resources)?;
     zip.start_file("imsmanifest.xml", options)?;
     zip.write_all(manifest_xml.as_bytes())?;
 
+    // Class-styled code shares one stylesheet, linked from every item.
+    if !code_css.is_empty() {
+        zip.start_file(CODE_STYLESHEET, options)?;
+        zip.write_all(code_css.as_bytes())?;
+    }
+
     // 2. assessment.xml
-    let assessment_xml = generate_assessment(quiz)?;
-    let _assessment_id = format!("assessment_{}", Uuid::new_v4());
-    
     zip.start_file("assessment.xml", options)?;
     zip.write_all(assessment_xml.as_bytes())?;
 
     // 3. Copy resources
-    let base_dir = output_path.parent().unwrap();
-    for resource in &resources {
-        let src_path = base_dir.join(resource);
-        if src_path.exists() {
-            let file_name = src_path.file_name().unwrap().to_str().unwrap();
-            let dest_path = format!("images/{}", file_name);
-            zip.start_file(dest_path, options)?;
-            
-            let mut f = File::open(&src_path)?;
-            let mut buffer = Vec::new();
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-        } else {
-            eprintln!("Warning: Image not found: {:?}", src_path);
+    let mut sizes = Vec::new();
+    for (dest_path, mut buffer) in images {
+        if package.optimize_images || package.max_image_width.is_some() {
+            let original = buffer.clone();
+            buffer = optimize_image(&dest_path, buffer, package.max_image_width).unwrap_or_else(|e| {
+                eprintln!("Warning: could not optimize {}: {}", dest_path, e);
+                *warnings += 1;
+                original
+            });
         }
+
+        zip.start_file(&dest_path, if is_precompressed(&dest_path) { stored } else { options })?;
+        zip.write_all(&buffer)?;
+        sizes.push((dest_path, buffer.len()));
     }
 
     zip.finish()?;
-    
-    println!("Generated QTI zip at: {:?}", zip_path);
-    Ok(())
+    Ok(sizes)
 }
 
+/// Where the first question that uses the image `src` is, as a line, or a
+/// line of an included file.
+fn question_line(quiz: &Quiz, src: &str) -> Option<String> {
+    quiz.questions
+        .iter()
+        .find(|q| {
+            std::iter::once(&q.prompt)
+                .chain(q.answers.iter().map(|a| &a.text))
+                .any(|text| lex_content(text).iter().any(|t| matches!(t, Token::Image { src: s, .. } if s == src)))
+        })
+        .map(|q| match &q.source {
+            Some(source) => format!("{} of {}", q.line, source.display()),
+            None => q.line.to_string(),
+        })
+}
 
-fn generate_manifest(_quiz: &Quiz, resources: &HashSet<String>) -> Result<String> {
+/// A byte count in KB or MB, for messages.
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1_000_000 {
+        format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+    } else {
+        format!("{:.1} KB", bytes as f64 / 1_000.0)
+    }
+}
+
+
+/// Name of the code stylesheet in the package, for `style: classes`.
+const CODE_STYLESHEET: &str = "code.css";
+
+fn generate_manifest(_quiz: &Quiz, images: &BTreeMap<String, Vec<u8>>, code_stylesheet: bool) -> Result<String> {
     let mut writer = Writer::new(Cursor::new(Vec::new()));
     writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
 
@@ -98,11 +252,16 @@ fn generate_manifest(_quiz: &Quiz, resources: &HashSet<String>) -> Result<String
     let mut file = quick_xml::events::BytesStart::new("file");
     file.push_attribute(("href", "assessment.xml"));
     writer.write_event(Event::Empty(file))?;
+
+    if code_stylesheet {
+        let mut css_file = quick_xml::events::BytesStart::new("file");
+        css_file.push_attribute(("href", CODE_STYLESHEET));
+        writer.write_event(Event::Empty(css_file))?;
+    }
     
-    for res in resources {
-        let file_name = Path::new(res).file_name().unwrap().to_str().unwrap();
+    for path in images.keys() {
         let mut img_file = quick_xml::events::BytesStart::new("file");
-        img_file.push_attribute(("href", format!("images/{}", file_name).as_str()));
+        img_file.push_attribute(("href", path.as_str()));
         writer.write_event(Event::Empty(img_file))?;
     }
 
@@ -116,171 +275,332 @@ fn generate_manifest(_quiz: &Quiz, resources: &HashSet<String>) -> Result<String
 
 // ... (generate_assessment and others remain mostly same)
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::process::Command;
+use sha2::{Digest, Sha256};
+
+/// A short hex digest of `parts`, stable across platforms and Rust versions
+/// so generated file names and identifiers do not change between builds.
+pub(crate) fn content_hash(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Length-prefixed, so ("ab", "c") and ("a", "bc") differ.
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize()[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
 
 // ...
 
-fn collect_resources(quiz: &Quiz, input_dir: &Path) -> HashSet<String> {
-    let mut resources = HashSet::new();
-    
+/// The `src` of every image the quiz's content refers to.
+pub(crate) fn collect_resources(quiz: &Quiz) -> BTreeSet<String> {
+    let mut resources = BTreeSet::new();
     for question in &quiz.questions {
-        extract_resources_from_text(&question.prompt, &mut resources, input_dir);
-        for answer in &question.answers {
-            extract_resources_from_text(&answer.text, &mut resources, input_dir);
+        let texts = std::iter::once(&question.prompt).chain(question.answers.iter().map(|a| &a.text));
+        for text in texts {
+            for token in lex_content(text) {
+                if let Token::Image { src, .. } = token {
+                    resources.insert(src);
+                }
+            }
         }
     }
-    
     resources
 }
 
-fn extract_resources_from_text(text: &str, resources: &mut HashSet<String>, input_dir: &Path) {
-    let tokens = lex_content(text);
-    for token in tokens {
-        match token {
-            Token::Image { src, .. } => {
-                resources.insert(src);
-            }
-            Token::Music(content) => {
-                if check_verovio_installed() {
-                    if let Ok(filename) = generate_verovio_svg(&content, input_dir) {
-                        resources.insert(filename);
-                    }
-                }
-            }
-            _ => {}
+/// Name of the SVG a MusicXML block is engraved to.
+pub(crate) fn music_filename(content: &str) -> String {
+    format!("music_{}.svg", content_hash(&[content]))
+}
+
+/// Name of the SVG an expression is rendered to for `math: svg`.
+fn math_filename(latex: &str, display: bool) -> String {
+    let mode = if display { "display" } else { "inline" };
+    format!("math_{}.svg", content_hash(&[latex, mode]))
+}
+
+/// Name of the SVG a diagram is drawn to. The alt text is part of the hash
+/// since the SVG carries it as its title.
+pub(crate) fn diagram_filename(lang: &str, content: &str, alt: &str) -> String {
+    format!("diagram_{}.svg", content_hash(&[lang, content, alt]))
+}
+
+/// Name of the SVG a plot is drawn to.
+pub(crate) fn plot_filename(spec: &str, alt: &str) -> String {
+    format!("plot_{}.svg", content_hash(&[spec, alt]))
+}
+
+// ...
+
+/// Settings from a quiz's front matter that change how its content is
+/// written.
+#[derive(Debug, Default)]
+pub(crate) struct ContentOptions {
+    pub math: MathRendering,
+    pub code: CodeOptions,
+    pub images: ImageOptions,
+    /// Images that were not found, written as their alt text.
+    pub missing_images: BTreeSet<String>,
+    /// SVGs rendered for math, music, diagrams and plots as their elements
+    /// are written, by package path. They stay in memory until the output
+    /// that refers to them packs or inlines them.
+    pub generated: RefCell<BTreeMap<String, Vec<u8>>>,
+    /// Warnings printed while writing content, such as for diagrams that
+    /// could not be drawn.
+    pub warnings: Cell<usize>,
+}
+
+impl ContentOptions {
+    pub(crate) fn for_quiz(quiz: &Quiz) -> Self {
+        Self {
+            math: quiz.math,
+            code: quiz.code.clone(),
+            images: quiz.images.clone(),
+            missing_images: BTreeSet::new(),
+            generated: RefCell::new(BTreeMap::new()),
+            warnings: Cell::new(0),
         }
     }
+
+    /// Prints a warning about the content and counts it.
+    fn warn(&self, message: String) {
+        eprintln!("Warning: {}", message);
+        self.warnings.set(self.warnings.get() + 1);
+    }
+
+    /// Keeps a rendered SVG and returns its path in the package.
+    fn add_generated(&self, filename: &str, svg: String) -> String {
+        let path = format!("images/{}", filename);
+        self.generated.borrow_mut().insert(path.clone(), svg.into_bytes());
+        path
+    }
 }
 
-fn generate_verovio_svg(content: &str, output_dir: &Path) -> Result<String> {
-    let mut hasher = DefaultHasher::new();
-    content.hash(&mut hasher);
-    let hash = hasher.finish();
-    let filename = format!("music_{}.svg", hash);
-    let output_path = output_dir.join(&filename);
-    
-    // Only generate if it doesn't exist to save time
-    if !output_path.exists() {
-        // Write content to temp file
-        let temp_input = output_dir.join(format!("temp_{}.musicxml", hash));
-        std::fs::write(&temp_input, content)?;
-        
-        // Run verovio
-        let status = Command::new("verovio")
-            .arg("-o")
-            .arg(&output_path) 
-            .arg(&temp_input)
-            .output()?;
-            
-        // Clean up temp input
-        let _ = std::fs::remove_file(temp_input);
-        
-        if !status.status.success() {
-            eprintln!("Error running verovio: {:?}", String::from_utf8_lossy(&status.stderr));
-            return Err(anyhow::anyhow!("Verovio failed"));
+/// Writes Markdown content as XHTML. Content that is a single paragraph is
+/// written inline, so it can sit directly in a `prompt`, `simpleChoice` or
+/// list item; anything longer is written as block elements.
+pub(crate) fn write_content(writer: &mut Writer<Cursor<Vec<u8>>>, text: &str, options: &ContentOptions) -> Result<()> {
+    let blocks = parse_blocks(text);
+    if let [Block::Paragraph(inlines)] = blocks.as_slice() {
+        return write_inlines(writer, inlines, options);
+    }
+    write_blocks(writer, blocks, options)
+}
+
+/// Writes Markdown content as block elements, wrapping even a single
+/// paragraph in `<p>`.
+pub(crate) fn write_block_content(writer: &mut Writer<Cursor<Vec<u8>>>, text: &str, options: &ContentOptions) -> Result<()> {
+    write_blocks(writer, parse_blocks(text), options)
+}
+
+fn write_blocks(writer: &mut Writer<Cursor<Vec<u8>>>, blocks: Vec<Block>, options: &ContentOptions) -> Result<()> {
+    for block in blocks {
+        match block {
+            Block::Paragraph(inlines) => {
+                writer.write_event(Event::Start(quick_xml::events::BytesStart::new("p")))?;
+                write_inlines(writer, &inlines, options)?;
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("p")))?;
+            }
+            Block::List { ordered, start, items } => {
+                let name = if ordered { "ol" } else { "ul" };
+                let mut list = quick_xml::events::BytesStart::new(name);
+                if ordered && start != 1 {
+                    list.push_attribute(("start", start.to_string().as_str()));
+                }
+                writer.write_event(Event::Start(list))?;
+                for item in items {
+                    writer.write_event(Event::Start(quick_xml::events::BytesStart::new("li")))?;
+                    write_inlines(writer, &item, options)?;
+                    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("li")))?;
+                }
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new(name)))?;
+            }
+            Block::Table(table) => write_table(writer, &table, options)?,
+            Block::Token(token) => write_token(writer, &token, options)?,
         }
     }
-    
-    Ok(filename)
+    Ok(())
 }
 
-// ...
+// Borders are inline styles for the same reason code highlighting is: the
+// LMS may strip stylesheets.
+const TABLE_STYLE: &str = "border-collapse: collapse;";
+const CELL_STYLE: &str = "border: 1px solid #767676; padding: 0.25em 0.5em;";
 
-use syntect::html::{highlighted_html_for_string, IncludeBackground};
-use syntect::parsing::SyntaxSet;
-use syntect::highlighting::ThemeSet;
-use std::sync::OnceLock;
+/// Writes a table with column headers scoped to their columns and, when the
+/// table has row headers, the first cell of each row scoped to its row.
+fn write_table(writer: &mut Writer<Cursor<Vec<u8>>>, table: &Table, options: &ContentOptions) -> Result<()> {
+    let mut start = quick_xml::events::BytesStart::new("table");
+    start.push_attribute(("style", TABLE_STYLE));
+    writer.write_event(Event::Start(start))?;
 
-static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
-static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    if let Some(caption) = &table.caption {
+        write_inline_element(writer, "caption", caption, options)?;
+    }
 
-fn get_syntax_set() -> &'static SyntaxSet {
-    SYNTAX_SET.get_or_init(|| SyntaxSet::load_defaults_newlines())
-}
+    writer.write_event(Event::Start(quick_xml::events::BytesStart::new("thead")))?;
+    writer.write_event(Event::Start(quick_xml::events::BytesStart::new("tr")))?;
+    for (i, cell) in table.header.iter().enumerate() {
+        let alignment = table.alignments.get(i).copied().unwrap_or(Alignment::Default);
+        if i == 0 && table.row_headers {
+            // The corner above the row headers labels nothing.
+            write_table_cell(writer, "td", None, alignment, cell, options)?;
+        } else {
+            write_table_cell(writer, "th", Some("col"), alignment, cell, options)?;
+        }
+    }
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("tr")))?;
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("thead")))?;
+
+    writer.write_event(Event::Start(quick_xml::events::BytesStart::new("tbody")))?;
+    for row in &table.rows {
+        writer.write_event(Event::Start(quick_xml::events::BytesStart::new("tr")))?;
+        for (i, cell) in row.iter().enumerate() {
+            let alignment = table.alignments.get(i).copied().unwrap_or(Alignment::Default);
+            if i == 0 && table.row_headers {
+                write_table_cell(writer, "th", Some("row"), alignment, cell, options)?;
+            } else {
+                write_table_cell(writer, "td", None, alignment, cell, options)?;
+            }
+        }
+        writer.write_event(Event::End(quick_xml::events::BytesEnd::new("tr")))?;
+    }
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("tbody")))?;
 
-fn get_theme_set() -> &'static ThemeSet {
-    THEME_SET.get_or_init(|| ThemeSet::load_defaults())
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("table")))?;
+    Ok(())
 }
 
-// ...
+fn write_table_cell(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    name: &str,
+    scope: Option<&str>,
+    alignment: Alignment,
+    inlines: &[Inline],
+    options: &ContentOptions,
+) -> Result<()> {
+    let mut cell = quick_xml::events::BytesStart::new(name);
+    if let Some(scope) = scope {
+        cell.push_attribute(("scope", scope));
+    }
+    let style = match alignment.css() {
+        Some(align) => format!("{} text-align: {};", CELL_STYLE, align),
+        None => CELL_STYLE.to_string(),
+    };
+    cell.push_attribute(("style", style.as_str()));
+    writer.write_event(Event::Start(cell))?;
+    write_inlines(writer, inlines, options)?;
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new(name)))?;
+    Ok(())
+}
 
-fn write_content(writer: &mut Writer<Cursor<Vec<u8>>>, text: &str) -> Result<()> {
-    let tokens = lex_content(text);
-    for token in tokens {
-        match token {
-            Token::Text(content) => {
-                writer.write_event(Event::Text(quick_xml::events::BytesText::new(&content)))?;
+fn write_inlines(writer: &mut Writer<Cursor<Vec<u8>>>, inlines: &[Inline], options: &ContentOptions) -> Result<()> {
+    for inline in inlines {
+        match inline {
+            Inline::Text(content) => {
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(content)))?;
             }
-            Token::LatexMath(latex) => {
-                write_latex_mathml(writer, &latex)?;
+            Inline::Strong(children) => write_inline_element(writer, "strong", children, options)?,
+            Inline::Emphasis(children) => write_inline_element(writer, "em", children, options)?,
+            Inline::Code(code) => {
+                writer.write_event(Event::Start(quick_xml::events::BytesStart::new("code")))?;
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(code)))?;
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("code")))?;
             }
-            Token::Chemistry(chem) => {
-                let latex = transpile_mhchem(&chem);
-                write_latex_mathml(writer, &latex)?;
-            }
-            Token::Image { src, alt } => {
-                if alt.trim().is_empty() {
-                    eprintln!("Warning: Accessibility issue - Image '{}' has missing or empty alt text.", src);
+            Inline::Link { href, children } => {
+                if is_safe_href(href) {
+                    let mut a = quick_xml::events::BytesStart::new("a");
+                    a.push_attribute(("href", href.as_str()));
+                    writer.write_event(Event::Start(a))?;
+                    write_inlines(writer, children, options)?;
+                    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("a")))?;
+                } else {
+                    write_inlines(writer, children, options)?;
                 }
-                
-                let file_name = Path::new(&src).file_name().unwrap().to_str().unwrap();
+            }
+            Inline::LineBreak => {
+                writer.write_event(Event::Empty(quick_xml::events::BytesStart::new("br")))?;
+            }
+            Inline::Token(token) => write_token(writer, token, options)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_inline_element(writer: &mut Writer<Cursor<Vec<u8>>>, name: &str, children: &[Inline], options: &ContentOptions) -> Result<()> {
+    writer.write_event(Event::Start(quick_xml::events::BytesStart::new(name)))?;
+    write_inlines(writer, children, options)?;
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new(name)))?;
+    Ok(())
+}
+
+fn write_token(writer: &mut Writer<Cursor<Vec<u8>>>, token: &Token, options: &ContentOptions) -> Result<()> {
+    match token {
+        Token::Text(content) => {
+            writer.write_event(Event::Text(quick_xml::events::BytesText::new(content)))?;
+        }
+        Token::LatexMath { latex, display } => {
+            write_math(writer, latex, *display, latex, options)?;
+        }
+        Token::Chemistry(chem) => {
+            let latex = transpile_mhchem(chem);
+            write_math(writer, &latex, false, &format!("\\ce{{{}}}", chem), options)?;
+        }
+        Token::Image { src, alt } if options.missing_images.contains(src) => {
+            writer.write_event(Event::Text(quick_xml::events::BytesText::new(&format!("[Image: {}]", alt))))?;
+        }
+        Token::Image { src, alt } => {
+            // Remote images that are linked rather than packaged keep their URL.
+            let href = package_path(src, &options.images).unwrap_or_else(|| src.clone());
+            let mut img = quick_xml::events::BytesStart::new("img");
+            img.push_attribute(("src", href.as_str()));
+            img.push_attribute(("alt", alt.as_str()));
+            writer.write_event(Event::Empty(img))?;
+        }
+        Token::Music(musicxml) => write_music(writer, Ok(musicxml.clone()), musicxml, options)?,
+        Token::Abc(abc) => write_music(writer, abc_to_musicxml(abc), abc, options)?,
+        Token::Diagram { lang, content, alt } => match diagram_to_svg(lang, content, alt) {
+            Ok(svg) => {
+                let src = options.add_generated(&diagram_filename(lang, content, alt), svg);
                 let mut img = quick_xml::events::BytesStart::new("img");
-                img.push_attribute(("src", format!("images/{}", file_name).as_str()));
+                img.push_attribute(("src", src.as_str()));
                 img.push_attribute(("alt", alt.as_str()));
                 writer.write_event(Event::Empty(img))?;
             }
-            Token::Music(musicxml) => {
-                if check_verovio_installed() {
-                    let mut hasher = DefaultHasher::new();
-                    musicxml.hash(&mut hasher);
-                    let hash = hasher.finish();
-                    let filename = format!("music_{}.svg", hash);
-                    
-                    let mut img = quick_xml::events::BytesStart::new("img");
-                    img.push_attribute(("src", format!("images/{}", filename).as_str()));
-                    img.push_attribute(("alt", "Music Notation"));
-                    writer.write_event(Event::Empty(img))?;
-                } else {
-                    eprintln!("Warning: Verovio not found. MusicXML block will be rendered as code.");
-                    eprintln!("To enable music notation, please install Verovio: https://verovio.org");
-                    
-                    writer.write_event(Event::Start(quick_xml::events::BytesStart::new("pre")))?;
-                    writer.write_event(Event::Text(quick_xml::events::BytesText::new(&musicxml)))?;
-                    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("pre")))?;
-                }
+            Err(e) => {
+                options.warn(format!("{} diagram could not be drawn and will be rendered as code: {}", lang, e));
+
+                writer.write_event(Event::Start(quick_xml::events::BytesStart::new("pre")))?;
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(content)))?;
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("pre")))?;
+            }
+        },
+        Token::Plot { spec, alt } => match plot_to_svg(spec, alt) {
+            Ok(plot) => {
+                let src = options.add_generated(&plot_filename(spec, alt), plot.svg);
+                let mut img = quick_xml::events::BytesStart::new("img");
+                img.push_attribute(("src", src.as_str()));
+                img.push_attribute(("alt", plot.alt.as_str()));
+                writer.write_event(Event::Empty(img))?;
+            }
+            Err(e) => {
+                options.warn(format!("plot could not be drawn and will be rendered as code: {}", e));
+
+                writer.write_event(Event::Start(quick_xml::events::BytesStart::new("pre")))?;
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(spec)))?;
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("pre")))?;
             }
-            Token::Code { lang, content } => {
-                let ss = get_syntax_set();
-                let ts = get_theme_set();
-                // Use InspiredGitHub as a light theme that works well on white backgrounds
-                let theme = &ts.themes["InspiredGitHub"];
-                
-                let syntax = ss.find_syntax_by_token(&lang)
-                    .unwrap_or_else(|| ss.find_syntax_plain_text());
-                
-                // Generate HTML with inline styles
-                let html = highlighted_html_for_string(&content, ss, syntax, theme)?;
-                
-                // Write the HTML directly. Since highlighted_html_for_string returns a full <pre> block,
-                // we need to parse it or write it as raw bytes if we trust it.
-                // However, writing raw bytes into quick-xml is tricky if we want to maintain structure.
-                // But wait, quick-xml's Writer writes events.
-                // We can parse the generated HTML and write events, OR we can write a CDATA block?
-                // No, CDATA might not be rendered by the browser.
-                // We should parse the HTML and write it as events to be safe and correct.
-                
-                // Actually, for simplicity and robustness, let's use the Reader to parse the HTML string
-                // and pipe it to the Writer, just like we did for MathML.
-                let mut reader = quick_xml::Reader::from_str(&html);
-                
-                loop {
-                    match reader.read_event() {
-                        Ok(Event::Eof) => break,
-                        Ok(e) => writer.write_event(e)?,
-                        Err(e) => return Err(anyhow::anyhow!("Error parsing generated HTML: {}", e)),
-                    }
+        },
+        Token::Code { lang, content, highlight, .. } => {
+            let html = highlight_code(lang, content, highlight, &options.code)?;
+
+            // Pipe the highlighted <pre> block through the reader, as for MathML.
+            let mut reader = quick_xml::Reader::from_str(&html);
+            
+            loop {
+                match reader.read_event() {
+                    Ok(Event::Eof) => break,
+                    Ok(e) => writer.write_event(e)?,
+                    Err(e) => return Err(anyhow::anyhow!("Error parsing generated HTML: {}", e)),
                 }
             }
         }
@@ -288,27 +608,52 @@ fn write_content(writer: &mut Writer<Cursor<Vec<u8>>>, text: &str) -> Result<()>
     Ok(())
 }
 
-fn check_verovio_installed() -> bool {
-    let output = std::process::Command::new("verovio")
-        .arg("--version")
-        .output();
-        
-    match output {
-        Ok(o) => {
-            if !o.status.success() {
-                eprintln!("Verovio check failed with status: {}", o.status);
-                return false;
-            }
-            true
-        },
+/// Alt text for a score without a title.
+pub(crate) const MUSIC_ALT: &str = "Music Notation";
+
+/// Writes the engraved image of a score, titled with the score's title, or
+/// `source` as code when the score cannot be read or engraved.
+fn write_music(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    musicxml: Result<String>,
+    source: &str,
+    options: &ContentOptions,
+) -> Result<()> {
+    match musicxml.and_then(|musicxml| musicxml_to_svg(&musicxml).map(|svg| (musicxml, svg))) {
+        Ok((musicxml, svg)) => {
+            let src = options.add_generated(&music_filename(&musicxml), svg);
+            let alt = musicxml_title(&musicxml).unwrap_or_else(|| MUSIC_ALT.to_string());
+
+            let mut img = quick_xml::events::BytesStart::new("img");
+            img.push_attribute(("src", src.as_str()));
+            img.push_attribute(("alt", alt.as_str()));
+            writer.write_event(Event::Empty(img))?;
+        }
         Err(e) => {
-            eprintln!("Verovio check failed to execute: {}", e);
-            false
+            options.warn(format!("music block could not be engraved and will be rendered as code: {}", e));
+
+            writer.write_event(Event::Start(quick_xml::events::BytesStart::new("pre")))?;
+            writer.write_event(Event::Text(quick_xml::events::BytesText::new(source)))?;
+            writer.write_event(Event::End(quick_xml::events::BytesEnd::new("pre")))?;
         }
     }
+    Ok(())
+}
+
+/// Writes each question as an `assessmentItem`, in quiz order.
+pub(crate) fn generate_items(quiz: &Quiz, options: &ContentOptions) -> Result<Vec<String>> {
+    quiz.questions
+        .iter()
+        .zip(item_identifiers(&quiz.questions))
+        .map(|(question, id)| {
+            let mut writer = Writer::new(Cursor::new(Vec::new()));
+            generate_question_item(&mut writer, question, &id, quiz.shuffle_answers, options)?;
+            Ok(String::from_utf8(writer.into_inner().into_inner())?)
+        })
+        .collect()
 }
 
-fn generate_assessment(quiz: &Quiz) -> Result<String> {
+fn generate_assessment(quiz: &Quiz, items: &[String]) -> Result<String> {
     let mut writer = Writer::new(Cursor::new(Vec::new()));
     writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
 
@@ -333,8 +678,8 @@ fn generate_assessment(quiz: &Quiz) -> Result<String> {
     writer.write_event(Event::Start(section))?;
 
     // Questions
-    for (i, question) in quiz.questions.iter().enumerate() {
-        generate_question_item(&mut writer, question, i)?;
+    for item in items {
+        writer.write_event(Event::Text(quick_xml::events::BytesText::from_escaped(item.as_str())))?;
     }
 
     writer.write_event(Event::End(quick_xml::events::BytesEnd::new("assessmentSection")))?;
@@ -344,11 +689,51 @@ fn generate_assessment(quiz: &Quiz) -> Result<String> {
     Ok(String::from_utf8(writer.into_inner().into_inner())?)
 }
 
-fn generate_question_item(writer: &mut Writer<Cursor<Vec<u8>>>, question: &Question, index: usize) -> Result<()> {
-    let id = format!("q{}", index + 1);
-    
+/// Each question's item identifier: its `id=` attribute, or one derived from
+/// its content, so identifiers do not depend on the order of questions.
+/// Identical questions are told apart by a numeric suffix.
+pub(crate) fn item_identifiers(questions: &[Question]) -> Vec<String> {
+    // Ids given explicitly, or carried in from a bank, are taken first, so
+    // a suffixed id cannot repeat one of them.
+    let mut taken: HashSet<String> = questions.iter().filter_map(|q| q.id.clone()).collect();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    questions
+        .iter()
+        .map(|question| {
+            if let Some(id) = &question.id {
+                return id.clone();
+            }
+            let question_type = format!("{:?}", question.question_type);
+            let mut parts = vec![question_type.as_str(), question.prompt.as_str()];
+            for answer in &question.answers {
+                parts.push(if answer.is_correct { "x" } else { " " });
+                parts.push(&answer.text);
+            }
+            let base = format!("item_{}", content_hash(&parts));
+            let count = counts.entry(base.clone()).or_insert(0);
+            loop {
+                *count += 1;
+                let id = match *count {
+                    1 => base.clone(),
+                    n => format!("{}_{}", base, n),
+                };
+                if taken.insert(id.clone()) {
+                    return id;
+                }
+            }
+        })
+        .collect()
+}
+
+fn generate_question_item(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    question: &Question,
+    id: &str,
+    shuffle: bool,
+    options: &ContentOptions,
+) -> Result<()> {
     let mut item = quick_xml::events::BytesStart::new("assessmentItem");
-    item.push_attribute(("identifier", id.as_str()));
+    item.push_attribute(("identifier", id));
     item.push_attribute(("title", question.title.as_str()));
     item.push_attribute(("adaptive", "false"));
     item.push_attribute(("timeDependent", "false"));
@@ -372,6 +757,13 @@ fn generate_question_item(writer: &mut Writer<Cursor<Vec<u8>>>, question: &Quest
     
     writer.write_event(Event::End(quick_xml::events::BytesEnd::new("outcomeDeclaration")))?;
 
+    if options.code.style == CodeStyle::Classes {
+        let mut stylesheet = quick_xml::events::BytesStart::new("stylesheet");
+        stylesheet.push_attribute(("href", CODE_STYLESHEET));
+        stylesheet.push_attribute(("type", "text/css"));
+        writer.write_event(Event::Empty(stylesheet))?;
+    }
+
     // Item Body
     writer.write_event(Event::Start(quick_xml::events::BytesStart::new("itemBody")))?;
     
@@ -381,7 +773,7 @@ fn generate_question_item(writer: &mut Writer<Cursor<Vec<u8>>>, question: &Quest
         QuestionType::MultipleChoice | QuestionType::MultipleAnswers | QuestionType::TrueFalse => {
             let mut choice_interaction = quick_xml::events::BytesStart::new("choiceInteraction");
             choice_interaction.push_attribute(("responseIdentifier", "RESPONSE"));
-            choice_interaction.push_attribute(("shuffle", "true")); // Could be from quiz settings
+            choice_interaction.push_attribute(("shuffle", if shuffle { "true" } else { "false" }));
             
             let max_choices = if question.question_type == QuestionType::MultipleAnswers { "0" } else { "1" };
             choice_interaction.push_attribute(("maxChoices", max_choices));
@@ -389,7 +781,7 @@ fn generate_question_item(writer: &mut Writer<Cursor<Vec<u8>>>, question: &Quest
             writer.write_event(Event::Start(choice_interaction))?;
             
             writer.write_event(Event::Start(quick_xml::events::BytesStart::new("prompt")))?;
-            write_content(writer, &question.prompt)?;
+            write_content(writer, &question.prompt, options)?;
             writer.write_event(Event::End(quick_xml::events::BytesEnd::new("prompt")))?;
 
             for (j, answer) in question.answers.iter().enumerate() {
@@ -397,7 +789,7 @@ fn generate_question_item(writer: &mut Writer<Cursor<Vec<u8>>>, question: &Quest
                 let mut simple_choice = quick_xml::events::BytesStart::new("simpleChoice");
                 simple_choice.push_attribute(("identifier", choice_id.as_str()));
                 writer.write_event(Event::Start(simple_choice))?;
-                write_content(writer, &answer.text)?;
+                write_content(writer, &answer.text, options)?;
                 writer.write_event(Event::End(quick_xml::events::BytesEnd::new("simpleChoice")))?;
             }
 
@@ -405,9 +797,7 @@ fn generate_question_item(writer: &mut Writer<Cursor<Vec<u8>>>, question: &Quest
         },
         _ => {
             // Placeholder for other types
-            writer.write_event(Event::Start(quick_xml::events::BytesStart::new("p")))?;
-            write_content(writer, &question.prompt)?;
-            writer.write_event(Event::End(quick_xml::events::BytesEnd::new("p")))?;
+            write_block_content(writer, &question.prompt, options)?;
         }
     }
 
@@ -462,84 +852,129 @@ fn generate_response_declaration(writer: &mut Writer<Cursor<Vec<u8>>>, question:
 }
 
 use crate::qti::lexer::{lex_content, Token};
+use crate::qti::markdown::{is_safe_href, parse_blocks, Alignment, Block, Inline, Table};
+
+
+/// Converts LaTeX to a MathML string. latex2mathml writes `<`, `>` and `&`
+/// operators unescaped (`<mo><</mo>`), which is not well-formed XML, so those
+/// are escaped here. Paired scripts such as `x_a^b` are grouped before
+/// conversion and joined into one `msubsup` (or `munderover`) after it.
+pub(crate) fn latex_to_mathml(latex: &str, display: bool) -> Result<String> {
+    let style = if display { latex2mathml::DisplayStyle::Block } else { latex2mathml::DisplayStyle::Inline };
+    let mathml = latex2mathml::latex_to_mathml(&group_scripts(latex), style)
+        .map_err(|e| anyhow::anyhow!("Could not convert LaTeX to MathML: {:?}", e))?;
+    let mathml = mathml
+        .replace("><</", ">&lt;</")
+        .replace(">></", ">&gt;</")
+        .replace(">&</", ">&amp;</");
+    if !["<msub><msup>", "<msup><msub>", "<msub><mover>", "<msup><munder>"].iter().any(|nested| mathml.contains(nested)) {
+        return Ok(mathml);
+    }
+    let mut math = parse_mathml(&mathml)?;
+    merge_scripts(&mut math);
+    write_mathml(&math)
+}
 
+// Keeps the MathML in the accessibility tree while the SVG shows instead.
+const VISUALLY_HIDDEN_STYLE: &str = "position: absolute; width: 1px; height: 1px; margin: -1px; padding: 0; overflow: hidden; clip: rect(0, 0, 0, 0); white-space: nowrap; border: 0;";
+
+/// Writes LaTeX as MathML, or for `math: svg` as a rendered image followed
+/// by the MathML, hidden visually so screen readers still read it.
+/// Expressions that cannot be rendered fall back to plain MathML.
+fn write_math(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    latex: &str,
+    display: bool,
+    source: &str,
+    options: &ContentOptions,
+) -> Result<()> {
+    let math = match options.math {
+        MathRendering::Svg => latex_to_svg(latex, display, source).ok(),
+        MathRendering::Mathml => None,
+    };
+    let Some(math) = math else {
+        return write_latex_mathml(writer, latex, display, source);
+    };
+
+    // Sized in ems so the image scales with the text, with its baseline on
+    // the text's baseline.
+    let mut style = format!(
+        "width: {:.3}em; height: {:.3}em; vertical-align: -{:.3}em;",
+        math.width, math.height, math.depth
+    );
+    if display {
+        style.push_str(" display: block; margin: 0.5em auto;");
+    }
+    let src = options.add_generated(&math_filename(latex, display), math.svg);
+    let mut img = quick_xml::events::BytesStart::new("img");
+    img.push_attribute(("src", src.as_str()));
+    img.push_attribute(("alt", ""));
+    img.push_attribute(("aria-hidden", "true"));
+    img.push_attribute(("style", style.as_str()));
+    writer.write_event(Event::Empty(img))?;
+
+    let mut hidden = quick_xml::events::BytesStart::new("span");
+    hidden.push_attribute(("style", VISUALLY_HIDDEN_STYLE));
+    writer.write_event(Event::Start(hidden))?;
+    write_latex_mathml(writer, latex, display, source)?;
+    writer.write_event(Event::End(quick_xml::events::BytesEnd::new("span")))?;
+    Ok(())
+}
 
-fn write_latex_mathml(writer: &mut Writer<Cursor<Vec<u8>>>, latex: &str) -> Result<()> {
-    if let Ok(mathml) = latex2mathml::latex_to_mathml(latex, latex2mathml::DisplayStyle::Inline) {
-        // Parse the MathML string into events and write them
-        let mut reader = quick_xml::Reader::from_str(&mathml);
-        reader.trim_text(true);
-        
-        let mut buf = Vec::new();
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Ok(Event::Eof) => break,
-                Ok(e) => writer.write_event(e)?,
-                Err(e) => {
-                    eprintln!("Error parsing generated MathML: {}", e);
-                    // Fallback: write raw latex as text
-                    writer.write_event(Event::Text(quick_xml::events::BytesText::new(&format!("${}$", latex))))?;
-                    break;
-                }
+/// Writes LaTeX as MathML, `display="block"` for display math. The source is
+/// kept as `alttext` and as a TeX annotation, for screen readers without
+/// MathML support and for tools that re-import the content. `source` is what
+/// the author wrote, which for chemistry is the `\ce{...}` form.
+fn write_latex_mathml(writer: &mut Writer<Cursor<Vec<u8>>>, latex: &str, display: bool, source: &str) -> Result<()> {
+    // Fallback when the MathML cannot be produced: the source, delimited the
+    // way it was written.
+    let delimiter = if display { "$$" } else { "$" };
+    let Ok(mathml) = latex_to_mathml(latex, display) else {
+        writer.write_event(Event::Text(quick_xml::events::BytesText::new(&format!("{}{}{}", delimiter, source, delimiter))))?;
+        return Ok(());
+    };
+
+    // Parse the MathML first, so nothing is written if it is malformed.
+    let mut reader = quick_xml::Reader::from_str(&mathml);
+    reader.trim_text(true);
+    let mut events = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(e) => events.push(e.into_owned()),
+            Err(e) => {
+                eprintln!("Error parsing generated MathML: {}", e);
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(&format!("{}{}{}", delimiter, source, delimiter))))?;
+                return Ok(());
             }
-            buf.clear();
         }
-    } else {
-        // Fallback
-        writer.write_event(Event::Text(quick_xml::events::BytesText::new(&format!("${}$", latex))))?;
     }
-    Ok(())
-}
 
-fn transpile_mhchem(ce: &str) -> String {
-    // Very basic mhchem transpiler to standard LaTeX
-    // 1. Wrap in \mathrm
-    // 2. H2O -> H_{2}O (Numbers after letters)
-    // 3. -> to \rightarrow
-    // 4. + to +
-    // 5. ^2+ to ^{2+}
-    
-    let mut result = String::new();
-    let mut chars = ce.chars().peekable();
-    
-    result.push_str("\\mathrm{");
-    
-    while let Some(c) = chars.next() {
-        if c.is_ascii_digit() {
-            result.push_str("_{");
-            result.push(c);
-            // Consume subsequent digits
-            while let Some(&next) = chars.peek() {
-                if next.is_ascii_digit() {
-                    result.push(chars.next().unwrap());
-                } else {
-                    break;
-                }
+    // <math alttext> wraps the presentation markup in <semantics>, whose first
+    // child must be a single element, followed by the TeX annotation.
+    for event in events {
+        match event {
+            Event::Start(e) if e.name().as_ref() == b"math" => {
+                let mut math = quick_xml::events::BytesStart::new("math");
+                math.push_attribute(("xmlns", "http://www.w3.org/1998/Math/MathML"));
+                math.push_attribute(("display", if display { "block" } else { "inline" }));
+                math.push_attribute(("alttext", source));
+                writer.write_event(Event::Start(math))?;
+                writer.write_event(Event::Start(quick_xml::events::BytesStart::new("semantics")))?;
+                writer.write_event(Event::Start(quick_xml::events::BytesStart::new("mrow")))?;
             }
-            result.push('}');
-        } else if c == '-' && chars.peek() == Some(&'>') {
-            chars.next(); // consume >
-            result.push_str("}\\rightarrow\\mathrm{");
-        } else if c == '^' {
-            result.push('^');
-            result.push('{');
-            // Consume until space or end? Or just next token?
-            // Simple assumption: ^2+ or ^+
-            while let Some(&next) = chars.peek() {
-                if next != ' ' {
-                    result.push(chars.next().unwrap());
-                } else {
-                    break;
-                }
+            Event::End(e) if e.name().as_ref() == b"math" => {
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("mrow")))?;
+                let mut annotation = quick_xml::events::BytesStart::new("annotation");
+                annotation.push_attribute(("encoding", "application/x-tex"));
+                writer.write_event(Event::Start(annotation))?;
+                writer.write_event(Event::Text(quick_xml::events::BytesText::new(source)))?;
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("annotation")))?;
+                writer.write_event(Event::End(quick_xml::events::BytesEnd::new("semantics")))?;
+                writer.write_event(Event::End(e))?;
             }
-            result.push('}');
-        } else if c == ' ' {
-            result.push(' '); // Keep spaces
-        } else {
-            result.push(c);
+            e => writer.write_event(e)?,
         }
     }
-    
-    result.push('}');
-    result
+    Ok(())
 }
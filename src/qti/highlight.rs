@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use crate::qti::a11y::{contrast_ratio, MIN_CONTRAST};
+use crate::qti::model::{CodeOptions, CodeStyle};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{
+    css_for_theme_with_class_style, line_tokens_to_classed_spans, styled_line_to_highlighted_html, ClassStyle, IncludeBackground,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+// Syntax highlighting for code blocks, as configured by the front matter's
+// `code:` settings. By default colours are inline styles, since the LMS may
+// strip stylesheets; `style: classes` writes class names instead, with one
+// shared stylesheet from `stylesheet`.
+
+const DEFAULT_THEME: &str = "InspiredGitHub";
+/// Prefix of every class written in class mode, so theme rules cannot clash
+/// with the LMS's own.
+const CLASS_PREFIX: &str = "code-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: CLASS_PREFIX };
+const LINE_NUMBER_STYLE: &str = "display: inline-block; min-width: 2em; padding-right: 1em; text-align: right; user-select: none;";
+const HIGHLIGHT_STYLE: &str = "display: inline-block; min-width: 100%;";
+
+static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+static DEFAULT: OnceLock<Arc<Theme>> = OnceLock::new();
+static THEME_FILES: LazyLock<Mutex<HashMap<String, Arc<Theme>>>> = LazyLock::new(Default::default);
+static DEFAULT_SYNTAXES: OnceLock<Arc<SyntaxSet>> = OnceLock::new();
+static CUSTOM_SYNTAXES: LazyLock<Mutex<HashMap<PathBuf, Arc<SyntaxSet>>>> = LazyLock::new(Default::default);
+
+fn theme_set() -> &'static ThemeSet {
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Names of the themes built into syntect, for `theme:` in the front matter.
+pub(crate) fn theme_names() -> Vec<&'static str> {
+    theme_set().themes.keys().map(String::as_str).collect()
+}
+
+/// The theme named in `options`: a built-in one, or a `.tmTheme` file.
+/// Without a name, `DEFAULT_THEME` with its colours darkened to meet WCAG
+/// AA contrast.
+fn theme(options: &CodeOptions) -> Result<Arc<Theme>> {
+    let Some(name) = options.theme.as_deref() else {
+        return Ok(DEFAULT.get_or_init(|| Arc::new(with_aa_contrast(theme_set().themes[DEFAULT_THEME].clone()))).clone());
+    };
+    if let Some(theme) = theme_set().themes.get(name) {
+        return Ok(Arc::new(theme.clone()));
+    }
+    let mut files = THEME_FILES.lock().unwrap();
+    if let Some(theme) = files.get(name) {
+        return Ok(theme.clone());
+    }
+    let theme = ThemeSet::get_theme(name).map_err(|e| anyhow!("Could not load code theme {}: {}", name, e))?;
+    let theme = Arc::new(theme);
+    files.insert(name.to_string(), theme.clone());
+    Ok(theme)
+}
+
+/// syntect's syntaxes, plus any `.sublime-syntax` files in the configured
+/// directory. A directory that fails to load is reported once and skipped.
+fn syntax_set(options: &CodeOptions) -> Arc<SyntaxSet> {
+    let defaults = DEFAULT_SYNTAXES.get_or_init(|| Arc::new(SyntaxSet::load_defaults_newlines()));
+    let Some(dir) = &options.syntaxes else {
+        return defaults.clone();
+    };
+    let mut custom = CUSTOM_SYNTAXES.lock().unwrap();
+    custom
+        .entry(dir.clone())
+        .or_insert_with(|| {
+            let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+            match builder.add_from_folder(dir, true) {
+                Ok(()) => Arc::new(builder.build()),
+                Err(e) => {
+                    eprintln!("Warning: could not load syntaxes from {}: {}", dir.display(), e);
+                    defaults.clone()
+                }
+            }
+        })
+        .clone()
+}
+
+/// Highlights `content` as a `<pre>` block of XHTML. Lines are numbered
+/// when the options ask for it, and lines listed in `highlight` (counting
+/// from 1) are marked.
+pub(crate) fn highlight_code(lang: &str, content: &str, highlight: &[usize], options: &CodeOptions) -> Result<String> {
+    let syntaxes = syntax_set(options);
+    let theme = theme(options)?;
+    // Quarto's executable cells are written ```{python}.
+    let token = lang.trim_start_matches('{').trim_end_matches('}');
+    let syntax = syntaxes.find_syntax_by_token(token).unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let background = theme.settings.background.unwrap_or(Color::WHITE);
+    let foreground = theme.settings.foreground.unwrap_or(Color::BLACK);
+
+    let mut html = match options.style {
+        CodeStyle::Inline => format!("<pre style=\"background-color:{};\">\n", hex(background)),
+        CodeStyle::Classes => format!("<pre class=\"{}code\">\n", CLASS_PREFIX),
+    };
+    let mut inline = HighlightLines::new(syntax, &theme);
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let count = LinesWithEndings::from(content).count();
+    let width = count.to_string().len();
+
+    for (i, line) in LinesWithEndings::from(content).enumerate() {
+        let mut code = match options.style {
+            CodeStyle::Inline => {
+                let regions = inline.highlight_line(line, &syntaxes)?;
+                styled_line_to_highlighted_html(&regions, IncludeBackground::IfDifferent(background))?
+            }
+            CodeStyle::Classes => {
+                // Each line opens the spans still open from the last, so
+                // lines can be wrapped on their own.
+                let mut code: String = stack.as_slice().iter().map(|scope| format!("<span class=\"{}\">", classes(&scope.build_string()))).collect();
+                let open = stack.len() as isize;
+                let ops = state.parse_line(line, &syntaxes)?;
+                let (spans, delta) = line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut stack)?;
+                code.push_str(&spans);
+                code.push_str(&"</span>".repeat((open + delta).max(0) as usize));
+                code
+            }
+        };
+        // The newline goes outside any wrapper, so a marked line's background
+        // ends with the line.
+        if let Some(newline) = code.rfind('\n') {
+            code.remove(newline);
+        }
+
+        if options.line_numbers {
+            let number = format!("{:>width$}", i + 1, width = width);
+            match options.style {
+                CodeStyle::Inline => {
+                    let _ = write!(html, "<span style=\"{} color:{};\">{}</span>", LINE_NUMBER_STYLE, hex(foreground), number);
+                }
+                CodeStyle::Classes => {
+                    let _ = write!(html, "<span class=\"{}line-number\">{}</span>", CLASS_PREFIX, number);
+                }
+            }
+        }
+        if highlight.contains(&(i + 1)) {
+            match options.style {
+                CodeStyle::Inline => {
+                    let marked = line_highlight(background);
+                    let _ = write!(html, "<span style=\"{} background-color:{};\">{}</span>", HIGHLIGHT_STYLE, hex(marked), code);
+                }
+                CodeStyle::Classes => {
+                    let _ = write!(html, "<span class=\"{}highlighted\">{}</span>", CLASS_PREFIX, code);
+                }
+            }
+        } else {
+            html.push_str(&code);
+        }
+        html.push('\n');
+    }
+    html.push_str("</pre>\n");
+    Ok(html)
+}
+
+/// The stylesheet that class-mode code needs: the theme's colours and the
+/// rules for line numbers and marked lines. Empty when code is styled
+/// inline.
+pub(crate) fn stylesheet(options: &CodeOptions) -> Result<String> {
+    if options.style == CodeStyle::Inline {
+        return Ok(String::new());
+    }
+    let theme = theme(options)?;
+    let mut css = css_for_theme_with_class_style(&theme, CLASS_STYLE)?;
+    let background = theme.settings.background.unwrap_or(Color::WHITE);
+    let _ = write!(
+        css,
+        "\n.{0}line-number {{ {1} color: {2}; }}\n.{0}highlighted {{ {3} background-color: {4}; }}\n",
+        CLASS_PREFIX,
+        LINE_NUMBER_STYLE,
+        hex(theme.settings.foreground.unwrap_or(Color::BLACK)),
+        HIGHLIGHT_STYLE,
+        hex(line_highlight(background))
+    );
+    Ok(css)
+}
+
+/// Darkens every foreground colour of a light theme until it has AA
+/// contrast on the background, and on the background of marked lines.
+fn with_aa_contrast(mut theme: Theme) -> Theme {
+    let background = theme.settings.background.unwrap_or(Color::WHITE);
+    let marked = line_highlight(background);
+    let darken = |mut color: Color, background: Color| {
+        let rgb = |c: Color| (c.r, c.g, c.b);
+        while contrast_ratio(rgb(color), rgb(background)).min(contrast_ratio(rgb(color), rgb(marked))) < MIN_CONTRAST
+            && (color.r, color.g, color.b) != (0, 0, 0)
+        {
+            let scale = |c: u8| (c as f32 * 0.9) as u8;
+            color = Color { r: scale(color.r), g: scale(color.g), b: scale(color.b), a: color.a };
+        }
+        color
+    };
+    theme.settings.foreground = Some(darken(theme.settings.foreground.unwrap_or(Color::BLACK), background));
+    for item in &mut theme.scopes {
+        if let Some(foreground) = item.style.foreground {
+            item.style.foreground = Some(darken(foreground, item.style.background.unwrap_or(background)));
+        }
+    }
+    theme
+}
+
+/// The class names syntect writes for a scope such as `string.quoted`.
+fn classes(scope: &str) -> String {
+    scope.split('.').map(|atom| format!("{}{}", CLASS_PREFIX, atom)).collect::<Vec<_>>().join(" ")
+}
+
+/// The colour of marked lines: pale yellow on light backgrounds, a lighter
+/// shade on dark ones. Themes' own `lineHighlight` is not used, as some
+/// share it with comments.
+fn line_highlight(background: Color) -> Color {
+    let luminance = 0.299 * background.r as f32 + 0.587 * background.g as f32 + 0.114 * background.b as f32;
+    if luminance > 128.0 {
+        Color { r: 0xff, g: 0xf5, b: 0xb1, a: 0xff }
+    } else {
+        let lift = |c: u8| c.saturating_add(0x28);
+        Color { r: lift(background.r), g: lift(background.g), b: lift(background.b), a: 0xff }
+    }
+}
+
+fn hex(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use crate::qti::generator::content_hash;
+use crate::qti::model::{ImageOptions, RemoteImages};
+use std::io::Read;
+use std::path::{Component, Path};
+use std::time::Duration;
+
+// Where the images a quiz refers to come from, and where they go in a
+// package. Files keep their path relative to the quiz, so `a/fig.png` and
+// `b/fig.png` stay apart; anything that cannot be placed that way (files
+// outside the quiz's directory, data URIs, fetched URLs) is named by a hash
+// of its source.
+
+/// Time allowed to download a remote image.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Remote images larger than this are refused.
+const FETCH_LIMIT: u64 = 20 << 20;
+
+/// Where an image's `src` points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ImageSource<'a> {
+    /// A file, relative to the quiz.
+    File(&'a str),
+    /// A `data:` URI carrying the image itself.
+    Data(&'a str),
+    /// An `http://` or `https://` URL.
+    Remote(&'a str),
+}
+
+pub(crate) fn image_source(src: &str) -> ImageSource<'_> {
+    let scheme = src.split_once(':').map(|(scheme, _)| scheme.to_ascii_lowercase());
+    match scheme.as_deref() {
+        Some("data") => ImageSource::Data(src),
+        Some("http") | Some("https") => ImageSource::Remote(src),
+        _ => ImageSource::File(src),
+    }
+}
+
+/// The path of the image in the package, or `None` when it stays a link to
+/// a remote URL.
+pub(crate) fn package_path(src: &str, options: &ImageOptions) -> Option<String> {
+    match image_source(src) {
+        ImageSource::File(path) => Some(match relative_path(path) {
+            Some(relative) => format!("images/{}", relative),
+            None => {
+                let name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or("");
+                hashed_path(name, src)
+            }
+        }),
+        ImageSource::Data(uri) => {
+            let extension = data_mime(uri).and_then(extension_for_mime).unwrap_or("bin");
+            Some(hashed_path(&format!("image.{}", extension), src))
+        }
+        ImageSource::Remote(url) => match options.remote {
+            RemoteImages::Fetch => {
+                let name = url::Url::parse(url)
+                    .ok()
+                    .and_then(|url| url.path_segments().and_then(|mut s| s.next_back()).map(str::to_string))
+                    .unwrap_or_default();
+                Some(hashed_path(&name, src))
+            }
+            RemoteImages::Link | RemoteImages::Error => None,
+        },
+    }
+}
+
+/// `path` as `/`-separated components, if it stays inside the quiz's
+/// directory.
+fn relative_path(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if parts.is_empty() { None } else { Some(parts.join("/")) }
+}
+
+/// `images/<stem>_<hash>.<extension>`, keeping `name`'s extension so the
+/// LMS serves the right type.
+fn hashed_path(name: &str, src: &str) -> String {
+    let hash = content_hash(&[src]);
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ if name.is_empty() => ("image", None),
+        _ => (name, None),
+    };
+    match extension {
+        Some(extension) => format!("images/{}_{}.{}", stem, hash, extension),
+        None => format!("images/{}_{}", stem, hash),
+    }
+}
+
+/// Whether `src` names a file that does not exist.
+pub(crate) fn is_missing(src: &str, base_dir: &Path) -> bool {
+    matches!(image_source(src), ImageSource::File(path) if !base_dir.join(path).is_file())
+}
+
+/// The bytes of the image at `src`: a file relative to `base_dir`, a data
+/// URI, or a remote URL when the quiz allows fetching.
+pub(crate) fn read_image(src: &str, base_dir: &Path, options: &ImageOptions) -> Result<Vec<u8>> {
+    match image_source(src) {
+        ImageSource::File(path) => {
+            let path = base_dir.join(path);
+            std::fs::read(&path).map_err(|e| anyhow!("could not read {}: {}", path.display(), e))
+        }
+        ImageSource::Data(uri) => decode_data_uri(uri).map(|(_, bytes)| bytes),
+        ImageSource::Remote(url) => match options.remote {
+            RemoteImages::Fetch => fetch(url),
+            RemoteImages::Link | RemoteImages::Error => bail!("{} is a remote image, which this quiz does not fetch", url),
+        },
+    }
+}
+
+/// The media type of a `data:` URI, e.g. `image/png`.
+fn data_mime(uri: &str) -> Option<&str> {
+    let header = uri.get(5..)?.split(',').next()?;
+    Some(header.split(';').next().unwrap_or("")).filter(|mime| !mime.is_empty())
+}
+
+/// The media type and contents of a `data:` URI, which is base64 when its
+/// header ends in `;base64` and percent-encoded otherwise.
+pub(crate) fn decode_data_uri(uri: &str) -> Result<(String, Vec<u8>)> {
+    let (header, data) = uri.get(5..).and_then(|rest| rest.split_once(',')).ok_or_else(|| anyhow!("data URI has no `,`"))?;
+    let mime = data_mime(uri).unwrap_or("text/plain").to_ascii_lowercase();
+    if extension_for_mime(&mime).is_none() {
+        bail!("data URI has type {}, which is not a supported image type", mime);
+    }
+    let bytes = if header.to_ascii_lowercase().ends_with(";base64") {
+        let data: String = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| anyhow!("data URI is not valid base64: {}", e))?
+    } else {
+        percent_decode(data)
+    };
+    Ok((mime, bytes))
+}
+
+fn percent_decode(data: &str) -> Vec<u8> {
+    let bytes = data.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn extension_for_mime(mime: &str) -> Option<&'static str> {
+    match mime.to_ascii_lowercase().as_str() {
+        "image/png" => Some("png"),
+        "image/jpeg" | "image/jpg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "image/svg+xml" => Some("svg"),
+        "image/webp" => Some("webp"),
+        _ => None,
+    }
+}
+
+/// The media type for an image file name, from its extension.
+pub(crate) fn mime_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().map(|e| e.to_ascii_lowercase()).as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Downloads a remote image. Runs on its own thread, as the blocking client
+/// cannot be used from inside the async runtime the commands run on.
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let owned = url.to_string();
+    std::thread::spawn(move || -> Result<Vec<u8>> {
+        let url = owned;
+        let client = reqwest::blocking::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+        let response = client.get(&url).send().and_then(|r| r.error_for_status()).map_err(|e| anyhow!("could not fetch {}: {}", url, e))?;
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        if !content_type.is_empty() && !content_type.starts_with("image/") {
+            bail!("{} is not an image: the server sent {}", url, content_type);
+        }
+        let mut bytes = Vec::new();
+        response.take(FETCH_LIMIT + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > FETCH_LIMIT {
+            bail!("{} is larger than {} MB", url, FETCH_LIMIT >> 20);
+        }
+        Ok(bytes)
+    })
+    .join()
+    .map_err(|_| anyhow!("fetching {} failed", url))?
+}
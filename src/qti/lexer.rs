@@ -1,73 +1,557 @@
-use regex::Regex;
+use std::ops::Range;
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Text(String),
-    LatexMath(String),
+    /// `display` is set for `$$...$$`.
+    LatexMath { latex: String, display: bool },
     Chemistry(String),
     Image { src: String, alt: String },
     Music(String),
-    Code { lang: String, content: String },
+    /// A tune in ABC notation, engraved through MusicXML like `Music`.
+    Abc(String),
+    /// A ```` ```dot ```` or ```` ```mermaid ```` graph, drawn as an SVG
+    /// image. `alt` comes from the fence's `alt="..."` or a `fig-alt` option
+    /// line, and is empty if the author gave none.
+    Diagram { lang: String, content: String, alt: String },
+    /// A ```` ```plot ```` spec of functions to graph. `alt` is taken as for
+    /// `Diagram`; when empty, one is described from the spec.
+    Plot { spec: String, alt: String },
+    /// Any other fence. `highlight` lists the lines to mark, from a
+    /// `{3,5-7}` group in the info string; `verify` is set by a `verify`
+    /// word there, asking `convert` to run the code and check its output.
+    Code { lang: String, content: String, highlight: Vec<usize>, verify: bool },
 }
 
 pub fn lex_content(text: &str) -> Vec<Token> {
-    // Matches $$...$$, $...$, \ce{...}, ![...](...), ```musicxml...```, or ```lang...```
-    // Group 1: $$...$$
-    // Group 2: $...$
-    // Group 3: \ce{...}
-    // Group 4: ![...](...) -> alt
-    // Group 5: ![...](...) -> src
-    // Group 6: ```musicxml...```
-    // Group 7: ```lang...``` -> lang
-    // Group 8: ```lang...``` -> content
-    let re = Regex::new(r"(\$\$[\s\S]*?\$\$)|(\$[\s\S]*?\$) |(\\ce\{[\s\S]*?\})|(!\[(.*?)\]\((.*?)\))|(```musicxml\n([\s\S]*?)```)|(```(\w*)\n([\s\S]*?)```)").unwrap();
-    let mut tokens = Vec::new();
-    let mut last_end = 0;
-
-    for cap in re.captures_iter(text) {
-        let m = cap.get(0).unwrap();
-        
-        // Push preceding text if any
-        if m.start() > last_end {
-            tokens.push(Token::Text(text[last_end..m.start()].to_string()));
-        }
-
-        if let Some(block) = cap.get(1) {
-            // Block Math $$...$$
-            let latex = block.as_str().trim_matches('$').trim();
-            tokens.push(Token::LatexMath(latex.to_string()));
-        } else if let Some(block) = cap.get(2) {
-            // Inline Math $...$
-            let latex = block.as_str().trim_matches('$').trim();
-            tokens.push(Token::LatexMath(latex.to_string()));
-        } else if let Some(block) = cap.get(3) {
-            // Chemistry \ce{...}
-            let content = block.as_str();
-            let inner = &content[4..content.len()-1];
-            tokens.push(Token::Chemistry(inner.to_string()));
-        } else if let Some(_) = cap.get(4) {
-            // Image ![alt](src)
-            let alt = cap.get(5).map_or("", |m| m.as_str()).to_string();
-            let src = cap.get(6).map_or("", |m| m.as_str()).to_string();
-            tokens.push(Token::Image { src, alt });
-        } else if let Some(block) = cap.get(7) {
-            // Music ```musicxml...```
-            let content = cap.get(8).map_or("", |m| m.as_str());
-            tokens.push(Token::Music(content.to_string()));
-        } else if let Some(block) = cap.get(9) {
-            // Code ```lang...```
-            let lang = cap.get(10).map_or("", |m| m.as_str()).to_string();
-            let content = cap.get(11).map_or("", |m| m.as_str()).to_string();
-            tokens.push(Token::Code { lang, content });
-        }
-        
-        last_end = m.end();
-    }
-
-    // Push remaining text
-    if last_end < text.len() {
-        tokens.push(Token::Text(text[last_end..].to_string()));
-    }
-
-    tokens
+    lex_content_spanned(text).into_iter().map(|(token, _)| token).collect()
+}
+
+/// Splits Markdown content into tokens, each paired with the byte range of
+/// the source it came from.
+///
+/// Recognises, roughly following CommonMark and Pandoc's math rules:
+/// - fenced code blocks (```` ``` ```` or `~~~`) at the start of a line;
+///   ```` ```musicxml ```` fences become `Music`, ```` ```abc ```` fences
+///   `Abc`, ```` ```dot ```` and ```` ```mermaid ```` fences `Diagram`,
+///   ```` ```plot ```` fences `Plot`, others `Code`
+/// - `$$...$$` display math and `$...$` inline math, where the opening `$`
+///   must be followed by a non-space, the closing `$` preceded by a non-space
+///   and not followed by a digit (so `$5 and $10` stays text)
+/// - `\ce{...}` chemistry with balanced braces, also inside `$...$`
+/// - `![alt](src "title")` images
+/// - `` `inline code` ``, whose contents are never lexed further
+/// - `\$` as a literal dollar sign
+///
+/// Everything else, including inline code spans, is returned as `Text`.
+pub fn lex_content_spanned(text: &str) -> Vec<(Token, Range<usize>)> {
+    Lexer::new(text).run()
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    tokens: Vec<(Token, Range<usize>)>,
+    text: String,
+    text_start: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            tokens: Vec::new(),
+            text: String::new(),
+            text_start: 0,
+        }
+    }
+
+    fn run(mut self) -> Vec<(Token, Range<usize>)> {
+        while self.pos < self.src.len() {
+            let start = self.pos;
+            if self.at_line_start()
+                && let Some((token, end)) = self.fenced_block(start)
+            {
+                self.emit(token, start, end);
+                continue;
+            }
+
+            let rest = &self.src[start..];
+            let lexed = if rest.starts_with("\\ce{") {
+                self.chemistry(start)
+            } else if rest.starts_with("\\$") {
+                self.push_text("$", start);
+                self.pos = start + 2;
+                continue;
+            } else if rest.starts_with('\\') {
+                // Keep other escapes verbatim, but step over the escaped
+                // character so it cannot open a construct.
+                let len = escape_len(rest);
+                self.push_text(&rest[..len], start);
+                self.pos = start + len;
+                continue;
+            } else if rest.starts_with('`') {
+                self.code_span(start)
+            } else if rest.starts_with("$$") {
+                self.display_math(start)
+            } else if rest.starts_with('$') {
+                self.inline_math(start)
+            } else if rest.starts_with("![") {
+                self.image(start)
+            } else {
+                None
+            };
+
+            match lexed {
+                Some((Some(token), end)) => self.emit(token, start, end),
+                // Matched but stays text (inline code spans).
+                Some((None, end)) => {
+                    self.push_text(&self.src[start..end], start);
+                    self.pos = end;
+                }
+                None => {
+                    let ch = rest.chars().next().unwrap();
+                    // A run of `$` or backticks that failed to open anything is
+                    // literal as a whole, so its tail must not be retried.
+                    let len = if ch == '$' || ch == '`' {
+                        rest.len() - rest.trim_start_matches(ch).len()
+                    } else {
+                        ch.len_utf8()
+                    };
+                    self.push_text(&rest[..len], start);
+                    self.pos = start + len;
+                }
+            }
+        }
+        self.flush_text();
+        self.tokens
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.pos == 0 || self.src.as_bytes()[self.pos - 1] == b'\n'
+    }
+
+    fn push_text(&mut self, s: &str, start: usize) {
+        if self.text.is_empty() {
+            self.text_start = start;
+        }
+        self.text.push_str(s);
+    }
+
+    fn flush_text(&mut self) {
+        if !self.text.is_empty() {
+            let text = std::mem::take(&mut self.text);
+            self.tokens.push((Token::Text(text), self.text_start..self.pos));
+        }
+    }
+
+    fn emit(&mut self, token: Token, start: usize, end: usize) {
+        let pos = self.pos;
+        self.pos = start;
+        self.flush_text();
+        self.pos = pos;
+        self.tokens.push((token, start..end));
+        self.pos = end;
+    }
+
+    /// A fence of three or more backticks or tildes, indented at most three
+    /// spaces. An unclosed fence runs to the end of the content.
+    fn fenced_block(&self, start: usize) -> Option<(Token, usize)> {
+        let line_end = self.src[start..].find('\n').map_or(self.src.len(), |i| start + i);
+        let line = &self.src[start..line_end];
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        if indent > 3 {
+            return None;
+        }
+        let fence_char = line[indent..].chars().next()?;
+        if fence_char != '`' && fence_char != '~' {
+            return None;
+        }
+        let fence_len = line[indent..].len() - line[indent..].trim_start_matches(fence_char).len();
+        if fence_len < 3 {
+            return None;
+        }
+        let info = line[indent + fence_len..].trim();
+        if fence_char == '`' && info.contains('`') {
+            return None;
+        }
+        let (info, highlight) = highlighted_lines(info);
+        let lang = info.split_whitespace().next().unwrap_or("").to_string();
+
+        let mut content = String::new();
+        let mut pos = line_end + 1;
+        // Unclosed fences run to the end of the content.
+        let mut end = self.src.len();
+        while pos < self.src.len() {
+            let next_end = self.src[pos..].find('\n').map_or(self.src.len(), |i| pos + i);
+            let body_line = &self.src[pos..next_end];
+            let trimmed = body_line.trim_start_matches(' ');
+            let closing_indent = body_line.len() - trimmed.len();
+            let run = trimmed.len() - trimmed.trim_start_matches(fence_char).len();
+            if closing_indent <= 3 && run >= fence_len && trimmed[run..].trim().is_empty() {
+                end = pos + body_line.trim_end().len();
+                break;
+            }
+            // Content lines lose up to as much indentation as the opening fence.
+            let strip = (body_line.len() - body_line.trim_start_matches(' ').len()).min(indent);
+            content.push_str(&body_line[strip..]);
+            content.push('\n');
+            pos = next_end + 1;
+        }
+
+        // Quarto writes diagram cells as ```{dot} and ```{mermaid}.
+        let figure_lang = lang.trim_start_matches('{').trim_end_matches('}');
+        let token = match lang.as_str() {
+            "musicxml" => Token::Music(content),
+            "abc" => Token::Abc(content),
+            _ if figure_lang == "dot" || figure_lang == "mermaid" => {
+                let (content, alt) = figure_source(&info, &content);
+                Token::Diagram {
+                    lang: figure_lang.to_string(),
+                    content,
+                    alt,
+                }
+            }
+            _ if figure_lang == "plot" => {
+                let (spec, alt) = figure_source(&info, &content);
+                Token::Plot { spec, alt }
+            }
+            _ => {
+                let verify = is_verify_info(&info);
+                Token::Code { lang, content, highlight, verify }
+            }
+        };
+        Some((token, end))
+    }
+
+    /// Index just past the `}` matching the `{` at `open`.
+    fn matching_brace(&self, open: usize) -> Option<usize> {
+        let mut depth = 0;
+        let mut chars = self.src[open..].char_indices();
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '\\' => {
+                    chars.next();
+                }
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(open + i + 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn chemistry(&self, start: usize) -> Option<(Option<Token>, usize)> {
+        let end = self.matching_brace(start + 3)?;
+        let inner = &self.src[start + 4..end - 1];
+        Some((Some(Token::Chemistry(inner.to_string())), end))
+    }
+
+    /// Inline code spans are returned as text, but matched here so `$` and
+    /// other markers inside them are left alone.
+    fn code_span(&self, start: usize) -> Option<(Option<Token>, usize)> {
+        let rest = &self.src[start..];
+        let ticks = rest.len() - rest.trim_start_matches('`').len();
+        let mut search = start + ticks;
+        while let Some(found) = self.src[search..].find('`') {
+            let run_start = search + found;
+            let run = &self.src[run_start..];
+            let run_len = run.len() - run.trim_start_matches('`').len();
+            if run_len == ticks {
+                return Some((None, run_start + run_len));
+            }
+            search = run_start + run_len;
+        }
+        None
+    }
+
+    fn display_math(&self, start: usize) -> Option<(Option<Token>, usize)> {
+        let body_start = start + 2;
+        let mut i = body_start;
+        while i < self.src.len() {
+            let rest = &self.src[i..];
+            if rest.starts_with('\\') {
+                i += escape_len(rest);
+            } else if rest.starts_with("$$") {
+                let latex = self.src[body_start..i].trim();
+                if latex.is_empty() {
+                    return None;
+                }
+                return Some((Some(math_token(latex, true)), i + 2));
+            } else {
+                i += rest.chars().next().unwrap().len_utf8();
+            }
+        }
+        None
+    }
+
+    fn inline_math(&self, start: usize) -> Option<(Option<Token>, usize)> {
+        let body_start = start + 1;
+        let first = self.src[body_start..].chars().next()?;
+        if first.is_whitespace() || first == '$' {
+            return None;
+        }
+
+        let mut i = body_start;
+        let mut prev = '$';
+        while i < self.src.len() {
+            let rest = &self.src[i..];
+            let ch = rest.chars().next().unwrap();
+            if ch == '\\' {
+                prev = rest[1..].chars().next().unwrap_or('\\');
+                i += escape_len(rest);
+                continue;
+            }
+            if ch == '\n' && self.src[i + 1..].trim_start_matches([' ', '\t']).starts_with('\n') {
+                // Inline math never spans a blank line.
+                return None;
+            }
+            if ch == '$' {
+                let followed_by_digit = self.src[i + 1..].chars().next().is_some_and(|c| c.is_ascii_digit());
+                if !prev.is_whitespace() && !followed_by_digit {
+                    let latex = self.src[body_start..i].trim();
+                    return Some((Some(math_token(latex, false)), i + 1));
+                }
+            }
+            prev = ch;
+            i += ch.len_utf8();
+        }
+        None
+    }
+
+    /// `![alt](src)` with balanced brackets in the alt text and balanced
+    /// parentheses in the destination; an optional title is dropped.
+    fn image(&self, start: usize) -> Option<(Option<Token>, usize)> {
+        let alt_start = start + 2;
+        let mut depth = 1;
+        let mut i = alt_start;
+        let bytes = self.src.as_bytes();
+        while i < bytes.len() && depth > 0 {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                b'\n' if bytes.get(i + 1) == Some(&b'\n') => return None,
+                _ => {}
+            }
+            i += 1;
+        }
+        if depth != 0 || bytes.get(i) != Some(&b'(') {
+            return None;
+        }
+        let alt = &self.src[alt_start..i - 1];
+
+        let dest_start = i + 1;
+        let mut depth = 1;
+        let mut j = dest_start;
+        while j < bytes.len() && depth > 0 {
+            match bytes[j] {
+                b'\\' => j += 1,
+                b'(' => depth += 1,
+                b')' => depth -= 1,
+                b'\n' => return None,
+                _ => {}
+            }
+            j += 1;
+        }
+        if depth != 0 {
+            return None;
+        }
+        let dest = self.src[dest_start..j - 1].trim();
+        let src = if let Some(stripped) = dest.strip_prefix('<') {
+            stripped.split('>').next().unwrap_or("")
+        } else {
+            dest.split_whitespace().next().unwrap_or("")
+        };
+
+        Some((
+            Some(Token::Image {
+                src: src.to_string(),
+                alt: alt.to_string(),
+            }),
+            j,
+        ))
+    }
+}
+
+/// Length of a backslash escape at the start of `rest`, including the
+/// escaped character.
+fn escape_len(rest: &str) -> usize {
+    1 + rest[1..].chars().next().map_or(0, char::len_utf8)
+}
+
+/// Splits a diagram or plot's alt text from its source. The alt text comes
+/// from `alt="..."` (or `fig-alt=`) in the fence's info string, or from a
+/// Quarto option line such as `//| fig-alt: ...`, `%%| fig-alt: ...` or
+/// `#| fig-alt: ...`; option lines are dropped from the source.
+fn figure_source(info: &str, content: &str) -> (String, String) {
+    let mut alt = info_attribute(info, "alt").or_else(|| info_attribute(info, "fig-alt"));
+    let mut source = String::new();
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        let option = ["//|", "%%|", "#|"].iter().find_map(|prefix| trimmed.strip_prefix(prefix));
+        match option {
+            Some(option) => {
+                if let Some((key, value)) = option.split_once(':')
+                    && key.trim() == "fig-alt"
+                {
+                    alt = Some(value.trim().trim_matches(['"', '\'']).to_string());
+                }
+            }
+            None => {
+                source.push_str(line);
+                source.push('\n');
+            }
+        }
+    }
+    (source, alt.unwrap_or_default())
+}
+
+/// Splits a `{3,5-7}` group of line numbers, as in ```` ```python {3,5} ````,
+/// out of a fence's info string. Returns the rest of the info string and
+/// the listed lines in order.
+fn highlighted_lines(info: &str) -> (String, Vec<usize>) {
+    let group = info.match_indices('{').find_map(|(open, _)| {
+        let close = open + info[open..].find('}')?;
+        let inner = &info[open + 1..close];
+        let is_lines = inner.chars().any(|c| c.is_ascii_digit()) && inner.chars().all(|c| c.is_ascii_digit() || matches!(c, ',' | '-' | ' '));
+        is_lines.then_some((open, close))
+    });
+    let Some((open, close)) = group else {
+        return (info.to_string(), Vec::new());
+    };
+    let mut lines = Vec::new();
+    for part in info[open + 1..close].split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        if let (Ok(first), Ok(last)) = (first.trim().parse::<usize>(), last.trim().parse::<usize>()) {
+            lines.extend(first..=last);
+        }
+    }
+    lines.sort_unstable();
+    lines.dedup();
+    (format!("{} {}", &info[..open], &info[close + 1..]).trim().to_string(), lines)
+}
+
+/// The value of `key="value"` or `key=value` in a fence's info string.
+/// Whether a fence's info string marks its code for verification, with a
+/// `verify` word after the language or a `verify=true` attribute.
+pub(crate) fn is_verify_info(info: &str) -> bool {
+    info.split_whitespace().skip(1).any(|word| word == "verify") || info_attribute(info, "verify").as_deref() == Some("true")
+}
+
+fn info_attribute(info: &str, key: &str) -> Option<String> {
+    let mut rest = info;
+    while let Some(found) = rest.find(key) {
+        let before = rest[..found].chars().next_back();
+        let after = &rest[found + key.len()..];
+        rest = after;
+        if before.is_some_and(|c| !c.is_whitespace() && c != '{') {
+            continue;
+        }
+        let Some(value) = after.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        return Some(match value.strip_prefix('"') {
+            Some(quoted) => quoted.split('"').next().unwrap_or("").to_string(),
+            None => value.split(|c: char| c.is_whitespace() || c == '}').next().unwrap_or("").to_string(),
+        });
+    }
+    None
+}
+
+/// `$\ce{...}$` is chemistry written inside math delimiters.
+fn math_token(latex: &str, display: bool) -> Token {
+    if let Some(inner) = latex.strip_prefix("\\ce{").and_then(|s| s.strip_suffix('}')) {
+        let balanced = inner.chars().try_fold(0i32, |depth, c| match c {
+            '{' => Some(depth + 1),
+            '}' if depth > 0 => Some(depth - 1),
+            '}' => None,
+            _ => Some(depth),
+        });
+        if balanced == Some(0) {
+            return Token::Chemistry(inner.to_string());
+        }
+    }
+    Token::LatexMath {
+        latex: latex.to_string(),
+        display,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lex_content, lex_content_spanned, Token};
+
+    fn math(latex: &str) -> Token {
+        Token::LatexMath { latex: latex.to_string(), display: false }
+    }
+
+    fn text(text: &str) -> Token {
+        Token::Text(text.to_string())
+    }
+
+    #[test]
+    fn inline_math_before_punctuation() {
+        assert_eq!(lex_content("Is $x$."), [text("Is "), math("x"), text(".")]);
+    }
+
+    #[test]
+    fn inline_math_at_end_of_line() {
+        assert_eq!(lex_content("Take $x$\nthen $y$"), [text("Take "), math("x"), text("\nthen "), math("y")]);
+    }
+
+    #[test]
+    fn dollar_amounts_stay_text() {
+        assert_eq!(lex_content("Pay $5 and $10 today"), [text("Pay $5 and $10 today")]);
+    }
+
+    #[test]
+    fn escaped_dollar_is_literal() {
+        assert_eq!(lex_content("Costs \\$x$ or so"), [text("Costs $x$ or so")]);
+    }
+
+    #[test]
+    fn code_spans_are_not_lexed() {
+        assert_eq!(lex_content("Run `echo $x$` and ``a ` $b$``"), [text("Run `echo $x$` and ``a ` $b$``")]);
+    }
+
+    #[test]
+    fn display_math_and_chemistry() {
+        assert_eq!(
+            lex_content("$$\\frac{a}{b}$$ and $\\ce{H2O}$ and \\ce{CO2}"),
+            [
+                Token::LatexMath { latex: "\\frac{a}{b}".to_string(), display: true },
+                text(" and "),
+                Token::Chemistry("H2O".to_string()),
+                text(" and "),
+                Token::Chemistry("CO2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_brackets_in_images() {
+        assert_eq!(
+            lex_content("![a [b] c](fig(1).png \"Title\")"),
+            [Token::Image { src: "fig(1).png".to_string(), alt: "a [b] c".to_string() }]
+        );
+    }
+
+    #[test]
+    fn spans_cover_the_source() {
+        let source = "Is $x$ in ![a](b.png)?\n```python\nprint(1)\n```";
+        let spans: Vec<(String, &str)> = lex_content_spanned(source)
+            .into_iter()
+            .map(|(token, span)| (format!("{:?}", token), &source[span]))
+            .collect();
+        let sources: Vec<&str> = spans.iter().map(|(_, s)| *s).collect();
+        assert_eq!(sources, ["Is ", "$x$", " in ", "![a](b.png)", "?\n", "```python\nprint(1)\n```"], "{:?}", spans);
+    }
 }
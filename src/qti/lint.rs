@@ -0,0 +1,383 @@
+use anyhow::Result;
+use crate::qti::diagnostics::{Diagnostic, Severity};
+use crate::qti::images::{decode_data_uri, image_source, is_missing, ImageSource};
+use crate::qti::lexer::{lex_content, Token};
+use crate::qti::model::{Question, QuestionType, Quiz};
+use crate::qti::parser;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum LintFormat {
+    Text,
+    Json,
+    Sarif,
+}
+
+pub struct LintOptions {
+    pub max_stem_words: usize,
+}
+
+/// Rule identifiers and descriptions, reported in SARIF output.
+const RULES: &[(&str, &str)] = &[
+    ("read", "The quiz file could not be read."),
+    ("parse", "The quiz file could not be parsed as written."),
+    ("correct-answer-count", "Single-answer questions must not have more than one correct choice."),
+    ("true-false-choices", "True/false questions must have exactly two choices."),
+    ("duplicate-choice", "Two choices in the same question have the same text."),
+    ("all-none-of-the-above", "\"All/none of the above\" choices lose their meaning when answers are shuffled."),
+    ("missing-image", "A referenced image file does not exist."),
+    ("invalid-image", "An image's data URI cannot be decoded."),
+    ("long-stem", "The question prompt is longer than the configured word limit."),
+    ("duplicate-question", "The same question prompt appears more than once."),
+];
+
+pub struct Finding {
+    pub rule: &'static str,
+    pub diagnostic: Diagnostic,
+}
+
+pub struct LintedFile {
+    pub path: PathBuf,
+    pub source: String,
+    pub findings: Vec<Finding>,
+}
+
+pub struct LintReport {
+    pub files: Vec<LintedFile>,
+}
+
+impl LintReport {
+    pub fn error_count(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.files
+            .iter()
+            .flat_map(|f| &f.findings)
+            .filter(|f| f.diagnostic.severity == severity)
+            .count()
+    }
+}
+
+/// Lints each quiz file. Parse diagnostics are included as `parse` findings,
+/// and questions that parsed are checked against the rules above; duplicate
+/// prompts are detected across all of the given files.
+pub fn lint_files(paths: &[PathBuf], options: &LintOptions) -> Result<LintReport> {
+    let mut files = Vec::new();
+    // Normalized prompt -> (file index, line) of its first occurrence.
+    let mut seen_prompts: HashMap<String, (usize, usize)> = HashMap::new();
+
+    for path in paths {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                let finding = Finding { rule: "read", diagnostic: Diagnostic::error(format!("could not read file: {}", e), 1, 1, 1) };
+                files.push(LintedFile { path: path.clone(), source: String::new(), findings: vec![finding] });
+                continue;
+            }
+        };
+        let (quiz, diagnostics) = parser::check_quiz(&source, path);
+        let mut findings: Vec<Finding> = diagnostics
+            .into_iter()
+            .map(|diagnostic| Finding { rule: "parse", diagnostic })
+            .collect();
+
+        if let Some(quiz) = &quiz {
+            let base_dir = path.parent().unwrap_or(Path::new("."));
+            lint_quiz(quiz, &source, base_dir, options, &mut findings);
+
+            for question in quiz.questions.iter().filter(|q| q.source.is_none()) {
+                let key = normalize(&question.prompt);
+                if key.is_empty() {
+                    continue;
+                }
+                match seen_prompts.get(&key) {
+                    Some(&(file_index, line)) => {
+                        let first = files.get(file_index).map_or(path, |f: &LintedFile| &f.path);
+                        let (column, length) = header_span(&source, question.line);
+                        findings.push(Finding {
+                            rule: "duplicate-question",
+                            diagnostic: Diagnostic::warning("duplicate question", question.line, column, length)
+                                .with_hint(format!("the same prompt appears at {}:{}", first.display(), line)),
+                        });
+                    }
+                    None => {
+                        seen_prompts.insert(key, (files.len(), question.line));
+                    }
+                }
+            }
+        }
+
+        findings.sort_by_key(|f| (f.diagnostic.line, f.diagnostic.column));
+        files.push(LintedFile { path: path.clone(), source, findings });
+    }
+
+    Ok(LintReport { files })
+}
+
+fn lint_quiz(quiz: &Quiz, source: &str, base_dir: &Path, options: &LintOptions, findings: &mut Vec<Finding>) {
+    // Included questions are linted with the file they are written in.
+    for question in quiz.questions.iter().filter(|q| q.source.is_none()) {
+        check_correct_answers(question, source, findings);
+        check_true_false(question, source, findings);
+        check_duplicate_choices(question, source, findings);
+        if quiz.shuffle_answers {
+            check_all_none_of_the_above(question, source, findings);
+        }
+        check_images(question, source, base_dir, findings);
+        check_stem_length(question, source, options.max_stem_words, findings);
+    }
+}
+
+/// Questions with no correct choice never get this far: the parser rejects
+/// them, and they are reported as `parse` findings.
+fn check_correct_answers(question: &Question, source: &str, findings: &mut Vec<Finding>) {
+    if !matches!(question.question_type, QuestionType::MultipleChoice | QuestionType::TrueFalse) {
+        return;
+    }
+    let correct = question.answers.iter().filter(|a| a.is_correct).count();
+    if correct > 1 {
+        let (column, length) = header_span(source, question.line);
+        findings.push(Finding {
+            rule: "correct-answer-count",
+            diagnostic: Diagnostic::error(
+                format!("single-answer question has {} correct choices", correct),
+                question.line,
+                column,
+                length,
+            )
+            .with_hint("mark exactly one choice with `- [x]`, or use type=multiple_answers"),
+        });
+    }
+}
+
+fn check_true_false(question: &Question, source: &str, findings: &mut Vec<Finding>) {
+    if question.question_type == QuestionType::TrueFalse && question.answers.len() != 2 {
+        let (column, length) = header_span(source, question.line);
+        findings.push(Finding {
+            rule: "true-false-choices",
+            diagnostic: Diagnostic::error(
+                format!("true/false question has {} choices", question.answers.len()),
+                question.line,
+                column,
+                length,
+            )
+            .with_hint("true/false questions take exactly two choices, usually `True` and `False`"),
+        });
+    }
+}
+
+fn check_duplicate_choices(question: &Question, source: &str, findings: &mut Vec<Finding>) {
+    let mut seen = HashSet::new();
+    for answer in &question.answers {
+        if !seen.insert(normalize(&answer.text)) {
+            let (column, length) = text_span(source, answer.line, &answer.text);
+            findings.push(Finding {
+                rule: "duplicate-choice",
+                diagnostic: Diagnostic::warning(format!("duplicate choice `{}`", answer.text), answer.line, column, length),
+            });
+        }
+    }
+}
+
+fn check_all_none_of_the_above(question: &Question, source: &str, findings: &mut Vec<Finding>) {
+    for answer in &question.answers {
+        let text = normalize(&answer.text);
+        if text.contains("all of the above") || text.contains("none of the above") {
+            let (column, length) = text_span(source, answer.line, &answer.text);
+            findings.push(Finding {
+                rule: "all-none-of-the-above",
+                diagnostic: Diagnostic::warning(
+                    format!("`{}` in a question with shuffled answers", answer.text),
+                    answer.line,
+                    column,
+                    length,
+                )
+                .with_hint("\"the above\" changes with each shuffle; reword the choice or set shuffle_answers: false"),
+            });
+        }
+    }
+}
+
+fn check_images(question: &Question, source: &str, base_dir: &Path, findings: &mut Vec<Finding>) {
+    for (token, line, column, length) in parser::question_tokens(source, question) {
+        let Token::Image { src, .. } = token else { continue };
+        if is_missing(&src, base_dir) {
+            findings.push(Finding {
+                rule: "missing-image",
+                diagnostic: Diagnostic::error(format!("image not found: {}", src), line, column, length)
+                    .with_hint("image paths are resolved relative to the quiz file"),
+            });
+        } else if let ImageSource::Data(uri) = image_source(&src)
+            && let Err(e) = decode_data_uri(uri)
+        {
+            findings.push(Finding {
+                rule: "invalid-image",
+                diagnostic: Diagnostic::error(format!("image cannot be decoded: {}", e), line, column, length)
+                    .with_hint("data URIs look like `data:image/png;base64,...`"),
+            });
+        }
+    }
+}
+
+fn check_stem_length(question: &Question, source: &str, max_words: usize, findings: &mut Vec<Finding>) {
+    let words: usize = lex_content(&question.prompt)
+        .iter()
+        .map(|token| match token {
+            Token::Text(text) => text.split_whitespace().count(),
+            _ => 0,
+        })
+        .sum();
+    if words > max_words {
+        let (column, length) = header_span(source, question.line);
+        findings.push(Finding {
+            rule: "long-stem",
+            diagnostic: Diagnostic::warning(
+                format!("question prompt is {} words long (limit {})", words, max_words),
+                question.line,
+                column,
+                length,
+            )
+            .with_hint("long stems test reading more than the concept; consider trimming or splitting the question"),
+        });
+    }
+}
+
+/// Column and length of the question header on `line`, without its
+/// indentation.
+fn header_span(source: &str, line: usize) -> (usize, usize) {
+    let header = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    let indent = header.chars().count() - header.trim_start().chars().count();
+    (indent + 1, header.trim().chars().count().max(1))
+}
+
+/// Column and length of `text` in `line`, falling back to the whole line
+/// when it cannot be found there.
+fn text_span(source: &str, line: usize, text: &str) -> (usize, usize) {
+    let line_text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    match line_text.find(text).filter(|_| !text.is_empty()) {
+        Some(offset) => (line_text[..offset].chars().count() + 1, text.chars().count()),
+        None => header_span(source, line),
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Formats the report for output in the requested format.
+pub fn format_report(report: &LintReport, format: LintFormat) -> Result<String> {
+    match format {
+        LintFormat::Text => Ok(format_text(report)),
+        LintFormat::Json => Ok(serde_json::to_string_pretty(&format_json(report))?),
+        LintFormat::Sarif => Ok(serde_json::to_string_pretty(&format_sarif(report))?),
+    }
+}
+
+fn format_text(report: &LintReport) -> String {
+    let mut out = String::new();
+    for file in &report.files {
+        let name = file.path.display().to_string();
+        for finding in &file.findings {
+            let mut rendered = finding.diagnostic.render(&name, &file.source);
+            // Tag the first line with the rule so it can be looked up.
+            if let Some(end) = rendered.find('\n') {
+                rendered.insert_str(end, &format!(" [{}]", finding.rule));
+            }
+            out.push_str(&rendered);
+            out.push('\n');
+        }
+    }
+    out.push_str(&format!(
+        "{} error(s), {} warning(s) in {} file(s)",
+        report.error_count(),
+        report.warning_count(),
+        report.files.len()
+    ));
+    out
+}
+
+fn severity_name(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    }
+}
+
+fn format_json(report: &LintReport) -> serde_json::Value {
+    let findings: Vec<serde_json::Value> = report
+        .files
+        .iter()
+        .flat_map(|file| {
+            file.findings.iter().map(move |f| {
+                json!({
+                    "file": file.path.display().to_string(),
+                    "line": f.diagnostic.line,
+                    "column": f.diagnostic.column,
+                    "severity": severity_name(f.diagnostic.severity),
+                    "rule": f.rule,
+                    "message": f.diagnostic.message,
+                    "hint": f.diagnostic.hint,
+                })
+            })
+        })
+        .collect();
+    serde_json::Value::Array(findings)
+}
+
+fn format_sarif(report: &LintReport) -> serde_json::Value {
+    let rules: Vec<serde_json::Value> = RULES
+        .iter()
+        .map(|(id, description)| json!({ "id": id, "shortDescription": { "text": description } }))
+        .collect();
+
+    let results: Vec<serde_json::Value> = report
+        .files
+        .iter()
+        .flat_map(|file| {
+            file.findings.iter().map(move |f| {
+                let mut text = f.diagnostic.message.clone();
+                if let Some(hint) = &f.diagnostic.hint {
+                    text.push_str(&format!(" ({})", hint));
+                }
+                json!({
+                    "ruleId": f.rule,
+                    "level": severity_name(f.diagnostic.severity),
+                    "message": { "text": text },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": file.path.to_string_lossy().replace('\\', "/") },
+                            "region": {
+                                "startLine": f.diagnostic.line,
+                                "startColumn": f.diagnostic.column,
+                            }
+                        }
+                    }]
+                })
+            })
+        })
+        .collect();
+
+    json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "brightspace-cli",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/chuck650/brightspace-cli",
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }]
+    })
+}
@@ -0,0 +1,717 @@
+use crate::qti::lexer::{lex_content, Token};
+use std::collections::HashMap;
+
+// Groups lexer tokens into Markdown blocks and parses inline formatting, so
+// every output format renders the same structure. Math, chemistry and images
+// stay as tokens inside the inline content; code, music, diagram and plot
+// fences are blocks of their own.
+
+pub(crate) enum Block {
+    Paragraph(Vec<Inline>),
+    List { ordered: bool, start: u64, items: Vec<Vec<Inline>> },
+    Table(Table),
+    /// A `Code`, `Music`, `Abc`, `Diagram` or `Plot` token.
+    Token(Token),
+}
+
+/// A GFM pipe table. A caption comes from a Pandoc-style `Table: ...` (or
+/// `: ...`) line directly before or after the table.
+pub(crate) struct Table {
+    pub caption: Option<Vec<Inline>>,
+    pub alignments: Vec<Alignment>,
+    pub header: Vec<Vec<Inline>>,
+    pub rows: Vec<Vec<Vec<Inline>>>,
+    /// Set when the top-left header cell is empty: the first column then
+    /// labels each row.
+    pub row_headers: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Alignment {
+    Default,
+    Left,
+    Center,
+    Right,
+}
+
+impl Alignment {
+    /// The CSS `text-align` value, if the column sets one.
+    pub(crate) fn css(self) -> Option<&'static str> {
+        match self {
+            Alignment::Default => None,
+            Alignment::Left => Some("left"),
+            Alignment::Center => Some("center"),
+            Alignment::Right => Some("right"),
+        }
+    }
+}
+
+pub(crate) enum Inline {
+    Text(String),
+    Strong(Vec<Inline>),
+    Emphasis(Vec<Inline>),
+    Code(String),
+    Link { href: String, children: Vec<Inline> },
+    LineBreak,
+    /// A `LatexMath`, `Chemistry` or `Image` token.
+    Token(Token),
+}
+
+/// Inline tokens stand in the text as one character each while the
+/// surrounding text is parsed, numbered from this code point by their place
+/// in the content, so each finds its own token wherever the text is cut.
+/// The range is private use; source characters from it are kept as `Text`
+/// tokens of their own.
+const PLACEHOLDER_BASE: u32 = 0xF0000;
+
+fn placeholder(index: usize) -> char {
+    char::from_u32(PLACEHOLDER_BASE + index as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+fn placeholder_index(ch: char) -> Option<usize> {
+    (ch as u32).checked_sub(PLACEHOLDER_BASE).map(|index| index as usize)
+}
+
+/// Source text with placeholders, and the tokens they stand for.
+#[derive(Default)]
+struct Inlines {
+    text: String,
+    tokens: Vec<(usize, Token)>,
+}
+
+impl Inlines {
+    fn push_token(&mut self, index: usize, token: Token) {
+        self.text.push(placeholder(index));
+        self.tokens.push((index, token));
+    }
+}
+
+enum Line {
+    Text(Inlines),
+    /// A `Code`, `Music`, `Abc`, `Diagram` or `Plot` token, which always starts on a line of its own.
+    Block(Token),
+}
+
+impl Line {
+    fn text(&self) -> Option<&str> {
+        match self {
+            Line::Text(inlines) => Some(&inlines.text),
+            Line::Block(_) => None,
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.text().is_some_and(|text| text.trim().is_empty())
+    }
+}
+
+enum Open {
+    Paragraph(Inlines),
+    List { ordered: bool, start: u64, items: Vec<Inlines> },
+}
+
+impl Open {
+    fn current(&mut self) -> &mut Inlines {
+        match self {
+            Open::Paragraph(inlines) => inlines,
+            Open::List { items, .. } => items.last_mut().unwrap(),
+        }
+    }
+}
+
+pub(crate) fn parse_blocks(text: &str) -> Vec<Block> {
+    let mut lines = split_lines(text);
+    let mut blocks = Vec::new();
+    let mut open: Option<Open> = None;
+    let mut i = 0;
+
+    while i < lines.len() {
+        if matches!(lines[i], Line::Block(_)) {
+            close(&mut open, &mut blocks);
+            if let Line::Block(token) = take_line(&mut lines, i) {
+                blocks.push(Block::Token(token));
+            }
+            i += 1;
+            continue;
+        }
+        if lines[i].is_blank() {
+            close(&mut open, &mut blocks);
+            i += 1;
+            continue;
+        }
+
+        // A caption line directly before a table, possibly with a blank line
+        // in between.
+        let table_after_caption = caption_text(lines[i].text().unwrap_or_default()).and_then(|_| {
+            let next = if lines.get(i + 1).is_some_and(Line::is_blank) { i + 2 } else { i + 1 };
+            is_table_start(&lines, next).then_some(next)
+        });
+        if let Some(table_start) = table_after_caption {
+            close(&mut open, &mut blocks);
+            let caption = take_caption(&mut lines, i);
+            let (mut table, next) = parse_table(&mut lines, table_start);
+            table.caption = caption.or(table.caption);
+            blocks.push(Block::Table(table));
+            i = next;
+            continue;
+        }
+        if is_table_start(&lines, i) {
+            close(&mut open, &mut blocks);
+            let (table, next) = parse_table(&mut lines, i);
+            blocks.push(Block::Table(table));
+            i = next;
+            continue;
+        }
+
+        let Line::Text(line) = take_line(&mut lines, i) else {
+            unreachable!()
+        };
+        i += 1;
+
+        // Only a list starting at 1 may interrupt a paragraph, so a wrapped
+        // line such as "2024. ..." stays text.
+        let marker = list_marker(&line.text).filter(|&(ordered, number, _)| {
+            !(ordered && number != 1 && matches!(open, Some(Open::Paragraph(_))))
+        });
+        if let Some((ordered, number, rest)) = marker {
+            let rest = rest.to_string();
+            match &mut open {
+                Some(Open::List { ordered: o, items, .. }) if *o == ordered => items.push(Inlines::default()),
+                _ => {
+                    close(&mut open, &mut blocks);
+                    open = Some(Open::List { ordered, start: number, items: vec![Inlines::default()] });
+                }
+            }
+            let item = open.as_mut().unwrap().current();
+            item.text = rest;
+            item.tokens = line.tokens;
+        } else {
+            let inlines = open.get_or_insert_with(|| Open::Paragraph(Inlines::default())).current();
+            if !inlines.text.is_empty() {
+                inlines.text.push('\n');
+            }
+            inlines.text.push_str(line.text.trim_start());
+            inlines.tokens.extend(line.tokens);
+        }
+    }
+    close(&mut open, &mut blocks);
+    blocks
+}
+
+/// Splits the lexed content into lines, keeping inline tokens as
+/// placeholders in the line they appear on.
+fn split_lines(text: &str) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut current = Inlines::default();
+    // The rest of a closing fence line is not a line of its own.
+    let mut after_block = false;
+    let mut next_index = 0;
+
+    for token in lex_content(text) {
+        match token {
+            Token::Text(content) => {
+                for (i, segment) in content.split('\n').enumerate() {
+                    if i > 0 {
+                        let line = std::mem::take(&mut current);
+                        if !(after_block && line.text.trim().is_empty()) {
+                            lines.push(Line::Text(line));
+                        }
+                        after_block = false;
+                    }
+                    for ch in segment.chars() {
+                        if placeholder_index(ch).is_some() {
+                            current.push_token(next_index, Token::Text(ch.to_string()));
+                            next_index += 1;
+                        } else {
+                            current.text.push(ch);
+                        }
+                    }
+                }
+            }
+            Token::Code { .. } | Token::Music(_) | Token::Abc(_) | Token::Diagram { .. } | Token::Plot { .. } => {
+                let line = std::mem::take(&mut current);
+                if !line.text.trim().is_empty() {
+                    lines.push(Line::Text(line));
+                }
+                lines.push(Line::Block(token));
+                after_block = true;
+            }
+            _ => {
+                current.push_token(next_index, token);
+                next_index += 1;
+            }
+        }
+    }
+    if !current.text.trim().is_empty() {
+        lines.push(Line::Text(current));
+    }
+    lines
+}
+
+fn take_line(lines: &mut [Line], i: usize) -> Line {
+    std::mem::replace(&mut lines[i], Line::Text(Inlines::default()))
+}
+
+fn close(open: &mut Option<Open>, blocks: &mut Vec<Block>) {
+    match open.take() {
+        Some(Open::Paragraph(inlines)) => blocks.push(Block::Paragraph(parse_inlines(inlines))),
+        Some(Open::List { ordered, start, items }) => blocks.push(Block::List {
+            ordered,
+            start,
+            items: items.into_iter().map(parse_inlines).collect(),
+        }),
+        None => {}
+    }
+}
+
+/// `Table: caption` or `: caption`.
+fn caption_text(line: &str) -> Option<&str> {
+    let trimmed = line.trim();
+    trimmed
+        .strip_prefix("Table:")
+        .or_else(|| trimmed.strip_prefix(": "))
+        .map(str::trim)
+        .filter(|caption| !caption.is_empty())
+}
+
+fn take_caption(lines: &mut [Line], i: usize) -> Option<Vec<Inline>> {
+    let Line::Text(mut inlines) = take_line(lines, i) else {
+        return None;
+    };
+    inlines.text = caption_text(&inlines.text)?.to_string();
+    Some(parse_inlines(inlines))
+}
+
+/// A header row followed by a delimiter row such as `|---|:--:|` with the
+/// same number of cells.
+fn is_table_start(lines: &[Line], i: usize) -> bool {
+    let (Some(header), Some(delimiter)) = (
+        lines.get(i).and_then(Line::text),
+        lines.get(i + 1).and_then(Line::text),
+    ) else {
+        return false;
+    };
+    if !delimiter.contains('|') || header.trim().is_empty() {
+        return false;
+    }
+    let alignments = delimiter_alignments(delimiter);
+    alignments.is_some_and(|a| a.len() == split_row(header).len())
+}
+
+fn delimiter_alignments(line: &str) -> Option<Vec<Alignment>> {
+    split_row(line)
+        .into_iter()
+        .map(|cell| {
+            let cell = cell.trim();
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            Some(match (cell.starts_with(':'), cell.ends_with(':')) {
+                (true, true) => Alignment::Center,
+                (true, false) => Alignment::Left,
+                (false, true) => Alignment::Right,
+                (false, false) => Alignment::Default,
+            })
+        })
+        .collect()
+}
+
+/// Parses the table starting at `i`, returning it and the index of the first
+/// line after it (and after a caption that follows it).
+fn parse_table(lines: &mut [Line], i: usize) -> (Table, usize) {
+    let header = split_cells(take_line(lines, i));
+    let alignments = delimiter_alignments(lines[i + 1].text().unwrap_or_default()).unwrap_or_default();
+    let columns = header.len();
+
+    let mut rows = Vec::new();
+    let mut next = i + 2;
+    while next < lines.len() {
+        let Some(text) = lines[next].text() else { break };
+        if text.trim().is_empty() || caption_text(text).is_some() {
+            break;
+        }
+        let mut cells = split_cells(take_line(lines, next));
+        cells.resize_with(columns, Inlines::default);
+        rows.push(cells.into_iter().map(parse_inlines).collect());
+        next += 1;
+    }
+
+    let caption_line = if lines.get(next).is_some_and(Line::is_blank) { next + 1 } else { next };
+    let mut caption = None;
+    if lines.get(caption_line).and_then(Line::text).and_then(caption_text).is_some() {
+        caption = take_caption(lines, caption_line);
+        next = caption_line + 1;
+    }
+
+    let row_headers = header.first().is_some_and(|cell| cell.text.is_empty());
+    let table = Table {
+        caption,
+        alignments,
+        header: header.into_iter().map(parse_inlines).collect(),
+        rows,
+        row_headers,
+    };
+    (table, next)
+}
+
+/// Splits a row on unescaped pipes, dropping optional outer pipes.
+fn split_row(line: &str) -> Vec<&str> {
+    let mut row = line.trim();
+    row = row.strip_prefix('|').unwrap_or(row);
+    if row.ends_with('|') && !row.ends_with("\\|") {
+        row = &row[..row.len() - 1];
+    }
+
+    let mut cells = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in row.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '|' if !escaped => {
+                cells.push(&row[start..i]);
+                start = i + 1;
+            }
+            _ => escaped = false,
+        }
+    }
+    cells.push(&row[start..]);
+    cells
+}
+
+/// Splits a row into cells, handing each cell the tokens whose placeholders
+/// it contains. `\|` becomes a literal pipe, even inside code spans.
+fn split_cells(row: Line) -> Vec<Inlines> {
+    let Line::Text(row) = row else {
+        return Vec::new();
+    };
+    let mut tokens: HashMap<usize, Token> = row.tokens.into_iter().collect();
+    split_row(&row.text)
+        .into_iter()
+        .map(|cell| {
+            let text = cell.trim().replace("\\|", "|");
+            let tokens = text.chars().filter_map(placeholder_index).filter_map(|index| tokens.remove_entry(&index)).collect();
+            Inlines { text, tokens }
+        })
+        .collect()
+}
+
+/// `- item`, `* item`, `+ item`, `1. item` or `1) item`, indented at most
+/// three spaces. Returns whether the list is ordered, the item number and
+/// the item text.
+fn list_marker(line: &str) -> Option<(bool, u64, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let (ordered, number, rest) = if let Some(rest) = trimmed.strip_prefix(['-', '*', '+']) {
+        (false, 1, rest)
+    } else {
+        let digits = trimmed.len() - trimmed.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 || digits > 9 {
+            return None;
+        }
+        let rest = trimmed[digits..].strip_prefix(['.', ')'])?;
+        (true, trimmed[..digits].parse().ok()?, rest)
+    };
+    if !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let item = rest.trim_start();
+    (!item.is_empty()).then_some((ordered, number, item))
+}
+
+fn parse_inlines(inlines: Inlines) -> Vec<Inline> {
+    let mut tokens = inlines.tokens.into_iter().collect();
+    parse_span(&inlines.text, &mut tokens)
+}
+
+fn parse_span(text: &str, tokens: &mut HashMap<usize, Token>) -> Vec<Inline> {
+    let mut out = Vec::new();
+    let mut plain = String::new();
+    let mut i = 0;
+
+    while i < text.len() {
+        let rest = &text[i..];
+        let ch = rest.chars().next().unwrap();
+        let prev = text[..i].chars().next_back();
+
+        match ch {
+            _ if let Some(index) = placeholder_index(ch) => {
+                match tokens.remove(&index) {
+                    Some(Token::Text(text)) => plain.push_str(&text),
+                    Some(token) => {
+                        flush(&mut plain, &mut out);
+                        out.push(Inline::Token(token));
+                    }
+                    None => {}
+                }
+                i += ch.len_utf8();
+            }
+            '\\' => {
+                let next = rest[1..].chars().next();
+                match next {
+                    Some('\n') => {
+                        flush(&mut plain, &mut out);
+                        out.push(Inline::LineBreak);
+                        i += 2;
+                    }
+                    Some(c) if c.is_ascii_punctuation() => {
+                        plain.push(c);
+                        i += 2;
+                    }
+                    _ => {
+                        plain.push('\\');
+                        i += 1;
+                    }
+                }
+            }
+            '\n' => {
+                // Two or more trailing spaces make a hard line break.
+                if plain.ends_with("  ") {
+                    plain.truncate(plain.trim_end_matches(' ').len());
+                    flush(&mut plain, &mut out);
+                    out.push(Inline::LineBreak);
+                } else {
+                    plain.truncate(plain.trim_end_matches(' ').len());
+                    plain.push('\n');
+                }
+                i += 1;
+            }
+            '`' => match code_span(rest) {
+                Some((code, len)) => {
+                    flush(&mut plain, &mut out);
+                    out.push(Inline::Code(restore_text(&code, tokens, true).unwrap_or_default()));
+                    i += len;
+                }
+                None => {
+                    let run = run_len(rest, '`');
+                    plain.push_str(&rest[..run]);
+                    i += run;
+                }
+            },
+            '*' | '_' => match emphasis(rest, prev, ch) {
+                Some((inner, run, len)) => {
+                    flush(&mut plain, &mut out);
+                    let children = parse_span(inner, tokens);
+                    out.push(match run {
+                        1 => Inline::Emphasis(children),
+                        2 => Inline::Strong(children),
+                        _ => Inline::Strong(vec![Inline::Emphasis(children)]),
+                    });
+                    i += len;
+                }
+                None => {
+                    let run = run_len(rest, ch);
+                    plain.push_str(&rest[..run]);
+                    i += run;
+                }
+            },
+            // A destination holding math or an image is not a link; its
+            // tokens are then placed as they come.
+            '[' => match link(rest).and_then(|(label, href, len)| Some((label, restore_text(href, tokens, false)?, len))) {
+                Some((label, href, len)) => {
+                    flush(&mut plain, &mut out);
+                    let children = parse_span(label, tokens);
+                    out.push(Inline::Link { href, children });
+                    i += len;
+                }
+                None => {
+                    plain.push('[');
+                    i += 1;
+                }
+            },
+            '<' => match autolink(rest) {
+                Some((href, len)) => {
+                    flush(&mut plain, &mut out);
+                    out.push(Inline::Link {
+                        href: href.to_string(),
+                        children: vec![Inline::Text(href.to_string())],
+                    });
+                    i += len;
+                }
+                None => {
+                    plain.push('<');
+                    i += 1;
+                }
+            },
+            _ => {
+                plain.push(ch);
+                i += ch.len_utf8();
+            }
+        }
+    }
+    flush(&mut plain, &mut out);
+    out
+}
+
+/// `text` with placeholders for source characters put back. Other tokens
+/// are dropped when `drop_tokens` is set, and make the result `None`
+/// otherwise.
+fn restore_text(text: &str, tokens: &mut HashMap<usize, Token>, drop_tokens: bool) -> Option<String> {
+    let mut out = String::new();
+    for ch in text.chars() {
+        match placeholder_index(ch).map(|index| (index, tokens.get(&index))) {
+            None => out.push(ch),
+            Some((_, Some(Token::Text(text)))) => out.push_str(text),
+            Some((index, _)) if drop_tokens => {
+                tokens.remove(&index);
+            }
+            Some(_) => return None,
+        }
+    }
+    Some(out)
+}
+
+fn flush(plain: &mut String, out: &mut Vec<Inline>) {
+    if !plain.is_empty() {
+        out.push(Inline::Text(std::mem::take(plain)));
+    }
+}
+
+fn run_len(text: &str, ch: char) -> usize {
+    text.len() - text.trim_start_matches(ch).len()
+}
+
+/// A backtick run closed by a run of the same length. Line endings become
+/// spaces, and one space is stripped from each side if both are present.
+fn code_span(text: &str) -> Option<(String, usize)> {
+    let ticks = run_len(text, '`');
+    let mut search = ticks;
+    while let Some(found) = text[search..].find('`') {
+        let start = search + found;
+        let run = run_len(&text[start..], '`');
+        if run == ticks {
+            let mut code = text[ticks..start].replace('\n', " ");
+            if code.len() >= 2 && code.starts_with(' ') && code.ends_with(' ') && !code.trim().is_empty() {
+                code = code[1..code.len() - 1].to_string();
+            }
+            return Some((code, start + run));
+        }
+        search = start + run;
+    }
+    None
+}
+
+/// Finds the inner text of `*em*`, `**strong**` or `***both***` (or the `_`
+/// forms) starting at `text`. The opener must be followed by a non-space and
+/// the closer preceded by one; `_` must also not touch a word on the outside.
+fn emphasis(text: &str, prev: Option<char>, delim: char) -> Option<(&str, usize, usize)> {
+    let run = run_len(text, delim);
+    if run > 3 {
+        return None;
+    }
+    let after = text[run..].chars().next()?;
+    if after.is_whitespace() || (delim == '_' && prev.is_some_and(|c| c.is_alphanumeric())) {
+        return None;
+    }
+
+    let mut i = run;
+    while i < text.len() {
+        let rest = &text[i..];
+        let ch = rest.chars().next().unwrap();
+        if ch == '\\' {
+            i += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+        } else if ch == '`' {
+            i += code_span(rest).map_or_else(|| run_len(rest, '`'), |(_, len)| len);
+        } else if ch == delim {
+            let closing = run_len(rest, delim);
+            let before = text[..i].chars().next_back().unwrap();
+            let next = rest[closing..].chars().next();
+            let word_after = delim == '_' && next.is_some_and(|c| c.is_alphanumeric());
+            if closing == run && i > run && !before.is_whitespace() && !word_after {
+                return Some((&text[run..i], run, i + closing));
+            }
+            i += closing;
+        } else {
+            i += ch.len_utf8();
+        }
+    }
+    None
+}
+
+/// `[label](href "title")`, with balanced brackets in the label and balanced
+/// parentheses in the destination. The title is dropped.
+fn link(text: &str) -> Option<(&str, &str, usize)> {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    let label_end = loop {
+        match bytes.get(i)? {
+            b'\\' => i += 1,
+            b'`' => {
+                i += code_span(&text[i..]).map_or(1, |(_, len)| len);
+                continue;
+            }
+            b'[' => depth += 1,
+            b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    break i;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    };
+    if bytes.get(label_end + 1) != Some(&b'(') {
+        return None;
+    }
+
+    let dest_start = label_end + 2;
+    let mut depth = 1;
+    let mut j = dest_start;
+    while depth > 0 {
+        match bytes.get(j)? {
+            b'\\' => j += 1,
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b'\n' => return None,
+            _ => {}
+        }
+        j += 1;
+    }
+    let dest = text[dest_start..j - 1].trim();
+    let href = match dest.strip_prefix('<') {
+        Some(stripped) => stripped.split('>').next().unwrap_or(""),
+        None => dest.split_whitespace().next().unwrap_or(""),
+    };
+    Some((&text[1..label_end], href, j))
+}
+
+/// `<https://example.com>` or `<mailto:someone@example.com>`.
+fn autolink(text: &str) -> Option<(&str, usize)> {
+    let end = text.find('>')?;
+    let href = &text[1..end];
+    let is_url = ["http://", "https://", "mailto:"].iter().any(|scheme| href.starts_with(scheme));
+    (is_url && !href.contains(char::is_whitespace)).then_some((href, end + 1))
+}
+
+/// Whether a link destination is safe to write as an `href`. Anything with a
+/// scheme other than http, https or mailto (e.g. `javascript:`) is rendered
+/// as plain text instead.
+pub(crate) fn is_safe_href(href: &str) -> bool {
+    match href.split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => {
+            matches!(scheme.to_ascii_lowercase().as_str(), "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+/// The plain text of inline content, e.g. for link labels or titles.
+pub(crate) fn plain_text(inlines: &[Inline]) -> String {
+    let mut out = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) | Inline::Code(text) => out.push_str(text),
+            Inline::Strong(children) | Inline::Emphasis(children) | Inline::Link { children, .. } => {
+                out.push_str(&plain_text(children))
+            }
+            Inline::LineBreak => out.push('\n'),
+            Inline::Token(_) => {}
+        }
+    }
+    out
+}
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use std::io::Cursor;
+
+// A minimal element tree for the MathML latex2mathml produces, shared by the
+// renderers that lay it out in other formats.
+
+pub(crate) struct MathNode {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub text: String,
+    pub children: Vec<MathNode>,
+}
+
+impl MathNode {
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses MathML into a tree, returning the `<math>` element.
+pub(crate) fn parse_mathml(mathml: &str) -> Result<MathNode> {
+    let mut reader = quick_xml::Reader::from_str(mathml);
+    let mut stack = vec![MathNode {
+        name: String::new(),
+        attrs: Vec::new(),
+        text: String::new(),
+        children: Vec::new(),
+    }];
+
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => stack.push(node_from_start(&e)?),
+            Event::Empty(e) => {
+                let node = node_from_start(&e)?;
+                stack.last_mut().unwrap().children.push(node);
+            }
+            Event::End(_) => {
+                let node = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => anyhow::bail!("Unbalanced MathML"),
+                }
+            }
+            Event::Text(t) => stack.last_mut().unwrap().text.push_str(&t.unescape()?),
+            _ => {}
+        }
+    }
+
+    let mut document = stack.pop().unwrap();
+    match document.children.pop() {
+        Some(math) => Ok(math),
+        None => anyhow::bail!("Empty MathML"),
+    }
+}
+
+fn node_from_start(e: &BytesStart) -> Result<MathNode> {
+    let mut attrs = Vec::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        attrs.push((
+            String::from_utf8(attr.key.as_ref().to_vec())?,
+            attr.unescape_value()?.into_owned(),
+        ));
+    }
+    Ok(MathNode {
+        name: String::from_utf8(e.name().as_ref().to_vec())?,
+        attrs,
+        text: String::new(),
+        children: Vec::new(),
+    })
+}
+
+/// Groups a base with its first script when a second follows, so `x_a^b`
+/// becomes `{x_a}^b`. latex2mathml reads `x_a^b` as `x_{a^b}`; grouped, it
+/// nests the scripts outward instead, for `merge_scripts` to join.
+pub(crate) fn group_scripts(latex: &str) -> String {
+    let chars: Vec<char> = latex.chars().collect();
+    let mut inserts = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if !matches!(c, '^' | '_') || i == 0 || chars[i - 1] == '\\' {
+            i += 1;
+            continue;
+        }
+        let first_end = script_end(&chars, i + 1);
+        let mut next = first_end;
+        while chars.get(next) == Some(&' ') {
+            next += 1;
+        }
+        match chars.get(next) {
+            Some(&other) if matches!(other, '^' | '_') && other != c => {
+                inserts.push((base_start(&chars, i), '{'));
+                inserts.push((first_end, '}'));
+                i = script_end(&chars, next + 1);
+            }
+            _ => i = first_end,
+        }
+    }
+    let mut chars = chars;
+    for (at, c) in inserts.into_iter().rev() {
+        chars.insert(at, c);
+    }
+    chars.into_iter().collect()
+}
+
+/// Where the script argument starting at `start` ends: after a `{...}`
+/// group, a `\command`, or one character.
+fn script_end(chars: &[char], start: usize) -> usize {
+    let mut i = start;
+    while chars.get(i) == Some(&' ') {
+        i += 1;
+    }
+    match chars.get(i) {
+        Some('{') => {
+            let mut depth = 0;
+            for (j, c) in chars.iter().enumerate().skip(i) {
+                match c {
+                    '{' if chars[j - 1] != '\\' => depth += 1,
+                    '}' if chars[j - 1] != '\\' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return j + 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            chars.len()
+        }
+        Some('\\') => {
+            let letters = chars[i + 1..].iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            (i + 1 + letters.max(1)).min(chars.len())
+        }
+        Some(_) => i + 1,
+        None => i,
+    }
+}
+
+/// Where the base of the script at `script` starts: a `{...}` group, with
+/// the command it is the argument of, a `\command`, or one character.
+fn base_start(chars: &[char], script: usize) -> usize {
+    let end = script - 1;
+    // The base, and where a command name ending at it would end.
+    let (start, name_end) = match chars[end] {
+        '}' => {
+            let mut depth = 0;
+            let mut open = end;
+            loop {
+                match chars[open] {
+                    '}' => depth += 1,
+                    '{' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 || open == 0 {
+                    break;
+                }
+                open -= 1;
+            }
+            (open, open)
+        }
+        c if c.is_ascii_alphabetic() => (end, end + 1),
+        _ => return end,
+    };
+    let name = chars[..name_end].iter().rev().take_while(|c| c.is_ascii_alphabetic()).count();
+    match name_end - name {
+        name_start if name > 0 && name_start > 0 && chars[name_start - 1] == '\\' => name_start - 1,
+        _ => start,
+    }
+}
+
+/// Sets nested scripts such as `{x_a}^b`, which latex2mathml writes as an
+/// `msub` inside an `msup` or the other way round, as one `msubsup`, so both
+/// scripts sit against the base. Limits nested the same way, as for
+/// `{\sum_{i=1}}^n`, become one `munderover`.
+pub(crate) fn merge_scripts(node: &mut MathNode) {
+    for child in &mut node.children {
+        merge_scripts(child);
+    }
+    // The nested element each outer one can hold, what the pair becomes,
+    // and whether the outer script is the lower one.
+    let (inner, merged, outer_is_lower) = match (node.name.as_str(), node.children.first().map(|c| c.name.as_str())) {
+        ("msub", Some("msup")) => ("msup", "msubsup", true),
+        ("msup", Some("msub")) => ("msub", "msubsup", false),
+        ("msub", Some("mover")) => ("mover", "munderover", true),
+        ("msup", Some("munder")) => ("munder", "munderover", false),
+        _ => return,
+    };
+    if node.children.len() != 2 || node.children[0].name != inner || node.children[0].children.len() != 2 {
+        return;
+    }
+    let outer_script = node.children.pop().unwrap();
+    let mut nested = node.children.pop().unwrap();
+    let inner_script = nested.children.pop().unwrap();
+    let base = nested.children.pop().unwrap();
+    let (lower, upper) = if outer_is_lower { (outer_script, inner_script) } else { (inner_script, outer_script) };
+    node.name = merged.to_string();
+    node.children = vec![base, lower, upper];
+}
+
+/// Writes a tree read by `parse_mathml` back out as MathML.
+pub(crate) fn write_mathml(node: &MathNode) -> Result<String> {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    write_node(&mut writer, node)?;
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+fn write_node(writer: &mut Writer<Cursor<Vec<u8>>>, node: &MathNode) -> Result<()> {
+    let mut start = BytesStart::new(node.name.as_str());
+    for (key, value) in &node.attrs {
+        start.push_attribute((key.as_str(), value.as_str()));
+    }
+    writer.write_event(Event::Start(start))?;
+    if !node.text.is_empty() {
+        writer.write_event(Event::Text(BytesText::new(&node.text)))?;
+    }
+    for child in &node.children {
+        write_node(writer, child)?;
+    }
+    writer.write_event(Event::End(BytesEnd::new(node.name.as_str())))?;
+    Ok(())
+}
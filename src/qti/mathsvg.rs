@@ -0,0 +1,989 @@
+use anyhow::Result;
+use crate::qti::generator::latex_to_mathml;
+use crate::qti::mathml::{parse_mathml, MathNode};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use ttf_parser::math::Constants;
+use ttf_parser::{Face, GlyphId, OutlineBuilder};
+
+// Lays out the MathML produced by latex2mathml as glyph outlines from New
+// Computer Modern Math, so the SVG draws the same in every browser without
+// any fonts installed. Positions come from the font's OpenType MATH
+// constants, following a simplified form of TeX's rules: no math kerning,
+// and scripts more than two levels deep stay at scriptscript size.
+//
+// Lengths are in font design units, with y pointing up from the baseline.
+
+const FONT_NAME: &str = "NewCMMath-Regular";
+
+/// An SVG rendering of one expression. Lengths are in ems, for sizing the
+/// image against the surrounding text.
+pub(crate) struct MathSvg {
+    pub svg: String,
+    pub width: f32,
+    pub height: f32,
+    /// How far the image extends below the text baseline.
+    pub depth: f32,
+}
+
+/// Renders LaTeX as a standalone SVG whose `<title>` is `title`.
+pub(crate) fn latex_to_svg(latex: &str, display: bool, title: &str) -> Result<MathSvg> {
+    let mathml = latex_to_mathml(latex, display)?;
+    let root = parse_mathml(&mathml)?;
+    let layout = Layout::new()?;
+    let style = Style { level: 0, display };
+    let math_box = layout.row(&root.children, style);
+    Ok(layout.to_svg(&math_box, title))
+}
+
+static FACE: OnceLock<Option<Face<'static>>> = OnceLock::new();
+
+fn math_face() -> Option<&'static Face<'static>> {
+    FACE.get_or_init(|| bundled_face(FONT_NAME)).as_ref()
+}
+
+/// Finds one of the fonts bundled with typst-assets by its PostScript name.
+pub(crate) fn bundled_face(postscript_name: &str) -> Option<Face<'static>> {
+    typst_assets::fonts()
+        .filter_map(|data| Face::parse(data, 0).ok())
+        .find(|face| {
+            face.names()
+                .into_iter()
+                .filter(|name| name.name_id == ttf_parser::name_id::POST_SCRIPT_NAME)
+                .any(|name| name.to_string().as_deref() == Some(postscript_name))
+        })
+}
+
+#[derive(Clone, Copy)]
+struct Style {
+    /// 0 for text size, 1 for scripts, 2 for scripts of scripts.
+    level: u8,
+    display: bool,
+}
+
+impl Style {
+    fn script(self) -> Style {
+        Style {
+            level: (self.level + 1).min(2),
+            display: false,
+        }
+    }
+
+    /// Style of a fraction's numerator and denominator.
+    fn fraction(self) -> Style {
+        if self.display {
+            Style { level: self.level, display: false }
+        } else {
+            self.script()
+        }
+    }
+}
+
+enum Item {
+    Glyph { glyph: GlyphId, x: f32, y: f32, scale: f32 },
+    Rule { x: f32, y: f32, width: f32, height: f32 },
+}
+
+#[derive(Default)]
+struct MathBox {
+    width: f32,
+    ascent: f32,
+    descent: f32,
+    /// Italic correction of a single slanted glyph, added before a superscript.
+    italic: f32,
+    items: Vec<Item>,
+}
+
+impl MathBox {
+    /// Adds `other` with its origin at (`x`, `y`), growing the box vertically
+    /// to fit. The width is left to the caller.
+    fn place(&mut self, other: MathBox, x: f32, y: f32) {
+        self.ascent = self.ascent.max(other.ascent + y);
+        self.descent = self.descent.max(other.descent - y);
+        for item in other.items {
+            self.items.push(match item {
+                Item::Glyph { glyph, x: gx, y: gy, scale } => Item::Glyph { glyph, x: gx + x, y: gy + y, scale },
+                Item::Rule { x: rx, y: ry, width, height } => Item::Rule { x: rx + x, y: ry + y, width, height },
+            });
+        }
+    }
+
+    fn rule(&mut self, x: f32, y: f32, width: f32, height: f32) {
+        self.ascent = self.ascent.max(y + height);
+        self.descent = self.descent.max(-y);
+        self.items.push(Item::Rule { x, y, width, height });
+    }
+
+    fn shift(self, dy: f32) -> MathBox {
+        let mut shifted = MathBox {
+            width: self.width,
+            italic: self.italic,
+            ..MathBox::default()
+        };
+        shifted.place(self, 0.0, dy);
+        shifted
+    }
+}
+
+/// TeX's atom classes, which decide the space between neighbours in a row.
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Ord,
+    Op,
+    Bin,
+    Rel,
+    Open,
+    Close,
+    Punct,
+}
+
+const RELATIONS: &str = "=<>≤≥≠≈≡∼≃≅∝∈∉∋⊂⊃⊆⊇→←↔⇒⇐⇔↦⟶⟵⟷⇌≪≫∣∥⊥:≔";
+const BINARIES: &str = "+-−±∓×÷·⋅∗∘∪∩∧∨⊕⊗⊙∖";
+const LARGE_OPERATORS: &str = "∑∏∐∫∬∭∮⋃⋂⋁⋀⨁⨂⨀";
+/// Operator names that take their limits as scripts outside display style.
+const LIMIT_NAMES: &[&str] = &["lim", "liminf", "limsup", "max", "min", "sup", "inf", "det", "gcd", "Pr"];
+
+struct Layout {
+    face: &'static Face<'static>,
+    constants: Constants<'static>,
+    math: ttf_parser::math::Table<'static>,
+    em: f32,
+}
+
+impl Layout {
+    fn new() -> Result<Self> {
+        let face = math_face().ok_or_else(|| anyhow::anyhow!("Math font {} is not available", FONT_NAME))?;
+        let math = face.tables().math.ok_or_else(|| anyhow::anyhow!("{} has no MATH table", FONT_NAME))?;
+        let constants = math.constants.ok_or_else(|| anyhow::anyhow!("{} has no MATH constants", FONT_NAME))?;
+        Ok(Self {
+            face,
+            constants,
+            math,
+            em: face.units_per_em() as f32,
+        })
+    }
+
+    fn scale(&self, style: Style) -> f32 {
+        match style.level {
+            0 => 1.0,
+            1 => self.constants.script_percent_scale_down() as f32 / 100.0,
+            _ => self.constants.script_script_percent_scale_down() as f32 / 100.0,
+        }
+    }
+
+    fn axis(&self, style: Style) -> f32 {
+        self.constants.axis_height().value as f32 * self.scale(style)
+    }
+
+    fn glyph(&self, c: char) -> GlyphId {
+        self.face.glyph_index(c).unwrap_or(GlyphId(0))
+    }
+
+    fn glyph_box(&self, glyph: GlyphId, scale: f32) -> MathBox {
+        let (ascent, descent) = match self.face.glyph_bounding_box(glyph) {
+            Some(bbox) => (bbox.y_max as f32 * scale, -bbox.y_min as f32 * scale),
+            None => (0.0, 0.0),
+        };
+        let italic = self
+            .math
+            .glyph_info
+            .and_then(|info| info.italic_corrections)
+            .and_then(|corrections| corrections.get(glyph))
+            .map_or(0.0, |value| value.value as f32 * scale);
+        MathBox {
+            width: self.face.glyph_hor_advance(glyph).unwrap_or(0) as f32 * scale,
+            ascent,
+            descent,
+            italic,
+            items: vec![Item::Glyph { glyph, x: 0.0, y: 0.0, scale }],
+        }
+    }
+
+    fn text(&self, text: &str, variant: &str, style: Style) -> MathBox {
+        let scale = self.scale(style);
+        let mut text_box = MathBox::default();
+        for c in text.chars() {
+            let glyph_box = self.glyph_box(self.glyph(styled_char(c, variant)), scale);
+            let x = text_box.width;
+            text_box.width += glyph_box.width;
+            text_box.italic = glyph_box.italic;
+            text_box.place(glyph_box, x, 0.0);
+        }
+        if text.chars().count() > 1 {
+            text_box.italic = 0.0;
+        }
+        text_box
+    }
+
+    fn node(&self, node: &MathNode, style: Style) -> MathBox {
+        let child = |i: usize| node.children.get(i);
+        match node.name.as_str() {
+            "mi" => {
+                let default = if node.text.chars().count() == 1 { "italic" } else { "normal" };
+                self.text(&node.text, node.attr("mathvariant").unwrap_or(default), style)
+            }
+            "mn" | "mtext" => self.text(&node.text, node.attr("mathvariant").unwrap_or("normal"), style),
+            "ms" => self.text(&format!("\"{}\"", node.text), "normal", style),
+            "mo" => self.operator(node, style),
+            "mspace" => MathBox {
+                width: node.attr("width").map_or(0.0, |w| self.length(w, style)),
+                ..MathBox::default()
+            },
+            "mfrac" => self.fraction(node, style),
+            "msqrt" => {
+                let content = self.row(&node.children, style);
+                self.radical(content, None, style)
+            }
+            "mroot" => {
+                let content = child(0).map_or_else(MathBox::default, |c| self.node(c, style));
+                let degree = child(1).map(|c| self.node(c, Style { level: 2, display: false }));
+                self.radical(content, degree, style)
+            }
+            // latex2mathml only writes msubsup for integrals, so `{X_a}^b`
+            // arrives as nested scripts; set them as one stacked pair.
+            "msub" if child(0).is_some_and(|b| b.name == "msup" && b.children.len() == 2) => {
+                let base = &node.children[0];
+                self.scripts(base.children.first(), child(1), base.children.get(1), style)
+            }
+            "msup" if child(0).is_some_and(|b| b.name == "msub" && b.children.len() == 2) => {
+                let base = &node.children[0];
+                self.scripts(base.children.first(), base.children.get(1), child(1), style)
+            }
+            "msub" => self.scripts(child(0), child(1), None, style),
+            "msup" => self.scripts(child(0), None, child(1), style),
+            "msubsup" => self.scripts(child(0), child(1), child(2), style),
+            "munder" => self.under_over(node, child(1), None, style),
+            "mover" => self.under_over(node, None, child(1), style),
+            "munderover" => self.under_over(node, child(1), child(2), style),
+            "mtable" => self.table(node, style),
+            "mphantom" => {
+                let mut phantom = self.row(&node.children, style);
+                phantom.items.clear();
+                phantom
+            }
+            "annotation" | "annotation-xml" => MathBox::default(),
+            "semantics" => child(0).map_or_else(MathBox::default, |c| self.node(c, style)),
+            // mrow, mstyle, mpadded and anything unrecognised
+            _ => self.row(&node.children, style),
+        }
+    }
+
+    /// Parses an `em` length such as latex2mathml's `mspace` widths.
+    fn length(&self, value: &str, style: Style) -> f32 {
+        value
+            .strip_suffix("em")
+            .and_then(|v| v.trim().parse::<f32>().ok())
+            .map_or(0.0, |ems| ems * self.em * self.scale(style))
+    }
+
+    fn operator(&self, node: &MathNode, style: Style) -> MathBox {
+        let text: String = node.text.chars().map(operator_char).collect();
+        let mut chars = text.chars();
+        if let (Some(c), None) = (chars.next(), chars.next())
+            && LARGE_OPERATORS.contains(c)
+        {
+            // Large operators are centred on the math axis, and grow in
+            // display style.
+            let scale = self.scale(style);
+            let glyph = self.glyph(c);
+            let mut op = self.glyph_box(glyph, scale);
+            if style.display {
+                let min_height = self.constants.display_operator_min_height() as f32 * scale;
+                if let Some(variant) = self.vertical_variants(glyph).find(|v| v.advance_measurement as f32 * scale >= min_height) {
+                    op = self.glyph_box(variant.variant_glyph, scale);
+                }
+            }
+            let shift = self.axis(style) - (op.ascent - op.descent) / 2.0;
+            return op.shift(shift);
+        }
+        self.text(&text, node.attr("mathvariant").unwrap_or("normal"), style)
+    }
+
+    fn vertical_variants(&self, glyph: GlyphId) -> impl Iterator<Item = ttf_parser::math::GlyphVariant> {
+        self.math
+            .variants
+            .and_then(|variants| variants.vertical_constructions.get(glyph))
+            .into_iter()
+            .flat_map(|construction| construction.variants)
+    }
+
+    /// A delimiter at least `height` tall: the first size variant that is
+    /// big enough, or else one assembled from the font's parts. The result
+    /// sits on the baseline.
+    fn tall_glyph(&self, c: char, height: f32, scale: f32) -> MathBox {
+        let glyph = self.glyph(c);
+        let Some(construction) = self
+            .math
+            .variants
+            .and_then(|variants| variants.vertical_constructions.get(glyph))
+        else {
+            return self.glyph_box(glyph, scale);
+        };
+        let mut largest = glyph;
+        for variant in construction.variants {
+            largest = variant.variant_glyph;
+            if variant.advance_measurement as f32 * scale >= height {
+                return self.glyph_box(variant.variant_glyph, scale);
+            }
+        }
+        let Some(assembly) = construction.assembly else {
+            return self.glyph_box(largest, scale);
+        };
+
+        let overlap = self.math.variants.map_or(0, |v| v.min_connector_overlap) as f32 * scale;
+        let parts: Vec<_> = assembly.parts.into_iter().collect();
+        let length = |repeats: usize| {
+            let mut total = 0.0;
+            let mut count = 0;
+            for part in &parts {
+                let n = if part.part_flags.extender() { repeats } else { 1 };
+                total += part.full_advance as f32 * scale * n as f32;
+                count += n;
+            }
+            total - overlap * count.saturating_sub(1) as f32
+        };
+        let repeats = (0..64).find(|&n| length(n) >= height).unwrap_or(64);
+
+        // Parts are listed bottom to top.
+        let mut assembled = MathBox::default();
+        let mut y = 0.0;
+        for part in &parts {
+            let n = if part.part_flags.extender() { repeats } else { 1 };
+            for _ in 0..n {
+                let part_box = self.glyph_box(part.glyph_id, scale);
+                let bottom = self.face.glyph_bounding_box(part.glyph_id).map_or(0.0, |b| b.y_min as f32 * scale);
+                assembled.width = assembled.width.max(part_box.width);
+                assembled.place(part_box, 0.0, y - bottom);
+                y += part.full_advance as f32 * scale - overlap;
+            }
+        }
+        assembled
+    }
+
+    /// Lays out a row, stretching `stretchy` fences to the height of the
+    /// rest of it, with TeX's spacing between operators.
+    fn row(&self, children: &[MathNode], style: Style) -> MathBox {
+        let children: Vec<&MathNode> = children
+            .iter()
+            .filter(|c| !matches!(c.name.as_str(), "annotation" | "annotation-xml"))
+            .collect();
+        let is_fence = |node: &MathNode| node.name == "mo" && node.attr("stretchy") == Some("true");
+
+        let mut boxes: Vec<Option<MathBox>> = children
+            .iter()
+            .map(|c| if is_fence(c) { None } else { Some(self.node(c, style)) })
+            .collect();
+
+        let axis = self.axis(style);
+        let scale = self.scale(style);
+        let extent = boxes
+            .iter()
+            .flatten()
+            .map(|b| (b.ascent - axis).max(b.descent + axis))
+            .fold(0.0f32, f32::max);
+        for (i, child) in children.iter().enumerate() {
+            if boxes[i].is_none() {
+                // TeX's \delimiterfactor: cover at least 90% of the contents.
+                let height = (2.0 * extent * 0.901).max(self.em * scale);
+                let c = child.text.chars().next().map_or('.', operator_char);
+                let fence = if c == '.' { MathBox::default() } else { self.tall_glyph(c, height, scale) };
+                let shift = axis - (fence.ascent - fence.descent) / 2.0;
+                boxes[i] = Some(fence.shift(shift));
+            }
+        }
+
+        let mut classes: Vec<Class> = children.iter().map(|c| class_of(c)).collect();
+        // A binary operator with nothing on one side is a sign.
+        for i in 0..classes.len() {
+            if classes[i] == Class::Bin {
+                let after_operand = i > 0 && matches!(classes[i - 1], Class::Ord | Class::Close);
+                let before_operand = i + 1 < classes.len() && !matches!(classes[i + 1], Class::Rel | Class::Close | Class::Punct);
+                if !after_operand || !before_operand {
+                    classes[i] = Class::Ord;
+                }
+            }
+        }
+
+        let thin = self.em * 3.0 / 18.0 * scale;
+        let medium = self.em * 4.0 / 18.0 * scale;
+        let thick = self.em * 5.0 / 18.0 * scale;
+        let mut row = MathBox::default();
+        let mut previous: Option<Class> = None;
+        for (math_box, class) in boxes.into_iter().flatten().zip(classes) {
+            if let Some(previous) = previous {
+                let space = match (previous, class) {
+                    (Class::Rel, Class::Rel) => 0.0,
+                    (Class::Rel, _) | (_, Class::Rel) if class != Class::Punct && previous != Class::Open && class != Class::Close => thick,
+                    (Class::Bin, _) | (_, Class::Bin) => medium,
+                    (Class::Punct, _) if class != Class::Close => thin,
+                    (Class::Op, Class::Ord | Class::Op) | (Class::Ord | Class::Close, Class::Op) => thin,
+                    _ => 0.0,
+                };
+                // Only thin spaces survive in script styles.
+                row.width += if style.level > 0 && space != thin { 0.0 } else { space };
+            }
+            previous = Some(class);
+            let x = row.width;
+            row.width += math_box.width;
+            row.italic = math_box.italic;
+            row.place(math_box, x, 0.0);
+        }
+        if children.len() > 1 {
+            row.italic = 0.0;
+        }
+        row
+    }
+
+    fn fraction(&self, node: &MathNode, style: Style) -> MathBox {
+        let c = &self.constants;
+        let scale = self.scale(style);
+        let inner = style.fraction();
+        let num = node.children.first().map_or_else(MathBox::default, |n| self.node(n, inner));
+        let den = node.children.get(1).map_or_else(MathBox::default, |n| self.node(n, inner));
+
+        let axis = self.axis(style);
+        let thickness = match node.attr("linethickness") {
+            Some("0") | Some("0px") | Some("0em") => 0.0,
+            _ => c.fraction_rule_thickness().value as f32 * scale,
+        };
+        let (num_shift, num_gap, den_shift, den_gap) = if style.display {
+            (
+                c.fraction_numerator_display_style_shift_up().value,
+                c.fraction_num_display_style_gap_min().value,
+                c.fraction_denominator_display_style_shift_down().value,
+                c.fraction_denom_display_style_gap_min().value,
+            )
+        } else {
+            (
+                c.fraction_numerator_shift_up().value,
+                c.fraction_numerator_gap_min().value,
+                c.fraction_denominator_shift_down().value,
+                c.fraction_denominator_gap_min().value,
+            )
+        };
+        let num_shift = (num_shift as f32 * scale).max(axis + thickness / 2.0 + num_gap as f32 * scale + num.descent);
+        let den_shift = (den_shift as f32 * scale).max(den.ascent + den_gap as f32 * scale - axis + thickness / 2.0);
+
+        // TeX's \nulldelimiterspace on each side keeps the rule clear of
+        // its neighbours.
+        let padding = self.em * 0.12 * scale;
+        let inner_width = num.width.max(den.width);
+        let mut fraction = MathBox {
+            width: inner_width + 2.0 * padding,
+            ..MathBox::default()
+        };
+        let (num_width, den_width) = (num.width, den.width);
+        fraction.place(num, padding + (inner_width - num_width) / 2.0, num_shift);
+        fraction.place(den, padding + (inner_width - den_width) / 2.0, -den_shift);
+        if thickness > 0.0 {
+            fraction.rule(padding, axis - thickness / 2.0, inner_width, thickness);
+        }
+        fraction
+    }
+
+    fn radical(&self, content: MathBox, degree: Option<MathBox>, style: Style) -> MathBox {
+        let c = &self.constants;
+        let scale = self.scale(style);
+        let thickness = c.radical_rule_thickness().value as f32 * scale;
+        let mut gap = if style.display {
+            c.radical_display_style_vertical_gap().value
+        } else {
+            c.radical_vertical_gap().value
+        } as f32
+            * scale;
+        let extra = c.radical_extra_ascender().value as f32 * scale;
+
+        let target = content.ascent + content.descent + gap + thickness;
+        let sign = self.tall_glyph('√', target, scale);
+        let sign_height = sign.ascent + sign.descent;
+        // Spare height from a larger sign goes to the gap.
+        if sign_height > target {
+            gap += (sign_height - target) / 2.0;
+        }
+        let top = content.ascent + gap + thickness;
+
+        let mut radical = MathBox::default();
+        let mut x = 0.0;
+        let sign_shift = top - sign.ascent;
+        if let Some(degree) = degree {
+            let kern_before = c.radical_kern_before_degree().value as f32 * scale;
+            let kern_after = c.radical_kern_after_degree().value as f32 * scale;
+            let raise = c.radical_degree_bottom_raise_percent() as f32 / 100.0 * sign_height;
+            let degree_width = degree.width;
+            radical.place(degree, kern_before, sign_shift - sign.descent + raise);
+            x = (kern_before + degree_width + kern_after).max(0.0);
+        }
+        let sign_width = sign.width;
+        radical.place(sign, x, sign_shift);
+        x += sign_width;
+        let content_width = content.width;
+        radical.place(content, x, 0.0);
+        radical.rule(x, top - thickness, content_width, thickness);
+        radical.ascent += extra;
+        radical.width = x + content_width;
+        radical
+    }
+
+    fn scripts(&self, base: Option<&MathNode>, sub: Option<&MathNode>, sup: Option<&MathNode>, style: Style) -> MathBox {
+        let base_box = base.map_or_else(MathBox::default, |b| self.node(b, style));
+        let is_char = base.is_some_and(|b| matches!(b.name.as_str(), "mi" | "mn" | "mo") && b.text.chars().count() == 1);
+        let is_large = base.is_some_and(is_large_operator);
+        let sub_box = sub.map(|s| self.node(s, style.script()));
+        let sup_box = sup.map(|s| self.node(s, style.script()));
+        self.attach_scripts(base_box, sub_box, sup_box, is_char && !is_large, is_large, style)
+    }
+
+    fn attach_scripts(
+        &self,
+        base: MathBox,
+        sub: Option<MathBox>,
+        sup: Option<MathBox>,
+        is_char: bool,
+        is_large: bool,
+        style: Style,
+    ) -> MathBox {
+        let c = &self.constants;
+        let s = self.scale(style);
+        let script_scale = self.scale(style.script());
+
+        let mut sup_shift = sup.as_ref().map(|sup| {
+            let mut shift = c.superscript_shift_up().value as f32 * s;
+            if !is_char {
+                shift = shift.max(base.ascent - c.superscript_baseline_drop_max().value as f32 * script_scale);
+            }
+            shift.max(sup.descent + c.superscript_bottom_min().value as f32 * s)
+        });
+        let mut sub_shift = sub.as_ref().map(|sub| {
+            let mut shift = c.subscript_shift_down().value as f32 * s;
+            if !is_char {
+                shift = shift.max(base.descent + c.subscript_baseline_drop_min().value as f32 * script_scale);
+            }
+            shift.max(sub.ascent - c.subscript_top_max().value as f32 * s)
+        });
+        if let (Some(sup), Some(sub), Some(up), Some(down)) = (&sup, &sub, sup_shift.as_mut(), sub_shift.as_mut()) {
+            let gap = (*up - sup.descent) - (sub.ascent - *down);
+            let min_gap = c.sub_superscript_gap_min().value as f32 * s;
+            if gap < min_gap {
+                *down += min_gap - gap;
+                // Move the superscript up if that keeps its bottom low enough.
+                let max_bottom = c.superscript_bottom_max_with_subscript().value as f32 * s;
+                let lift = (max_bottom - (*up - sup.descent)).clamp(0.0, min_gap - gap);
+                *up += lift;
+                *down -= lift;
+            }
+        }
+
+        let italic = base.italic;
+        let base_width = base.width;
+        let mut result = MathBox {
+            width: base_width,
+            ..MathBox::default()
+        };
+        result.place(base, 0.0, 0.0);
+        let mut end = base_width;
+        if let (Some(sup), Some(shift)) = (sup, sup_shift) {
+            let x = base_width + if is_large { 0.0 } else { italic };
+            end = end.max(x + sup.width);
+            result.place(sup, x, shift);
+        }
+        if let (Some(sub), Some(shift)) = (sub, sub_shift) {
+            // Subscripts tuck under the slant of large operators.
+            let x = base_width - if is_large { italic } else { 0.0 };
+            end = end.max(x + sub.width);
+            result.place(sub, x, -shift);
+        }
+        result.width = end + c.space_after_script().value as f32 * s;
+        result
+    }
+
+    fn under_over(&self, node: &MathNode, under: Option<&MathNode>, over: Option<&MathNode>, style: Style) -> MathBox {
+        let Some(base) = node.children.first() else {
+            return MathBox::default();
+        };
+        let movable = is_large_operator(base) || (base.name == "mi" && LIMIT_NAMES.contains(&base.text.as_str()));
+        if movable && !style.display {
+            return self.scripts(Some(base), under, over, style);
+        }
+
+        let c = &self.constants;
+        let s = self.scale(style);
+        let base_box = self.node(base, style);
+        let mut stack = MathBox::default();
+        let mut width = base_box.width;
+        let mut pieces: Vec<(MathBox, f32)> = Vec::new();
+
+        if let Some(over) = over {
+            let accent = node.attr("accent") == Some("true") || over.attr("accent") == Some("true");
+            if accent {
+                pieces.push(self.accent(&over.text, &base_box, style, true));
+            } else {
+                let over_box = self.node(over, style.script());
+                let gap = (c.upper_limit_gap_min().value as f32 * s)
+                    .max(c.upper_limit_baseline_rise_min().value as f32 * s - over_box.descent);
+                let shift = base_box.ascent + gap + over_box.descent;
+                pieces.push((over_box, shift));
+            }
+        }
+        if let Some(under) = under {
+            let accent = node.attr("accentunder") == Some("true") || under.attr("accent") == Some("true");
+            if accent {
+                pieces.push(self.accent(&under.text, &base_box, style, false));
+            } else {
+                let under_box = self.node(under, style.script());
+                let gap = (c.lower_limit_gap_min().value as f32 * s)
+                    .max(c.lower_limit_baseline_drop_min().value as f32 * s - under_box.ascent);
+                let shift = base_box.descent + gap + under_box.ascent;
+                pieces.push((under_box, -shift));
+            }
+        }
+
+        for (piece, _) in &pieces {
+            width = width.max(piece.width);
+        }
+        let base_width = base_box.width;
+        stack.place(base_box, (width - base_width) / 2.0, 0.0);
+        for (piece, shift) in pieces {
+            let piece_width = piece.width;
+            stack.place(piece, (width - piece_width) / 2.0, shift);
+        }
+        stack.width = width;
+        stack
+    }
+
+    /// An accent over (or under) `base`, as a box the base's width and the
+    /// shift to place it at.
+    fn accent(&self, text: &str, base: &MathBox, style: Style, over: bool) -> (MathBox, f32) {
+        let c = &self.constants;
+        let s = self.scale(style);
+        let mark = text.chars().next().unwrap_or(' ');
+        if matches!(mark, '_' | '‾') {
+            // A bar is a rule the width of the base.
+            let thickness;
+            let mut bar = MathBox { width: base.width, ..MathBox::default() };
+            let shift = if over {
+                thickness = c.overbar_rule_thickness().value as f32 * s;
+                bar.rule(0.0, 0.0, base.width, thickness);
+                bar.ascent += c.overbar_extra_ascender().value as f32 * s;
+                base.ascent + c.overbar_vertical_gap().value as f32 * s
+            } else {
+                thickness = c.underbar_rule_thickness().value as f32 * s;
+                bar.rule(0.0, 0.0, base.width, thickness);
+                bar.descent += c.underbar_extra_descender().value as f32 * s;
+                -(base.descent + c.underbar_vertical_gap().value as f32 * s + thickness)
+            };
+            return (bar, shift);
+        }
+
+        // As in TeX, the widest variant that is no wider than the base.
+        let glyph = self.glyph(combining_accent(mark));
+        let glyph = self
+            .math
+            .variants
+            .and_then(|variants| variants.horizontal_constructions.get(glyph))
+            .and_then(|construction| {
+                construction
+                    .variants
+                    .into_iter()
+                    .take_while(|v| v.advance_measurement as f32 * s <= base.width)
+                    .last()
+            })
+            .map_or(glyph, |variant| variant.variant_glyph);
+
+        let mark_box = self.glyph_box(glyph, s);
+        let (ink_left, ink_width) = self
+            .face
+            .glyph_bounding_box(glyph)
+            .map_or((0.0, mark_box.width), |b| (b.x_min as f32 * s, (b.x_max - b.x_min) as f32 * s));
+        // Centre the ink, not the advance: combining marks have none.
+        let mut centred = MathBox { width: base.width, ..MathBox::default() };
+        centred.place(mark_box, (base.width - ink_width) / 2.0 - ink_left, 0.0);
+        centred.width = base.width.max(ink_width);
+        let shift = if over {
+            (base.ascent - c.accent_base_height().value as f32 * s).max(0.0)
+        } else {
+            -(base.descent + centred.ascent)
+        };
+        (centred, shift)
+    }
+
+    fn table(&self, node: &MathNode, style: Style) -> MathBox {
+        let scale = self.scale(style);
+        let strut_ascent = 0.7 * self.em * scale;
+        let strut_descent = 0.3 * self.em * scale;
+        let column_gap = self.em * scale;
+
+        let rows: Vec<Vec<MathBox>> = node
+            .children
+            .iter()
+            .map(|row| row.children.iter().map(|cell| self.row(&cell.children, style)).collect())
+            .collect();
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<f32> = (0..columns)
+            .map(|i| rows.iter().filter_map(|r| r.get(i)).map(|b| b.width).fold(0.0, f32::max))
+            .collect();
+        let alignments: Vec<&str> = node.attr("columnalign").map_or_else(Vec::new, |a| a.split_whitespace().collect());
+
+        let mut table = MathBox::default();
+        let mut y = 0.0;
+        for row in rows {
+            let ascent = row.iter().map(|b| b.ascent).fold(strut_ascent, f32::max);
+            let descent = row.iter().map(|b| b.descent).fold(strut_descent, f32::max);
+            y -= ascent;
+            let mut x = 0.0;
+            for (i, cell) in row.into_iter().enumerate() {
+                let align = alignments.get(i).or(alignments.last()).copied().unwrap_or("center");
+                let offset = match align {
+                    "left" => 0.0,
+                    "right" => widths[i] - cell.width,
+                    _ => (widths[i] - cell.width) / 2.0,
+                };
+                table.place(cell, x + offset, y);
+                x += widths[i] + column_gap;
+            }
+            y -= descent;
+        }
+        table.width = widths.iter().sum::<f32>() + column_gap * columns.saturating_sub(1) as f32;
+        // Centre the table on the math axis.
+        let height = -y;
+        table.ascent = 0.0;
+        table.descent = height;
+        let shift = self.axis(style) + height / 2.0;
+        table.shift(shift)
+    }
+
+    fn to_svg(&self, math_box: &MathBox, title: &str) -> MathSvg {
+        // The view box covers the ink as well as the layout box, since slanted
+        // glyphs overhang their advance.
+        let (mut left, mut right) = (0.0f32, math_box.width);
+        let (mut top, mut bottom) = (math_box.ascent, math_box.descent);
+        let mut paths: BTreeMap<u16, String> = BTreeMap::new();
+        let mut uses = String::new();
+
+        for item in &math_box.items {
+            match *item {
+                Item::Glyph { glyph, x, y, scale } => {
+                    let Some(bbox) = self.face.glyph_bounding_box(glyph) else {
+                        continue;
+                    };
+                    left = left.min(x + bbox.x_min as f32 * scale);
+                    right = right.max(x + bbox.x_max as f32 * scale);
+                    top = top.max(y + bbox.y_max as f32 * scale);
+                    bottom = bottom.max(-(y + bbox.y_min as f32 * scale));
+                    paths.entry(glyph.0).or_insert_with(|| {
+                        let mut path = PathData(String::new());
+                        self.face.outline_glyph(glyph, &mut path);
+                        path.0
+                    });
+                    let _ = write!(
+                        uses,
+                        "<use xlink:href=\"#g{}\" transform=\"translate({} {}) scale({} -{})\"/>",
+                        glyph.0,
+                        number(x),
+                        number(-y),
+                        number(scale),
+                        number(scale)
+                    );
+                }
+                Item::Rule { x, y, width, height } => {
+                    let _ = write!(
+                        uses,
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>",
+                        number(x),
+                        number(-(y + height)),
+                        number(width),
+                        number(height)
+                    );
+                }
+            }
+        }
+
+        let (width, height, depth) = ((right - left) / self.em, (top + bottom) / self.em, bottom / self.em);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" \
+             width=\"{}em\" height=\"{}em\" viewBox=\"{} {} {} {}\" role=\"img\"><title>{}</title><defs>",
+            number(width),
+            number(height),
+            number(left),
+            number(-top),
+            number(right - left),
+            number(top + bottom),
+            quick_xml::escape::escape(title)
+        );
+        for (id, d) in &paths {
+            let _ = write!(svg, "<path id=\"g{}\" d=\"{}\"/>", id, d);
+        }
+        svg.push_str("</defs>");
+        svg.push_str(&uses);
+        svg.push_str("</svg>\n");
+
+        MathSvg { svg, width, height, depth }
+    }
+}
+
+/// Collects a glyph outline as SVG path data.
+pub(crate) struct PathData(pub String);
+
+impl OutlineBuilder for PathData {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let _ = write!(self.0, "M{} {}", number(x), number(y));
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let _ = write!(self.0, "L{} {}", number(x), number(y));
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let _ = write!(self.0, "Q{} {} {} {}", number(x1), number(y1), number(x), number(y));
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let _ = write!(
+            self.0,
+            "C{} {} {} {} {} {}",
+            number(x1),
+            number(y1),
+            number(x2),
+            number(y2),
+            number(x),
+            number(y)
+        );
+    }
+
+    fn close(&mut self) {
+        self.0.push('Z');
+    }
+}
+
+/// Formats a length with at most three decimals and no trailing zeros.
+pub(crate) fn number(value: f32) -> String {
+    let rounded = (value * 1000.0).round() / 1000.0;
+    let text = format!("{:.3}", if rounded == 0.0 { 0.0 } else { rounded });
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn class_of(node: &MathNode) -> Class {
+    match node.name.as_str() {
+        "mo" => {
+            let c = node.text.chars().next().unwrap_or(' ');
+            match node.attr("form") {
+                Some("prefix") if node.attr("stretchy") == Some("true") => return Class::Open,
+                Some("postfix") if node.attr("stretchy") == Some("true") => return Class::Close,
+                _ => {}
+            }
+            if node.text.chars().count() != 1 || LARGE_OPERATORS.contains(c) {
+                Class::Op
+            } else if RELATIONS.contains(c) {
+                Class::Rel
+            } else if BINARIES.contains(c) {
+                Class::Bin
+            } else if "([{⟨⌈⌊".contains(c) {
+                Class::Open
+            } else if ")]}⟩⌉⌋".contains(c) {
+                Class::Close
+            } else if ",;".contains(c) {
+                Class::Punct
+            } else {
+                Class::Ord
+            }
+        }
+        "mi" if node.text.chars().count() > 1 => Class::Op,
+        "msub" | "msup" | "msubsup" | "munder" | "mover" | "munderover" => {
+            node.children.first().map_or(Class::Ord, |base| match class_of(base) {
+                Class::Op => Class::Op,
+                _ => Class::Ord,
+            })
+        }
+        _ => Class::Ord,
+    }
+}
+
+fn is_large_operator(node: &MathNode) -> bool {
+    node.name == "mo" && node.text.chars().count() == 1 && node.text.chars().all(|c| LARGE_OPERATORS.contains(c))
+}
+
+/// The character the font draws for an operator, where the MathML text is
+/// an ASCII stand-in.
+fn operator_char(c: char) -> char {
+    match c {
+        '-' => '−',
+        '*' => '∗',
+        '\'' => '′',
+        c => c,
+    }
+}
+
+/// The combining mark the font positions over a base for an accent.
+fn combining_accent(c: char) -> char {
+    match c {
+        '^' | 'ˆ' => '\u{302}',
+        '~' | '˜' => '\u{303}',
+        '¯' => '\u{304}',
+        '→' | '⃗' => '\u{20D7}',
+        '˙' | '.' => '\u{307}',
+        '¨' => '\u{308}',
+        'ˇ' => '\u{30C}',
+        '˘' => '\u{306}',
+        '´' => '\u{301}',
+        '`' => '\u{300}',
+        c => c,
+    }
+}
+
+/// Maps a character to its Mathematical Alphanumeric Symbols form for a
+/// `mathvariant`, leaving it unchanged where Unicode has no such form.
+fn styled_char(c: char, variant: &str) -> char {
+    // Letters that predate the block live in Letterlike Symbols.
+    let exception = match (variant, c) {
+        ("italic", 'h') => Some('ℎ'),
+        ("double-struck", 'C') => Some('ℂ'),
+        ("double-struck", 'H') => Some('ℍ'),
+        ("double-struck", 'N') => Some('ℕ'),
+        ("double-struck", 'P') => Some('ℙ'),
+        ("double-struck", 'Q') => Some('ℚ'),
+        ("double-struck", 'R') => Some('ℝ'),
+        ("double-struck", 'Z') => Some('ℤ'),
+        ("script", 'B') => Some('ℬ'),
+        ("script", 'E') => Some('ℰ'),
+        ("script", 'F') => Some('ℱ'),
+        ("script", 'H') => Some('ℋ'),
+        ("script", 'I') => Some('ℐ'),
+        ("script", 'L') => Some('ℒ'),
+        ("script", 'M') => Some('ℳ'),
+        ("script", 'R') => Some('ℛ'),
+        ("fraktur", 'C') => Some('ℭ'),
+        ("fraktur", 'H') => Some('ℌ'),
+        ("fraktur", 'I') => Some('ℑ'),
+        ("fraktur", 'R') => Some('ℜ'),
+        ("fraktur", 'Z') => Some('ℨ'),
+        _ => None,
+    };
+    if let Some(exception) = exception {
+        return exception;
+    }
+
+    // First code points of the capital, small and digit runs.
+    let (upper, lower, digit) = match variant {
+        "bold" => (0x1D400, 0x1D41A, Some(0x1D7CE)),
+        "italic" => (0x1D434, 0x1D44E, None),
+        "bold-italic" => (0x1D468, 0x1D482, None),
+        "script" => (0x1D49C, 0x1D4B6, None),
+        "fraktur" => (0x1D504, 0x1D51E, None),
+        "double-struck" => (0x1D538, 0x1D552, Some(0x1D7D8)),
+        "sans-serif" => (0x1D5A0, 0x1D5BA, Some(0x1D7E2)),
+        "monospace" => (0x1D670, 0x1D68A, Some(0x1D7F6)),
+        _ => return c,
+    };
+    let mapped = match c {
+        'A'..='Z' => upper + (c as u32 - 'A' as u32),
+        'a'..='z' => lower + (c as u32 - 'a' as u32),
+        '0'..='9' => match digit {
+            Some(digit) => digit + (c as u32 - '0' as u32),
+            None => return c,
+        },
+        // Lowercase Greek is slanted in math; capitals stay upright as in TeX.
+        'α'..='ω' if variant == "italic" => 0x1D6FC + (c as u32 - 'α' as u32),
+        _ => return c,
+    };
+    char::from_u32(mapped).unwrap_or(c)
+}
@@ -0,0 +1,389 @@
+use anyhow::{bail, Result};
+use crate::qti::diagram::{Direction, Edge, Graph, LineStyle, Shape};
+use regex::Regex;
+use std::sync::LazyLock;
+
+// Reads Mermaid flowcharts (`graph`/`flowchart`) and state diagrams
+// (`stateDiagram`/`stateDiagram-v2`) into a `Graph`. Subgraphs and composite
+// states are flattened, and styling (`classDef`, `style`, `click`, ...) is
+// ignored, since every diagram is drawn in black on white.
+
+/// `A --> B`, `A --- B`, `A -.-> B`, `A ==> B`, `A --o B`, `A <--> B`, ...
+static LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(<|o|x)?(-\.+-|-{2,}|={2,}|~{3,})(>|o|x)?").unwrap());
+/// `A -- text --> B`, `A -. text .-> B`, `A == text ==> B`
+static TEXT_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(<)?(--|-\.|==)\s+([^-=.][^\n]*?)\s*(-{2,}>|-{3,}|\.-+>|\.-+|={2,}>|={3,})").unwrap());
+static NODE_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[\p{L}\p{N}_]+").unwrap());
+
+/// Opening and closing brackets of each flowchart shape, longest first so
+/// `((` is not read as `(`.
+const SHAPES: &[(&str, &str, Shape)] = &[
+    ("(((", ")))", Shape::DoubleCircle),
+    ("((", "))", Shape::Circle),
+    ("([", "])", Shape::Rounded),
+    ("[[", "]]", Shape::Box),
+    ("[(", ")]", Shape::Box),
+    ("[/", "/]", Shape::Box),
+    ("[/", "\\]", Shape::Box),
+    ("[\\", "\\]", Shape::Box),
+    ("[\\", "/]", Shape::Box),
+    ("{{", "}}", Shape::Hexagon),
+    ("[", "]", Shape::Box),
+    ("(", ")", Shape::Rounded),
+    ("{", "}", Shape::Diamond),
+    (">", "]", Shape::Box),
+];
+
+pub(crate) fn parse_mermaid(source: &str) -> Result<Graph> {
+    let mut lines = source
+        .lines()
+        .map(|line| line.split("%%").next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty());
+    let header = lines.next().unwrap_or("");
+    let mut words = header.split_whitespace();
+    let kind = words.next().unwrap_or("");
+    let mut graph = Graph::default();
+    match kind {
+        "graph" | "flowchart" => {
+            graph.direction = direction(words.next().unwrap_or("TB").trim_end_matches(';'));
+            let mut parser = Flowchart { graph };
+            for line in lines {
+                for statement in split_statements(line) {
+                    parser.statement(statement.trim())?;
+                }
+            }
+            Ok(parser.graph)
+        }
+        "stateDiagram" | "stateDiagram-v2" => {
+            let mut parser = StateDiagram {
+                graph,
+                depth: 0,
+                in_note: false,
+            };
+            for line in lines {
+                parser.statement(line)?;
+            }
+            Ok(parser.graph)
+        }
+        "" => bail!("The Mermaid diagram is empty"),
+        _ => bail!("Mermaid `{}` diagrams are not supported; use a flowchart or stateDiagram", kind),
+    }
+}
+
+fn direction(word: &str) -> Direction {
+    match word {
+        "LR" => Direction::LeftRight,
+        "RL" => Direction::RightLeft,
+        "BT" => Direction::BottomTop,
+        _ => Direction::TopBottom,
+    }
+}
+
+/// Splits a line at `;` outside quotes and brackets.
+fn split_statements(line: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let (mut depth, mut quoted, mut start) = (0i32, false, 0);
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '[' | '(' | '{' if !quoted => depth += 1,
+            ']' | ')' | '}' if !quoted => depth -= 1,
+            ';' if !quoted && depth <= 0 => {
+                statements.push(&line[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&line[start..]);
+    statements
+}
+
+/// Mermaid label markup reduced to plain text: quotes dropped and `<br>` as
+/// a line break.
+fn label_text(text: &str) -> String {
+    let text = text.trim();
+    let text = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(text);
+    let text = text.strip_prefix('`').and_then(|t| t.strip_suffix('`')).unwrap_or(text);
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        match rest[open..].find('>') {
+            Some(close) => {
+                if rest[open + 1..].to_lowercase().starts_with("br") {
+                    out.push('\n');
+                }
+                rest = &rest[open + close + 1..];
+            }
+            None => {
+                out.push('<');
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out.replace("#quot;", "\"").replace("#35;", "#").replace("**", "")
+}
+
+struct Flowchart {
+    graph: Graph,
+}
+
+impl Flowchart {
+    fn statement(&mut self, statement: &str) -> Result<()> {
+        let first = statement.split_whitespace().next().unwrap_or("");
+        if statement.is_empty()
+            || matches!(first, "subgraph" | "end" | "direction" | "classDef" | "class" | "style" | "linkStyle" | "click" | "accTitle" | "accDescr")
+            || first.starts_with("accTitle:")
+            || first.starts_with("accDescr")
+        {
+            return Ok(());
+        }
+
+        let mut rest = statement;
+        let mut previous = self.node_group(&mut rest)?;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                return Ok(());
+            }
+            let Some((style, head, tail, mut label)) = link(&mut rest) else {
+                bail!("Could not read `{}` in the Mermaid flowchart", rest);
+            };
+            rest = rest.trim_start();
+            if let Some(piped) = rest.strip_prefix('|') {
+                let Some(close) = piped.find('|') else {
+                    bail!("Unclosed `|` label in the Mermaid flowchart");
+                };
+                label = label_text(&piped[..close]);
+                rest = &piped[close + 1..];
+            }
+            let next = self.node_group(&mut rest)?;
+            if let Some(style) = style {
+                for &from in &previous {
+                    for &to in &next {
+                        self.graph.edges.push(Edge {
+                            from,
+                            to,
+                            label: label.clone(),
+                            style,
+                            head,
+                            tail,
+                        });
+                    }
+                }
+            }
+            previous = next;
+        }
+    }
+
+    /// `A & B & C`, each with an optional shape and label.
+    fn node_group(&mut self, rest: &mut &str) -> Result<Vec<usize>> {
+        let mut nodes = vec![self.node(rest)?];
+        loop {
+            let trimmed = rest.trim_start();
+            let Some(after) = trimmed.strip_prefix('&') else {
+                return Ok(nodes);
+            };
+            *rest = after.trim_start();
+            nodes.push(self.node(rest)?);
+        }
+    }
+
+    fn node(&mut self, rest: &mut &str) -> Result<usize> {
+        *rest = rest.trim_start();
+        let Some(id) = NODE_ID.find(rest) else {
+            bail!("Expected a node at `{}` in the Mermaid flowchart", rest);
+        };
+        let id = id.as_str();
+        *rest = &rest[id.len()..];
+        let index = self.graph.node(id, Shape::Box);
+
+        for &(open, close, shape) in SHAPES {
+            let Some(inner) = rest.strip_prefix(open) else {
+                continue;
+            };
+            // A quoted label may contain the closing bracket.
+            let end = if inner.trim_start().starts_with('"') {
+                let quote = inner.find('"').unwrap();
+                inner[quote + 1..].find('"').and_then(|q| inner[quote + q + 2..].find(close).map(|c| quote + q + 2 + c))
+            } else {
+                inner.find(close)
+            };
+            let Some(end) = end else {
+                continue;
+            };
+            let node = &mut self.graph.nodes[index];
+            node.shape = shape;
+            node.label = label_text(&inner[..end]);
+            *rest = &inner[end + close.len()..];
+            break;
+        }
+        // `A:::className`
+        if let Some(after) = rest.strip_prefix(":::") {
+            let class_len = after.find(|c: char| c.is_whitespace() || c == '&').unwrap_or(after.len());
+            *rest = &after[class_len..];
+        }
+        Ok(index)
+    }
+}
+
+/// Reads a link, returning its style (none for an invisible `~~~` link),
+/// whether it has arrowheads at its head and tail, and any inline label.
+fn link(rest: &mut &str) -> Option<(Option<LineStyle>, bool, bool, String)> {
+    let (captures, label) = if let Some(captures) = TEXT_LINK.captures(rest) {
+        let label = label_text(&captures[3]);
+        (captures, label)
+    } else {
+        (LINK.captures(rest)?, String::new())
+    };
+    let whole = captures.get(0).unwrap().as_str();
+    let line = captures.get(2).map_or("", |m| m.as_str());
+    let end = captures.get(captures.len() - 1).map_or("", |m| m.as_str());
+    // `o` and `x` ends are circles and crosses, only if not the start of
+    // the next node's id.
+    let after = &rest[whole.len()..];
+    if matches!(end, "o" | "x") && after.chars().next().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    let head = end.ends_with('>') || matches!(end, "o" | "x");
+    let tail = captures.get(1).is_some();
+    let style = if line.starts_with('~') {
+        None
+    } else if whole.contains('.') {
+        Some(LineStyle::Dashed)
+    } else if whole.contains('=') {
+        Some(LineStyle::Bold)
+    } else {
+        Some(LineStyle::Solid)
+    };
+    *rest = after;
+    Some((style, head, tail, label))
+}
+
+struct StateDiagram {
+    graph: Graph,
+    /// Nesting of composite states, which are flattened.
+    depth: usize,
+    in_note: bool,
+}
+
+impl StateDiagram {
+    fn statement(&mut self, line: &str) -> Result<()> {
+        if self.in_note {
+            self.in_note = !line.starts_with("end note");
+            return Ok(());
+        }
+        let first = line.split_whitespace().next().unwrap_or("");
+        match first {
+            "note" => {
+                // Only `note left of A : text` fits on one line.
+                self.in_note = !line.contains(':');
+                return Ok(());
+            }
+            "direction" => {
+                if self.depth == 0 {
+                    self.graph.direction = direction(line.split_whitespace().nth(1).unwrap_or("TB"));
+                }
+                return Ok(());
+            }
+            "}" => {
+                self.depth = self.depth.saturating_sub(1);
+                return Ok(());
+            }
+            "--" | "classDef" | "class" | "style" | "scale" | "hide" | "accTitle:" | "accDescr:" => return Ok(()),
+            _ => {}
+        }
+
+        if let Some(declaration) = line.strip_prefix("state ") {
+            return self.state(declaration.trim());
+        }
+
+        if let Some((from, rest)) = line.split_once("-->") {
+            let (to, label) = match rest.split_once(':') {
+                Some((to, label)) => (to, label_text(label)),
+                None => (rest, String::new()),
+            };
+            let from = self.state_node(from.trim(), true);
+            let to = self.state_node(to.trim(), false);
+            self.graph.edges.push(Edge {
+                from,
+                to,
+                label,
+                style: LineStyle::Solid,
+                head: true,
+                tail: false,
+            });
+            return Ok(());
+        }
+
+        // `A : description` adds a line to the state's label.
+        if let Some((id, description)) = line.split_once(':') {
+            let index = self.state_node(id.trim(), false);
+            let node = &mut self.graph.nodes[index];
+            let description = label_text(description);
+            if node.label == node.id {
+                node.label = description;
+            } else {
+                node.label = format!("{}\n{}", node.label, description);
+            }
+            return Ok(());
+        }
+
+        let id = line.trim_end_matches(';');
+        if NODE_ID.find(id).is_some_and(|m| m.as_str() == id) {
+            self.state_node(id, false);
+            return Ok(());
+        }
+        bail!("Could not read `{}` in the Mermaid state diagram", line)
+    }
+
+    /// `state "Long name" as A`, `state A {`, `state A <<choice>>`
+    fn state(&mut self, declaration: &str) -> Result<()> {
+        let mut declaration = declaration.trim_end_matches(';').trim();
+        if let Some(inner) = declaration.strip_suffix('{') {
+            self.depth += 1;
+            declaration = inner.trim();
+        }
+        if let Some(quoted) = declaration.strip_prefix('"') {
+            let Some((label, rest)) = quoted.split_once('"') else {
+                bail!("Unclosed quote in `state {}`", declaration);
+            };
+            let id = rest.trim().strip_prefix("as").map(str::trim).unwrap_or(label);
+            let index = self.state_node(id, false);
+            self.graph.nodes[index].label = label_text(label);
+            return Ok(());
+        }
+        let (id, stereotype) = match declaration.split_once("<<") {
+            Some((id, stereotype)) => (id.trim(), stereotype.trim_end_matches(">>").trim()),
+            None => (declaration, ""),
+        };
+        let index = self.state_node(id, false);
+        let node = &mut self.graph.nodes[index];
+        match stereotype {
+            "choice" => {
+                node.shape = Shape::Diamond;
+                node.label.clear();
+            }
+            "fork" | "join" => {
+                node.shape = Shape::Box;
+                node.label.clear();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The state `id`, where `[*]` is the start state as a source and the
+    /// end state as a target.
+    fn state_node(&mut self, id: &str, source: bool) -> usize {
+        if id == "[*]" {
+            let (id, shape) = if source { ("[*] start", Shape::Start) } else { ("[*] end", Shape::End) };
+            let index = self.graph.node(id, shape);
+            self.graph.nodes[index].label.clear();
+            return index;
+        }
+        self.graph.node(id, Shape::Rounded)
+    }
+}
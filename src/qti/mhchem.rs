@@ -0,0 +1,657 @@
+// Translates mhchem's `\ce{...}` notation into plain LaTeX that
+// latex2mathml can convert, following the mhchem manual: stoichiometric
+// coefficients, formulas with subscripts and charges, isotopes, states of
+// aggregation, bonds, addition compounds, reaction arrows with conditions,
+// and precipitate and gas marks.
+//
+// latex2mathml loses `\mathrm` on a base that carries a script and nests a
+// superscript that follows a subscript inside it, so every element is
+// written as its own braced `{\mathrm{..}}` group and a charge after a
+// subscript wraps the subscripted atom: `{{\mathrm{O}}_{4}}^{2-}`.
+
+/// Arrows, longest first so `<-->` is not read as `<-`. Each has the form
+/// used on its own and the longer one drawn under conditions.
+const ARROWS: &[(&str, &str, &str)] = &[
+    ("<-->", "\\rightleftarrows", "\\rightleftarrows"),
+    ("<=>>", "\\rightleftharpoons", "\\rightleftharpoons"),
+    ("<<=>", "\\leftrightharpoons", "\\leftrightharpoons"),
+    ("<=>", "\\rightleftharpoons", "\\rightleftharpoons"),
+    ("<->", "\\leftrightarrow", "\\longleftrightarrow"),
+    ("->", "\\rightarrow", "\\longrightarrow"),
+    ("<-", "\\leftarrow", "\\longleftarrow"),
+];
+
+/// States of aggregation written in parentheses after a formula.
+const STATES: &[&str] = &["s", "l", "g", "aq", "cr", "sln", "ads", "am", "lc", "vit"];
+
+/// Converts the contents of `\ce{...}` to LaTeX.
+pub(crate) fn transpile_mhchem(ce: &str) -> String {
+    let mut parts = Vec::new();
+    for word in split_words(ce) {
+        parts.push(translate_word(&word));
+    }
+    parts.join(" ")
+}
+
+/// Splits on whitespace outside braces, brackets and `$...$`, so arrow
+/// conditions such as `->[{heat it}]` stay in one word.
+fn split_words(ce: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut depth = 0;
+    let mut in_math = false;
+    let mut chars = ce.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                word.push(c);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+                continue;
+            }
+            '$' => in_math = !in_math,
+            '{' | '[' if !in_math => depth += 1,
+            '}' | ']' if !in_math => depth -= 1,
+            c if c.is_whitespace() && depth <= 0 && !in_math => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn translate_word(word: &str) -> String {
+    match word {
+        "+" => return "+".to_string(),
+        "=" => return "=".to_string(),
+        "v" | "(v)" => return "\\downarrow".to_string(),
+        "^" | "(^)" => return "\\uparrow".to_string(),
+        _ => {}
+    }
+    if let Some((arrow, plain, long)) = ARROWS.iter().find(|(arrow, _, _)| word.starts_with(arrow)) {
+        return translate_arrow(&word[arrow.len()..], plain, long);
+    }
+    if let Some(math) = word.strip_prefix('$').and_then(|w| w.strip_suffix('$'))
+        && !math.contains('$')
+    {
+        return math.to_string();
+    }
+    // A lone lowercase letter is a variable coefficient, as in `\ce{n H2O}`.
+    let mut chars = word.chars();
+    if let (Some(c), None) = (chars.next(), chars.next())
+        && c.is_ascii_lowercase()
+        && c != 'e'
+    {
+        return c.to_string();
+    }
+    Species::new(word).translate()
+}
+
+/// An arrow followed by up to two `[...]` conditions, written above and
+/// below it.
+fn translate_arrow(rest: &str, plain: &str, long: &str) -> String {
+    let mut conditions = Vec::new();
+    let mut rest = rest;
+    while let Some(inner) = rest.strip_prefix('[') {
+        let Some(end) = matching_close(inner, '[', ']') else {
+            break;
+        };
+        conditions.push(translate_condition(&inner[..end]));
+        rest = &inner[end + 1..];
+    }
+
+    let above = conditions.first().filter(|c| !c.is_empty());
+    let below = conditions.get(1).filter(|c| !c.is_empty());
+    match (above, below) {
+        (None, None) => plain.to_string(),
+        (Some(above), None) => format!("\\overset{{{}}}{{{}}}", above, long),
+        (None, Some(below)) => format!("\\underset{{{}}}{{{}}}", below, long),
+        (Some(above), Some(below)) => format!("\\underset{{{}}}{{\\overset{{{}}}{{{}}}}}", below, above, long),
+    }
+}
+
+/// Arrow conditions are chemistry unless wrapped in braces, which make
+/// them text.
+fn translate_condition(condition: &str) -> String {
+    let condition = condition.trim();
+    if let Some(text) = condition.strip_prefix('{').and_then(|c| c.strip_suffix('}'))
+        && matching_close(&condition[1..], '{', '}') == Some(condition.len() - 2)
+    {
+        return latex_text(text);
+    }
+    transpile_mhchem(condition)
+}
+
+/// Text for latex2mathml, which drops spaces inside `\text{}` and cannot
+/// parse parentheses there.
+fn latex_text(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| {
+            if word.contains(['(', ')', '{', '}', '\\', '$']) {
+                format!("\\mathrm{{{}}}", word)
+            } else {
+                format!("\\text{{{}}}", word)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\\;")
+}
+
+/// Index of the `close` that balances an already opened `open` in `text`.
+fn matching_close(text: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 1;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+/// A formula with its scripts, waiting for a possible charge.
+#[derive(Default)]
+struct Atom {
+    base: String,
+    sub: Option<String>,
+    sup: Option<String>,
+}
+
+impl Atom {
+    fn write(self, out: &mut String) {
+        match (self.sub, self.sup) {
+            (None, None) => out.push_str(&self.base),
+            (Some(sub), None) => out.push_str(&format!("{}_{{{}}}", self.base, sub)),
+            (None, Some(sup)) => out.push_str(&format!("{}^{{{}}}", self.base, sup)),
+            (Some(sub), Some(sup)) => out.push_str(&format!("{{{}_{{{}}}}}^{{{}}}", self.base, sub, sup)),
+        }
+    }
+}
+
+/// One whitespace-free word: a coefficient, then formulas, charges,
+/// states, bonds and `*` or `.` addition dots.
+struct Species {
+    chars: Vec<char>,
+    pos: usize,
+    out: String,
+    atom: Option<Atom>,
+}
+
+impl Species {
+    fn new(word: &str) -> Self {
+        Self {
+            chars: word.chars().collect(),
+            pos: 0,
+            out: String::new(),
+            atom: None,
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn rest(&self) -> String {
+        self.chars[self.pos..].iter().collect()
+    }
+
+    fn flush(&mut self) {
+        if let Some(atom) = self.atom.take() {
+            atom.write(&mut self.out);
+        }
+    }
+
+    fn start_atom(&mut self, base: String) {
+        self.flush();
+        self.atom = Some(Atom {
+            base,
+            ..Atom::default()
+        });
+    }
+
+    fn translate(mut self) -> String {
+        self.coefficient();
+        self.isotope();
+        while let Some(c) = self.peek(0) {
+            match c {
+                'A'..='Z' => {
+                    let mut symbol = c.to_string();
+                    self.pos += 1;
+                    while let Some(lower) = self.peek(0).filter(char::is_ascii_lowercase) {
+                        symbol.push(lower);
+                        self.pos += 1;
+                    }
+                    self.start_atom(format!("{{\\mathrm{{{}}}}}", symbol));
+                }
+                // Particles such as `e` and `n`, upright like elements.
+                'a'..='z' => {
+                    let mut symbol = String::new();
+                    while let Some(lower) = self.peek(0).filter(char::is_ascii_lowercase) {
+                        symbol.push(lower);
+                        self.pos += 1;
+                    }
+                    self.start_atom(format!("{{\\mathrm{{{}}}}}", symbol));
+                }
+                // Subscripts are whole numbers, so `CuSO4.5H2O` keeps its dot.
+                '0'..='9' => {
+                    let number = self.digits();
+                    if let Some(atom) = self.atom.as_mut().filter(|a| a.sub.is_none()) {
+                        atom.sub = Some(number);
+                    } else {
+                        self.flush();
+                        self.out.push_str(&number);
+                    }
+                }
+                '_' => {
+                    self.pos += 1;
+                    let sub = self.script_argument();
+                    match self.atom.as_mut() {
+                        Some(atom) if atom.sub.is_none() => atom.sub = Some(sub),
+                        _ => {
+                            self.flush();
+                            self.start_atom("{}".to_string());
+                            self.atom.as_mut().unwrap().sub = Some(sub);
+                        }
+                    }
+                }
+                '^' => {
+                    self.pos += 1;
+                    let sup = self.superscript_argument();
+                    self.set_charge(sup);
+                }
+                '+' | '-' if self.at_charge_end() => {
+                    let charge = self.signs();
+                    self.set_charge(charge);
+                }
+                // Bonds between parts of a formula.
+                '-' | '=' | '#' => {
+                    self.flush();
+                    self.out.push_str(match c {
+                        '-' => "{-}",
+                        '=' => "{=}",
+                        _ => "{\\equiv}",
+                    });
+                    self.pos += 1;
+                }
+                '(' if self.state().is_some() => {
+                    let state = self.state().unwrap();
+                    self.flush();
+                    self.out.push_str(&format!("(\\mathrm{{{}}})", state));
+                    self.pos += state.chars().count() + 2;
+                }
+                '(' | '[' => {
+                    self.flush();
+                    self.out.push(c);
+                    self.pos += 1;
+                }
+                ')' | ']' => {
+                    self.pos += 1;
+                    self.start_atom(c.to_string());
+                }
+                // Addition compounds: `CuSO4*5H2O`, `CuSO4.5H2O`.
+                '*' | '.' | '·' | '•' => {
+                    self.flush();
+                    self.out.push_str("\\cdot ");
+                    self.pos += 1;
+                    self.coefficient();
+                }
+                '$' => {
+                    self.flush();
+                    let rest = self.rest();
+                    let end = rest[1..].find('$').map_or(rest.len(), |i| i + 1);
+                    let math: String = rest[1..end].to_string();
+                    self.out.push_str(&math);
+                    self.pos += rest[..end.min(rest.len())].chars().count() + 1;
+                }
+                '\\' => {
+                    let mut command = String::from('\\');
+                    self.pos += 1;
+                    while let Some(letter) = self.peek(0).filter(char::is_ascii_alphabetic) {
+                        command.push(letter);
+                        self.pos += 1;
+                    }
+                    if command.len() == 1
+                        && let Some(other) = self.peek(0)
+                    {
+                        command.push(other);
+                        self.pos += 1;
+                    }
+                    self.start_atom(format!("{{{}}}", command));
+                }
+                '{' => {
+                    self.pos += 1;
+                    let text = self.group();
+                    self.start_atom(format!("{{{}}}", latex_text(&text)));
+                }
+                _ => {
+                    self.flush();
+                    self.out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.flush();
+        self.out
+    }
+
+    /// A leading number, decimal or fraction, such as `2`, `0.5` or `1/2`.
+    fn coefficient(&mut self) {
+        if !self.peek(0).is_some_and(|c| c.is_ascii_digit()) {
+            return;
+        }
+        let number = self.number();
+        if self.peek(0) == Some('/') && self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+            let denominator = self.number();
+            self.out.push_str(&format!("\\frac{{{}}}{{{}}}", number, denominator));
+        } else {
+            self.out.push_str(&number);
+        }
+    }
+
+    /// Mass and atomic numbers written before a symbol: `^{14}C`,
+    /// `^{227}_{90}Th`.
+    fn isotope(&mut self) {
+        if self.peek(0) != Some('^') {
+            return;
+        }
+        self.pos += 1;
+        let mass = self.script_argument();
+        if self.peek(0) == Some('_') {
+            self.pos += 1;
+            let number = self.script_argument();
+            // One script pair on an empty base, so both numbers sit flush
+            // against the symbol, one above the other.
+            self.out.push_str(&format!("{{}}^{{{}}}_{{{}}}", mass, number));
+        } else {
+            self.out.push_str(&format!("{{}}^{{{}}}", mass));
+        }
+    }
+
+    fn number(&mut self) -> String {
+        let mut number = String::new();
+        while let Some(c) = self.peek(0) {
+            let decimal = c == '.' && !number.is_empty() && self.peek(1).is_some_and(|n| n.is_ascii_digit());
+            if !c.is_ascii_digit() && !decimal {
+                break;
+            }
+            number.push(c);
+            self.pos += 1;
+        }
+        number
+    }
+
+    fn digits(&mut self) -> String {
+        let mut digits = String::new();
+        while let Some(c) = self.peek(0).filter(char::is_ascii_digit) {
+            digits.push(c);
+            self.pos += 1;
+        }
+        digits
+    }
+
+    fn signs(&mut self) -> String {
+        let mut signs = String::new();
+        while let Some(c) = self.peek(0).filter(|c| *c == '+' || *c == '-') {
+            signs.push(c);
+            self.pos += 1;
+        }
+        signs
+    }
+
+    /// True when the signs from here end the word or precede a state, so
+    /// they are a charge: `Cl-`, `NO3-(aq)`. As in mhchem, digits before
+    /// them stay a subscript; a multi-digit charge needs `^`, as in `Ca^2+`.
+    fn at_charge_end(&self) -> bool {
+        let mut i = self.pos;
+        let signs_start = i;
+        while self.chars.get(i).is_some_and(|c| *c == '+' || *c == '-') {
+            i += 1;
+        }
+        if i == signs_start {
+            return false;
+        }
+        match self.chars.get(i) {
+            None => true,
+            Some('(') => {
+                let rest: String = self.chars[i..].iter().collect();
+                state_at(&rest).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn set_charge(&mut self, charge: String) {
+        match self.atom.as_mut() {
+            Some(atom) if atom.sup.is_none() => atom.sup = Some(charge),
+            _ => {
+                self.flush();
+                self.out.push_str(&format!("{{}}^{{{}}}", charge));
+            }
+        }
+    }
+
+    fn state(&self) -> Option<String> {
+        state_at(&self.rest())
+    }
+
+    /// The text of a `{...}` group, after its opening brace.
+    fn group(&mut self) -> String {
+        let rest = self.rest();
+        let end = matching_close(&rest, '{', '}').unwrap_or(rest.len());
+        self.pos += rest[..end].chars().count() + 1;
+        rest[..end].to_string()
+    }
+
+    /// The argument of `_`: a braced group, a number, or one character.
+    fn script_argument(&mut self) -> String {
+        match self.peek(0) {
+            Some('{') => {
+                self.pos += 1;
+                self.group()
+            }
+            Some('-') if self.peek(1).is_some_and(|c| c.is_ascii_digit()) => {
+                self.pos += 1;
+                format!("-{}", self.number())
+            }
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) => {
+                self.pos += 1;
+                c.to_string()
+            }
+            None => String::new(),
+        }
+    }
+
+    /// The argument of `^`: a braced group, a charge such as `2-`, or an
+    /// oxidation state such as `II`. Roman numerals are set upright.
+    fn superscript_argument(&mut self) -> String {
+        let argument = match self.peek(0) {
+            Some('{') => {
+                self.pos += 1;
+                self.group()
+            }
+            Some(c) if c.is_ascii_digit() || c == '+' || c == '-' => {
+                let mut charge = self.number();
+                charge.push_str(&self.signs());
+                charge
+            }
+            Some(c) if c.is_ascii_uppercase() => {
+                let mut numeral = String::new();
+                while let Some(c) = self.peek(0).filter(|c| "IVX".contains(*c)) {
+                    numeral.push(c);
+                    self.pos += 1;
+                }
+                if numeral.is_empty() {
+                    self.pos += 1;
+                    numeral.push(c);
+                }
+                numeral
+            }
+            Some(c) => {
+                self.pos += 1;
+                c.to_string()
+            }
+            None => String::new(),
+        };
+        if !argument.is_empty() && argument.chars().all(|c| "IVX".contains(c)) {
+            format!("\\mathrm{{{}}}", argument)
+        } else {
+            argument
+        }
+    }
+}
+
+/// The state of aggregation at the start of `text`, such as `aq` for
+/// `(aq)`.
+fn state_at(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('(')?;
+    let end = inner.find(')')?;
+    let state = &inner[..end];
+    STATES.contains(&state).then(|| state.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::transpile_mhchem;
+    use crate::qti::generator::latex_to_mathml;
+
+    /// Examples from the mhchem manual, with the LaTeX they become.
+    const CASES: &[(&str, &str)] = &[
+        // Chemical formulae
+        ("H2O", "{\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        ("Sb2O3", "{\\mathrm{Sb}}_{2}{\\mathrm{O}}_{3}"),
+        ("NO_x", "{\\mathrm{N}}{\\mathrm{O}}_{x}"),
+        ("Ca(OH)2", "{\\mathrm{Ca}}({\\mathrm{O}}{\\mathrm{H}})_{2}"),
+        ("(NH4)2S", "({\\mathrm{N}}{\\mathrm{H}}_{4})_{2}{\\mathrm{S}}"),
+        // Charges
+        ("H+", "{\\mathrm{H}}^{+}"),
+        ("CrO4^2-", "{\\mathrm{Cr}}{{\\mathrm{O}}_{4}}^{2-}"),
+        ("[AgCl2]-", "[{\\mathrm{Ag}}{\\mathrm{Cl}}_{2}]^{-}"),
+        ("Y^99+", "{\\mathrm{Y}}^{99+}"),
+        ("Y^{99+}", "{\\mathrm{Y}}^{99+}"),
+        ("Ca^2+", "{\\mathrm{Ca}}^{2+}"),
+        ("NH4+", "{\\mathrm{N}}{{\\mathrm{H}}_{4}}^{+}"),
+        ("e-", "{\\mathrm{e}}^{-}"),
+        ("[Cu(NH3)4]^2+", "[{\\mathrm{Cu}}({\\mathrm{N}}{\\mathrm{H}}_{3})_{4}]^{2+}"),
+        // Oxidation states
+        (
+            "Fe^{II}Fe^{III}2O4",
+            "{\\mathrm{Fe}}^{\\mathrm{II}}{{\\mathrm{Fe}}_{2}}^{\\mathrm{III}}{\\mathrm{O}}_{4}",
+        ),
+        // Stoichiometric coefficients
+        ("2H2O", "2{\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        ("2 H2O", "2 {\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        ("0.5H2O", "0.5{\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        ("1/2H2O", "\\frac{1}{2}{\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        ("n H2O", "n {\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        // Isotopes
+        ("^{14}C", "{}^{14}{\\mathrm{C}}"),
+        ("^{227}_{90}Th+", "{}^{227}_{90}{\\mathrm{Th}}^{+}"),
+        ("^0_{-1}n^-", "{}^{0}_{-1}{\\mathrm{n}}^{-}"),
+        // States of aggregation
+        ("H2O(l)", "{\\mathrm{H}}_{2}{\\mathrm{O}}(\\mathrm{l})"),
+        ("NaOH(aq)", "{\\mathrm{Na}}{\\mathrm{O}}{\\mathrm{H}}(\\mathrm{aq})"),
+        ("NO3-(aq)", "{\\mathrm{N}}{{\\mathrm{O}}_{3}}^{-}(\\mathrm{aq})"),
+        // Addition compounds
+        (
+            "KCr(SO4)2*12H2O",
+            "{\\mathrm{K}}{\\mathrm{Cr}}({\\mathrm{S}}{\\mathrm{O}}_{4})_{2}\\cdot 12{\\mathrm{H}}_{2}{\\mathrm{O}}",
+        ),
+        ("CuSO4.5H2O", "{\\mathrm{Cu}}{\\mathrm{S}}{\\mathrm{O}}_{4}\\cdot 5{\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        ("CuSO4·5H2O", "{\\mathrm{Cu}}{\\mathrm{S}}{\\mathrm{O}}_{4}\\cdot 5{\\mathrm{H}}_{2}{\\mathrm{O}}"),
+        // Bonds
+        ("C6H5-CHO", "{\\mathrm{C}}_{6}{\\mathrm{H}}_{5}{-}{\\mathrm{C}}{\\mathrm{H}}{\\mathrm{O}}"),
+        ("CH2=CH2", "{\\mathrm{C}}{\\mathrm{H}}_{2}{=}{\\mathrm{C}}{\\mathrm{H}}_{2}"),
+        ("HC#CH", "{\\mathrm{H}}{\\mathrm{C}}{\\equiv}{\\mathrm{C}}{\\mathrm{H}}"),
+        ("A-B=C#D", "{\\mathrm{A}}{-}{\\mathrm{B}}{=}{\\mathrm{C}}{\\equiv}{\\mathrm{D}}"),
+        // Reaction arrows
+        ("A -> B", "{\\mathrm{A}} \\rightarrow {\\mathrm{B}}"),
+        ("A <- B", "{\\mathrm{A}} \\leftarrow {\\mathrm{B}}"),
+        ("A <-> B", "{\\mathrm{A}} \\leftrightarrow {\\mathrm{B}}"),
+        ("A <--> B", "{\\mathrm{A}} \\rightleftarrows {\\mathrm{B}}"),
+        ("A <=> B", "{\\mathrm{A}} \\rightleftharpoons {\\mathrm{B}}"),
+        ("A <=>> B", "{\\mathrm{A}} \\rightleftharpoons {\\mathrm{B}}"),
+        ("A <<=> B", "{\\mathrm{A}} \\leftrightharpoons {\\mathrm{B}}"),
+        (
+            "A ->[H2O] B",
+            "{\\mathrm{A}} \\overset{{\\mathrm{H}}_{2}{\\mathrm{O}}}{\\longrightarrow} {\\mathrm{B}}",
+        ),
+        (
+            "A ->[{text above}][{text below}] B",
+            "{\\mathrm{A}} \\underset{\\text{text}\\;\\text{below}}{\\overset{\\text{text}\\;\\text{above}}{\\longrightarrow}} {\\mathrm{B}}",
+        ),
+        (
+            "A ->[H2O][{heat}] B",
+            "{\\mathrm{A}} \\underset{\\text{heat}}{\\overset{{\\mathrm{H}}_{2}{\\mathrm{O}}}{\\longrightarrow}} {\\mathrm{B}}",
+        ),
+        ("A ->[$x$][$x_i$] B", "{\\mathrm{A}} \\underset{x_i}{\\overset{x}{\\longrightarrow}} {\\mathrm{B}}"),
+        // Equations, precipitates and gases
+        (
+            "CO2 + C -> 2 CO",
+            "{\\mathrm{C}}{\\mathrm{O}}_{2} + {\\mathrm{C}} \\rightarrow 2 {\\mathrm{C}}{\\mathrm{O}}",
+        ),
+        (
+            "CH4 + 2O2 -> CO2 + 2H2O",
+            "{\\mathrm{C}}{\\mathrm{H}}_{4} + 2{\\mathrm{O}}_{2} \\rightarrow {\\mathrm{C}}{\\mathrm{O}}_{2} + 2{\\mathrm{H}}_{2}{\\mathrm{O}}",
+        ),
+        (
+            "SO4^2- + Ba^2+ -> BaSO4 v",
+            "{\\mathrm{S}}{{\\mathrm{O}}_{4}}^{2-} + {\\mathrm{Ba}}^{2+} \\rightarrow {\\mathrm{Ba}}{\\mathrm{S}}{\\mathrm{O}}_{4} \\downarrow",
+        ),
+        ("CO2 ^", "{\\mathrm{C}}{\\mathrm{O}}_{2} \\uparrow"),
+        ("A v B (v) -> B ^ B (^)", "{\\mathrm{A}} \\downarrow {\\mathrm{B}} \\downarrow \\rightarrow {\\mathrm{B}} \\uparrow {\\mathrm{B}} \\uparrow"),
+        // Greek letters and math
+        ("\\alpha-Fe", "{\\alpha}{-}{\\mathrm{Fe}}"),
+        ("$x$ Na(NH4)HPO4", "x {\\mathrm{Na}}({\\mathrm{N}}{\\mathrm{H}}_{4}){\\mathrm{H}}{\\mathrm{P}}{\\mathrm{O}}_{4}"),
+    ];
+
+    #[test]
+    fn manual_examples() {
+        for (ce, latex) in CASES {
+            assert_eq!(transpile_mhchem(ce), *latex, "\\ce{{{}}}", ce);
+        }
+    }
+
+    #[test]
+    fn manual_examples_convert_to_mathml() {
+        for (ce, _) in CASES {
+            let mathml = latex_to_mathml(&transpile_mhchem(ce), false).unwrap();
+            assert!(!mathml.contains("PARSE ERROR"), "\\ce{{{}}} gave {}", ce, mathml);
+        }
+    }
+
+    #[test]
+    fn isotope_numbers_share_one_script_pair() {
+        let mathml = latex_to_mathml(&transpile_mhchem("^{227}_{90}Th+"), false).unwrap();
+        assert!(mathml.contains("<msubsup><mrow></mrow><mn>90</mn><mn>227</mn></msubsup>"), "{}", mathml);
+        let mathml = latex_to_mathml(&transpile_mhchem("^0_{-1}n^-"), false).unwrap();
+        assert!(mathml.contains("<msubsup><mrow></mrow><mrow><mo>-</mo><mn>1</mn></mrow><mn>0</mn></msubsup>"), "{}", mathml);
+    }
+
+    #[test]
+    fn elements_stay_upright() {
+        let mathml = latex_to_mathml(&transpile_mhchem("SO4^2-"), false).unwrap();
+        assert!(mathml.contains(r#"<mi mathvariant="normal">S</mi>"#));
+        assert!(mathml.contains(r#"<mi mathvariant="normal">O</mi>"#));
+        assert!(!mathml.contains("<mi>"));
+    }
+}
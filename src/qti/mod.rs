@@ -1,14 +1,153 @@
+pub mod a11y;
+pub mod batch;
 pub mod model;
 pub mod parser;
+pub mod diagnostics;
 pub mod generator;
+pub mod docx;
+pub mod lint;
+pub mod preview;
+pub mod print;
+pub mod serve;
+mod abc;
+mod diagram;
+mod dot;
+mod highlight;
+mod images;
 mod lexer;
+mod mathml;
+mod mathsvg;
+mod mermaid;
+mod mhchem;
+mod music;
+mod markdown;
+mod omml;
+mod optimize;
+mod plot;
+mod verify;
 
 use anyhow::Result;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-pub fn convert_to_qti(input_path: &Path) -> Result<()> {
+/// Reads and parses a quiz file, reporting diagnostics against its path.
+pub fn load_quiz(input_path: &Path) -> Result<model::Quiz> {
+    Ok(read_quiz(input_path)?.0)
+}
+
+/// `load_quiz`, also returning the number of warnings printed.
+fn read_quiz(input_path: &Path) -> Result<(model::Quiz, usize)> {
     let content = std::fs::read_to_string(input_path)?;
-    let quiz = parser::parse_quiz(&content)?;
-    generator::generate_qti(&quiz, input_path)?;
+    let (mut quiz, warnings) = parser::parse_quiz(&content, input_path)?;
+    // Theme and syntax files are named relative to the quiz.
+    let base = input_path.parent().unwrap_or(Path::new(""));
+    if let Some(theme) = &mut quiz.code.theme
+        && theme.ends_with(".tmTheme")
+    {
+        *theme = base.join(&*theme).display().to_string();
+    }
+    if let Some(syntaxes) = &mut quiz.code.syntaxes {
+        *syntaxes = base.join(&*syntaxes);
+    }
+    Ok((quiz, warnings))
+}
+
+/// What `convert_to_qti` produced from one quiz file.
+pub struct Conversion {
+    pub zip_path: PathBuf,
+    pub questions: usize,
+    pub points: f32,
+    /// Parse, content, accessibility and size warnings reported along the
+    /// way.
+    pub warnings: usize,
+    /// Line and id of each question given an id, for `write_question_ids`
+    /// to add to the quiz file.
+    pub added_ids: Vec<(usize, String)>,
+}
+
+/// Converts a quiz to a QTI zip at `zip_path`, or next to the quiz as
+/// `<stem>.zip` when no path is given. With `verify`, code blocks marked
+/// `verify` are run first and checked against their answers. The quiz file
+/// itself is left as it was; the ids it was given are returned for
+/// `write_question_ids`.
+pub fn convert_to_qti(input_path: &Path, zip_path: Option<&Path>, verify: bool, package: &generator::PackageOptions) -> Result<Conversion> {
+    let (mut quiz, parse_warnings) = read_quiz(input_path)?;
+    if verify {
+        verify::verify_quiz(&quiz, input_path)?;
+    }
+    let added = assign_question_ids(&mut quiz);
+    let zip_path = match zip_path {
+        Some(path) => path.to_path_buf(),
+        None => input_path.with_extension("zip"),
+    };
+    let package_warnings = generator::generate_qti(&quiz, input_path, &zip_path, package)?;
+    Ok(Conversion {
+        zip_path,
+        questions: quiz.questions.len(),
+        points: quiz.questions.iter().map(|q| q.points).sum(),
+        warnings: parse_warnings + package_warnings,
+        added_ids: added,
+    })
+}
+
+/// Gives questions without an `id=` the identifier derived from their
+/// content, and returns the line and id of each one that `write_question_ids`
+/// should add to the quiz file. Later edits then keep the identifier, so
+/// re-imports update the same items in the LMS.
+fn assign_question_ids(quiz: &mut model::Quiz) -> Vec<(usize, String)> {
+    let ids = generator::item_identifiers(&quiz.questions);
+    let mut added = Vec::new();
+    for (question, id) in quiz.questions.iter_mut().zip(ids) {
+        if question.id.is_none() {
+            // Included questions keep theirs in memory only; their ids are
+            // written when their own file is converted.
+            if question.source.is_none() {
+                added.push((question.line, id.clone()));
+            }
+            question.id = Some(id);
+        }
+    }
+    added
+}
+
+/// Writes the ids `assign_question_ids` added into the quiz file. Returns
+/// the number of warnings printed.
+pub fn write_question_ids(input_path: &Path, added: &[(usize, String)]) -> usize {
+    if added.is_empty() {
+        return 0;
+    }
+    let written = std::fs::read_to_string(input_path)
+        .and_then(|content| std::fs::write(input_path, parser::insert_question_ids(&content, added)));
+    match written {
+        Ok(()) => {
+            println!(
+                "Added ids to {} question{} in {}",
+                added.len(),
+                if added.len() == 1 { "" } else { "s" },
+                input_path.display()
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Warning: could not write question ids to {}: {}", input_path.display(), e);
+            1
+        }
+    }
+}
+
+pub fn preview_quiz(input_path: &Path, options: &preview::PreviewOptions) -> Result<()> {
+    let quiz = load_quiz(input_path)?;
+    preview::write_preview(&quiz, input_path, options)?;
+    Ok(())
+}
+
+pub fn print_quiz(input_path: &Path, options: &print::PrintOptions) -> Result<()> {
+    let quiz = load_quiz(input_path)?;
+    print::write_print(&quiz, input_path, options)?;
+    Ok(())
+}
+
+pub fn export_docx(input_path: &Path) -> Result<()> {
+    let quiz = load_quiz(input_path)?;
+    docx::write_docx(&quiz, input_path)?;
     Ok(())
 }
@@ -1,20 +1,103 @@
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Quiz {
     pub title: String,
     pub description: Option<String>,
     pub shuffle_answers: bool,
+    pub math: MathRendering,
+    pub code: CodeOptions,
+    pub images: ImageOptions,
     pub questions: Vec<Question>,
 }
 
+/// How LaTeX is written into generated content.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MathRendering {
+    /// MathML, rendered by the browser.
+    #[default]
+    Mathml,
+    /// Pre-rendered SVG images, with the MathML kept for screen readers.
+    Svg,
+}
+
+/// How code blocks are highlighted, from the front matter's `code:` map.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CodeOptions {
+    /// A syntect theme name, or the path of a `.tmTheme` file.
+    pub theme: Option<String>,
+    pub line_numbers: bool,
+    /// Directory of extra `.sublime-syntax` files.
+    pub syntaxes: Option<PathBuf>,
+    pub style: CodeStyle,
+}
+
+/// Where code colours are written.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeStyle {
+    /// Inline `style` attributes on every span.
+    #[default]
+    Inline,
+    /// Class names, styled by one shared stylesheet.
+    Classes,
+}
+
+/// How images are packaged, from the front matter's `images:` map.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageOptions {
+    pub remote: RemoteImages,
+    pub missing: MissingImages,
+}
+
+/// What happens to `http://` and `https://` images.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteImages {
+    /// Left as links to the URL, loaded when the question is viewed.
+    #[default]
+    Link,
+    /// Downloaded and packaged like local images.
+    Fetch,
+    /// Rejected, for packages that must be self-contained.
+    Error,
+}
+
+/// What happens when an image file does not exist.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingImages {
+    /// Conversion fails.
+    #[default]
+    Error,
+    /// A warning is printed and the image's alt text is written instead.
+    Warn,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Question {
+    /// Item identifier from the header's `id=` attribute. Questions without
+    /// one are given an identifier derived from their content.
+    pub id: Option<String>,
     pub title: String,
     pub prompt: String,
     pub question_type: QuestionType,
     pub points: f32,
     pub answers: Vec<Answer>,
+    /// Tags from the header's `tags=` attribute, for selecting questions
+    /// with `{{< include >}}`.
+    pub tags: Vec<String>,
+    /// Line of the `:::{.question}` header in the source file.
+    #[serde(skip)]
+    pub line: usize,
+    /// The file the question was included from, which `line` refers to.
+    /// `None` for questions written in the quiz itself.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -33,4 +116,7 @@ pub struct Answer {
     pub text: String,
     pub is_correct: bool,
     pub feedback: Option<String>,
+    /// Line of the `- [ ]` item in the source file.
+    #[serde(skip)]
+    pub line: usize,
 }
@@ -0,0 +1,1602 @@
+use anyhow::Result;
+use crate::qti::mathsvg::{bundled_face, number, PathData};
+use quick_xml::events::{BytesStart, Event};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::OnceLock;
+use ttf_parser::Face;
+
+// Engraves the common subset of MusicXML used in quizzes as SVG, so music
+// needs no external tools: one part on one staff, first voice only, with
+// clefs, key and time signatures, notes, chords, rests, accidentals, dots,
+// flags, beams, tuplet numbers and repeat signs. Ties, slurs, lyrics, dynamics
+// and articulations are left out. The symbols are drawn as paths below; time signature digits come from
+// New Computer Modern Bold.
+//
+// Lengths are in tenths of a staff space, with y pointing down from the top
+// staff line. Staff positions count half spaces up from the bottom line, so
+// the bottom line is 0 and the top line 8.
+
+const DIGIT_FONT: &str = "NewCM10-Bold";
+/// Output pixels per unit, making a staff space 8 pixels.
+const PIXELS_PER_UNIT: f32 = 0.8;
+/// Systems wrap once they would grow wider than this.
+const MAX_SYSTEM_WIDTH: f32 = 700.0;
+const MARGIN: f32 = 10.0;
+const SYSTEM_GAP: f32 = 30.0;
+const CLEF_SPACE: f32 = 36.0;
+const KEY_ACCIDENTAL_SPACE: f32 = 9.0;
+const MEASURE_PADDING: f32 = 10.0;
+const HEAD_WIDTH: f32 = 12.0;
+const WHOLE_HEAD_WIDTH: f32 = 16.0;
+const STEM_WIDTH: f32 = 1.2;
+const STEM_LENGTH: f32 = 35.0;
+const BEAM_THICKNESS: f32 = 5.0;
+const BEAM_SPACING: f32 = 7.5;
+const LINE_WIDTH: f32 = 1.0;
+/// Height of time signature digits: two staff spaces.
+const DIGIT_HEIGHT: f32 = 20.0;
+const TUPLET_DIGIT_HEIGHT: f32 = 11.0;
+/// Room taken by a repeat sign's bar lines and dots.
+const REPEAT_SPACE: f32 = 14.0;
+
+/// A symbol drawn around its own origin, with how far it reaches above and
+/// below that origin.
+struct Glyph {
+    id: &'static str,
+    markup: &'static str,
+    top: f32,
+    bottom: f32,
+}
+
+/// Origin on the G line.
+const TREBLE_CLEF: Glyph = Glyph {
+    id: "treble",
+    markup: r##"<path d="M11.5 1C11 -6 21 -7 21 1" fill="none" stroke-width="1.6"/><path d="M21 1C21 9 12 12 6 9" fill="none" stroke-width="2.4"/><path d="M6 9C0 6 -1 -4 6 -11" fill="none" stroke-width="3.4"/><path d="M6 -11C12 -17 20 -25 19 -36" fill="none" stroke-width="2.6"/><path d="M19 -36C18.5 -45 12 -47 11 -40" fill="none" stroke-width="1.5"/><path d="M11 -40C10 -33 12 -10 14.5 10" fill="none" stroke-width="2.1"/><path d="M14.5 10C15.5 17 15 23 11 24.5C7 26 4 23 5.5 20" fill="none" stroke-width="1.8"/><circle cx="8.2" cy="20.8" r="3.2" stroke="none"/>"##,
+    top: -48.0,
+    bottom: 27.0,
+};
+/// Origin on the F line.
+const BASS_CLEF: Glyph = Glyph {
+    id: "bass",
+    markup: r##"<path d="M2 -1C3 -9 19 -12 21 -1C22 9 12 19 0 25L0 23.6C9 17 15 9 14.5 -1C14 -9 6 -9 4.5 -4Z" stroke="none"/><circle cx="4" cy="-0.5" r="3.6" stroke="none"/><circle cx="26" cy="-5" r="1.8" stroke="none"/><circle cx="26" cy="5" r="1.8" stroke="none"/>"##,
+    top: -10.0,
+    bottom: 25.0,
+};
+/// Origin on the C line.
+const C_CLEF: Glyph = Glyph {
+    id: "c-clef",
+    markup: r##"<rect x="0" y="-20" width="4.5" height="40" stroke="none"/><rect x="6.5" y="-20" width="1.6" height="40" stroke="none"/><path d="M8 0L11 -4.5C13 -2.5 20 -4 19.5 -12C19 -19 11 -21 10.5 -15" fill="none" stroke-width="2.2"/><path d="M8 0L11 4.5C13 2.5 20 4 19.5 12C19 19 11 21 10.5 15" fill="none" stroke-width="2.2"/><circle cx="12.5" cy="-15" r="2.8" stroke="none"/><circle cx="12.5" cy="15" r="2.8" stroke="none"/>"##,
+    top: -20.0,
+    bottom: 20.0,
+};
+/// Origin on the middle line.
+const PERCUSSION_CLEF: Glyph = Glyph {
+    id: "percussion",
+    markup: r##"<rect x="2" y="-10" width="3.5" height="20" stroke="none"/><rect x="9" y="-10" width="3.5" height="20" stroke="none"/>"##,
+    top: -10.0,
+    bottom: 10.0,
+};
+/// Noteheads have their origin at their centre.
+const FILLED_HEAD: Glyph = Glyph {
+    id: "head-filled",
+    markup: r##"<path d="M5.638 -2.052A6 4.3 -20 1 0 -5.638 2.052A6 4.3 -20 1 0 5.638 -2.052Z" stroke="none"/>"##,
+    top: -5.0,
+    bottom: 5.0,
+};
+const HALF_HEAD: Glyph = Glyph {
+    id: "head-half",
+    markup: r##"<path fill-rule="evenodd" d="M5.638 -2.052A6 4.3 -20 1 0 -5.638 2.052A6 4.3 -20 1 0 5.638 -2.052ZM4.169 -1.944A4.6 1.9 -25 1 0 -4.169 1.944A4.6 1.9 -25 1 0 4.169 -1.944Z" stroke="none"/>"##,
+    top: -5.0,
+    bottom: 5.0,
+};
+const WHOLE_HEAD: Glyph = Glyph {
+    id: "head-whole",
+    markup: r##"<path fill-rule="evenodd" d="M8 0A8 5 0 1 0 -8 0A8 5 0 1 0 8 0ZM2.1 -3.637A4.2 2.5 -60 1 0 -2.1 3.637A4.2 2.5 -60 1 0 2.1 -3.637Z" stroke="none"/>"##,
+    top: -5.0,
+    bottom: 5.0,
+};
+/// Hangs from its origin on the fourth line.
+const WHOLE_REST: Glyph = Glyph {
+    id: "rest-whole",
+    markup: r##"<rect x="0" y="0" width="12" height="5" stroke="none"/>"##,
+    top: 0.0,
+    bottom: 5.0,
+};
+/// The other rests have their origin on the middle line.
+const HALF_REST: Glyph = Glyph {
+    id: "rest-half",
+    markup: r##"<rect x="0" y="-5" width="12" height="5" stroke="none"/>"##,
+    top: -5.0,
+    bottom: 0.0,
+};
+const QUARTER_REST: Glyph = Glyph {
+    id: "rest-quarter",
+    markup: r##"<path d="M3 -15L10 -7C7 -4 6.5 -1 10.5 3.5L9.8 4.2C6 2.5 2.5 4.5 6.5 11C1 8.5 0.5 1 8 2.7L2.5 -3.5C5.5 -6.5 6 -9 2.2 -14.2Z" stroke="none"/>"##,
+    top: -15.0,
+    bottom: 11.0,
+};
+const EIGHTH_REST: Glyph = Glyph {
+    id: "rest-eighth",
+    markup: r##"<path d="M9.5 -6L4.5 12M3 -2C5.5 -1.5 8 -3 9.5 -6" fill="none" stroke-width="1.4"/><circle cx="3" cy="-4.5" r="2.6" stroke="none"/>"##,
+    top: -7.5,
+    bottom: 12.5,
+};
+const SIXTEENTH_REST: Glyph = Glyph {
+    id: "rest-16th",
+    markup: r##"<path d="M11 -6L4 20M4.5 -2C7 -1.5 9.5 -3 11 -6M2.4 6C5 6.5 7.5 5 8.8 2" fill="none" stroke-width="1.4"/><circle cx="4.5" cy="-4.5" r="2.6" stroke="none"/><circle cx="2.4" cy="3.5" r="2.6" stroke="none"/>"##,
+    top: -7.5,
+    bottom: 20.5,
+};
+/// Accidentals have their origin at their left edge, level with the note.
+const SHARP: Glyph = Glyph {
+    id: "sharp",
+    markup: r##"<path d="M2.5 -11V9M6.5 -9V11" fill="none" stroke-width="1.1"/><path d="M0 -2.5L9 -5.5V-3L0 0ZM0 5L9 2V4.5L0 7.5Z" stroke="none"/>"##,
+    top: -11.0,
+    bottom: 11.0,
+};
+const FLAT: Glyph = Glyph {
+    id: "flat",
+    markup: r##"<path d="M1 -15V4" fill="none" stroke-width="1.3"/><path d="M1 4C5 1 9 -2 8 -5C7 -7.5 3.5 -6.5 1 -3.5V-1.8C3 -4 6 -5 6 -3C6 -1 3.5 1.5 1 3Z" stroke="none"/>"##,
+    top: -15.0,
+    bottom: 4.0,
+};
+const NATURAL: Glyph = Glyph {
+    id: "natural",
+    markup: r##"<path d="M1 -11V5.5M7 -5.5V11" fill="none" stroke-width="1.2"/><path d="M1 -3L7 -5V-2.5L1 -0.5ZM1 3.5L7 1.5V4L1 6Z" stroke="none"/>"##,
+    top: -11.0,
+    bottom: 11.0,
+};
+const DOUBLE_SHARP: Glyph = Glyph {
+    id: "double-sharp",
+    markup: r##"<path d="M1.5 -2.5L6.5 2.5M1.5 2.5L6.5 -2.5" fill="none" stroke-width="1.3"/><path d="M0 -4H3V-1H0ZM5 -4H8V-1H5ZM0 1H3V4H0ZM5 1H8V4H5Z" stroke="none"/>"##,
+    top: -4.0,
+    bottom: 4.0,
+};
+const DOUBLE_FLAT: Glyph = Glyph {
+    id: "double-flat",
+    markup: r##"<path d="M1 -15V4M7 -15V4" fill="none" stroke-width="1.3"/><path d="M1 4C5 1 9 -2 8 -5C7 -7.5 3.5 -6.5 1 -3.5V-1.8C3 -4 6 -5 6 -3C6 -1 3.5 1.5 1 3ZM7 4C11 1 15 -2 14 -5C13 -7.5 9.5 -6.5 7 -3.5V-1.8C9 -4 12 -5 12 -3C12 -1 9.5 1.5 7 3Z" stroke="none"/>"##,
+    top: -15.0,
+    bottom: 4.0,
+};
+/// Hangs from the top of an up stem; flipped for down stems.
+const FLAG: Glyph = Glyph {
+    id: "flag",
+    markup: r##"<path d="M0 0C1 7 11 9 8 21C7.6 22 6.8 22 7.1 20.8C9 12 3 9.5 0 8Z" stroke="none"/>"##,
+    top: 0.0,
+    bottom: 22.0,
+};
+
+/// Renders a MusicXML score as a standalone SVG.
+pub(crate) fn musicxml_to_svg(musicxml: &str) -> Result<String> {
+    let measures = parse_score(musicxml)?;
+    let digits = digit_face().ok_or_else(|| anyhow::anyhow!("Font {} is not available", DIGIT_FONT))?;
+    Ok(Engraver::new(digits).engrave(&measures))
+}
+
+static DIGIT_FACE: OnceLock<Option<Face<'static>>> = OnceLock::new();
+
+fn digit_face() -> Option<&'static Face<'static>> {
+    DIGIT_FACE.get_or_init(|| bundled_face(DIGIT_FONT)).as_ref()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClefSign {
+    G,
+    F,
+    C,
+    Percussion,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Clef {
+    sign: ClefSign,
+    /// Staff line the clef sits on, counted from the bottom.
+    line: i32,
+}
+
+impl Clef {
+    const TREBLE: Clef = Clef { sign: ClefSign::G, line: 2 };
+
+    /// Staff position of a pitch, from its step (0 for C) and octave.
+    fn position(self, step: i32, octave: i32) -> i32 {
+        // Unpitched notes are placed as they would be in treble clef.
+        let (clef, reference) = match self.sign {
+            ClefSign::G => (self, 4 * 7 + 4),
+            ClefSign::F => (self, 3 * 7 + 3),
+            ClefSign::C => (self, 4 * 7),
+            ClefSign::Percussion => (Clef::TREBLE, 4 * 7 + 4),
+        };
+        2 * (clef.line - 1) + octave * 7 + step - reference
+    }
+
+    fn glyph(self) -> (&'static Glyph, i32) {
+        match self.sign {
+            ClefSign::G => (&TREBLE_CLEF, 2 * (self.line - 1)),
+            ClefSign::F => (&BASS_CLEF, 2 * (self.line - 1)),
+            ClefSign::C => (&C_CLEF, 2 * (self.line - 1)),
+            ClefSign::Percussion => (&PERCUSSION_CLEF, 4),
+        }
+    }
+}
+
+enum Time {
+    Numbers(String, String),
+    Common,
+    Cut,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Accidental {
+    Sharp,
+    Flat,
+    Natural,
+    DoubleSharp,
+    DoubleFlat,
+}
+
+impl Accidental {
+    fn from_alter(alter: i32) -> Option<Self> {
+        match alter {
+            2 => Some(Accidental::DoubleSharp),
+            1 => Some(Accidental::Sharp),
+            0 => Some(Accidental::Natural),
+            -1 => Some(Accidental::Flat),
+            -2 => Some(Accidental::DoubleFlat),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sharp" => Some(Accidental::Sharp),
+            "flat" => Some(Accidental::Flat),
+            "natural" => Some(Accidental::Natural),
+            "double-sharp" | "sharp-sharp" => Some(Accidental::DoubleSharp),
+            "flat-flat" | "double-flat" => Some(Accidental::DoubleFlat),
+            _ => None,
+        }
+    }
+
+    fn glyph(self) -> &'static Glyph {
+        match self {
+            Accidental::Sharp => &SHARP,
+            Accidental::Flat => &FLAT,
+            Accidental::Natural => &NATURAL,
+            Accidental::DoubleSharp => &DOUBLE_SHARP,
+            Accidental::DoubleFlat => &DOUBLE_FLAT,
+        }
+    }
+
+    fn width(self) -> f32 {
+        match self {
+            Accidental::Sharp => 9.0,
+            Accidental::DoubleFlat => 14.0,
+            _ => 8.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum NoteType {
+    SixtyFourth,
+    ThirtySecond,
+    Sixteenth,
+    Eighth,
+    Quarter,
+    Half,
+    Whole,
+    Breve,
+}
+
+impl NoteType {
+    const ALL: [NoteType; 8] = [
+        NoteType::SixtyFourth,
+        NoteType::ThirtySecond,
+        NoteType::Sixteenth,
+        NoteType::Eighth,
+        NoteType::Quarter,
+        NoteType::Half,
+        NoteType::Whole,
+        NoteType::Breve,
+    ];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "64th" => Some(NoteType::SixtyFourth),
+            "32nd" => Some(NoteType::ThirtySecond),
+            "16th" => Some(NoteType::Sixteenth),
+            "eighth" => Some(NoteType::Eighth),
+            "quarter" => Some(NoteType::Quarter),
+            "half" => Some(NoteType::Half),
+            "whole" => Some(NoteType::Whole),
+            "breve" => Some(NoteType::Breve),
+            _ => None,
+        }
+    }
+
+    fn quarters(self) -> f32 {
+        match self {
+            NoteType::SixtyFourth => 0.0625,
+            NoteType::ThirtySecond => 0.125,
+            NoteType::Sixteenth => 0.25,
+            NoteType::Eighth => 0.5,
+            NoteType::Quarter => 1.0,
+            NoteType::Half => 2.0,
+            NoteType::Whole => 4.0,
+            NoteType::Breve => 8.0,
+        }
+    }
+
+    fn flags(self) -> usize {
+        match self {
+            NoteType::SixtyFourth => 4,
+            NoteType::ThirtySecond => 3,
+            NoteType::Sixteenth => 2,
+            NoteType::Eighth => 1,
+            _ => 0,
+        }
+    }
+
+    /// The type and dots for a length in quarter notes, for notes that
+    /// leave out `<type>`.
+    fn from_quarters(quarters: f32) -> (Self, u8) {
+        let kind = NoteType::ALL
+            .into_iter()
+            .rev()
+            .find(|kind| kind.quarters() <= quarters + 1e-3)
+            .unwrap_or(NoteType::SixtyFourth);
+        let mut dots = 0;
+        let mut length = kind.quarters();
+        let mut dot = kind.quarters() / 2.0;
+        while dots < 2 && length + dot <= quarters + 1e-3 {
+            length += dot;
+            dot /= 2.0;
+            dots += 1;
+        }
+        (kind, dots)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Beam {
+    Begin,
+    Continue,
+    End,
+}
+
+struct Pitch {
+    /// 0 for C up to 6 for B.
+    step: i32,
+    octave: i32,
+    alter: i32,
+    accidental: Option<Accidental>,
+}
+
+/// A rest, or a note with any chord notes stacked on it.
+struct Note {
+    pitches: Vec<Pitch>,
+    rest: bool,
+    /// A rest filling the whole measure, centred whatever its type.
+    whole_measure: bool,
+    kind: NoteType,
+    dots: u8,
+    /// The primary beam.
+    beam: Option<Beam>,
+    /// How many beams the note carries.
+    beams: usize,
+    /// `Some(true)` for a stem the file points up.
+    stem_up: Option<bool>,
+    /// The number of notes in the tuplet the note belongs to.
+    tuplet: Option<u32>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Barline {
+    #[default]
+    Regular,
+    Double,
+    Final,
+    RepeatEnd,
+}
+
+#[derive(Default)]
+struct Measure {
+    clef: Option<Clef>,
+    fifths: Option<i32>,
+    time: Option<Time>,
+    notes: Vec<Note>,
+    repeat_start: bool,
+    barline: Barline,
+}
+
+/// The score's `work-title`, or its `movement-title` when it has none, as
+/// ABC's `T:` field is written.
+pub(crate) fn musicxml_title(musicxml: &str) -> Option<String> {
+    let mut reader = quick_xml::Reader::from_str(musicxml);
+    let (mut current, mut work, mut movement) = (None, None, None);
+    loop {
+        match reader.read_event().ok()? {
+            Event::Eof => break,
+            Event::Start(e) => current = Some(e.name().as_ref().to_vec()),
+            Event::End(_) => current = None,
+            Event::Text(t) => {
+                let text = t.unescape().ok()?.trim().to_string();
+                match current.as_deref() {
+                    Some(b"work-title") if work.is_none() && !text.is_empty() => work = Some(text),
+                    Some(b"movement-title") if movement.is_none() && !text.is_empty() => movement = Some(text),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    work.or(movement)
+}
+
+fn parse_score(musicxml: &str) -> Result<Vec<Measure>> {
+    let mut parser = ScoreParser {
+        divisions: 1.0,
+        ..ScoreParser::default()
+    };
+    let mut reader = quick_xml::Reader::from_str(musicxml);
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => parser.start(&e)?,
+            Event::Empty(e) => {
+                parser.start(&e)?;
+                parser.end(e.name().as_ref());
+            }
+            Event::End(e) => parser.end(e.name().as_ref()),
+            Event::Text(t) => parser.text.push_str(&t.unescape()?),
+            _ => {}
+        }
+    }
+    if parser.parts == 0 {
+        anyhow::bail!("No <part> found; only score-partwise MusicXML is supported");
+    }
+    if parser.measures.iter().all(|m| m.notes.is_empty()) {
+        anyhow::bail!("The score has no notes");
+    }
+    Ok(parser.measures)
+}
+
+/// Reads the first part of a score-partwise file, keeping the notes of the
+/// first voice on the first staff.
+#[derive(Default)]
+struct ScoreParser {
+    measures: Vec<Measure>,
+    parts: usize,
+    divisions: f32,
+    text: String,
+    note: Option<NoteDraft>,
+    clef: Option<ClefDraft>,
+    time: Option<TimeDraft>,
+    beam_number: usize,
+    voice: Option<String>,
+    right_barline: bool,
+}
+
+#[derive(Default)]
+struct NoteDraft {
+    rest: bool,
+    whole_measure: bool,
+    chord: bool,
+    grace: bool,
+    step: Option<i32>,
+    octave: i32,
+    alter: i32,
+    accidental: Option<Accidental>,
+    duration: Option<f32>,
+    kind: Option<NoteType>,
+    dots: u8,
+    beam: Option<Beam>,
+    beams: usize,
+    stem_up: Option<bool>,
+    tuplet: Option<u32>,
+    voice: Option<String>,
+    staff: Option<String>,
+}
+
+#[derive(Default)]
+struct ClefDraft {
+    /// Clefs for other staves are read and dropped.
+    other_staff: bool,
+    sign: Option<ClefSign>,
+    line: Option<i32>,
+}
+
+#[derive(Default)]
+struct TimeDraft {
+    symbol: Option<String>,
+    beats: String,
+    beat_type: String,
+}
+
+impl ScoreParser {
+    fn start(&mut self, e: &BytesStart) -> Result<()> {
+        self.text.clear();
+        let attr = |key: &[u8]| -> Option<String> {
+            e.attributes()
+                .flatten()
+                .find(|a| a.key.as_ref() == key)
+                .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+        };
+        match e.name().as_ref() {
+            b"score-timewise" => anyhow::bail!("score-timewise MusicXML is not supported; export it as score-partwise"),
+            b"part" => self.parts += 1,
+            _ => {}
+        }
+        if self.parts != 1 {
+            return Ok(());
+        }
+
+        match e.name().as_ref() {
+            b"measure" => self.measures.push(Measure::default()),
+            b"note" => self.note = Some(NoteDraft::default()),
+            b"clef" => {
+                self.clef = Some(ClefDraft {
+                    other_staff: attr(b"number").is_some_and(|n| n != "1"),
+                    ..ClefDraft::default()
+                })
+            }
+            b"time" => {
+                self.time = Some(TimeDraft {
+                    symbol: attr(b"symbol"),
+                    ..TimeDraft::default()
+                })
+            }
+            b"beam" => self.beam_number = attr(b"number").and_then(|n| n.parse().ok()).unwrap_or(1),
+            b"barline" => self.right_barline = attr(b"location").is_none_or(|l| l == "right"),
+            b"repeat" => {
+                if let Some(measure) = self.measures.last_mut() {
+                    match attr(b"direction").as_deref() {
+                        Some("backward") if self.right_barline => measure.barline = Barline::RepeatEnd,
+                        Some("forward") if !self.right_barline => measure.repeat_start = true,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Some(note) = self.note.as_mut() {
+            match e.name().as_ref() {
+                b"rest" => {
+                    note.rest = true;
+                    note.whole_measure = attr(b"measure").as_deref() == Some("yes");
+                }
+                b"chord" => note.chord = true,
+                b"grace" => note.grace = true,
+                b"dot" => note.dots += 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        if self.parts != 1 {
+            return;
+        }
+        let text = std::mem::take(&mut self.text);
+        let text = text.trim();
+
+        if let Some(note) = self.note.as_mut() {
+            match name {
+                b"step" | b"display-step" => note.step = "CDEFGAB".find(text).map(|i| i as i32),
+                b"octave" | b"display-octave" => note.octave = text.parse().unwrap_or(4),
+                b"alter" => note.alter = text.parse::<f32>().map_or(0, |a| a.round() as i32),
+                b"accidental" => note.accidental = Accidental::from_name(text),
+                b"duration" => note.duration = text.parse().ok(),
+                b"type" => note.kind = NoteType::parse(text),
+                b"actual-notes" => note.tuplet = text.parse().ok(),
+                b"stem" => note.stem_up = Some(text == "up").filter(|_| text == "up" || text == "down"),
+                b"voice" => note.voice = Some(text.to_string()),
+                b"staff" => note.staff = Some(text.to_string()),
+                b"beam" => {
+                    let beam = match text {
+                        "begin" => Some(Beam::Begin),
+                        "continue" => Some(Beam::Continue),
+                        "end" => Some(Beam::End),
+                        _ => None,
+                    };
+                    note.beams = note.beams.max(self.beam_number);
+                    if self.beam_number == 1 {
+                        note.beam = beam;
+                    }
+                }
+                b"note" => self.finish_note(),
+                _ => {}
+            }
+            return;
+        }
+
+        if let Some(clef) = self.clef.as_mut() {
+            match name {
+                b"sign" => {
+                    clef.sign = match text {
+                        "G" => Some(ClefSign::G),
+                        "F" => Some(ClefSign::F),
+                        "C" => Some(ClefSign::C),
+                        "percussion" => Some(ClefSign::Percussion),
+                        _ => None,
+                    }
+                }
+                b"line" => clef.line = text.parse().ok(),
+                b"clef" => {
+                    let clef = self.clef.take().unwrap();
+                    if let (Some(sign), false, Some(measure)) = (clef.sign, clef.other_staff, self.measures.last_mut()) {
+                        let default_line = match sign {
+                            ClefSign::G => 2,
+                            ClefSign::F => 4,
+                            _ => 3,
+                        };
+                        measure.clef = Some(Clef {
+                            sign,
+                            line: clef.line.unwrap_or(default_line),
+                        });
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        if let Some(time) = self.time.as_mut() {
+            match name {
+                b"beats" => time.beats = text.to_string(),
+                b"beat-type" => time.beat_type = text.to_string(),
+                b"time" => {
+                    let time = self.time.take().unwrap();
+                    if let Some(measure) = self.measures.last_mut() {
+                        measure.time = Some(match time.symbol.as_deref() {
+                            Some("common") => Time::Common,
+                            Some("cut") => Time::Cut,
+                            _ => Time::Numbers(time.beats, time.beat_type),
+                        });
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let Some(measure) = self.measures.last_mut() else {
+            return;
+        };
+        match name {
+            b"divisions" => {
+                if let Some(divisions) = text.parse().ok().filter(|d: &f32| *d > 0.0) {
+                    self.divisions = divisions;
+                }
+            }
+            b"fifths" => measure.fifths = text.parse().ok(),
+            b"bar-style" if self.right_barline => {
+                measure.barline = match text {
+                    "light-light" => Barline::Double,
+                    "light-heavy" => Barline::Final,
+                    _ => Barline::Regular,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish_note(&mut self) {
+        let Some(draft) = self.note.take() else {
+            return;
+        };
+        let Some(measure) = self.measures.last_mut() else {
+            return;
+        };
+        if draft.grace || draft.staff.as_deref().is_some_and(|s| s != "1") {
+            return;
+        }
+        let voice = draft.voice.unwrap_or_default();
+        match &self.voice {
+            Some(first) if *first != voice => return,
+            Some(_) => {}
+            None => self.voice = Some(voice),
+        }
+
+        let pitch = draft.step.map(|step| Pitch {
+            step,
+            octave: draft.octave,
+            alter: draft.alter,
+            accidental: draft.accidental,
+        });
+        if draft.chord
+            && !draft.rest
+            && let Some(previous) = measure.notes.last_mut()
+            && !previous.rest
+        {
+            previous.pitches.extend(pitch);
+            return;
+        }
+
+        let (kind, dots) = match draft.kind {
+            Some(kind) => (kind, draft.dots),
+            None => NoteType::from_quarters(draft.duration.unwrap_or(self.divisions) / self.divisions),
+        };
+        if !draft.rest && pitch.is_none() {
+            return;
+        }
+        measure.notes.push(Note {
+            pitches: pitch.into_iter().collect(),
+            rest: draft.rest,
+            whole_measure: draft.whole_measure,
+            kind,
+            dots,
+            beam: draft.beam,
+            beams: draft.beams,
+            stem_up: draft.stem_up,
+            tuplet: draft.tuplet,
+        });
+    }
+}
+
+/// The alteration a key signature gives a step.
+pub(crate) fn key_alter(fifths: i32, step: i32) -> i32 {
+    // F C G D A E B, as steps from C.
+    const SHARPS: [i32; 7] = [3, 0, 4, 1, 5, 2, 6];
+    let count = fifths.unsigned_abs().min(7) as usize;
+    if fifths > 0 && SHARPS[..count].contains(&step) {
+        1
+    } else if fifths < 0 && SHARPS[7 - count..].contains(&step) {
+        -1
+    } else {
+        0
+    }
+}
+
+/// The accidentals of a key signature and their staff positions.
+fn key_signature(fifths: i32, clef: Clef) -> Vec<(Accidental, i32)> {
+    // Positions in treble clef, in the order they are written.
+    const SHARPS: [i32; 7] = [8, 5, 9, 6, 3, 7, 4];
+    const FLATS: [i32; 7] = [4, 7, 3, 6, 2, 5, 1];
+    // Other clefs keep the treble pattern, moved to where G4 falls, except
+    // that tenor clef writes its sharps lower.
+    let mut shift = clef.position(4, 4) - 2;
+    while shift > 3 {
+        shift -= 7;
+    }
+    while shift < -3 {
+        shift += 7;
+    }
+    let tenor = clef.sign == ClefSign::C && clef.line == 4;
+    let count = fifths.unsigned_abs().min(7) as usize;
+    if fifths > 0 {
+        SHARPS[..count]
+            .iter()
+            .map(|p| {
+                let position = p + shift;
+                (Accidental::Sharp, if tenor && position >= 9 { position - 7 } else { position })
+            })
+            .collect()
+    } else {
+        FLATS[..count].iter().map(|p| (Accidental::Flat, p + shift)).collect()
+    }
+}
+
+fn key_width(fifths: i32) -> f32 {
+    match fifths.unsigned_abs().min(7) {
+        0 => 0.0,
+        count => count as f32 * KEY_ACCIDENTAL_SPACE + 6.0,
+    }
+}
+
+fn position_y(position: i32) -> f32 {
+    (8 - position) as f32 * 5.0
+}
+
+/// A notehead, with the column its accidental is pushed into when several
+/// accidentals in a chord would collide.
+struct Head {
+    position: i32,
+    accidental: Option<Accidental>,
+    accidental_column: usize,
+}
+
+/// A measure with the clef and key in force, and its notes' horizontal
+/// space: `lead` before the notehead for accidentals and displaced heads,
+/// and `space` after, which stretches when a system is justified.
+struct Prepared<'a> {
+    measure: &'a Measure,
+    clef: Clef,
+    fifths: i32,
+    clef_changed: bool,
+    key_changed: bool,
+    heads: Vec<Vec<Head>>,
+    columns: Vec<(f32, f32)>,
+}
+
+impl Prepared<'_> {
+    /// Width of the clef, key and time signature when they start a system.
+    fn header_width(&self, engraver: &Engraver) -> f32 {
+        CLEF_SPACE + key_width(self.fifths) + self.measure.time.as_ref().map_or(0.0, |t| engraver.time_width(t))
+    }
+
+    /// Width of any clef, key or time change inside a system.
+    fn change_width(&self, engraver: &Engraver) -> f32 {
+        let clef = if self.clef_changed { CLEF_SPACE } else { 0.0 };
+        let key = if self.key_changed { key_width(self.fifths) } else { 0.0 };
+        clef + key + self.measure.time.as_ref().map_or(0.0, |t| engraver.time_width(t))
+    }
+
+    fn fixed_width(&self) -> f32 {
+        let repeats = [self.measure.repeat_start, self.measure.barline == Barline::RepeatEnd]
+            .iter()
+            .filter(|&&repeat| repeat)
+            .count();
+        MEASURE_PADDING + repeats as f32 * REPEAT_SPACE + self.columns.iter().map(|(lead, _)| lead).sum::<f32>()
+    }
+
+    fn flexible_width(&self) -> f32 {
+        self.columns.iter().map(|(_, space)| space).sum()
+    }
+}
+
+fn prepare(measures: &[Measure]) -> Vec<Prepared<'_>> {
+    let mut clef = Clef::TREBLE;
+    let mut fifths = 0;
+    let mut prepared = Vec::new();
+    for (i, measure) in measures.iter().enumerate() {
+        let clef_changed = measure.clef.is_some_and(|c| i == 0 || c != clef);
+        clef = measure.clef.unwrap_or(clef);
+        let key_changed = measure.fifths.is_some_and(|f| i == 0 || f != fifths);
+        fifths = measure.fifths.unwrap_or(fifths);
+
+        // Accidentals last to the end of the measure, on the same line or space.
+        let mut altered: HashMap<(i32, i32), i32> = HashMap::new();
+        let mut heads = Vec::new();
+        let mut columns = Vec::new();
+        for note in &measure.notes {
+            let mut chord: Vec<Head> = note
+                .pitches
+                .iter()
+                .map(|pitch| {
+                    let expected = altered
+                        .get(&(pitch.step, pitch.octave))
+                        .copied()
+                        .unwrap_or_else(|| key_alter(fifths, pitch.step));
+                    altered.insert((pitch.step, pitch.octave), pitch.alter);
+                    let accidental = match clef.sign {
+                        ClefSign::Percussion => None,
+                        _ => pitch
+                            .accidental
+                            .or_else(|| (pitch.alter != expected).then(|| Accidental::from_alter(pitch.alter)).flatten()),
+                    };
+                    Head {
+                        position: clef.position(pitch.step, pitch.octave),
+                        accidental,
+                        accidental_column: 0,
+                    }
+                })
+                .collect();
+            chord.sort_by_key(|head| head.position);
+            let accidental_columns = assign_accidental_columns(&mut chord);
+            let seconds = chord.windows(2).any(|pair| pair[1].position - pair[0].position == 1);
+
+            let mut lead = accidental_columns as f32 * 10.0;
+            if lead > 0.0 {
+                lead += 2.0;
+            }
+            let mut space = note_space(note);
+            if seconds {
+                lead += HEAD_WIDTH - STEM_WIDTH;
+                space += HEAD_WIDTH - STEM_WIDTH;
+            }
+            heads.push(chord);
+            columns.push((lead, space));
+        }
+        if measure.notes.iter().all(|n| n.whole_measure) {
+            for column in &mut columns {
+                column.1 = column.1.max(40.0);
+            }
+        }
+
+        prepared.push(Prepared {
+            measure,
+            clef,
+            fifths,
+            clef_changed,
+            key_changed,
+            heads,
+            columns,
+        });
+    }
+    prepared
+}
+
+/// Gives each accidental in a chord, top down, the first column where it
+/// clears the accidentals already there. Returns the number of columns.
+fn assign_accidental_columns(chord: &mut [Head]) -> usize {
+    let mut columns: Vec<Vec<i32>> = Vec::new();
+    for head in chord.iter_mut().rev().filter(|h| h.accidental.is_some()) {
+        let column = columns
+            .iter()
+            .position(|c| c.iter().all(|p| (p - head.position).abs() >= 6))
+            .unwrap_or(columns.len());
+        if column == columns.len() {
+            columns.push(Vec::new());
+        }
+        columns[column].push(head.position);
+        head.accidental_column = column;
+    }
+    columns.len()
+}
+
+/// Horizontal space after a note, growing with its length.
+fn note_space(note: &Note) -> f32 {
+    let dotted = 2.0 - 0.5f32.powi(note.dots as i32);
+    let quarters = note.kind.quarters() * dotted;
+    let mut space = (28.0 + 9.0 * quarters.log2()).max(18.0);
+    if note.beam.is_none() && note.kind.flags() > 0 {
+        space = space.max(24.0);
+    }
+    space + 5.0 * note.dots as f32
+}
+
+/// Groups of notes joined by a primary beam, as indices into `notes`.
+fn beam_groups(notes: &[Note]) -> Vec<Vec<usize>> {
+    let mut groups = Vec::new();
+    let mut current: Option<Vec<usize>> = None;
+    for (i, note) in notes.iter().enumerate() {
+        match note.beam {
+            Some(Beam::Begin) => current = Some(vec![i]),
+            Some(Beam::Continue) => {
+                if let Some(group) = current.as_mut() {
+                    group.push(i);
+                }
+            }
+            Some(Beam::End) => {
+                if let Some(mut group) = current.take() {
+                    group.push(i);
+                    groups.push(group);
+                }
+            }
+            None => {}
+        }
+    }
+    groups
+}
+
+/// A drawn stem: `base` is the y of the notehead it starts from, `near` the
+/// y of the notehead closest to its free end, and `end` where it stops.
+struct Stem {
+    x: f32,
+    base: f32,
+    near: f32,
+    end: f32,
+    up: bool,
+}
+
+/// SVG markup for one system, with how far it reaches above and below the
+/// staff.
+struct System {
+    body: String,
+    width: f32,
+    top: f32,
+    bottom: f32,
+}
+
+struct Engraver {
+    digits: &'static Face<'static>,
+    defs: BTreeMap<String, String>,
+    body: String,
+    top: f32,
+    bottom: f32,
+}
+
+impl Engraver {
+    fn new(digits: &'static Face<'static>) -> Self {
+        Self {
+            digits,
+            defs: BTreeMap::new(),
+            body: String::new(),
+            top: 0.0,
+            bottom: 40.0,
+        }
+    }
+
+    fn engrave(mut self, measures: &[Measure]) -> String {
+        let prepared = prepare(measures);
+
+        // Fill each system with as many measures as fit.
+        let mut breaks = Vec::new();
+        let mut width = 0.0;
+        for (i, measure) in prepared.iter().enumerate() {
+            let content = measure.fixed_width() + measure.flexible_width();
+            let inside = measure.change_width(&self) + content;
+            if i > 0 && width + inside <= MAX_SYSTEM_WIDTH {
+                width += inside;
+            } else {
+                breaks.push(i);
+                width = measure.header_width(&self) + content;
+            }
+        }
+        breaks.push(prepared.len());
+
+        let systems: Vec<System> = breaks
+            .windows(2)
+            .map(|range| {
+                let last = range[1] == prepared.len();
+                self.system(&prepared[range[0]..range[1]], last)
+            })
+            .collect();
+
+        let width = systems.iter().map(|s| s.width).fold(0.0, f32::max) + 2.0 * MARGIN;
+        let mut y = MARGIN;
+        let mut groups = String::new();
+        for (i, system) in systems.iter().enumerate() {
+            if i > 0 {
+                y += SYSTEM_GAP;
+            }
+            y -= system.top;
+            let _ = write!(
+                groups,
+                "<g transform=\"translate({} {})\">{}</g>",
+                number(MARGIN),
+                number(y),
+                system.body
+            );
+            y += system.bottom;
+        }
+        let height = y + MARGIN;
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" \
+             width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" role=\"img\"><title>Music notation</title><defs>",
+            number(width * PIXELS_PER_UNIT),
+            number(height * PIXELS_PER_UNIT),
+            number(width),
+            number(height)
+        );
+        for markup in self.defs.values() {
+            svg.push_str(markup);
+        }
+        let _ = writeln!(svg, "</defs><g stroke=\"#000\" fill=\"#000\">{}</g></svg>", groups);
+        svg
+    }
+
+    fn system(&mut self, measures: &[Prepared], last: bool) -> System {
+        self.body.clear();
+        self.top = 0.0;
+        self.bottom = 40.0;
+
+        let first = &measures[0];
+        let fixed = first.header_width(self)
+            + measures[1..].iter().map(|m| m.change_width(self)).sum::<f32>()
+            + measures.iter().map(|m| m.fixed_width()).sum::<f32>();
+        let flexible: f32 = measures.iter().map(|m| m.flexible_width()).sum();
+        // Every system but the last is stretched to the full width.
+        let stretch = if last || flexible <= 0.0 {
+            1.0
+        } else {
+            ((MAX_SYSTEM_WIDTH - fixed) / flexible).max(1.0)
+        };
+
+        let mut x = self.clef(first.clef, 0.0);
+        x = self.key(first.fifths, first.clef, x);
+        if let Some(time) = &first.measure.time {
+            x = self.time(time, x);
+        }
+        for (i, measure) in measures.iter().enumerate() {
+            if i > 0 {
+                if measure.clef_changed {
+                    x = self.clef(measure.clef, x);
+                }
+                if measure.key_changed {
+                    x = self.key(measure.fifths, measure.clef, x);
+                }
+                if let Some(time) = &measure.measure.time {
+                    x = self.time(time, x);
+                }
+            }
+            x = self.measure(measure, x, stretch);
+            self.barline(measure.measure.barline, x);
+        }
+
+        let staff: String = (0..5).map(|line| format!("M0 {}H{}", line * 10, number(x))).collect();
+        let body = format!(
+            "<path d=\"{}\" fill=\"none\" stroke-width=\"{}\"/>{}",
+            staff,
+            number(LINE_WIDTH),
+            self.body
+        );
+        System {
+            body,
+            width: x,
+            top: self.top,
+            bottom: self.bottom,
+        }
+    }
+
+    fn extend(&mut self, top: f32, bottom: f32) {
+        self.top = self.top.min(top);
+        self.bottom = self.bottom.max(bottom);
+    }
+
+    fn glyph(&mut self, glyph: &Glyph, x: f32, y: f32) {
+        self.define(glyph);
+        let _ = write!(
+            self.body,
+            "<use xlink:href=\"#{}\" x=\"{}\" y=\"{}\"/>",
+            glyph.id,
+            number(x),
+            number(y)
+        );
+        self.extend(y + glyph.top, y + glyph.bottom);
+    }
+
+    /// Draws a glyph upside down, for flags on down stems.
+    fn flipped_glyph(&mut self, glyph: &Glyph, x: f32, y: f32) {
+        self.define(glyph);
+        let _ = write!(
+            self.body,
+            "<use xlink:href=\"#{}\" transform=\"translate({} {}) scale(1 -1)\"/>",
+            glyph.id,
+            number(x),
+            number(y)
+        );
+        self.extend(y - glyph.bottom, y - glyph.top);
+    }
+
+    fn define(&mut self, glyph: &Glyph) {
+        self.defs.entry(glyph.id.to_string()).or_insert_with(|| {
+            format!(
+                "<g id=\"{}\" fill=\"#000\" stroke=\"#000\" stroke-linecap=\"round\">{}</g>",
+                glyph.id, glyph.markup
+            )
+        });
+    }
+
+    fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, width: f32) {
+        let _ = write!(
+            self.body,
+            "<path d=\"M{} {}L{} {}\" stroke-width=\"{}\"/>",
+            number(x1),
+            number(y1),
+            number(x2),
+            number(y2),
+            number(width)
+        );
+        self.extend(y1.min(y2), y1.max(y2));
+    }
+
+    fn clef(&mut self, clef: Clef, x: f32) -> f32 {
+        let (glyph, position) = clef.glyph();
+        self.glyph(glyph, x + 6.0, position_y(position));
+        x + CLEF_SPACE
+    }
+
+    fn key(&mut self, fifths: i32, clef: Clef, x: f32) -> f32 {
+        for (i, (accidental, position)) in key_signature(fifths, clef).into_iter().enumerate() {
+            self.glyph(accidental.glyph(), x + i as f32 * KEY_ACCIDENTAL_SPACE, position_y(position));
+        }
+        x + key_width(fifths)
+    }
+
+    fn digit_scale(&self, size: f32) -> f32 {
+        let height = self
+            .digits
+            .glyph_index('0')
+            .and_then(|g| self.digits.glyph_bounding_box(g))
+            .map_or(self.digits.units_per_em() as f32 * 0.7, |b| b.height() as f32);
+        size / height
+    }
+
+    fn text_width(&self, text: &str, size: f32) -> f32 {
+        let scale = self.digit_scale(size);
+        text.chars()
+            .filter_map(|c| self.digits.glyph_index(c))
+            .map(|g| self.digits.glyph_hor_advance(g).unwrap_or(0) as f32 * scale)
+            .sum()
+    }
+
+    fn time_width(&self, time: &Time) -> f32 {
+        let width = match time {
+            Time::Numbers(beats, beat_type) => self.text_width(beats, DIGIT_HEIGHT).max(self.text_width(beat_type, DIGIT_HEIGHT)),
+            Time::Common | Time::Cut => self.text_width("C", DIGIT_HEIGHT),
+        };
+        width + 10.0
+    }
+
+    /// Writes `text` in the digit font with digits `size` high, centred on
+    /// `center` with its baseline at `baseline`.
+    fn text(&mut self, text: &str, center: f32, baseline: f32, size: f32) {
+        let scale = self.digit_scale(size);
+        let mut x = center - self.text_width(text, size) / 2.0;
+        for c in text.chars() {
+            let Some(glyph) = self.digits.glyph_index(c) else {
+                continue;
+            };
+            let id = format!("t{}", glyph.0);
+            let face = self.digits;
+            self.defs.entry(id.clone()).or_insert_with(|| {
+                let mut path = PathData(String::new());
+                face.outline_glyph(glyph, &mut path);
+                format!("<path id=\"{}\" d=\"{}\"/>", id, path.0)
+            });
+            let _ = write!(
+                self.body,
+                "<use xlink:href=\"#{}\" transform=\"translate({} {}) scale({} -{})\" stroke=\"none\"/>",
+                id,
+                number(x),
+                number(baseline),
+                number(scale),
+                number(scale)
+            );
+            x += self.digits.glyph_hor_advance(glyph).unwrap_or(0) as f32 * scale;
+        }
+    }
+
+    fn time(&mut self, time: &Time, x: f32) -> f32 {
+        let width = self.time_width(time);
+        let center = x + (width - 4.0) / 2.0;
+        match time {
+            Time::Numbers(beats, beat_type) => {
+                self.text(beats, center, 20.0, DIGIT_HEIGHT);
+                self.text(beat_type, center, 40.0, DIGIT_HEIGHT);
+            }
+            Time::Common => self.text("C", center, 30.0, DIGIT_HEIGHT),
+            Time::Cut => {
+                self.text("C", center, 30.0, DIGIT_HEIGHT);
+                self.line(center, 4.0, center, 36.0, 1.5);
+            }
+        }
+        x + width
+    }
+
+    fn barline(&mut self, barline: Barline, x: f32) {
+        match barline {
+            Barline::Regular => self.line(x, 0.0, x, 40.0, STEM_WIDTH),
+            Barline::Double => {
+                self.line(x - 4.0, 0.0, x - 4.0, 40.0, STEM_WIDTH);
+                self.line(x, 0.0, x, 40.0, STEM_WIDTH);
+            }
+            Barline::Final => {
+                self.line(x - 7.0, 0.0, x - 7.0, 40.0, STEM_WIDTH);
+                self.line(x - 2.0, 0.0, x - 2.0, 40.0, 4.0);
+            }
+            Barline::RepeatEnd => {
+                self.repeat_dots(x - 12.0);
+                self.line(x - 7.0, 0.0, x - 7.0, 40.0, STEM_WIDTH);
+                self.line(x - 2.0, 0.0, x - 2.0, 40.0, 4.0);
+            }
+        }
+    }
+
+    /// Draws the thick and thin lines and dots that open a repeat at `x`.
+    fn repeat_start(&mut self, x: f32) -> f32 {
+        self.line(x + 2.0, 0.0, x + 2.0, 40.0, 4.0);
+        self.line(x + 7.0, 0.0, x + 7.0, 40.0, STEM_WIDTH);
+        self.repeat_dots(x + 12.0);
+        x + REPEAT_SPACE
+    }
+
+    fn repeat_dots(&mut self, x: f32) {
+        for y in [15.0, 25.0] {
+            let _ = write!(
+                self.body,
+                "<circle cx=\"{}\" cy=\"{}\" r=\"2\" stroke=\"none\"/>",
+                number(x),
+                number(y)
+            );
+        }
+    }
+
+    /// Draws a measure's notes from `x` and returns where its barline goes.
+    fn measure(&mut self, prepared: &Prepared, x: f32, stretch: f32) -> f32 {
+        let notes = &prepared.measure.notes;
+        let groups = beam_groups(notes);
+        let ups = stem_directions(notes, &prepared.heads, &groups);
+
+        let start = if prepared.measure.repeat_start { self.repeat_start(x) } else { x };
+        let mut x = start + MEASURE_PADDING;
+        let mut stems: Vec<Option<Stem>> = Vec::new();
+        let mut centers = Vec::new();
+        let mut whole_measure_rests = Vec::new();
+        for (i, note) in notes.iter().enumerate() {
+            let (lead, space) = prepared.columns[i];
+            let left = x + lead;
+            centers.push(left + HEAD_WIDTH / 2.0);
+            if note.whole_measure {
+                whole_measure_rests.push(note);
+                stems.push(None);
+            } else if note.rest {
+                self.rest(note, left);
+                stems.push(None);
+            } else {
+                stems.push(self.chord(note, &prepared.heads[i], left, ups[i]));
+            }
+            x += lead + space * stretch;
+        }
+        for note in whole_measure_rests {
+            let center = (start + MEASURE_PADDING + x) / 2.0;
+            self.glyph(&WHOLE_REST, center - 6.0, 10.0);
+            self.dots(note.dots, center + 10.0, &[5]);
+        }
+
+        let mut beamed = vec![false; notes.len()];
+        for group in &groups {
+            if group.iter().all(|&i| stems[i].is_some()) && group.len() > 1 {
+                self.beam(notes, group, &mut stems);
+                for &i in group {
+                    beamed[i] = true;
+                }
+            }
+        }
+        for (i, stem) in stems.iter().enumerate() {
+            if let Some(stem) = stem
+                && !beamed[i]
+            {
+                self.stem(stem, notes[i].kind.flags());
+            }
+        }
+
+        let mut i = 0;
+        while i < notes.len() {
+            let Some(count) = notes[i].tuplet else {
+                i += 1;
+                continue;
+            };
+            let end = i + notes[i..].iter().take(count as usize).take_while(|n| n.tuplet == Some(count)).count();
+            self.tuplet(count, &centers[i..end], &stems[i..end]);
+            i = end;
+        }
+
+        if prepared.measure.barline == Barline::RepeatEnd {
+            x += REPEAT_SPACE;
+        }
+        x
+    }
+
+    /// Numbers a tuplet on its stems' side, clear of its notes, or above the
+    /// staff for a tuplet of rests.
+    fn tuplet(&mut self, count: u32, centers: &[f32], stems: &[Option<Stem>]) {
+        let up = stems.iter().flatten().next().is_none_or(|stem| stem.up);
+        let mut ys = stems.iter().flatten().flat_map(|stem| [stem.base, stem.end]).peekable();
+        let center = (centers[0] + centers[centers.len() - 1]) / 2.0;
+        let baseline = if ys.peek().is_none() {
+            -5.0
+        } else if up {
+            ys.fold(f32::MAX, f32::min) - 5.0
+        } else {
+            ys.fold(f32::MIN, f32::max) + 5.0 + TUPLET_DIGIT_HEIGHT
+        };
+        self.text(&count.to_string(), center, baseline, TUPLET_DIGIT_HEIGHT);
+        self.extend(baseline - TUPLET_DIGIT_HEIGHT, baseline);
+    }
+
+    fn rest(&mut self, note: &Note, x: f32) {
+        let (glyph, y) = match note.kind {
+            NoteType::Breve | NoteType::Whole => (&WHOLE_REST, 10.0),
+            NoteType::Half => (&HALF_REST, 20.0),
+            NoteType::Quarter => (&QUARTER_REST, 20.0),
+            NoteType::Eighth => (&EIGHTH_REST, 20.0),
+            _ => (&SIXTEENTH_REST, 20.0),
+        };
+        self.glyph(glyph, x, y);
+        self.dots(note.dots, x + 12.0, &[5]);
+    }
+
+    /// Draws a column of dots after `x` for each staff position, moving dots
+    /// on lines up into the space above.
+    fn dots(&mut self, dots: u8, x: f32, positions: &[i32]) {
+        for &position in positions {
+            let position = if position % 2 == 0 { position + 1 } else { position };
+            for d in 0..dots {
+                let _ = write!(
+                    self.body,
+                    "<circle cx=\"{}\" cy=\"{}\" r=\"1.8\" stroke=\"none\"/>",
+                    number(x + 4.0 + d as f32 * 5.0),
+                    number(position_y(position))
+                );
+            }
+        }
+    }
+
+    /// Draws the noteheads, accidentals, ledger lines and dots of a note or
+    /// chord whose heads start at `left`, and returns its stem, if it has one.
+    fn chord(&mut self, note: &Note, heads: &[Head], left: f32, up: bool) -> Option<Stem> {
+        let whole = note.kind >= NoteType::Whole;
+        let (glyph, width) = match note.kind {
+            NoteType::Breve | NoteType::Whole => (&WHOLE_HEAD, WHOLE_HEAD_WIDTH),
+            NoteType::Half => (&HALF_HEAD, HEAD_WIDTH),
+            _ => (&FILLED_HEAD, HEAD_WIDTH),
+        };
+        let seconds = heads.windows(2).any(|pair| pair[1].position - pair[0].position == 1);
+        let shift = width - STEM_WIDTH;
+        // With a down stem, displaced heads go left, into the room left for them.
+        let center = left + width / 2.0 + if seconds { shift } else { 0.0 };
+
+        // In a second, the upper head of an up stem moves right of the stem
+        // and the lower head of a down stem moves left.
+        let mut offsets = vec![0.0; heads.len()];
+        if up {
+            for i in 1..heads.len() {
+                if heads[i].position - heads[i - 1].position == 1 && offsets[i - 1] == 0.0 {
+                    offsets[i] = shift;
+                }
+            }
+        } else {
+            for i in (0..heads.len().saturating_sub(1)).rev() {
+                if heads[i + 1].position - heads[i].position == 1 && offsets[i + 1] == 0.0 {
+                    offsets[i] = -shift;
+                }
+            }
+        }
+
+        let heads_left = center - width / 2.0 + offsets.iter().copied().fold(0.0, f32::min);
+        for (head, offset) in heads.iter().zip(&offsets) {
+            let x = center + offset;
+            let y = position_y(head.position);
+            for ledger in ledger_lines(head.position) {
+                let ledger_y = position_y(ledger);
+                self.line(x - width / 2.0 - 3.5, ledger_y, x + width / 2.0 + 3.5, ledger_y, LINE_WIDTH);
+            }
+            self.glyph(glyph, x, y);
+            if let Some(accidental) = head.accidental {
+                let accidental_x = heads_left - 2.0 - accidental.width() - head.accidental_column as f32 * 10.0;
+                self.glyph(accidental.glyph(), accidental_x, y);
+            }
+        }
+        let right = center + width / 2.0 + offsets.iter().copied().fold(0.0, f32::max);
+        let positions: Vec<i32> = heads.iter().map(|h| h.position).collect();
+        self.dots(note.dots, right, &positions);
+
+        if whole {
+            return None;
+        }
+        let lowest = position_y(heads.first()?.position);
+        let highest = position_y(heads.last()?.position);
+        // Extra flags lengthen the stem; stems reach at least the middle line.
+        let extra = note.kind.flags().saturating_sub(1) as f32 * 5.0;
+        Some(if up {
+            Stem {
+                x: center + width / 2.0 - STEM_WIDTH / 2.0,
+                base: lowest,
+                near: highest,
+                end: (highest - STEM_LENGTH - extra).min(20.0),
+                up,
+            }
+        } else {
+            Stem {
+                x: center - width / 2.0 + STEM_WIDTH / 2.0,
+                base: highest,
+                near: lowest,
+                end: (lowest + STEM_LENGTH + extra).max(20.0),
+                up,
+            }
+        })
+    }
+
+    fn stem(&mut self, stem: &Stem, flags: usize) {
+        self.line(stem.x, stem.base, stem.x, stem.end, STEM_WIDTH);
+        let x = stem.x - STEM_WIDTH / 2.0;
+        for f in 0..flags {
+            if stem.up {
+                self.glyph(&FLAG, x, stem.end + f as f32 * BEAM_SPACING);
+            } else {
+                self.flipped_glyph(&FLAG, x, stem.end - f as f32 * BEAM_SPACING);
+            }
+        }
+    }
+
+    /// Joins a group's stems with a straight beam, sloped with the melody but
+    /// by no more than a staff space, and moved clear of every notehead.
+    fn beam(&mut self, notes: &[Note], group: &[usize], stems: &mut [Option<Stem>]) {
+        let levels = group.iter().map(|&i| notes[i].beams.max(1)).max().unwrap_or(1);
+        let (first, last) = (stems[group[0]].as_ref().unwrap(), stems[*group.last().unwrap()].as_ref().unwrap());
+        let up = first.up;
+        let x0 = first.x;
+        let dx = last.x - first.x;
+        let slope = if dx > 0.0 { ((last.end - first.end) / dx).clamp(-10.0 / dx, 10.0 / dx) } else { 0.0 };
+        let inner = STEM_LENGTH - 5.0 + (levels - 1) as f32 * BEAM_SPACING;
+
+        let mut y0 = first.end;
+        for &i in group {
+            let stem = stems[i].as_ref().unwrap();
+            let beam_y = y0 + slope * (stem.x - x0);
+            if up && beam_y > stem.near - inner {
+                y0 -= beam_y - (stem.near - inner);
+            } else if !up && beam_y < stem.near + inner {
+                y0 += stem.near + inner - beam_y;
+            }
+        }
+        let beam_y = |x: f32| y0 + slope * (x - x0);
+
+        for &i in group {
+            let stem = stems[i].as_mut().unwrap();
+            stem.end = beam_y(stem.x);
+            let stem = stems[i].as_ref().unwrap();
+            self.line(stem.x, stem.base, stem.x, stem.end, STEM_WIDTH);
+        }
+
+        let direction = if up { 1.0 } else { -1.0 };
+        for level in 1..=levels {
+            let offset = (level - 1) as f32 * BEAM_SPACING * direction;
+            for (k, &i) in group.iter().enumerate() {
+                if notes[i].beams.max(1) < level {
+                    continue;
+                }
+                let x = stems[i].as_ref().unwrap().x;
+                let next = group.get(k + 1).filter(|&&j| notes[j].beams.max(1) >= level);
+                let previous = k.checked_sub(1).map(|p| group[p]).filter(|&j| notes[j].beams.max(1) >= level);
+                let (from, to) = match (next, previous) {
+                    (Some(&j), _) => (x, stems[j].as_ref().unwrap().x),
+                    (None, Some(_)) => continue,
+                    // A lone secondary beam is a short hook toward its neighbour.
+                    (None, None) if k == 0 => (x, x + 8.0),
+                    (None, None) => (x - 8.0, x),
+                };
+                self.beam_segment(from - STEM_WIDTH / 2.0, to + STEM_WIDTH / 2.0, &beam_y, offset, up);
+            }
+        }
+    }
+
+    fn beam_segment(&mut self, from: f32, to: f32, beam_y: &dyn Fn(f32) -> f32, offset: f32, up: bool) {
+        let thickness = if up { BEAM_THICKNESS } else { -BEAM_THICKNESS };
+        let (y1, y2) = (beam_y(from) + offset, beam_y(to) + offset);
+        let _ = write!(
+            self.body,
+            "<path d=\"M{} {}L{} {}L{} {}L{} {}Z\" stroke=\"none\"/>",
+            number(from),
+            number(y1),
+            number(to),
+            number(y2),
+            number(to),
+            number(y2 + thickness),
+            number(from),
+            number(y1 + thickness)
+        );
+        self.extend(y1.min(y2).min(y1 + thickness), y1.max(y2).max(y2 + thickness));
+    }
+}
+
+/// Staff positions of the ledger lines a notehead needs.
+fn ledger_lines(position: i32) -> Vec<i32> {
+    if position <= -2 {
+        (position..=-2).filter(|p| p % 2 == 0).collect()
+    } else if position >= 10 {
+        (10..=position).filter(|p| p % 2 == 0).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Whether each note's stem points up. A note's stem goes away from the
+/// middle line, down for notes on it, and a beamed group follows the note
+/// furthest from the middle line. Directions in the file take precedence.
+fn stem_directions(notes: &[Note], heads: &[Vec<Head>], groups: &[Vec<usize>]) -> Vec<bool> {
+    let extremes = |i: usize| {
+        let low = heads[i].first().map_or(4, |h| h.position);
+        let high = heads[i].last().map_or(4, |h| h.position);
+        (low, high)
+    };
+    let mut ups: Vec<bool> = notes
+        .iter()
+        .enumerate()
+        .map(|(i, note)| {
+            note.stem_up.unwrap_or_else(|| {
+                let (low, high) = extremes(i);
+                high - 4 < 4 - low
+            })
+        })
+        .collect();
+    for group in groups {
+        let up = group.iter().find_map(|&i| notes[i].stem_up).unwrap_or_else(|| {
+            let (low, high) = group.iter().fold((i32::MAX, i32::MIN), |(low, high), &i| {
+                let (l, h) = extremes(i);
+                (low.min(l), high.max(h))
+            });
+            high - 4 < 4 - low
+        });
+        for &i in group {
+            ups[i] = up;
+        }
+    }
+    ups
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use crate::qti::generator::latex_to_mathml;
+use crate::qti::mathml::{parse_mathml, MathNode};
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use std::io::Cursor;
+
+// Converts the MathML produced by latex2mathml into Office Math Markup
+// (OMML), the equation format Word edits natively. Only the presentation
+// elements latex2mathml emits are mapped; anything else contributes its
+// children so no content is dropped.
+
+/// Writes `<m:oMath>` for the given LaTeX, or returns an error if
+/// latex2mathml cannot parse it.
+pub fn write_latex_omml(writer: &mut Writer<Cursor<Vec<u8>>>, latex: &str) -> Result<()> {
+    let mathml = latex_to_mathml(latex, false)?;
+    let root = parse_mathml(&mathml)?;
+
+    writer.write_event(Event::Start(BytesStart::new("m:oMath")))?;
+    write_children(writer, &root)?;
+    writer.write_event(Event::End(BytesEnd::new("m:oMath")))?;
+    Ok(())
+}
+
+fn write_children(writer: &mut Writer<Cursor<Vec<u8>>>, node: &MathNode) -> Result<()> {
+    for child in &node.children {
+        write_node(writer, child)?;
+    }
+    Ok(())
+}
+
+fn write_wrapped(writer: &mut Writer<Cursor<Vec<u8>>>, tag: &str, node: Option<&MathNode>) -> Result<()> {
+    writer.write_event(Event::Start(BytesStart::new(tag)))?;
+    if let Some(node) = node {
+        write_node(writer, node)?;
+    }
+    writer.write_event(Event::End(BytesEnd::new(tag)))?;
+    Ok(())
+}
+
+fn write_run(writer: &mut Writer<Cursor<Vec<u8>>>, text: &str, upright: bool) -> Result<()> {
+    writer.write_event(Event::Start(BytesStart::new("m:r")))?;
+    if upright {
+        writer.write_event(Event::Start(BytesStart::new("m:rPr")))?;
+        let mut sty = BytesStart::new("m:sty");
+        sty.push_attribute(("m:val", "p"));
+        writer.write_event(Event::Empty(sty))?;
+        writer.write_event(Event::End(BytesEnd::new("m:rPr")))?;
+    }
+    let mut t = BytesStart::new("m:t");
+    t.push_attribute(("xml:space", "preserve"));
+    writer.write_event(Event::Start(t))?;
+    writer.write_event(Event::Text(BytesText::new(text)))?;
+    writer.write_event(Event::End(BytesEnd::new("m:t")))?;
+    writer.write_event(Event::End(BytesEnd::new("m:r")))?;
+    Ok(())
+}
+
+fn write_node(writer: &mut Writer<Cursor<Vec<u8>>>, node: &MathNode) -> Result<()> {
+    let child = |i: usize| node.children.get(i);
+
+    match node.name.as_str() {
+        "mi" => {
+            let upright = node.attr("mathvariant") == Some("normal") || node.text.chars().count() > 1;
+            write_run(writer, &node.text, upright)?;
+        }
+        "mn" | "mo" | "mtext" | "ms" => write_run(writer, &node.text, true)?,
+        "mspace" => write_run(writer, " ", true)?,
+        "mfrac" => {
+            writer.write_event(Event::Start(BytesStart::new("m:f")))?;
+            write_wrapped(writer, "m:num", child(0))?;
+            write_wrapped(writer, "m:den", child(1))?;
+            writer.write_event(Event::End(BytesEnd::new("m:f")))?;
+        }
+        "msqrt" => {
+            writer.write_event(Event::Start(BytesStart::new("m:rad")))?;
+            writer.write_event(Event::Start(BytesStart::new("m:radPr")))?;
+            let mut deg_hide = BytesStart::new("m:degHide");
+            deg_hide.push_attribute(("m:val", "1"));
+            writer.write_event(Event::Empty(deg_hide))?;
+            writer.write_event(Event::End(BytesEnd::new("m:radPr")))?;
+            writer.write_event(Event::Empty(BytesStart::new("m:deg")))?;
+            writer.write_event(Event::Start(BytesStart::new("m:e")))?;
+            write_children(writer, node)?;
+            writer.write_event(Event::End(BytesEnd::new("m:e")))?;
+            writer.write_event(Event::End(BytesEnd::new("m:rad")))?;
+        }
+        "mroot" => {
+            writer.write_event(Event::Start(BytesStart::new("m:rad")))?;
+            write_wrapped(writer, "m:deg", child(1))?;
+            write_wrapped(writer, "m:e", child(0))?;
+            writer.write_event(Event::End(BytesEnd::new("m:rad")))?;
+        }
+        "msup" => {
+            writer.write_event(Event::Start(BytesStart::new("m:sSup")))?;
+            write_wrapped(writer, "m:e", child(0))?;
+            write_wrapped(writer, "m:sup", child(1))?;
+            writer.write_event(Event::End(BytesEnd::new("m:sSup")))?;
+        }
+        "msub" => {
+            writer.write_event(Event::Start(BytesStart::new("m:sSub")))?;
+            write_wrapped(writer, "m:e", child(0))?;
+            write_wrapped(writer, "m:sub", child(1))?;
+            writer.write_event(Event::End(BytesEnd::new("m:sSub")))?;
+        }
+        "msubsup" => {
+            writer.write_event(Event::Start(BytesStart::new("m:sSubSup")))?;
+            write_wrapped(writer, "m:e", child(0))?;
+            write_wrapped(writer, "m:sub", child(1))?;
+            write_wrapped(writer, "m:sup", child(2))?;
+            writer.write_event(Event::End(BytesEnd::new("m:sSubSup")))?;
+        }
+        "munder" => {
+            writer.write_event(Event::Start(BytesStart::new("m:limLow")))?;
+            write_wrapped(writer, "m:e", child(0))?;
+            write_wrapped(writer, "m:lim", child(1))?;
+            writer.write_event(Event::End(BytesEnd::new("m:limLow")))?;
+        }
+        "mover" => {
+            let accent = child(1).filter(|c| c.attr("accent") == Some("true") || node.attr("accent") == Some("true"));
+            if let Some(accent) = accent {
+                writer.write_event(Event::Start(BytesStart::new("m:acc")))?;
+                writer.write_event(Event::Start(BytesStart::new("m:accPr")))?;
+                let mut chr = BytesStart::new("m:chr");
+                let symbol = if accent.text == "_" { "\u{0305}" } else { accent.text.as_str() };
+                chr.push_attribute(("m:val", symbol));
+                writer.write_event(Event::Empty(chr))?;
+                writer.write_event(Event::End(BytesEnd::new("m:accPr")))?;
+                write_wrapped(writer, "m:e", child(0))?;
+                writer.write_event(Event::End(BytesEnd::new("m:acc")))?;
+            } else {
+                writer.write_event(Event::Start(BytesStart::new("m:limUpp")))?;
+                write_wrapped(writer, "m:e", child(0))?;
+                write_wrapped(writer, "m:lim", child(1))?;
+                writer.write_event(Event::End(BytesEnd::new("m:limUpp")))?;
+            }
+        }
+        "munderover" => {
+            writer.write_event(Event::Start(BytesStart::new("m:limUpp")))?;
+            writer.write_event(Event::Start(BytesStart::new("m:e")))?;
+            writer.write_event(Event::Start(BytesStart::new("m:limLow")))?;
+            write_wrapped(writer, "m:e", child(0))?;
+            write_wrapped(writer, "m:lim", child(1))?;
+            writer.write_event(Event::End(BytesEnd::new("m:limLow")))?;
+            writer.write_event(Event::End(BytesEnd::new("m:e")))?;
+            write_wrapped(writer, "m:lim", child(2))?;
+            writer.write_event(Event::End(BytesEnd::new("m:limUpp")))?;
+        }
+        "mtable" => {
+            writer.write_event(Event::Start(BytesStart::new("m:m")))?;
+            for row in &node.children {
+                writer.write_event(Event::Start(BytesStart::new("m:mr")))?;
+                for cell in &row.children {
+                    writer.write_event(Event::Start(BytesStart::new("m:e")))?;
+                    write_children(writer, cell)?;
+                    writer.write_event(Event::End(BytesEnd::new("m:e")))?;
+                }
+                writer.write_event(Event::End(BytesEnd::new("m:mr")))?;
+            }
+            writer.write_event(Event::End(BytesEnd::new("m:m")))?;
+        }
+        "annotation" | "annotation-xml" => {}
+        // mrow, mstyle, semantics and anything unrecognised
+        _ => {
+            if !node.text.trim().is_empty() {
+                write_run(writer, &node.text, true)?;
+            }
+            write_children(writer, node)?;
+        }
+    }
+    Ok(())
+}
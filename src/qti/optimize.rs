@@ -0,0 +1,146 @@
+use anyhow::{bail, Result};
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+// Shrinking images as they are packed: lossless PNG recompression with
+// optional downscaling, and SVG minification. JPEG and GIF are copied as
+// they are, since there is no encoder for them here.
+
+/// Whether a file is already compressed, so deflating it again would cost
+/// time for no gain.
+pub(crate) fn is_precompressed(name: &str) -> bool {
+    let extension = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp" | "zip")
+}
+
+/// Returns `data`, the contents of `name`, made smaller where possible. PNGs
+/// wider than `max_width` are scaled down to it.
+pub(crate) fn optimize_image(name: &str, data: Vec<u8>, max_width: Option<u32>) -> Result<Vec<u8>> {
+    let extension = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    let optimized = match extension.as_str() {
+        "png" => recompress_png(&data, max_width)?,
+        "svg" => minify_svg(std::str::from_utf8(&data)?)?.into_bytes(),
+        _ => return Ok(data),
+    };
+    // A resized image is kept even if larger; otherwise keep the smaller.
+    let resized = extension == "png" && max_width.is_some_and(|max| png_width(&data).is_some_and(|w| w > max));
+    Ok(if resized || optimized.len() < data.len() { optimized } else { data })
+}
+
+fn png_width(data: &[u8]) -> Option<u32> {
+    // The width is the first field of the IHDR chunk.
+    data.get(16..20).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Re-encodes a PNG at the best compression with adaptive filtering,
+/// downscaling it first if it is wider than `max_width`.
+fn recompress_png(data: &[u8], max_width: Option<u32>) -> Result<Vec<u8>> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    if reader.info().is_animated() {
+        bail!("animated PNGs are not recompressed");
+    }
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut pixels)?;
+    pixels.truncate(frame.buffer_size());
+    let channels = frame.color_type.samples();
+    let (mut width, mut height) = (frame.width, frame.height);
+
+    if let Some(max_width) = max_width
+        && width > max_width
+    {
+        let new_height = ((height as u64 * max_width as u64 + width as u64 / 2) / width as u64).max(1) as u32;
+        pixels = downscale(&pixels, channels, width, height, max_width, new_height);
+        (width, height) = (max_width, new_height);
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(frame.color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Best);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// Box-filter downscaling: each output pixel averages the source pixels it
+/// covers. Colour is weighted by alpha, so transparent pixels do not darken
+/// the edges of what they surround.
+fn downscale(pixels: &[u8], channels: usize, width: u32, height: u32, new_width: u32, new_height: u32) -> Vec<u8> {
+    let (width, height, new_width, new_height) = (width as usize, height as usize, new_width as usize, new_height as usize);
+    let alpha = if channels == 2 || channels == 4 { Some(channels - 1) } else { None };
+    let span = |i: usize, from: usize, to: usize| {
+        let start = i * from / to;
+        (start, ((i + 1) * from / to).max(start + 1))
+    };
+
+    let mut out = Vec::with_capacity(new_width * new_height * channels);
+    for y in 0..new_height {
+        let (y0, y1) = span(y, height, new_height);
+        for x in 0..new_width {
+            let (x0, x1) = span(x, width, new_width);
+            let mut sums = [0u64; 4];
+            let mut weight = 0u64;
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let pixel = &pixels[(sy * width + sx) * channels..][..channels];
+                    let w = alpha.map_or(1, |a| pixel[a] as u64);
+                    weight += w;
+                    for (c, value) in pixel.iter().enumerate() {
+                        sums[c] += if Some(c) == alpha { *value as u64 } else { *value as u64 * w };
+                    }
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as u64;
+            for (c, sum) in sums.iter().take(channels).enumerate() {
+                let divisor = if Some(c) == alpha || alpha.is_none() { count } else { weight };
+                out.push((sum + divisor / 2).checked_div(divisor).unwrap_or(0) as u8);
+            }
+        }
+    }
+    out
+}
+
+/// Elements whose whitespace is content, not layout.
+const TEXT_ELEMENTS: &[&[u8]] = &[b"text", b"tspan", b"textPath", b"title", b"desc", b"style", b"script"];
+
+/// Removes comments, editor metadata and whitespace between tags from an
+/// SVG. The drawing itself is unchanged.
+pub(crate) fn minify_svg(svg: &str) -> Result<String> {
+    let mut reader = Reader::from_str(svg);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    // Depth inside text-like elements, and inside `<metadata>`, which is
+    // dropped along with its contents.
+    let (mut in_text, mut in_metadata) = (0usize, 0usize);
+
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Eof => break,
+            Event::Start(e) if in_metadata > 0 || e.local_name().as_ref() == b"metadata" => {
+                in_metadata += 1;
+                continue;
+            }
+            Event::End(_) if in_metadata > 0 => {
+                in_metadata -= 1;
+                continue;
+            }
+            _ if in_metadata > 0 => continue,
+            Event::Empty(e) if e.local_name().as_ref() == b"metadata" => continue,
+            Event::Comment(_) => continue,
+            // Keep a doctype that declares entities the document uses.
+            Event::DocType(doctype) if !doctype.contains(&b'[') => continue,
+            Event::Start(e) if TEXT_ELEMENTS.contains(&e.local_name().as_ref()) => in_text += 1,
+            Event::End(e) if TEXT_ELEMENTS.contains(&e.local_name().as_ref()) => in_text = in_text.saturating_sub(1),
+            Event::Text(text) if in_text == 0 && text.iter().all(u8::is_ascii_whitespace) => continue,
+            _ => {}
+        }
+        writer.write_event(event)?;
+    }
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
@@ -1,6 +1,15 @@
-use anyhow::{Context, Result};
-use crate::qti::model::{Quiz, Question, QuestionType, Answer};
+use anyhow::Result;
+use crate::qti::diagnostics::{render_all, suggest, Diagnostic, Severity};
+use crate::qti::model::{Quiz, Question, QuestionType, Answer, MathRendering, CodeOptions, ImageOptions, RemoteImages};
+use crate::qti::generator::item_identifiers;
+use crate::qti::images::{image_source, ImageSource};
+use crate::qti::lexer::{lex_content_spanned, Token};
+use crate::qti::highlight;
+use regex::Regex;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
 
 #[derive(Deserialize)]
 struct FrontMatter {
@@ -8,62 +17,504 @@ struct FrontMatter {
     description: Option<String>,
     #[serde(default)]
     shuffle_answers: bool,
+    #[serde(default)]
+    math: MathRendering,
+    #[serde(default)]
+    code: CodeOptions,
+    #[serde(default)]
+    images: ImageOptions,
 }
 
-pub fn parse_quiz(content: &str) -> Result<Quiz> {
+const QUESTION_TYPES: &[&str] = &[
+    "multiple_choice",
+    "multiple_answers",
+    "true_false",
+    "short_answer",
+    "essay",
+    "file_upload",
+];
+
+const QUESTION_ATTRIBUTES: &[&str] = &["id", "type", "points", "title", "tags"];
+
+const INCLUDE_OPTIONS: &[&str] = &["tags"];
+
+/// Parses a quiz read from `path`, printing any warnings to stderr, and
+/// returns it with the number of warnings. If the file has errors, all
+/// diagnostics are returned rendered against the file.
+pub fn parse_quiz(content: &str, path: &Path) -> Result<(Quiz, usize)> {
+    let file_name = &path.display().to_string();
+    let (quiz, diagnostics) = check_quiz(content, path);
+    match quiz {
+        Some(quiz) if !diagnostics.iter().any(|d| d.severity == Severity::Error) => {
+            if !diagnostics.is_empty() {
+                eprint!("{}", render_all(&diagnostics, file_name, content));
+            }
+            Ok((quiz, diagnostics.len()))
+        }
+        _ => Err(anyhow::anyhow!("{}", render_all(&diagnostics, file_name, content))),
+    }
+}
+
+/// Adds `id=` attributes to question headers. `ids` pairs the 1-based line
+/// of a `:::{.question ...}` header with the id to give it; the rest of the
+/// source, line endings included, is left as it was.
+pub fn insert_question_ids(content: &str, ids: &[(usize, String)]) -> String {
+    let mut out = String::with_capacity(content.len() + ids.len() * 24);
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        let id = ids.iter().find(|(line_no, _)| *line_no == i + 1).map(|(_, id)| id);
+        match (id, line.rfind('}')) {
+            (Some(id), Some(close)) if line.trim_start().starts_with(":::{.question") => {
+                let attributes = line[..close].trim_end();
+                out.push_str(attributes);
+                out.push_str(" id=");
+                out.push_str(id);
+                out.push_str(&line[close..]);
+            }
+            _ => out.push_str(line),
+        }
+    }
+    out
+}
+
+/// Whether `content` starts with front matter, as a quiz does. Banks of
+/// questions for other quizzes to include may leave it out.
+pub fn has_front_matter(content: &str) -> bool {
+    matches!(content.splitn(3, "---").collect::<Vec<_>>().as_slice(), [before, _, _] if before.trim().is_empty())
+}
+
+/// Parses a quiz read from `path` and returns every diagnostic found along
+/// the way. The quiz is `None` only when the front matter is unusable;
+/// otherwise it holds the questions that parsed, even if others had errors.
+/// Files named by `{{< include >}}` lines are resolved relative to `path`.
+pub fn check_quiz(content: &str, path: &Path) -> (Option<Quiz>, Vec<Diagnostic>) {
+    let mut diagnostics = Vec::new();
+
     let parts: Vec<&str> = content.splitn(3, "---").collect();
     if parts.len() < 3 {
-        anyhow::bail!("Invalid file format: Missing YAML front matter");
+        diagnostics.push(
+            Diagnostic::error("missing YAML front matter", 1, 1, 1)
+                .with_hint("start the file with a `---` line, then `title: ...`, then another `---` line"),
+        );
+        return (None, diagnostics);
     }
 
     let front_matter_str = parts[1];
     let markdown_content = parts[2];
 
-    let front_matter: FrontMatter = serde_yaml::from_str(front_matter_str)
-        .context("Failed to parse YAML front matter")?;
+    // 1-based line on which each part starts.
+    let front_matter_line = 1 + parts[0].matches('\n').count();
+    let markdown_line = front_matter_line + front_matter_str.matches('\n').count();
+
+    let front_matter: FrontMatter = match serde_yaml::from_str(front_matter_str) {
+        Ok(front_matter) => front_matter,
+        Err(e) => {
+            let (line, column) = e
+                .location()
+                .map(|loc| (front_matter_line + loc.line() - 1, loc.column()))
+                .unwrap_or((front_matter_line, 1));
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or(&message);
+            diagnostics.push(
+                Diagnostic::error(format!("failed to parse YAML front matter: {}", message), line, column, 1)
+                    .with_hint("the front matter needs at least `title:`; `description:`, `shuffle_answers:`, `math:`, `code:` and `images:` are optional"),
+            );
+            return (None, diagnostics);
+        }
+    };
+
+    if let Some(theme) = &front_matter.code.theme
+        && !theme.ends_with(".tmTheme")
+    {
+        let names = highlight::theme_names();
+        if !names.contains(&theme.as_str()) {
+            let (line, column) = front_matter_str
+                .lines()
+                .enumerate()
+                .find(|(_, l)| l.trim_start().starts_with("theme:"))
+                .and_then(|(i, l)| l.find(theme.as_str()).map(|offset| (front_matter_line + i, column_of(l, &l[offset..]))))
+                .unwrap_or((front_matter_line, 1));
+            let hint = match suggest(theme, &names) {
+                Some(s) => format!("did you mean `{}`?", s),
+                None => format!("use a `.tmTheme` file or one of: {}", names.join(", ")),
+            };
+            diagnostics.push(
+                Diagnostic::error(format!("unknown code theme `{}`", theme), line, column, theme.chars().count().max(1)).with_hint(hint),
+            );
+        }
+    }
 
-    let questions = parse_questions(markdown_content)?;
+    let mut includes = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+    let remote = front_matter.images.remote;
+    let questions = parse_questions(markdown_content, markdown_line, path, remote, &mut includes, &mut diagnostics);
+    if front_matter.images.remote == RemoteImages::Error {
+        check_remote_images(&questions, content, &mut diagnostics);
+    }
 
-    Ok(Quiz {
+    let quiz = Quiz {
         title: front_matter.title,
         description: front_matter.description,
         shuffle_answers: front_matter.shuffle_answers,
+        math: front_matter.math,
+        code: front_matter.code,
+        images: front_matter.images,
         questions,
-    })
+    };
+    (Some(quiz), diagnostics)
 }
 
-fn parse_questions(content: &str) -> Result<Vec<Question>> {
+/// Parses the question blocks in `content`, which starts on `first_line` of
+/// the file at `path`. `includes` holds the files being included, outermost
+/// first, so an include cycle can be reported. `remote` is the including
+/// quiz's setting, which applies to the questions it includes.
+fn parse_questions(
+    content: &str,
+    first_line: usize,
+    path: &Path,
+    remote: RemoteImages,
+    includes: &mut Vec<PathBuf>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<Question> {
     let mut questions = Vec::new();
-    let mut current_lines: Vec<&str> = Vec::new();
+    let mut current_lines: Vec<(usize, &str)> = Vec::new();
     let mut in_question_block = false;
+    // Explicit id -> line of the question that first used it.
+    let mut seen_ids: HashMap<String, usize> = HashMap::new();
 
-    for line in content.lines() {
+    for (i, line) in content.lines().enumerate() {
+        let line_no = first_line + i;
+        if !in_question_block && line.trim_start().starts_with("{{< include") {
+            for question in include_questions(line, line_no, path, remote, includes, diagnostics) {
+                if let Some(id) = &question.id {
+                    if let Some(first_line) = seen_ids.get(id) {
+                        diagnostics.push(
+                            Diagnostic::error(format!("duplicate question id `{}`", id), line_no, 1, line.trim_end().chars().count())
+                                .with_hint(format!("this include brings in `{}`, which is already used on line {}", id, first_line)),
+                        );
+                        continue;
+                    }
+                    seen_ids.insert(id.clone(), line_no);
+                }
+                questions.push(question);
+            }
+            continue;
+        }
         if line.trim().starts_with(":::{.question") {
             if in_question_block {
-                // This shouldn't happen in valid format, but handle it by closing previous
-                if !current_lines.is_empty() {
-                    questions.push(parse_single_question(&current_lines)?);
-                    current_lines.clear();
-                }
+                diagnostics.push(unclosed_block(&current_lines).with_hint(
+                    "add a `:::` line to close this question before the next one starts",
+                ));
+                current_lines.clear();
             }
             in_question_block = true;
-            current_lines.push(line);
+            current_lines.push((line_no, line));
         } else if line.trim() == ":::" && in_question_block {
-            current_lines.push(line);
-            questions.push(parse_single_question(&current_lines)?);
+            current_lines.push((line_no, line));
+            if let Some(question) = parse_single_question(&current_lines, diagnostics) {
+                if let Some(id) = &question.id {
+                    match seen_ids.get(id) {
+                        Some(first_line) => {
+                            let header = current_lines[0].1;
+                            let column = header.find(&format!("id={}", id)).map_or(1, |offset| column_of(header, &header[offset..]));
+                            diagnostics.push(
+                                Diagnostic::error(format!("duplicate question id `{}`", id), question.line, column, id.chars().count() + 3)
+                                    .with_hint(format!("the same id is used on line {}; ids must be unique within a quiz", first_line)),
+                            );
+                        }
+                        None => {
+                            seen_ids.insert(id.clone(), question.line);
+                        }
+                    }
+                }
+                questions.push(question);
+            }
             current_lines.clear();
             in_question_block = false;
         } else if in_question_block {
-            current_lines.push(line);
+            current_lines.push((line_no, line));
+        }
+    }
+
+    if in_question_block {
+        diagnostics.push(unclosed_block(&current_lines).with_hint("add a `:::` line at the end of the question"));
+    }
+
+    questions
+}
+
+fn unclosed_block(lines: &[(usize, &str)]) -> Diagnostic {
+    let (line_no, header) = lines[0];
+    let indent = header.len() - header.trim_start().len();
+    Diagnostic::error("unclosed question block", line_no, indent + 1, header.trim().chars().count())
+}
+
+/// Reports every remote image in the questions written in `content`, for
+/// quizzes with `images: {remote: error}`. Included questions are checked
+/// against their own file by `include_questions`.
+fn check_remote_images<'a>(questions: impl IntoIterator<Item = &'a Question>, content: &str, diagnostics: &mut Vec<Diagnostic>) {
+    for question in questions.into_iter().filter(|q| q.source.is_none()) {
+        for (token, line, column, length) in question_tokens(content, question) {
+            let Token::Image { src, .. } = token else { continue };
+            if !matches!(image_source(&src), ImageSource::Remote(_)) {
+                continue;
+            }
+            diagnostics.push(
+                Diagnostic::error(format!("remote image `{}`", src), line, column, length)
+                    .with_hint("this quiz sets `images: {remote: error}`; save the image next to the quiz, or use `remote: fetch` to package a copy"),
+            );
+        }
+    }
+}
+
+/// The tokens of `question`'s block in `content`, the file it was parsed
+/// from, each with the 1-based line and column it starts at and its length
+/// in characters on that line.
+pub(crate) fn question_tokens(content: &str, question: &Question) -> Vec<(Token, usize, usize, usize)> {
+    let start: usize = content.split_inclusive('\n').take(question.line).map(str::len).sum();
+    let body_len: usize = content[start..]
+        .split_inclusive('\n')
+        .take_while(|line| line.trim() != ":::")
+        .map(str::len)
+        .sum();
+    let body = &content[start..start + body_len];
+    lex_content_spanned(body)
+        .into_iter()
+        .map(|(token, span)| {
+            let before = &body[..span.start];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            let line = question.line + 1 + before.matches('\n').count();
+            let column = before[line_start..].chars().count() + 1;
+            let length = body[span].lines().next().unwrap_or("").chars().count();
+            (token, line, column, length.max(1))
+        })
+        .collect()
+}
+
+/// Questions pulled in by an include line: every question in a file with
+/// `{{< include bank.qmd >}}`, one by id with `{{< include bank.qmd#id >}}`,
+/// or those with any of the given tags with `{{< include bank.qmd
+/// tags=a,b >}}`. Image paths in them are made relative to `path`.
+fn include_questions(
+    line: &str,
+    line_no: usize,
+    path: &Path,
+    remote: RemoteImages,
+    includes: &mut Vec<PathBuf>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<Question> {
+    let trimmed = line.trim();
+    let indent = line.len() - line.trim_start().len();
+    let whole = (indent + 1, trimmed.chars().count());
+    let Some(inner) = trimmed.strip_prefix("{{<").and_then(|rest| rest.strip_suffix(">}}")) else {
+        diagnostics.push(Diagnostic::error("unclosed include", line_no, whole.0, whole.1).with_hint("end the line with `>}}`"));
+        return Vec::new();
+    };
+    let mut words = inner.split_whitespace().skip(1);
+    let Some(target) = words.next() else {
+        diagnostics.push(
+            Diagnostic::error("include without a file", line_no, whole.0, whole.1).with_hint("write `{{< include bank.qmd >}}`"),
+        );
+        return Vec::new();
+    };
+    let (file, id) = match target.split_once('#') {
+        Some((file, id)) => (file, Some(id)),
+        None => (target, None),
+    };
+    let target_span = (column_of(line, target), target.chars().count());
+
+    let mut tags: Vec<&str> = Vec::new();
+    for option in words {
+        match option.split_once('=') {
+            Some(("tags", value)) => tags.extend(value.split(',').filter(|tag| !tag.is_empty())),
+            _ => {
+                let key = option.split('=').next().unwrap_or(option);
+                let hint = match suggest(key, INCLUDE_OPTIONS) {
+                    Some(s) => format!("did you mean `{}`?", s),
+                    None => format!("known options: {}", INCLUDE_OPTIONS.join(", ")),
+                };
+                diagnostics.push(
+                    Diagnostic::warning(format!("unknown include option `{}`", option), line_no, column_of(line, option), option.chars().count())
+                        .with_hint(hint),
+                );
+            }
+        }
+    }
+
+    let include_path = path.parent().unwrap_or(Path::new("")).join(file);
+    let (Ok(canonical), Ok(content)) = (include_path.canonicalize(), std::fs::read_to_string(&include_path)) else {
+        diagnostics.push(
+            Diagnostic::error(format!("included file not found: {}", file), line_no, target_span.0, target_span.1)
+                .with_hint("include paths are resolved relative to the including file"),
+        );
+        return Vec::new();
+    };
+    if let Some(start) = includes.iter().position(|p| *p == canonical) {
+        let chain: Vec<String> = includes[start..].iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+        diagnostics.push(
+            Diagnostic::error(format!("include cycle: {}", chain.join(" -> ")), line_no, target_span.0, target_span.1)
+                .with_hint("a file cannot include itself, directly or through other files"),
+        );
+        return Vec::new();
+    }
+
+    // A bank may be a quiz of its own, with front matter, or just questions.
+    let (markdown, first_line) = match content.splitn(3, "---").collect::<Vec<_>>().as_slice() {
+        [before, front_matter, markdown] if before.trim().is_empty() => {
+            (*markdown, 1 + before.matches('\n').count() + front_matter.matches('\n').count())
+        }
+        _ => (content.as_str(), 1),
+    };
+    includes.push(canonical);
+    let mut nested = Vec::new();
+    let questions = parse_questions(markdown, first_line, &include_path, remote, includes, &mut nested);
+    includes.pop();
+
+    let errors: Vec<String> = nested
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(|d| d.render(&include_path.display().to_string(), &content).trim_end().to_string())
+        .collect();
+    if !errors.is_empty() {
+        diagnostics.push(
+            Diagnostic::error(format!("included file {} has errors", file), line_no, target_span.0, target_span.1)
+                .with_hint(errors.join("\n")),
+        );
+        return Vec::new();
+    }
+
+    // Ids are derived from the bank's own text and order, before image paths
+    // are rebased, so a question keeps the id it gets when the bank itself
+    // is converted.
+    let derived = item_identifiers(&questions);
+    let questions: Vec<(Question, String)> = questions.into_iter().zip(derived).collect();
+
+    let selected: Vec<(Question, String)> = match id {
+        Some(id) => {
+            let ids: Vec<&str> = questions.iter().filter_map(|(q, _)| q.id.as_deref()).collect();
+            if !ids.contains(&id) {
+                let hint = match suggest(id, &ids) {
+                    Some(s) => format!("did you mean `{}`?", s),
+                    None if ids.is_empty() => format!("no question in {} has an `id=`", file),
+                    None => format!("ids in {}: {}", file, ids.join(", ")),
+                };
+                diagnostics.push(
+                    Diagnostic::error(format!("no question with id `{}` in {}", id, file), line_no, target_span.0, target_span.1)
+                        .with_hint(hint),
+                );
+                return Vec::new();
+            }
+            questions.into_iter().filter(|(q, _)| q.id.as_deref() == Some(id)).collect()
         }
+        None => questions
+            .into_iter()
+            .filter(|(q, _)| tags.is_empty() || q.tags.iter().any(|t| tags.contains(&t.as_str())))
+            .collect(),
+    };
+    if selected.is_empty() {
+        let message = if tags.is_empty() {
+            format!("{} has no questions", file)
+        } else {
+            format!("no questions in {} are tagged {}", file, tags.join(" or "))
+        };
+        diagnostics.push(Diagnostic::warning(message, line_no, whole.0, whole.1));
     }
 
-    Ok(questions)
+    if remote == RemoteImages::Error {
+        let mut remote_images = Vec::new();
+        check_remote_images(selected.iter().map(|(q, _)| q), &content, &mut remote_images);
+        if !remote_images.is_empty() {
+            let rendered: Vec<String> = remote_images
+                .iter()
+                .map(|d| d.render(&include_path.display().to_string(), &content).trim_end().to_string())
+                .collect();
+            diagnostics.push(
+                Diagnostic::error(format!("included file {} has remote images", file), line_no, target_span.0, target_span.1)
+                    .with_hint(rendered.join("\n")),
+            );
+            return Vec::new();
+        }
+    }
+
+    let dir = Path::new(file).parent().unwrap_or(Path::new(""));
+    selected
+        .into_iter()
+        .map(|(mut question, derived)| {
+            question.id.get_or_insert(derived);
+            question.prompt = rebase_images(&question.prompt, dir);
+            for answer in &mut question.answers {
+                answer.text = rebase_images(&answer.text, dir);
+            }
+            // Nested includes keep the file the question is written in.
+            question.source.get_or_insert_with(|| include_path.clone());
+            question
+        })
+        .collect()
 }
 
-fn parse_single_question(lines: &[&str]) -> Result<Question> {
+static IMAGE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(!\[[^\]]*\]\(\s*<?)([^)\s>]+)").unwrap());
+
+/// Prefixes relative image paths in `text` with `dir`, the included file's
+/// directory relative to the file including it.
+fn rebase_images(text: &str, dir: &Path) -> String {
+    if dir.as_os_str().is_empty() {
+        return text.to_string();
+    }
+    IMAGE
+        .replace_all(text, |caps: &regex::Captures| {
+            let src = &caps[2];
+            match image_source(src) {
+                ImageSource::File(file) if Path::new(file).is_relative() => format!("{}{}", &caps[1], join_relative(dir, file)),
+                _ => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// `dir` joined with `file` as a `/`-separated path, with `.` dropped and
+/// `..` applied where it can be.
+fn join_relative(dir: &Path, file: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    for component in dir.join(file).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if parts.last().is_some_and(|p| p != ".." && !p.is_empty()) => {
+                parts.pop();
+            }
+            // Joined with `/`, an empty first part makes the path absolute.
+            Component::RootDir => parts.push(String::new()),
+            component => parts.push(component.as_os_str().to_string_lossy().into_owned()),
+        }
+    }
+    parts.join("/")
+}
+
+/// Whether `value` can be used as a QTI identifier, which must be an XML
+/// name without colons.
+fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// 1-based character column of `part`, which must be a subslice of `line`.
+fn column_of(line: &str, part: &str) -> usize {
+    let offset = part.as_ptr() as usize - line.as_ptr() as usize;
+    line[..offset].chars().count() + 1
+}
+
+/// The mark and text of a choice line: `- [`, one character, `]`, then a
+/// space or the end of the line. Other `- [` lines, such as a bullet that
+/// starts with a link, are prompt text.
+fn answer_mark(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix("- [")?;
+    let mark_len = rest.chars().next()?.len_utf8();
+    let text = rest[mark_len..].strip_prefix(']')?;
+    (text.is_empty() || text.starts_with(' ')).then(|| (&rest[..mark_len], text))
+}
+
+fn parse_single_question(lines: &[(usize, &str)], diagnostics: &mut Vec<Diagnostic>) -> Option<Question> {
     // Parse attributes from the first line: :::{.question type=multiple_choice points=1}
-    let header = lines[0].trim();
+    let (header_line, header_raw) = lines[0];
+    let header = header_raw.trim();
     let attributes_str = header
         .trim_start_matches(":::{.question")
         .trim_end_matches('}')
@@ -72,30 +523,81 @@ fn parse_single_question(lines: &[&str]) -> Result<Question> {
     let mut question_type = QuestionType::MultipleChoice;
     let mut points = 1.0;
     let mut title = String::new(); // Title is optional or derived
+    let mut id = None;
+    let mut tags = Vec::new();
+    let mut has_errors = false;
 
     for attr in attributes_str.split_whitespace() {
-        if let Some((key, value)) = attr.split_once('=') {
-            match key {
-                "type" => {
-                    question_type = match value {
-                        "multiple_choice" => QuestionType::MultipleChoice,
-                        "multiple_answers" => QuestionType::MultipleAnswers,
-                        "true_false" => QuestionType::TrueFalse,
-                        "short_answer" => QuestionType::ShortAnswer,
-                        "essay" => QuestionType::Essay,
-                        "file_upload" => QuestionType::FileUpload,
-                        _ => QuestionType::MultipleChoice,
-                    };
-                }
-                "points" => {
-                    if let Ok(val) = value.parse::<f32>() {
-                        points = val;
+        let column = column_of(header_raw, attr);
+        let Some((key, value)) = attr.split_once('=') else {
+            diagnostics.push(
+                Diagnostic::warning(format!("unknown attribute `{}`", attr), header_line, column, attr.chars().count())
+                    .with_hint(format!("question attributes are written as key=value; known keys: {}", QUESTION_ATTRIBUTES.join(", "))),
+            );
+            continue;
+        };
+        let value_column = column + key.chars().count() + 1;
+        match key {
+            "type" => {
+                question_type = match value {
+                    "multiple_choice" => QuestionType::MultipleChoice,
+                    "multiple_answers" => QuestionType::MultipleAnswers,
+                    "true_false" => QuestionType::TrueFalse,
+                    "short_answer" => QuestionType::ShortAnswer,
+                    "essay" => QuestionType::Essay,
+                    "file_upload" => QuestionType::FileUpload,
+                    _ => {
+                        let hint = match suggest(value, QUESTION_TYPES) {
+                            Some(s) => format!("did you mean `{}`?", s),
+                            None => format!("expected one of: {}", QUESTION_TYPES.join(", ")),
+                        };
+                        diagnostics.push(
+                            Diagnostic::error(format!("unknown question type `{}`", value), header_line, value_column, value.chars().count().max(1))
+                                .with_hint(hint),
+                        );
+                        has_errors = true;
+                        QuestionType::MultipleChoice
+                    }
+                };
+            }
+            "points" => {
+                match value.parse::<f32>() {
+                    Ok(val) if val.is_finite() && val >= 0.0 => points = val,
+                    _ => {
+                        diagnostics.push(
+                            Diagnostic::error(format!("invalid points value `{}`", value), header_line, value_column, value.chars().count().max(1))
+                                .with_hint("points must be a non-negative number, e.g. points=2 or points=0.5"),
+                        );
+                        has_errors = true;
                     }
                 }
-                "title" => {
-                    title = value.replace("_", " ").to_string(); // Simple handling for now
+            }
+            "title" => {
+                title = value.replace("_", " ").to_string(); // Simple handling for now
+            }
+            "tags" => {
+                tags = value.split(',').filter(|tag| !tag.is_empty()).map(str::to_string).collect();
+            }
+            "id" => {
+                if is_identifier(value) {
+                    id = Some(value.to_string());
+                } else {
+                    diagnostics.push(
+                        Diagnostic::error(format!("invalid question id `{}`", value), header_line, value_column, value.chars().count().max(1))
+                            .with_hint("ids start with a letter or `_` and contain only letters, digits, `_`, `-` and `.`"),
+                    );
+                    has_errors = true;
                 }
-                _ => {}
+            }
+            _ => {
+                let hint = match suggest(key, QUESTION_ATTRIBUTES) {
+                    Some(s) => format!("did you mean `{}`?", s),
+                    None => format!("known attributes: {}", QUESTION_ATTRIBUTES.join(", ")),
+                };
+                diagnostics.push(
+                    Diagnostic::warning(format!("unknown attribute `{}`", key), header_line, column, key.chars().count())
+                        .with_hint(hint),
+                );
             }
         }
     }
@@ -105,20 +607,37 @@ fn parse_single_question(lines: &[&str]) -> Result<Question> {
     let mut parsing_answers = false;
 
     // Skip first and last line (:::)
-    for line in &lines[1..lines.len()-1] {
+    for (line_no, line) in &lines[1..lines.len()-1] {
         let trimmed = line.trim();
         if trimmed.is_empty() {
+            // Blank lines separate Markdown paragraphs in the prompt.
+            if !parsing_answers {
+                prompt_lines.push("");
+            }
             continue;
         }
 
-        if trimmed.starts_with("- [") {
+        if let Some((mark, text)) = answer_mark(trimmed) {
             parsing_answers = true;
-            let is_correct = trimmed.starts_with("- [x]");
-            let text = trimmed[5..].trim().to_string();
+            let mark_column = line.len() - line.trim_start().len() + 4;
+            let is_correct = match mark {
+                "x" | "X" => true,
+                " " => false,
+                _ => {
+                    diagnostics.push(
+                        Diagnostic::error(format!("unknown answer mark `[{}]`", mark), *line_no, mark_column, mark.chars().count())
+                            .with_hint("write `- [x]` for a correct choice and `- [ ]` for the others"),
+                    );
+                    has_errors = true;
+                    continue;
+                }
+            };
+            let text = text.trim().to_string();
             answers.push(Answer {
                 text,
                 is_correct,
                 feedback: None,
+                line: *line_no,
             });
         } else {
             if !parsing_answers {
@@ -126,21 +645,99 @@ fn parse_single_question(lines: &[&str]) -> Result<Question> {
             }
         }
     }
-    
+
+    let is_choice = matches!(
+        question_type,
+        QuestionType::MultipleChoice | QuestionType::MultipleAnswers | QuestionType::TrueFalse
+    );
+    if is_choice && !has_errors && !answers.iter().any(|a| a.is_correct) {
+        let indent = header_raw.len() - header_raw.trim_start().len();
+        let message = if answers.is_empty() { "question has no choices" } else { "question has no correct answer" };
+        diagnostics.push(
+            Diagnostic::error(message, header_line, indent + 1, header.chars().count())
+                .with_hint("mark the correct choice with `- [x]`"),
+        );
+        has_errors = true;
+    }
+
+    if has_errors {
+        return None;
+    }
+
     // If title is empty, use truncated prompt
     if title.is_empty() {
-        let prompt = prompt_lines.join(" ");
+        let prompt = prompt_lines.iter().filter(|l| !l.is_empty()).copied().collect::<Vec<_>>().join(" ");
         title = prompt.chars().take(50).collect::<String>();
         if prompt.len() > 50 {
             title.push_str("...");
         }
     }
 
-    Ok(Question {
+    Some(Question {
+        id,
         title,
         prompt: prompt_lines.join("\n").trim().to_string(),
         question_type,
         points,
         answers,
+        tags,
+        line: header_line,
+        source: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_quiz, question_tokens};
+    use crate::qti::lexer::Token;
+    use std::path::Path;
+
+    /// Checks a quiz whose only question has the given answer lines.
+    fn check_answers(answers: &str) -> (Vec<(bool, String)>, Vec<String>) {
+        let content = format!("---\ntitle: t\n---\n:::{{.question type=multiple_choice}}\nPick one\n{}\n- [ ] other\n:::\n", answers);
+        let (quiz, diagnostics) = check_quiz(&content, Path::new("quiz.qmd"));
+        let answers = quiz
+            .map(|quiz| quiz.questions.iter().flat_map(|q| q.answers.iter().map(|a| (a.is_correct, a.text.clone()))).collect())
+            .unwrap_or_default();
+        (answers, diagnostics.into_iter().map(|d| d.message).collect())
+    }
+
+    #[test]
+    fn bullet_starting_with_a_link_is_prompt_text() {
+        let content = "---\ntitle: t\n---\n:::{.question}\nRead\n\n- [docs](https://example.com)\n- [ ] no\n- [x] yes\n:::\n";
+        let (quiz, diagnostics) = check_quiz(content, Path::new("quiz.qmd"));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let question = &quiz.unwrap().questions[0];
+        assert_eq!(question.prompt, "Read\n\n- [docs](https://example.com)");
+        assert_eq!(question.answers.len(), 2);
+    }
+
+    #[test]
+    fn unknown_mark_is_an_error() {
+        let (answers, messages) = check_answers("- [✓] yes");
+        assert!(answers.is_empty());
+        assert_eq!(messages, ["unknown answer mark `[✓]`"]);
+    }
+
+    #[test]
+    fn capital_x_marks_a_correct_answer() {
+        let (answers, messages) = check_answers("- [X] yes");
+        assert!(messages.is_empty(), "{:?}", messages);
+        assert_eq!(answers[0], (true, "yes".to_string()));
+    }
+
+    #[test]
+    fn question_tokens_are_located_in_the_file() {
+        let content = "---\ntitle: t\n---\n:::{.question}\nSee\n```\nx\n```\nthe ![plot](a.png) here\n- [x] ![b](b.png)\n:::\n";
+        let (quiz, _) = check_quiz(content, Path::new("quiz.qmd"));
+        let quiz = quiz.unwrap();
+        let images: Vec<(String, usize, usize, usize)> = question_tokens(content, &quiz.questions[0])
+            .into_iter()
+            .filter_map(|(token, line, column, length)| match token {
+                Token::Image { src, .. } => Some((src, line, column, length)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(images, [("a.png".to_string(), 9, 5, 14), ("b.png".to_string(), 10, 7, 11)]);
+    }
+}
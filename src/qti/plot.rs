@@ -0,0 +1,1017 @@
+use anyhow::{anyhow, bail, Result};
+use crate::qti::mathsvg::{bundled_face, number, PathData};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::OnceLock;
+use ttf_parser::Face;
+
+// Draws the function plots of ```plot fences. A block holds a small YAML
+// spec of functions of x, points and shaded regions, which is drawn on
+// gridded axes as SVG with text in New Computer Modern, and which also
+// yields a plain-language description for alt text when the author has not
+// written one.
+
+const FONT: &str = "NewCM10-Regular";
+const TICK_SIZE: f32 = 13.0;
+const LABEL_SIZE: f32 = 14.0;
+const TITLE_SIZE: f32 = 16.0;
+const DEFAULT_WIDTH: f32 = 480.0;
+const DEFAULT_HEIGHT: f32 = 320.0;
+const PADDING: f32 = 10.0;
+const TICK_LENGTH: f32 = 5.0;
+const SAMPLES: usize = 600;
+const POINT_RADIUS: f32 = 3.5;
+/// Colours for successive functions, each with its own dash pattern so
+/// curves can be told apart without colour.
+const COLORS: &[&str] = &["#0b61a4", "#c0392b", "#1e8449", "#7d3c98"];
+const DASHES: &[&str] = &["", "7 4", "2 3", "9 3 2 3"];
+
+/// A rendered plot and the alt text it carries: the author's, or one
+/// described from the spec.
+pub(crate) struct PlotSvg {
+    pub svg: String,
+    pub alt: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    title: Option<String>,
+    /// Range of the x axis; defaults to -5 to 5.
+    x: Option<[Formula; 2]>,
+    /// Range of the y axis; defaults to fit the functions and points.
+    y: Option<[Formula; 2]>,
+    xlabel: Option<String>,
+    ylabel: Option<String>,
+    #[serde(default = "default_grid")]
+    grid: bool,
+    width: Option<f32>,
+    height: Option<f32>,
+    #[serde(default)]
+    functions: Vec<FunctionSpec>,
+    #[serde(default)]
+    points: Vec<PointSpec>,
+    #[serde(default)]
+    shade: Vec<ShadeSpec>,
+    alt: Option<String>,
+}
+
+fn default_grid() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FunctionSpec {
+    Formula(Formula),
+    Full {
+        y: Formula,
+        /// Lets other formulas call this one, as in `f(x + 1)`.
+        name: Option<String>,
+        label: Option<String>,
+        domain: Option<[Formula; 2]>,
+        style: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PointSpec {
+    At([Formula; 2]),
+    Full {
+        at: [Formula; 2],
+        label: Option<String>,
+        #[serde(default)]
+        open: bool,
+    },
+}
+
+/// The region under one function, or between two, over `from`..`to`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShadeSpec {
+    under: Option<Formula>,
+    between: Option<[Formula; 2]>,
+    from: Option<Formula>,
+    to: Option<Formula>,
+}
+
+/// A formula in `x`. YAML reads a constant such as `2` as a number, so
+/// numbers are accepted as well as strings.
+#[derive(Clone)]
+struct Formula(String);
+
+impl<'de> Deserialize<'de> for Formula {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::String(text) => Ok(Formula(text)),
+            serde_yaml::Value::Number(value) => Ok(Formula(value.to_string())),
+            _ => Err(D::Error::custom("expected a formula in x")),
+        }
+    }
+}
+
+/// Renders a plot spec as an SVG whose title is `alt`, or the spec's own
+/// description when `alt` is empty.
+pub(crate) fn plot_to_svg(source: &str, alt: &str) -> Result<PlotSvg> {
+    let spec: Spec = serde_yaml::from_str(source).map_err(|e| anyhow!("Invalid plot spec: {}", e))?;
+    let plot = Plot::new(&spec)?;
+    let alt = if !alt.trim().is_empty() {
+        alt.trim().to_string()
+    } else if let Some(alt) = &spec.alt {
+        alt.trim().to_string()
+    } else {
+        plot.describe(&spec)
+    };
+    let face = label_face().ok_or_else(|| anyhow!("Font {} is not available", FONT))?;
+    let svg = Canvas::new(face).render(&plot, &spec, &alt);
+    Ok(PlotSvg { svg, alt })
+}
+
+static FACE: OnceLock<Option<Face<'static>>> = OnceLock::new();
+
+fn label_face() -> Option<&'static Face<'static>> {
+    FACE.get_or_init(|| bundled_face(FONT)).as_ref()
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(f64),
+    X,
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(String, Box<Expr>),
+}
+
+const BUILTINS: &[&str] = &[
+    "sin", "cos", "tan", "asin", "acos", "atan", "sinh", "cosh", "tanh", "exp", "ln", "log", "sqrt", "abs", "floor", "ceil",
+    "sign",
+];
+
+/// Parses formulas such as `2x^2 - 3`, `sin(pi x)/x` or `|x - 1|`, with
+/// implicit multiplication and `^` or `**` for powers.
+struct FormulaParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    source: &'a str,
+    names: &'a [String],
+}
+
+impl<'a> FormulaParser<'a> {
+    fn parse(source: &'a str, names: &'a [String]) -> Result<Expr> {
+        let mut parser = Self {
+            chars: source.chars().collect(),
+            pos: 0,
+            source,
+            names,
+        };
+        let expr = parser.sum()?;
+        parser.skip_spaces();
+        if parser.pos < parser.chars.len() {
+            bail!("Unexpected `{}` in formula `{}`", parser.chars[parser.pos], source);
+        }
+        Ok(expr)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut left = self.product()?;
+        while let Some(op) = self.peek().filter(|c| matches!(c, '+' | '-' | '−')) {
+            self.pos += 1;
+            let op = if op == '+' { '+' } else { '-' };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut left = self.unary()?;
+        loop {
+            match self.peek() {
+                Some('*') if self.chars.get(self.pos + 1) != Some(&'*') => {
+                    self.pos += 1;
+                    left = Expr::Binary('*', Box::new(left), Box::new(self.unary()?));
+                }
+                Some('·' | '×') => {
+                    self.pos += 1;
+                    left = Expr::Binary('*', Box::new(left), Box::new(self.unary()?));
+                }
+                Some('/') => {
+                    self.pos += 1;
+                    left = Expr::Binary('/', Box::new(left), Box::new(self.unary()?));
+                }
+                // Implicit multiplication: `2x`, `3(x + 1)`, `x sin(x)`.
+                Some(c) if c.is_alphanumeric() || c == '(' || c == '.' || c == 'π' => {
+                    left = Expr::Binary('*', Box::new(left), Box::new(self.power()?));
+                }
+                _ => return Ok(left),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some('-' | '−') => {
+                self.pos += 1;
+                Ok(Expr::Negate(Box::new(self.unary()?)))
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<Expr> {
+        let base = self.atom()?;
+        match self.peek() {
+            Some('^') => self.pos += 1,
+            Some('*') if self.chars.get(self.pos + 1) == Some(&'*') => self.pos += 2,
+            _ => return Ok(base),
+        }
+        Ok(Expr::Binary('^', Box::new(base), Box::new(self.unary()?)))
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let Some(c) = self.peek() else {
+            bail!("Formula `{}` ends too early", self.source);
+        };
+        if c.is_ascii_digit() || c == '.' {
+            let start = self.pos;
+            while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                self.pos += 1;
+            }
+            // An exponent, as in 1.5e-3, but not the constant e in `2e^x`.
+            if self.chars.get(self.pos) == Some(&'e')
+                && let Some(next) = self.chars.get(self.pos + 1)
+                && (next.is_ascii_digit() || (matches!(next, '-' | '+') && self.chars.get(self.pos + 2).is_some_and(|c| c.is_ascii_digit())))
+            {
+                self.pos += 2;
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+            }
+            let text: String = self.chars[start..self.pos].iter().collect();
+            return text.parse().map(Expr::Number).map_err(|_| anyhow!("Bad number `{}` in formula `{}`", text, self.source));
+        }
+        if c == '(' {
+            self.pos += 1;
+            let inner = self.sum()?;
+            self.expect(')')?;
+            return Ok(inner);
+        }
+        if c == '|' {
+            self.pos += 1;
+            let inner = self.sum()?;
+            self.expect('|')?;
+            return Ok(Expr::Call("abs".to_string(), Box::new(inner)));
+        }
+        if c == 'π' {
+            self.pos += 1;
+            return Ok(Expr::Number(std::f64::consts::PI));
+        }
+        if c.is_alphabetic() {
+            let start = self.pos;
+            while self.chars.get(self.pos).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                self.pos += 1;
+            }
+            let word: String = self.chars[start..self.pos].iter().collect();
+            return self.word(&word);
+        }
+        bail!("Unexpected `{}` in formula `{}`", c, self.source)
+    }
+
+    /// A name, which may run several together as in `xsin(x)` or `pix`.
+    fn word(&mut self, word: &str) -> Result<Expr> {
+        let callable = |name: &str| BUILTINS.contains(&name) || self.names.iter().any(|n| n == name);
+        if callable(word) {
+            // A function named alone, as in a shade's `under: f`, means f(x).
+            if self.peek() != Some('(') {
+                return Ok(Expr::Call(word.to_string(), Box::new(Expr::X)));
+            }
+            self.pos += 1;
+            let argument = self.sum()?;
+            self.expect(')')?;
+            return Ok(Expr::Call(word.to_string(), Box::new(argument)));
+        }
+        for (prefix, value) in [("pi", Some(std::f64::consts::PI)), ("x", None), ("e", Some(std::f64::consts::E))] {
+            if let Some(rest) = word.strip_prefix(prefix) {
+                let first = value.map_or(Expr::X, Expr::Number);
+                if rest.is_empty() {
+                    return Ok(first);
+                }
+                return Ok(Expr::Binary('*', Box::new(first), Box::new(self.word(rest)?)));
+            }
+        }
+        bail!("Unknown name `{}` in formula `{}`", word, self.source)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!("Expected `{}` in formula `{}`", c, self.source);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+}
+
+/// Named functions a formula may call.
+type Definitions = HashMap<String, Expr>;
+
+fn evaluate(expr: &Expr, x: f64, definitions: &Definitions, depth: usize) -> f64 {
+    if depth > 32 {
+        return f64::NAN;
+    }
+    let eval = |e: &Expr, x: f64| evaluate(e, x, definitions, depth + 1);
+    match expr {
+        Expr::Number(value) => *value,
+        Expr::X => x,
+        Expr::Negate(inner) => -eval(inner, x),
+        Expr::Binary(op, left, right) => {
+            let (a, b) = (eval(left, x), eval(right, x));
+            match op {
+                '+' => a + b,
+                '-' => a - b,
+                '*' => a * b,
+                '/' => a / b,
+                _ => {
+                    // x^(1/3) and other odd roots are real for negative x.
+                    let root = 1.0 / b;
+                    if a < 0.0 && (root - root.round()).abs() < 1e-9 && root.round() as i64 % 2 != 0 {
+                        -(-a).powf(b)
+                    } else {
+                        a.powf(b)
+                    }
+                }
+            }
+        }
+        Expr::Call(name, argument) => {
+            let a = eval(argument, x);
+            match name.as_str() {
+                "sin" => a.sin(),
+                "cos" => a.cos(),
+                "tan" => a.tan(),
+                "asin" => a.asin(),
+                "acos" => a.acos(),
+                "atan" => a.atan(),
+                "sinh" => a.sinh(),
+                "cosh" => a.cosh(),
+                "tanh" => a.tanh(),
+                "exp" => a.exp(),
+                "ln" => a.ln(),
+                "log" => a.log10(),
+                "sqrt" => a.sqrt(),
+                "abs" => a.abs(),
+                "floor" => a.floor(),
+                "ceil" => a.ceil(),
+                "sign" => {
+                    if a == 0.0 { 0.0 } else { a.signum() }
+                }
+                _ => definitions.get(name).map_or(f64::NAN, |body| evaluate(body, a, definitions, depth + 1)),
+            }
+        }
+    }
+}
+
+struct Curve {
+    formula: String,
+    name: Option<String>,
+    label: Option<String>,
+    expr: Expr,
+    domain: (f64, f64),
+    color: &'static str,
+    dash: &'static str,
+}
+
+struct Region {
+    upper: Expr,
+    lower: Expr,
+    upper_text: String,
+    lower_text: Option<String>,
+    from: f64,
+    to: f64,
+    color: &'static str,
+}
+
+struct Marker {
+    x: f64,
+    y: f64,
+    label: Option<String>,
+    open: bool,
+}
+
+/// A spec with its formulas parsed and axis ranges settled.
+struct Plot {
+    definitions: Definitions,
+    curves: Vec<Curve>,
+    regions: Vec<Region>,
+    markers: Vec<Marker>,
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+    x_step: f64,
+    y_step: f64,
+}
+
+impl Plot {
+    fn new(spec: &Spec) -> Result<Self> {
+        let names: Vec<String> = spec
+            .functions
+            .iter()
+            .filter_map(|f| match f {
+                FunctionSpec::Full { name, .. } => name.clone(),
+                FunctionSpec::Formula(_) => None,
+            })
+            .collect();
+        let constant = |formula: &Formula| -> Result<f64> {
+            let value = evaluate(&FormulaParser::parse(&formula.0, &names)?, f64::NAN, &Definitions::new(), 0);
+            if !value.is_finite() {
+                bail!("`{}` is not a number", formula.0);
+            }
+            Ok(value)
+        };
+        let range = |pair: &[Formula; 2]| -> Result<(f64, f64)> {
+            let (low, high) = (constant(&pair[0])?, constant(&pair[1])?);
+            if low >= high {
+                bail!("Range [{}, {}] must go from low to high", pair[0].0, pair[1].0);
+            }
+            Ok((low, high))
+        };
+
+        let x_range = match &spec.x {
+            Some(x) => range(x)?,
+            None => (-5.0, 5.0),
+        };
+        let mut definitions = Definitions::new();
+        let mut curves = Vec::new();
+        for (i, function) in spec.functions.iter().enumerate() {
+            let (formula, name, label, domain, style) = match function {
+                FunctionSpec::Formula(formula) => (formula, None, None, None, None),
+                FunctionSpec::Full { y, name, label, domain, style } => (y, name.clone(), label.clone(), domain.as_ref(), style.as_deref()),
+            };
+            let domain = match domain {
+                Some(domain) => range(domain)?,
+                None => x_range,
+            };
+            let dash = match style {
+                Some("solid") => "",
+                Some("dashed") => DASHES[1],
+                Some("dotted") => DASHES[2],
+                Some(other) => bail!("Unknown line style `{}`; use solid, dashed or dotted", other),
+                None => DASHES[i % DASHES.len()],
+            };
+            if let Some(name) = &name {
+                definitions.insert(name.clone(), FormulaParser::parse(&formula.0, &names)?);
+            }
+            curves.push(Curve {
+                formula: formula.0.trim().to_string(),
+                name,
+                label,
+                expr: FormulaParser::parse(&formula.0, &names)?,
+                domain,
+                color: COLORS[i % COLORS.len()],
+                dash,
+            });
+        }
+
+        let mut regions = Vec::new();
+        for (i, shade) in spec.shade.iter().enumerate() {
+            let (upper, lower) = match (&shade.under, &shade.between) {
+                (Some(under), None) => (under.clone(), None),
+                (None, Some([a, b])) => (a.clone(), Some(b.clone())),
+                _ => bail!("Each shaded region needs either `under` or `between`"),
+            };
+            let from = shade.from.as_ref().map(&constant).transpose()?.unwrap_or(x_range.0);
+            let to = shade.to.as_ref().map(&constant).transpose()?.unwrap_or(x_range.1);
+            regions.push(Region {
+                upper: FormulaParser::parse(&upper.0, &names)?,
+                lower: match &lower {
+                    Some(lower) => FormulaParser::parse(&lower.0, &names)?,
+                    None => Expr::Number(0.0),
+                },
+                upper_text: upper.0.trim().to_string(),
+                lower_text: lower.map(|l| l.0.trim().to_string()),
+                from: from.min(to),
+                to: from.max(to),
+                color: COLORS[i % COLORS.len()],
+            });
+        }
+
+        let markers = spec
+            .points
+            .iter()
+            .map(|point| {
+                let (at, label, open) = match point {
+                    PointSpec::At(at) => (at, None, false),
+                    PointSpec::Full { at, label, open } => (at, label.clone(), *open),
+                };
+                Ok(Marker {
+                    x: constant(&at[0])?,
+                    y: constant(&at[1])?,
+                    label,
+                    open,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut plot = Plot {
+            definitions,
+            curves,
+            regions,
+            markers,
+            x_range,
+            y_range: (0.0, 0.0),
+            x_step: nice_step((x_range.1 - x_range.0) / 8.0),
+            y_step: 1.0,
+        };
+        match &spec.y {
+            Some(y) => {
+                plot.y_range = range(y)?;
+                plot.y_step = nice_step((plot.y_range.1 - plot.y_range.0) / 6.0);
+            }
+            None => plot.fit_y(),
+        }
+        Ok(plot)
+    }
+
+    fn eval(&self, expr: &Expr, x: f64) -> f64 {
+        evaluate(expr, x, &self.definitions, 0)
+    }
+
+    /// Samples of `expr` across `domain`, clipped to the x axis.
+    fn samples(&self, expr: &Expr, domain: (f64, f64)) -> Vec<(f64, f64)> {
+        let (from, to) = (domain.0.max(self.x_range.0), domain.1.min(self.x_range.1));
+        if from >= to {
+            return Vec::new();
+        }
+        (0..=SAMPLES)
+            .map(|i| {
+                let x = from + (to - from) * i as f64 / SAMPLES as f64;
+                (x, self.eval(expr, x))
+            })
+            .collect()
+    }
+
+    /// Fits the y axis to the curves and points, ignoring the spikes of
+    /// asymptotes, and takes in zero when it is near.
+    fn fit_y(&mut self) {
+        let mut values: Vec<f64> = self
+            .curves
+            .iter()
+            .flat_map(|c| self.samples(&c.expr, c.domain))
+            .map(|(_, y)| y)
+            .chain(self.markers.iter().map(|m| m.y))
+            .filter(|y| y.is_finite())
+            .collect();
+        values.sort_by(f64::total_cmp);
+        let (mut low, mut high) = match (values.first(), values.last()) {
+            (Some(&low), Some(&high)) => (low, high),
+            _ => (-1.0, 1.0),
+        };
+        if values.len() > 20 {
+            let percentile = |p: f64| values[((values.len() - 1) as f64 * p) as usize];
+            let (p5, p95) = (percentile(0.05), percentile(0.95));
+            if high - low > 10.0 * (p95 - p5).max(1e-9) {
+                (low, high) = (percentile(0.02), percentile(0.98));
+            }
+        }
+        for marker in &self.markers {
+            low = low.min(marker.y);
+            high = high.max(marker.y);
+        }
+        if low > 0.0 && low < (high - low) * 0.5 {
+            low = 0.0;
+        }
+        if high < 0.0 && -high < (high - low) * 0.5 {
+            high = 0.0;
+        }
+        if high - low < 1e-9 {
+            (low, high) = (low - 1.0, high + 1.0);
+        }
+        // Pad away from the data, but not below a zero baseline.
+        let padding = (high - low) * 0.05;
+        let low = if low == 0.0 { low } else { low - padding };
+        let high = if high == 0.0 { high } else { high + padding };
+        let step = nice_step((high - low) / 6.0);
+        self.y_step = step;
+        self.y_range = ((low / step).floor() * step, (high / step).ceil() * step);
+    }
+
+    /// Describes the plot for alt text, from the formulas as written.
+    fn describe(&self, spec: &Spec) -> String {
+        let mut alt = String::new();
+        if let Some(title) = &spec.title {
+            let _ = write!(alt, "{}: ", title.trim());
+        }
+        let curves: Vec<String> = self.curves.iter().map(curve_name).collect();
+        if curves.is_empty() {
+            alt.push_str("Axes");
+        } else {
+            let _ = write!(alt, "Graph of {}", join_list(&curves));
+        }
+        let _ = write!(
+            alt,
+            " for x from {} to {}, with y from {} to {}.",
+            plain_number(self.x_range.0),
+            plain_number(self.x_range.1),
+            plain_number(self.y_range.0),
+            plain_number(self.y_range.1)
+        );
+        if spec.xlabel.is_some() || spec.ylabel.is_some() {
+            let axes: Vec<String> = [("x", &spec.xlabel), ("y", &spec.ylabel)]
+                .iter()
+                .filter_map(|(axis, label)| label.as_ref().map(|l| format!("the {} axis is {}", axis, l.trim())))
+                .collect();
+            let _ = write!(alt, " {}.", capitalize(&join_list(&axes)));
+        }
+        for region in &self.regions {
+            let lower = region.lower_text.as_ref().map_or("the x axis".to_string(), |l| self.formula_name(l));
+            let _ = write!(
+                alt,
+                " The region between {} and {} from x = {} to x = {} is shaded.",
+                self.formula_name(&region.upper_text),
+                lower,
+                plain_number(region.from),
+                plain_number(region.to)
+            );
+        }
+        if !self.markers.is_empty() {
+            let points: Vec<String> = self
+                .markers
+                .iter()
+                .map(|m| {
+                    let mut point = format!("({}, {})", plain_number(m.x), plain_number(m.y));
+                    if let Some(label) = &m.label {
+                        point = format!("{} {}", label.trim(), point);
+                    }
+                    if m.open {
+                        point.push_str(", an open circle");
+                    }
+                    point
+                })
+                .collect();
+            let _ = write!(alt, " Marked point{}: {}.", if points.len() == 1 { "" } else { "s" }, points.join("; "));
+        }
+        alt
+    }
+
+    /// How a formula in a shaded region reads: by its function's name or
+    /// label when it is one, otherwise as y = formula.
+    fn formula_name(&self, formula: &str) -> String {
+        self.curves
+            .iter()
+            .find(|c| c.name.as_deref() == Some(formula) || c.formula == formula)
+            .map_or_else(|| format!("y = {}", formula), curve_name)
+    }
+}
+
+fn curve_name(curve: &Curve) -> String {
+    match (&curve.label, &curve.name) {
+        (Some(label), _) => label.trim().to_string(),
+        (None, Some(name)) => format!("{}(x) = {}", name, curve.formula),
+        (None, None) => format!("y = {}", curve.formula),
+    }
+}
+
+fn join_list(items: &[String]) -> String {
+    match items {
+        [] => String::new(),
+        [one] => one.clone(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars.next().map_or(String::new(), |first| first.to_uppercase().chain(chars).collect())
+}
+
+/// A round step of about `raw`: 1, 2 or 5 times a power of ten.
+fn nice_step(raw: f64) -> f64 {
+    let magnitude = 10f64.powf(raw.log10().floor());
+    let residual = raw / magnitude;
+    let factor = if residual < 1.5 {
+        1.0
+    } else if residual < 3.0 {
+        2.0
+    } else if residual < 7.0 {
+        5.0
+    } else {
+        10.0
+    };
+    factor * magnitude
+}
+
+/// Multiples of `step` within `range`.
+fn ticks(range: (f64, f64), step: f64) -> Vec<f64> {
+    let first = (range.0 / step - 1e-9).ceil() as i64;
+    let last = (range.1 / step + 1e-9).floor() as i64;
+    (first..=last).map(|i| i as f64 * step).collect()
+}
+
+/// A number for alt text, without needless decimals.
+fn plain_number(value: f64) -> String {
+    let rounded = (value * 1000.0).round() / 1000.0;
+    let text = format!("{:.3}", if rounded == 0.0 { 0.0 } else { rounded });
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// A tick label with as many decimals as the step needs, and a true minus
+/// sign.
+fn tick_label(value: f64, step: f64) -> String {
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    let text = format!("{:.*}", decimals, if value.abs() < step * 1e-6 { 0.0 } else { value });
+    text.replace('-', "\u{2212}")
+}
+
+#[derive(Clone, Copy)]
+enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+struct Canvas {
+    face: &'static Face<'static>,
+    defs: BTreeMap<String, String>,
+    body: String,
+}
+
+impl Canvas {
+    fn new(face: &'static Face<'static>) -> Self {
+        Self {
+            face,
+            defs: BTreeMap::new(),
+            body: String::new(),
+        }
+    }
+
+    fn render(mut self, plot: &Plot, spec: &Spec, alt: &str) -> String {
+        let width = spec.width.unwrap_or(DEFAULT_WIDTH);
+        let height = spec.height.unwrap_or(DEFAULT_HEIGHT);
+        let x_ticks = ticks(plot.x_range, plot.x_step);
+        let y_ticks = ticks(plot.y_range, plot.y_step);
+
+        let y_label_width = y_ticks
+            .iter()
+            .map(|&y| self.text_width(&tick_label(y, plot.y_step), TICK_SIZE))
+            .fold(0.0, f32::max);
+        let mut top = PADDING;
+        if let Some(title) = &spec.title {
+            top += TITLE_SIZE * 1.4;
+            self.text(title.trim(), width / 2.0, PADDING + TITLE_SIZE, TITLE_SIZE, Anchor::Middle);
+        }
+        let left = PADDING + y_label_width + TICK_LENGTH + 4.0;
+        if let Some(label) = &spec.ylabel {
+            self.text(label.trim(), left, top + LABEL_SIZE, LABEL_SIZE, Anchor::Start);
+            top += LABEL_SIZE * 1.5;
+        }
+        let right = width - PADDING - self.text_width(&tick_label(plot.x_range.1, plot.x_step), TICK_SIZE) / 2.0;
+        let mut bottom = height - PADDING - TICK_LENGTH - 4.0 - TICK_SIZE;
+        if let Some(label) = &spec.xlabel {
+            self.text(label.trim(), (left + right) / 2.0, height - PADDING, LABEL_SIZE, Anchor::Middle);
+            bottom -= LABEL_SIZE * 1.5;
+        }
+
+        let (x0, x1) = plot.x_range;
+        let (y0, y1) = plot.y_range;
+        let sx = |x: f64| left + ((x - x0) / (x1 - x0)) as f32 * (right - left);
+        let sy = |y: f64| bottom - ((y - y0) / (y1 - y0)) as f32 * (bottom - top);
+
+        // Grid, and tick labels outside the frame.
+        let mut grid = String::new();
+        for &x in &x_ticks {
+            let px = sx(x);
+            if spec.grid {
+                let _ = write!(grid, "M{} {}V{}", number(px), number(top), number(bottom));
+            }
+            let _ = write!(self.body, "<path d=\"M{} {}v{}\" stroke=\"#000\"/>", number(px), number(bottom), number(TICK_LENGTH));
+            self.text(&tick_label(x, plot.x_step), px, bottom + TICK_LENGTH + 3.0 + TICK_SIZE * 0.72, TICK_SIZE, Anchor::Middle);
+        }
+        for &y in &y_ticks {
+            let py = sy(y);
+            if spec.grid {
+                let _ = write!(grid, "M{} {}H{}", number(left), number(py), number(right));
+            }
+            let _ = write!(self.body, "<path d=\"M{} {}h-{}\" stroke=\"#000\"/>", number(left), number(py), number(TICK_LENGTH));
+            self.text(&tick_label(y, plot.y_step), left - TICK_LENGTH - 3.0, py + TICK_SIZE * 0.36, TICK_SIZE, Anchor::End);
+        }
+
+        let mut clipped = String::new();
+        for region in &plot.regions {
+            let (from, to) = (region.from.max(x0), region.to.min(x1));
+            if from >= to {
+                continue;
+            }
+            let steps = SAMPLES / 2;
+            let xs: Vec<f64> = (0..=steps).map(|i| from + (to - from) * i as f64 / steps as f64).collect();
+            let clamp = |y: f64| if y.is_finite() { y.clamp(y0 - (y1 - y0), y1 + (y1 - y0)) } else { 0.0 };
+            let mut d = String::new();
+            for (i, &x) in xs.iter().enumerate() {
+                let y = clamp(plot.eval(&region.upper, x));
+                let _ = write!(d, "{}{} {}", if i == 0 { "M" } else { "L" }, number(sx(x)), number(sy(y)));
+            }
+            for &x in xs.iter().rev() {
+                let y = clamp(plot.eval(&region.lower, x));
+                let _ = write!(d, "L{} {}", number(sx(x)), number(sy(y)));
+            }
+            let _ = write!(clipped, "<path d=\"{}Z\" fill=\"{}\" fill-opacity=\"0.25\"/>", d, region.color);
+        }
+        // The axes themselves, where zero is on the plot.
+        if y0 < 0.0 && y1 > 0.0 {
+            let _ = write!(clipped, "<path d=\"M{} {}H{}\" stroke=\"#000\"/>", number(left), number(sy(0.0)), number(right));
+        }
+        if x0 < 0.0 && x1 > 0.0 {
+            let _ = write!(clipped, "<path d=\"M{} {}V{}\" stroke=\"#000\"/>", number(sx(0.0)), number(top), number(bottom));
+        }
+        let mut drawn = Vec::new();
+        for curve in &plot.curves {
+            let samples = plot.samples(&curve.expr, curve.domain);
+            drawn.extend(samples.iter().filter(|(_, y)| y.is_finite()).map(|&(x, y)| (sx(x), sy(y))));
+            let d = curve_path(&samples, plot.y_range, &sx, &sy);
+            let dash = if curve.dash.is_empty() { String::new() } else { format!(" stroke-dasharray=\"{}\"", curve.dash) };
+            let _ = write!(
+                clipped,
+                "<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\" stroke-linejoin=\"round\"{}/>",
+                d, curve.color, dash
+            );
+        }
+
+        for marker in &plot.markers {
+            let (px, py) = (sx(marker.x), sy(marker.y));
+            let _ = write!(
+                self.body,
+                "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\" stroke=\"#000\" stroke-width=\"1.5\"/>",
+                number(px),
+                number(py),
+                number(POINT_RADIUS),
+                if marker.open { "#fff" } else { "#000" }
+            );
+            if let Some(label) = &marker.label {
+                self.text(label.trim(), px + POINT_RADIUS + 3.0, py - POINT_RADIUS - 3.0, LABEL_SIZE, Anchor::Start);
+            }
+        }
+
+        self.legend(plot, (left, top, right, bottom), &drawn);
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" \
+             width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" role=\"img\"><title>{}</title><defs>",
+            number(width),
+            number(height),
+            number(width),
+            number(height),
+            quick_xml::escape::escape(alt)
+        );
+        for markup in self.defs.values() {
+            svg.push_str(markup);
+        }
+        let _ = writeln!(
+            svg,
+            "<clipPath id=\"plot-area\"><rect x=\"{0}\" y=\"{1}\" width=\"{2}\" height=\"{3}\"/></clipPath></defs>\
+             <rect width=\"{4}\" height=\"{5}\" fill=\"#fff\"/>\
+             <path d=\"{6}\" stroke=\"#ddd\"/>\
+             <g clip-path=\"url(#plot-area)\">{7}</g>\
+             <rect x=\"{0}\" y=\"{1}\" width=\"{2}\" height=\"{3}\" fill=\"none\" stroke=\"#000\"/>{8}</svg>",
+            number(left),
+            number(top),
+            number(right - left),
+            number(bottom - top),
+            number(width),
+            number(height),
+            grid,
+            clipped,
+            self.body
+        );
+        svg
+    }
+
+    /// A key to the labelled curves, in whichever corner of the plot area
+    /// covers the fewest points of the curves drawn.
+    fn legend(&mut self, plot: &Plot, area: (f32, f32, f32, f32), drawn: &[(f32, f32)]) {
+        let entries: Vec<&Curve> = plot.curves.iter().filter(|c| c.label.is_some()).collect();
+        if entries.is_empty() {
+            return;
+        }
+        let sample = 28.0;
+        let row = LABEL_SIZE * 1.4;
+        let text_width = entries
+            .iter()
+            .map(|c| self.text_width(c.label.as_deref().unwrap_or("").trim(), LABEL_SIZE))
+            .fold(0.0, f32::max);
+        let box_width = 8.0 + sample + 6.0 + text_width + 8.0;
+        let box_height = 6.0 + row * entries.len() as f32;
+        let (left, top, right, bottom) = area;
+        let corners = [
+            (right - box_width - 6.0, top + 6.0),
+            (left + 6.0, top + 6.0),
+            (right - box_width - 6.0, bottom - box_height - 6.0),
+            (left + 6.0, bottom - box_height - 6.0),
+        ];
+        let covered = |&(x, y): &(f32, f32)| {
+            drawn
+                .iter()
+                .filter(|(px, py)| (x..=x + box_width).contains(px) && (y..=y + box_height).contains(py))
+                .count()
+        };
+        let (bx, by) = corners.iter().copied().min_by_key(covered).unwrap();
+        let _ = write!(
+            self.body,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#fff\" stroke=\"#999\"/>",
+            number(bx),
+            number(by),
+            number(box_width),
+            number(box_height)
+        );
+        for (i, curve) in entries.iter().enumerate() {
+            let middle = by + 3.0 + row * (i as f32 + 0.5);
+            let dash = if curve.dash.is_empty() { String::new() } else { format!(" stroke-dasharray=\"{}\"", curve.dash) };
+            let _ = write!(
+                self.body,
+                "<path d=\"M{} {}h{}\" stroke=\"{}\" stroke-width=\"2\"{}/>",
+                number(bx + 8.0),
+                number(middle),
+                number(sample),
+                curve.color,
+                dash
+            );
+            let label = curve.label.as_deref().unwrap_or("").trim().to_string();
+            self.text(&label, bx + 8.0 + sample + 6.0, middle + LABEL_SIZE * 0.34, LABEL_SIZE, Anchor::Start);
+        }
+    }
+
+    fn text_width(&self, text: &str, size: f32) -> f32 {
+        let scale = size / self.face.units_per_em() as f32;
+        text.chars()
+            .filter_map(|c| self.face.glyph_index(c))
+            .map(|g| self.face.glyph_hor_advance(g).unwrap_or(0) as f32 * scale)
+            .sum()
+    }
+
+    /// Draws `text` on `baseline`, placed at `x` by `anchor`.
+    fn text(&mut self, text: &str, x: f32, baseline: f32, size: f32, anchor: Anchor) {
+        let face = self.face;
+        let scale = size / face.units_per_em() as f32;
+        let mut x = match anchor {
+            Anchor::Start => x,
+            Anchor::Middle => x - self.text_width(text, size) / 2.0,
+            Anchor::End => x - self.text_width(text, size),
+        };
+        for c in text.chars() {
+            let Some(glyph) = face.glyph_index(c) else {
+                continue;
+            };
+            let id = format!("g{}", glyph.0);
+            self.defs.entry(id.clone()).or_insert_with(|| {
+                let mut path = PathData(String::new());
+                face.outline_glyph(glyph, &mut path);
+                format!("<path id=\"{}\" d=\"{}\"/>", id, path.0)
+            });
+            let _ = write!(
+                self.body,
+                "<use xlink:href=\"#{}\" transform=\"translate({} {}) scale({} -{})\"/>",
+                id,
+                number(x),
+                number(baseline),
+                number(scale),
+                number(scale)
+            );
+            x += face.glyph_hor_advance(glyph).unwrap_or(0) as f32 * scale;
+        }
+    }
+}
+
+/// Path data through the samples, broken where the function is undefined
+/// or jumps across the plot at an asymptote. Values far off the plot are
+/// pulled in so the path stays a sane size; the clip hides them.
+fn curve_path(samples: &[(f64, f64)], y_range: (f64, f64), sx: &impl Fn(f64) -> f32, sy: &impl Fn(f64) -> f32) -> String {
+    let (y0, y1) = y_range;
+    let span = y1 - y0;
+    let side = |y: f64| if y > y1 { 1 } else if y < y0 { -1 } else { 0 };
+    let mut d = String::new();
+    let mut previous: Option<f64> = None;
+    for &(x, y) in samples {
+        if !y.is_finite() {
+            previous = None;
+            continue;
+        }
+        let jump = previous.is_some_and(|p| side(p) != 0 && side(y) == -side(p));
+        let command = if previous.is_none() || jump { "M" } else { "L" };
+        let clamped = y.clamp(y0 - span, y1 + span);
+        let _ = write!(d, "{}{} {}", command, number(sx(x)), number(sy(clamped)));
+        previous = Some(y);
+    }
+    d
+}
@@ -0,0 +1,218 @@
+use anyhow::Result;
+use crate::qti::generator::{collect_resources, write_content, ContentOptions};
+use crate::qti::highlight::stylesheet;
+use crate::qti::images::{image_source, mime_type, package_path, read_image, ImageSource};
+use crate::qti::model::{Quiz, QuestionType};
+use base64::Engine;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+pub struct PreviewOptions {
+    pub show_answers: bool,
+}
+
+const PREVIEW_CSS: &str = "
+body { font-family: system-ui, sans-serif; max-width: 50rem; margin: 2rem auto; padding: 0 1rem; color: #222; }
+h1 { margin-bottom: 0.25rem; }
+.description { color: #555; margin-top: 0; }
+.question { margin: 1.5rem 0; padding: 1rem; border: 1px solid #ddd; border-radius: 6px; }
+.question-meta { font-size: 0.85rem; color: #666; margin-bottom: 0.5rem; }
+.prompt pre { padding: 0.5rem; overflow-x: auto; }
+.prompt > p:first-child { margin-top: 0; }
+.choices li { margin: 0.25rem 0; padding: 0.25rem 0.5rem; }
+.choices li.correct { background: #e6f4ea; border-left: 4px solid #1e8e3e; }
+img { max-width: 100%; }
+";
+
+/// Renders the quiz as a self-contained HTML page and returns the markup.
+///
+/// Content goes through the same `write_content` path as the QTI package, so
+/// MathML, highlighted code and images look the way they will after import.
+/// Images are inlined as data URIs so the page can be opened or shared alone.
+pub fn generate_preview(quiz: &Quiz, input_path: &Path, options: &PreviewOptions) -> Result<String> {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let css = format!("{}{}", PREVIEW_CSS, stylesheet(&quiz.code)?);
+    write_html_head(&mut writer, &quiz.title, &css)?;
+    write_text_element(&mut writer, "h1", &quiz.title)?;
+    if let Some(description) = &quiz.description {
+        let mut p = BytesStart::new("p");
+        p.push_attribute(("class", "description"));
+        writer.write_event(Event::Start(p))?;
+        writer.write_event(Event::Text(BytesText::new(description)))?;
+        writer.write_event(Event::End(BytesEnd::new("p")))?;
+    }
+
+    let content_options = ContentOptions::for_quiz(quiz);
+    writer.write_event(Event::Start(BytesStart::new("ol")))?;
+    for question in &quiz.questions {
+        let mut li = BytesStart::new("li");
+        li.push_attribute(("class", "question"));
+        writer.write_event(Event::Start(li))?;
+
+        let mut meta = BytesStart::new("div");
+        meta.push_attribute(("class", "question-meta"));
+        writer.write_event(Event::Start(meta))?;
+        let points = if question.points == 1.0 { "point" } else { "points" };
+        writer.write_event(Event::Text(BytesText::new(&format!(
+            "{} · {} {}",
+            question_type_label(&question.question_type),
+            question.points,
+            points
+        ))))?;
+        writer.write_event(Event::End(BytesEnd::new("div")))?;
+
+        let mut prompt = BytesStart::new("div");
+        prompt.push_attribute(("class", "prompt"));
+        writer.write_event(Event::Start(prompt))?;
+        write_content(&mut writer, &question.prompt, &content_options)?;
+        writer.write_event(Event::End(BytesEnd::new("div")))?;
+
+        if !question.answers.is_empty() {
+            let mut choices = BytesStart::new("ol");
+            choices.push_attribute(("class", "choices"));
+            choices.push_attribute(("type", "A"));
+            writer.write_event(Event::Start(choices))?;
+            for answer in &question.answers {
+                let mut choice = BytesStart::new("li");
+                if options.show_answers && answer.is_correct {
+                    choice.push_attribute(("class", "correct"));
+                }
+                writer.write_event(Event::Start(choice))?;
+                write_content(&mut writer, &answer.text, &content_options)?;
+                writer.write_event(Event::End(BytesEnd::new("li")))?;
+            }
+            writer.write_event(Event::End(BytesEnd::new("ol")))?;
+        }
+
+        writer.write_event(Event::End(BytesEnd::new("li")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("ol")))?;
+
+    writer.write_event(Event::End(BytesEnd::new("body")))?;
+    writer.write_event(Event::End(BytesEnd::new("html")))?;
+
+    let html = String::from_utf8(writer.into_inner().into_inner())?;
+    inline_quiz_images(&html, quiz, input_path.parent().unwrap(), &content_options)
+}
+
+/// Writes the preview next to the input as `<stem>.html`.
+pub fn write_preview(quiz: &Quiz, input_path: &Path, options: &PreviewOptions) -> Result<PathBuf> {
+    let html = generate_preview(quiz, input_path, options)?;
+    let output_path = input_path.with_extension("html");
+    std::fs::write(&output_path, html)?;
+    println!("Generated HTML preview at: {:?}", output_path);
+    Ok(output_path)
+}
+
+/// Writes the doctype, `<head>` with the given title and stylesheet, and opens
+/// `<body>`. Callers close `body` and `html` themselves.
+pub(crate) fn write_html_head(writer: &mut Writer<Cursor<Vec<u8>>>, title: &str, css: &str) -> Result<()> {
+    writer.write_event(Event::DocType(BytesText::from_escaped("html")))?;
+
+    let mut html = BytesStart::new("html");
+    html.push_attribute(("lang", "en"));
+    writer.write_event(Event::Start(html))?;
+
+    writer.write_event(Event::Start(BytesStart::new("head")))?;
+    let mut meta = BytesStart::new("meta");
+    meta.push_attribute(("charset", "UTF-8"));
+    writer.write_event(Event::Empty(meta))?;
+    write_text_element(writer, "title", title)?;
+    writer.write_event(Event::Start(BytesStart::new("style")))?;
+    writer.write_event(Event::Text(BytesText::from_escaped(css)))?;
+    writer.write_event(Event::End(BytesEnd::new("style")))?;
+    writer.write_event(Event::End(BytesEnd::new("head")))?;
+
+    writer.write_event(Event::Start(BytesStart::new("body")))?;
+    Ok(())
+}
+
+/// Replaces the `images/` package paths that `write_content` emits with data
+/// URIs of the images they came from, or of the SVGs it rendered into
+/// `options`. Linked remote images keep their URL.
+pub(crate) fn inline_quiz_images(html: &str, quiz: &Quiz, base_dir: &Path, options: &ContentOptions) -> Result<String> {
+    let resources = collect_resources(quiz);
+    let mut image_uris: HashMap<String, String> = resources
+        .iter()
+        .filter_map(|src| {
+            let path = package_path(src, &quiz.images)?;
+            let uri = match image_source(src) {
+                ImageSource::Data(uri) => uri.to_string(),
+                _ => data_uri(&path, read_image(src, base_dir, &quiz.images).ok()?),
+            };
+            Some((path, uri))
+        })
+        .collect();
+    for (path, svg) in options.generated.borrow().iter() {
+        image_uris.insert(path.clone(), data_uri(path, svg.clone()));
+    }
+
+    inline_images(html, &image_uris)
+}
+
+pub(crate) fn write_text_element(writer: &mut Writer<Cursor<Vec<u8>>>, name: &str, text: &str) -> Result<()> {
+    writer.write_event(Event::Start(BytesStart::new(name)))?;
+    writer.write_event(Event::Text(BytesText::new(text)))?;
+    writer.write_event(Event::End(BytesEnd::new(name)))?;
+    Ok(())
+}
+
+pub(crate) fn question_type_label(question_type: &QuestionType) -> &'static str {
+    match question_type {
+        QuestionType::MultipleChoice => "Multiple choice",
+        QuestionType::MultipleAnswers => "Multiple answers",
+        QuestionType::TrueFalse => "True/false",
+        QuestionType::ShortAnswer => "Short answer",
+        QuestionType::Essay => "Essay",
+        QuestionType::FileUpload => "File upload",
+    }
+}
+
+fn inline_images(html: &str, image_uris: &HashMap<String, String>) -> Result<String> {
+    let mut reader = quick_xml::Reader::from_str(html);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(Event::Empty(e)) if e.name().as_ref() == b"img" => {
+                writer.write_event(Event::Empty(rewrite_img(&e, image_uris)?))?;
+            }
+            Ok(e) => writer.write_event(e)?,
+            Err(e) => return Err(anyhow::anyhow!("Error parsing preview HTML: {}", e)),
+        }
+    }
+
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+fn rewrite_img(img: &BytesStart, image_uris: &HashMap<String, String>) -> Result<BytesStart<'static>> {
+    let mut rewritten = BytesStart::new("img");
+    for attr in img.attributes() {
+        let attr = attr?;
+        let value = attr.unescape_value()?.into_owned();
+        let key = String::from_utf8(attr.key.as_ref().to_vec())?;
+        if key == "src" {
+            if let Some(data_uri) = image_uris.get(&value) {
+                rewritten.push_attribute(("src", data_uri.as_str()));
+                continue;
+            }
+            if !matches!(image_source(&value), ImageSource::Remote(_)) {
+                eprintln!("Warning: Could not inline image in preview: {}", value);
+            }
+        }
+        rewritten.push_attribute((key.as_str(), value.as_str()));
+    }
+    Ok(rewritten)
+}
+
+fn data_uri(name: &str, bytes: Vec<u8>) -> String {
+    format!(
+        "data:{};base64,{}",
+        mime_type(name),
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    )
+}
@@ -0,0 +1,317 @@
+use anyhow::Result;
+use crate::qti::generator::{write_content, ContentOptions};
+use crate::qti::model::{Question, QuestionType, Quiz};
+use crate::qti::highlight::stylesheet;
+use crate::qti::preview::{inline_quiz_images, write_html_head, write_text_element};
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+pub struct PrintOptions {
+    pub versions: usize,
+    pub seed: Option<u64>,
+}
+
+const PRINT_CSS: &str = "
+body { font-family: Georgia, 'Times New Roman', serif; max-width: 48rem; margin: 1.5rem auto; padding: 0 1rem; color: #000; }
+header { border-bottom: 2px solid #000; margin-bottom: 1rem; }
+header h1 { margin: 0 0 0.25rem 0; }
+.student { display: flex; gap: 2rem; margin: 0.75rem 0; }
+.student span { flex: 1; border-bottom: 1px solid #000; }
+.version { float: right; font-weight: bold; }
+.questions > li { margin: 1.25rem 0; break-inside: avoid; page-break-inside: avoid; }
+.points { font-size: 0.85rem; font-style: italic; }
+.choices { margin-top: 0.5rem; }
+.choices li { margin: 0.2rem 0; }
+.response { border: 1px solid #000; margin-top: 0.5rem; }
+.response.short { height: 4rem; }
+.response.essay { height: 16rem; }
+.key th, .key td { border: 1px solid #000; padding: 0.25rem 0.5rem; text-align: left; vertical-align: top; }
+.key { border-collapse: collapse; width: 100%; }
+img { max-width: 100%; }
+@media print { body { margin: 0; max-width: none; } a { color: inherit; } }
+";
+
+/// One printed version of the exam: the order questions appear in, and for
+/// each of those the order of its choices, as indices into the source quiz.
+struct ExamVersion {
+    label: String,
+    questions: Vec<(usize, Vec<usize>)>,
+}
+
+/// Writes `<stem>-<version>.html` and `<stem>-<version>-key.html` for each
+/// requested version next to the input and returns the paths written.
+///
+/// Without a seed the single version keeps the source order. With a seed,
+/// every version shuffles question order, and choice order when the quiz sets
+/// `shuffle_answers`, so the same seed always reprints the same exams.
+pub fn write_print(quiz: &Quiz, input_path: &Path, options: &PrintOptions) -> Result<Vec<PathBuf>> {
+    if options.versions == 0 || options.versions > 26 {
+        anyhow::bail!("Number of versions must be between 1 and 26");
+    }
+    if options.versions > 1 && options.seed.is_none() {
+        anyhow::bail!("Printing more than one version requires a --seed to shuffle from");
+    }
+
+    let base_dir = input_path.parent().unwrap();
+    let stem = input_path.file_stem().unwrap().to_str().unwrap();
+    let mut written = Vec::new();
+
+    let content_options = ContentOptions::for_quiz(quiz);
+    for i in 0..options.versions {
+        let version = build_version(quiz, i, options.seed);
+
+        let exam = generate_exam(quiz, &version, &content_options)?;
+        let exam = inline_quiz_images(&exam, quiz, base_dir, &content_options)?;
+        let exam_path = input_path.with_file_name(format!("{}-{}.html", stem, version.label));
+        std::fs::write(&exam_path, exam)?;
+        println!("Generated printable exam at: {:?}", exam_path);
+        written.push(exam_path);
+
+        let key = generate_answer_key(quiz, &version, &content_options)?;
+        let key = inline_quiz_images(&key, quiz, base_dir, &content_options)?;
+        let key_path = input_path.with_file_name(format!("{}-{}-key.html", stem, version.label));
+        std::fs::write(&key_path, key)?;
+        println!("Generated answer key at: {:?}", key_path);
+        written.push(key_path);
+    }
+
+    Ok(written)
+}
+
+fn build_version(quiz: &Quiz, index: usize, seed: Option<u64>) -> ExamVersion {
+    let label = choice_label(index);
+    let mut question_order: Vec<usize> = (0..quiz.questions.len()).collect();
+
+    let Some(seed) = seed else {
+        let questions = question_order
+            .into_iter()
+            .map(|q| (q, (0..quiz.questions[q].answers.len()).collect()))
+            .collect();
+        return ExamVersion { label, questions };
+    };
+
+    let mut rng = SplitMix64::new(seed ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    rng.shuffle(&mut question_order);
+
+    let questions = question_order
+        .into_iter()
+        .map(|q| {
+            let question = &quiz.questions[q];
+            let mut choices: Vec<usize> = (0..question.answers.len()).collect();
+            // True/false keeps its natural order even when answers are shuffled.
+            if quiz.shuffle_answers && question.question_type != QuestionType::TrueFalse {
+                rng.shuffle(&mut choices);
+            }
+            (q, choices)
+        })
+        .collect();
+
+    ExamVersion { label, questions }
+}
+
+fn has_choices(question: &Question) -> bool {
+    matches!(
+        question.question_type,
+        QuestionType::MultipleChoice | QuestionType::MultipleAnswers | QuestionType::TrueFalse
+    )
+}
+
+/// `A` to `Z`, then `AA`, `AB` and so on, as spreadsheet columns are named.
+pub(crate) fn choice_label(position: usize) -> String {
+    let mut label = Vec::new();
+    let mut n = position + 1;
+    while n > 0 {
+        n -= 1;
+        label.push(b'A' + (n % 26) as u8);
+        n /= 26;
+    }
+    label.reverse();
+    String::from_utf8(label).unwrap()
+}
+
+fn points_label(points: f32) -> String {
+    if points == 1.0 {
+        "1 point".to_string()
+    } else {
+        format!("{} points", points)
+    }
+}
+
+fn write_exam_header(writer: &mut Writer<Cursor<Vec<u8>>>, quiz: &Quiz, version: &ExamVersion, subtitle: &str) -> Result<()> {
+    writer.write_event(Event::Start(BytesStart::new("header")))?;
+
+    let mut label = BytesStart::new("div");
+    label.push_attribute(("class", "version"));
+    writer.write_event(Event::Start(label))?;
+    writer.write_event(Event::Text(BytesText::new(&format!("Version {}", version.label))))?;
+    writer.write_event(Event::End(BytesEnd::new("div")))?;
+
+    write_text_element(writer, "h1", &format!("{}{}", quiz.title, subtitle))?;
+    if let Some(description) = &quiz.description {
+        write_text_element(writer, "p", description)?;
+    }
+
+    writer.write_event(Event::End(BytesEnd::new("header")))?;
+    Ok(())
+}
+
+fn generate_exam(quiz: &Quiz, version: &ExamVersion, options: &ContentOptions) -> Result<String> {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let css = format!("{}{}", PRINT_CSS, stylesheet(&quiz.code)?);
+    write_html_head(&mut writer, &format!("{} (Version {})", quiz.title, version.label), &css)?;
+    write_exam_header(&mut writer, quiz, version, "")?;
+
+    let mut student = BytesStart::new("div");
+    student.push_attribute(("class", "student"));
+    writer.write_event(Event::Start(student))?;
+    for field in ["Name:", "Date:"] {
+        writer.write_event(Event::Text(BytesText::new(field)))?;
+        writer.write_event(Event::Start(BytesStart::new("span")))?;
+        writer.write_event(Event::End(BytesEnd::new("span")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("div")))?;
+
+    let mut list = BytesStart::new("ol");
+    list.push_attribute(("class", "questions"));
+    writer.write_event(Event::Start(list))?;
+
+    for (q, choices) in &version.questions {
+        let question = &quiz.questions[*q];
+        writer.write_event(Event::Start(BytesStart::new("li")))?;
+
+        write_content(&mut writer, &question.prompt, options)?;
+        writer.write_event(Event::Text(BytesText::new(" ")))?;
+        let mut points = BytesStart::new("span");
+        points.push_attribute(("class", "points"));
+        writer.write_event(Event::Start(points))?;
+        writer.write_event(Event::Text(BytesText::new(&format!("({})", points_label(question.points)))))?;
+        writer.write_event(Event::End(BytesEnd::new("span")))?;
+
+        if has_choices(question) {
+            let mut choice_list = BytesStart::new("ol");
+            choice_list.push_attribute(("class", "choices"));
+            choice_list.push_attribute(("type", "A"));
+            writer.write_event(Event::Start(choice_list))?;
+            for &a in choices {
+                writer.write_event(Event::Start(BytesStart::new("li")))?;
+                write_content(&mut writer, &question.answers[a].text, options)?;
+                writer.write_event(Event::End(BytesEnd::new("li")))?;
+            }
+            writer.write_event(Event::End(BytesEnd::new("ol")))?;
+        } else {
+            let class = match question.question_type {
+                QuestionType::Essay => "response essay",
+                _ => "response short",
+            };
+            if question.question_type == QuestionType::FileUpload {
+                write_text_element(&mut writer, "p", "Submit your work separately.")?;
+            } else {
+                let mut response = BytesStart::new("div");
+                response.push_attribute(("class", class));
+                writer.write_event(Event::Start(response))?;
+                writer.write_event(Event::End(BytesEnd::new("div")))?;
+            }
+        }
+
+        writer.write_event(Event::End(BytesEnd::new("li")))?;
+    }
+
+    writer.write_event(Event::End(BytesEnd::new("ol")))?;
+    writer.write_event(Event::End(BytesEnd::new("body")))?;
+    writer.write_event(Event::End(BytesEnd::new("html")))?;
+
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+fn generate_answer_key(quiz: &Quiz, version: &ExamVersion, options: &ContentOptions) -> Result<String> {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let css = format!("{}{}", PRINT_CSS, stylesheet(&quiz.code)?);
+    write_html_head(&mut writer, &format!("{} (Version {} Key)", quiz.title, version.label), &css)?;
+    write_exam_header(&mut writer, quiz, version, " — Answer Key")?;
+
+    let mut table = BytesStart::new("table");
+    table.push_attribute(("class", "key"));
+    writer.write_event(Event::Start(table))?;
+
+    writer.write_event(Event::Start(BytesStart::new("thead")))?;
+    writer.write_event(Event::Start(BytesStart::new("tr")))?;
+    for heading in ["#", "Answer", "Points"] {
+        let mut th = BytesStart::new("th");
+        th.push_attribute(("scope", "col"));
+        writer.write_event(Event::Start(th))?;
+        writer.write_event(Event::Text(BytesText::new(heading)))?;
+        writer.write_event(Event::End(BytesEnd::new("th")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("tr")))?;
+    writer.write_event(Event::End(BytesEnd::new("thead")))?;
+
+    writer.write_event(Event::Start(BytesStart::new("tbody")))?;
+    let mut total = 0.0;
+    for (number, (q, choices)) in version.questions.iter().enumerate() {
+        let question = &quiz.questions[*q];
+        total += question.points;
+
+        writer.write_event(Event::Start(BytesStart::new("tr")))?;
+        write_text_element(&mut writer, "td", &(number + 1).to_string())?;
+
+        writer.write_event(Event::Start(BytesStart::new("td")))?;
+        let correct: Vec<(usize, usize)> = choices
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| question.answers[**a].is_correct)
+            .map(|(position, a)| (position, *a))
+            .collect();
+        if correct.is_empty() {
+            writer.write_event(Event::Text(BytesText::new("—")))?;
+        }
+        for (i, (position, a)) in correct.iter().enumerate() {
+            if i > 0 {
+                writer.write_event(Event::Empty(BytesStart::new("br")))?;
+            }
+            if has_choices(question) {
+                writer.write_event(Event::Text(BytesText::new(&format!("{}. ", choice_label(*position)))))?;
+            }
+            write_content(&mut writer, &question.answers[*a].text, options)?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("td")))?;
+
+        write_text_element(&mut writer, "td", &question.points.to_string())?;
+        writer.write_event(Event::End(BytesEnd::new("tr")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("tbody")))?;
+    writer.write_event(Event::End(BytesEnd::new("table")))?;
+
+    write_text_element(&mut writer, "p", &format!("Total: {}", points_label(total)))?;
+
+    writer.write_event(Event::End(BytesEnd::new("body")))?;
+    writer.write_event(Event::End(BytesEnd::new("html")))?;
+
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+/// Small fixed PRNG so a seed produces the same exams on every platform and
+/// toolchain, which matters when a version has to be reprinted later.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
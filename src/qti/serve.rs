@@ -0,0 +1,228 @@
+use anyhow::Result;
+use crate::qti::images::{image_source, ImageSource};
+use crate::qti::lexer::{lex_content, Token};
+use crate::qti::{load_quiz, parser};
+use crate::qti::preview::{generate_preview, PreviewOptions};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const VERSION_ROUTE: &str = "/__version";
+/// How long a connection may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request header read; the preview needs only the request line.
+const REQUEST_LIMIT: usize = 16 * 1024;
+
+// Polls the version route and reloads the page once the fingerprint of the
+// quiz and its images changes.
+const RELOAD_SCRIPT: &str = r#"<script>
+(function () {
+  var current = null;
+  setInterval(function () {
+    fetch("/__version", { cache: "no-store" })
+      .then(function (r) { return r.text(); })
+      .then(function (v) {
+        if (current === null) { current = v; }
+        else if (v !== current) { location.reload(); }
+      })
+      .catch(function () {});
+  }, 1000);
+})();
+</script>"#;
+
+/// What was last read from disk, reused until a watched file changes.
+#[derive(Default)]
+struct Cache {
+    /// The quiz, the banks it includes and the images it references, each
+    /// with its modification time when the quiz was last parsed.
+    stamps: Vec<(PathBuf, Option<SystemTime>)>,
+    version: u64,
+    /// The page rendered for `version`.
+    page: Option<String>,
+}
+
+/// Serves a live HTML preview of the quiz on `127.0.0.1:<port>`.
+///
+/// The browser polls a fingerprint of the modification times of the source,
+/// its includes and its images, and reloads itself when it changes. Polls
+/// only check those times; the quiz is re-parsed and the page rendered again
+/// once one of them has changed. Parse errors are shown in the page instead
+/// of stopping the server.
+pub async fn serve_preview(input_path: &Path, options: PreviewOptions, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Serving preview of {:?} at http://127.0.0.1:{}/", input_path, port);
+    println!("Press Ctrl+C to stop.");
+
+    let input_path = Arc::new(input_path.to_path_buf());
+    let options = Arc::new(options);
+    let cache = Arc::new(Mutex::new(Cache::default()));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        // Each connection gets its own task, so a browser holding one open
+        // does not hold up the others.
+        let (input_path, options, cache) = (input_path.clone(), options.clone(), cache.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, input_path, options, cache).await {
+                eprintln!("Error handling preview request: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    input_path: Arc<PathBuf>,
+    options: Arc<PreviewOptions>,
+    cache: Arc<Mutex<Cache>>,
+) -> Result<()> {
+    // A connection kept alive without another request is closed quietly.
+    let Ok(request) = tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await else {
+        return Ok(());
+    };
+    let request = request?;
+
+    let request = String::from_utf8_lossy(&request);
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/");
+    // Cache-busting queries such as `/?t=1` still get the page.
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+
+    // Reading and parsing files blocks, so it is kept off the async workers.
+    let (status, content_type, body) = match path {
+        "/" | "/index.html" => {
+            let page = tokio::task::spawn_blocking(move || cached_page(&input_path, &options, &cache)).await?;
+            ("200 OK", "text/html; charset=utf-8", page)
+        }
+        VERSION_ROUTE => {
+            let version = tokio::task::spawn_blocking(move || fingerprint(&input_path, &mut cache.lock().unwrap())).await?;
+            ("200 OK", "text/plain", version.to_string())
+        }
+        _ => ("404 Not Found", "text/plain", "Not found".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads up to the end of the request headers, or `REQUEST_LIMIT` bytes.
+async fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < REQUEST_LIMIT {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(request)
+}
+
+/// The page for the current fingerprint, rendered again only after a
+/// change, so warnings are printed once per edit rather than per load.
+fn cached_page(input_path: &Path, options: &PreviewOptions, cache: &Mutex<Cache>) -> String {
+    let mut cache = cache.lock().unwrap();
+    fingerprint(input_path, &mut cache);
+    cache.page.get_or_insert_with(|| render_page(input_path, options)).clone()
+}
+
+fn render_page(input_path: &Path, options: &PreviewOptions) -> String {
+    let html = load_quiz(input_path)
+        .and_then(|quiz| generate_preview(&quiz, input_path, options))
+        .unwrap_or_else(|e| error_page(input_path, &e));
+
+    match html.rfind("</body>") {
+        Some(pos) => format!("{}{}{}", &html[..pos], RELOAD_SCRIPT, &html[pos..]),
+        None => format!("{}{}", html, RELOAD_SCRIPT),
+    }
+}
+
+fn error_page(input_path: &Path, error: &anyhow::Error) -> String {
+    format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\"/><title>Preview error</title></head>\
+         <body style=\"font-family: system-ui, sans-serif; margin: 2rem;\">\
+         <h1 style=\"color: #b3261e;\">Could not render {}</h1>\
+         <pre style=\"background: #fdecea; padding: 1rem; white-space: pre-wrap;\">{}</pre></body></html>",
+        escape_html(&input_path.display().to_string()),
+        escape_html(&format!("{:#}", error))
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
+
+/// Hashes the modification times of the quiz file, its includes and the
+/// images it references, so any edit to one changes the value. The list of
+/// files is kept in `cache` and only read from the quiz again when one of
+/// them has changed; the cached page is dropped then too.
+fn fingerprint(input_path: &Path, cache: &mut Cache) -> u64 {
+    let unchanged = !cache.stamps.is_empty() && cache.stamps.iter().all(|(path, modified)| modified_time(path) == *modified);
+    if unchanged {
+        return cache.version;
+    }
+    cache.stamps = watched_paths(input_path)
+        .into_iter()
+        .map(|path| {
+            let modified = modified_time(&path);
+            (path, modified)
+        })
+        .collect();
+    let mut hasher = DefaultHasher::new();
+    cache.stamps.hash(&mut hasher);
+    let version = hasher.finish();
+    if version != cache.version {
+        cache.page = None;
+    }
+    cache.version = version;
+    version
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn watched_paths(input_path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![input_path.to_path_buf()];
+    let base_dir = input_path.parent().unwrap_or(Path::new("."));
+
+    let Ok(content) = std::fs::read_to_string(input_path) else {
+        return paths;
+    };
+    let (Some(quiz), _) = parser::check_quiz(&content, input_path) else {
+        return paths;
+    };
+
+    for question in &quiz.questions {
+        if let Some(source) = &question.source {
+            paths.push(source.clone());
+        }
+        let texts = std::iter::once(&question.prompt).chain(question.answers.iter().map(|a| &a.text));
+        for text in texts {
+            for token in lex_content(text) {
+                if let Token::Image { src, .. } = token
+                    && let ImageSource::File(path) = image_source(&src)
+                {
+                    paths.push(base_dir.join(path));
+                }
+            }
+        }
+    }
+
+    paths
+}
@@ -0,0 +1,337 @@
+use anyhow::{anyhow, bail, Result};
+use crate::qti::diagnostics::Diagnostic;
+use crate::qti::lexer::{is_verify_info, lex_content, Token};
+use crate::qti::model::{Question, Quiz};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Runs code blocks marked `verify` and checks that the question's marked
+// answer is what the code prints, so "what does this print?" answers cannot
+// go stale when the code is edited.
+//
+// Snippets only run when `convert --verify` is given. Each runs in its own
+// empty temporary directory, which is also its HOME, with a scrubbed
+// environment, no stdin and a time limit. On Linux it also runs in a new
+// user and network namespace when the system allows it, so it cannot reach
+// the network. It still runs as the user with their file permissions, so
+// this keeps honest mistakes contained; it is not a defence against hostile
+// code.
+
+/// Time allowed to compile a snippet, or to compile and run it for
+/// toolchains that do both in one step.
+const COMPILE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time allowed to run a compiled snippet.
+const RUN_TIMEOUT: Duration = Duration::from_secs(10);
+/// A snippet writing more than this is stopped, so a runaway loop cannot
+/// fill memory.
+const OUTPUT_LIMIT: u64 = 1 << 20;
+/// Variables passed through to toolchains, which need them to find their
+/// installations; everything else is cleared.
+const ENVIRONMENT: &[&str] = &[
+    "PATH",
+    "LANG",
+    "SYSTEMROOT",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "CARGO_HOME",
+    "PYENV_ROOT",
+    "PYENV_VERSION",
+    "JAVA_HOME",
+];
+/// Toolchain directories that default to a path under HOME. HOME is the
+/// snippet's directory, so these are set from the real HOME when unset.
+const HOME_DEFAULTS: &[(&str, &str)] = &[("RUSTUP_HOME", ".rustup"), ("CARGO_HOME", ".cargo"), ("PYENV_ROOT", ".pyenv")];
+const LANGUAGES: &[&str] = &["python", "rust", "java"];
+
+#[derive(Clone, Copy)]
+enum Toolchain {
+    Python,
+    Rust,
+    Java,
+}
+
+impl Toolchain {
+    fn for_lang(lang: &str) -> Option<Self> {
+        // Quarto's executable cells are written ```{python}.
+        match lang.trim_start_matches('{').trim_end_matches('}') {
+            "python" | "python3" | "py" => Some(Self::Python),
+            "rust" | "rs" => Some(Self::Rust),
+            "java" => Some(Self::Java),
+            _ => None,
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Self::Python => "python3",
+            Self::Rust => "rustc",
+            Self::Java => "java",
+        }
+    }
+}
+
+/// Runs every `verify` code block in the quiz and checks each against its
+/// question's marked answers. Fails with the mismatches rendered against the
+/// source file, or the included file a question is written in.
+pub(crate) fn verify_quiz(quiz: &Quiz, input_path: &Path) -> Result<()> {
+    let mut sources: HashMap<&Path, String> = HashMap::new();
+    let mut message = String::new();
+    let mut errors = 0;
+    for question in &quiz.questions {
+        let path = question.source.as_deref().unwrap_or(input_path);
+        if !sources.contains_key(path) {
+            sources.insert(path, std::fs::read_to_string(path)?);
+        }
+        let source = &sources[path];
+        let mut diagnostics = Vec::new();
+        verify_question(question, source, &mut diagnostics);
+        for diagnostic in &diagnostics {
+            message.push_str(&diagnostic.render(&path.display().to_string(), source));
+            message.push('\n');
+        }
+        errors += diagnostics.len();
+    }
+    if errors == 0 {
+        return Ok(());
+    }
+
+    message.push_str(&format!(
+        "could not verify {} due to {} previous error{}",
+        input_path.display(),
+        errors,
+        if errors == 1 { "" } else { "s" }
+    ));
+    Err(anyhow!("{}", message))
+}
+
+fn verify_question(question: &Question, source: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let blocks = lex_content(&question.prompt).into_iter().filter_map(|token| match token {
+        Token::Code { lang, content, verify: true, .. } => Some((lang, content)),
+        _ => None,
+    });
+    let mut fences = fence_lines(question, source);
+
+    for (lang, content) in blocks {
+        let (line, column, length) = fences.next().unwrap_or((question.line, 1, 3));
+        let Some(toolchain) = Toolchain::for_lang(&lang) else {
+            diagnostics.push(
+                Diagnostic::error(format!("cannot verify `{}` code", lang), line, column, length)
+                    .with_hint(format!("verify supports: {}", LANGUAGES.join(", "))),
+            );
+            continue;
+        };
+        let output = match run_snippet(toolchain, &content) {
+            Ok(output) => output,
+            Err(e) => {
+                let mut message = e.to_string();
+                let detail = message.split_off(message.find('\n').unwrap_or(message.len()));
+                let mut diagnostic = Diagnostic::error(format!("verified {} code failed: {}", lang, message), line, column, length);
+                if !detail.trim().is_empty() {
+                    diagnostic = diagnostic.with_hint(detail.trim().to_string());
+                }
+                diagnostics.push(diagnostic);
+                continue;
+            }
+        };
+
+        let printed = normalize(&output);
+        if question.answers.iter().any(|a| a.is_correct && normalize(&a.text) == printed) {
+            continue;
+        }
+        let Some(marked) = question.answers.iter().find(|a| a.is_correct) else {
+            diagnostics.push(
+                Diagnostic::error("verified code has no marked answer to check", line, column, length)
+                    .with_hint(format!("the code prints `{}`; mark it with `- [x]`", printed)),
+            );
+            continue;
+        };
+        let answer_line = source.lines().nth(marked.line - 1).unwrap_or("");
+        let answer_column = answer_line.find(marked.text.as_str()).map_or(1, |offset| answer_line[..offset].chars().count() + 1);
+        let hint = match question.answers.iter().find(|a| normalize(&a.text) == printed) {
+            Some(unmarked) => format!("the code prints `{}`, which is the choice on line {}", printed, unmarked.line),
+            None => format!("the code prints `{}`", printed),
+        };
+        diagnostics.push(
+            Diagnostic::error("marked answer does not match the verified code's output", marked.line, answer_column, marked.text.chars().count().max(1))
+                .with_hint(hint),
+        );
+    }
+}
+
+/// Line, column and length of each `verify` fence opening in the question's
+/// block, in order.
+fn fence_lines<'a>(question: &Question, source: &'a str) -> impl Iterator<Item = (usize, usize, usize)> + 'a {
+    let first = question.line;
+    source
+        .lines()
+        .enumerate()
+        .skip(first)
+        .take_while(|(_, line)| line.trim() != ":::")
+        .filter(|(_, line)| {
+            let trimmed = line.trim_start();
+            let fence = match trimmed {
+                _ if trimmed.starts_with("```") => '`',
+                _ if trimmed.starts_with("~~~") => '~',
+                _ => return false,
+            };
+            is_verify_info(trimmed.trim_start_matches(fence).trim())
+        })
+        .map(|(i, line)| {
+            let indent = line.len() - line.trim_start().len();
+            (i + 1, indent + 1, line.trim().chars().count())
+        })
+}
+
+/// Answers are one line, so output is compared with runs of whitespace,
+/// including line breaks, collapsed to single spaces. An answer written as
+/// inline code is compared without its backticks.
+fn normalize(text: &str) -> String {
+    let text = text.trim();
+    let text = text.strip_prefix('`').and_then(|t| t.strip_suffix('`')).unwrap_or(text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Runs `code` with the toolchain in a fresh directory and returns what it
+/// printed to stdout.
+fn run_snippet(toolchain: Toolchain, code: &str) -> Result<String> {
+    which::which(toolchain.program()).map_err(|_| anyhow!("`{}` was not found on PATH", toolchain.program()))?;
+
+    let dir = std::env::temp_dir().join(format!("brightspace-verify-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let result = run_in(toolchain, code, &dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn run_in(toolchain: Toolchain, code: &str, dir: &Path) -> Result<String> {
+    match toolchain {
+        Toolchain::Python => {
+            std::fs::write(dir.join("main.py"), code)?;
+            // -I ignores the user's site-packages and PYTHON* variables.
+            run(dir, &["python3", "-I", "main.py"], RUN_TIMEOUT)
+        }
+        Toolchain::Rust => {
+            std::fs::write(dir.join("main.rs"), code)?;
+            run(dir, &["rustc", "--edition", "2021", "--error-format=short", "-o", "main", "main.rs"], COMPILE_TIMEOUT)?;
+            let binary = dir.join(format!("main{}", std::env::consts::EXE_SUFFIX));
+            run(dir, &[binary.to_str().ok_or_else(|| anyhow!("temporary directory is not valid UTF-8"))?], RUN_TIMEOUT)
+        }
+        Toolchain::Java => {
+            // The source launcher compiles and runs a single file in one step.
+            std::fs::write(dir.join("Main.java"), code)?;
+            run(dir, &["java", "-Xmx256m", "Main.java"], COMPILE_TIMEOUT)
+        }
+    }
+}
+
+/// Runs `args` in `dir`, returning stdout. Fails with stderr when the
+/// command exits unsuccessfully or outlives `timeout`.
+fn run(dir: &Path, args: &[&str], timeout: Duration) -> Result<String> {
+    let mut command = if network_sandbox() {
+        let mut command = Command::new("unshare");
+        command.args(["--map-root-user", "--net", "--"]).args(args);
+        command
+    } else {
+        let mut command = Command::new(args[0]);
+        command.args(&args[1..]);
+        command
+    };
+    command
+        .current_dir(dir)
+        .env_clear()
+        .envs(ENVIRONMENT.iter().filter_map(|key| std::env::var_os(key).map(|value| (key, value))))
+        .env("HOME", dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if let Some(home) = std::env::home_dir() {
+        for (key, default) in HOME_DEFAULTS {
+            if std::env::var_os(key).is_none() {
+                command.env(key, home.join(default));
+            }
+        }
+    }
+
+    let mut child = command.spawn()?;
+    let overflowed = Arc::new(AtomicBool::new(false));
+    let stdout = read_pipe(child.stdout.take(), overflowed.clone());
+    let stderr = read_pipe(child.stderr.take(), overflowed.clone());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if overflowed.load(Ordering::Relaxed) {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("`{}` exceeded the output limit of {} MiB", args[0], OUTPUT_LIMIT >> 20);
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("`{}` did not finish within {} seconds", args[0], timeout.as_secs());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        // Tracebacks end with the cause, so keep the end of stderr.
+        // Paths are shown relative to the temporary directory.
+        let stderr = stderr.replace(&format!("{}{}", dir.display(), std::path::MAIN_SEPARATOR), "");
+        let lines: Vec<&str> = stderr.trim_end().lines().collect();
+        let tail = lines[lines.len().saturating_sub(12)..].join("\n");
+        bail!("`{}` exited with {}\n{}", args[0], status, tail);
+    }
+    Ok(stdout)
+}
+
+/// Reads a child's pipe on its own thread, so a full pipe cannot stall the
+/// child while we wait for it. Past `OUTPUT_LIMIT` the rest is read and
+/// discarded, and `overflowed` is set.
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>, overflowed: Arc<AtomicBool>) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = (&mut pipe).take(OUTPUT_LIMIT).read_to_end(&mut buffer);
+            let mut discard = [0; 8192];
+            loop {
+                match pipe.read(&mut discard) {
+                    Ok(0) => break,
+                    Ok(_) => overflowed.store(true, Ordering::Relaxed),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    })
+}
+
+/// Whether snippets can run without network access, checked once. Needs
+/// Linux with unprivileged user namespaces.
+fn network_sandbox() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let available = cfg!(target_os = "linux")
+            && Command::new("unshare")
+                .args(["--map-root-user", "--net", "true"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success());
+        if !available {
+            eprintln!("Warning: network isolation is unavailable; verified code will run with network access");
+        }
+        available
+    })
+}